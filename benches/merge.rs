@@ -0,0 +1,85 @@
+//! Compares the merge strategies on a wide merge of in-memory inputs, and with the `fadvise`
+//! feature, merging files with and without access hints.
+//!
+//! Run with `cargo bench`.
+
+use std::hint;
+use std::io;
+use std::time;
+
+use merge_sorted_files_rs::*;
+
+const LINES_PER_INPUT: usize = 2_000;
+
+fn inputs(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| {
+            (0..LINES_PER_INPUT)
+                .map(|j| format!("{:010}\n", j * count + i))
+                .collect()
+        })
+        .collect()
+}
+
+fn bench(strategy: Strategy, inputs: &[String]) -> io::Result<time::Duration> {
+    let start = time::Instant::now();
+    let mut heap = Heap::new().with_strategy(strategy);
+    for (i, input) in inputs.iter().enumerate() {
+        heap.add_reader(i.to_string(), input.as_bytes())?;
+    }
+    for line in heap {
+        hint::black_box(line?);
+    }
+    Ok(start.elapsed())
+}
+
+/// Merge `inputs` written to files, advising the kernel how they are read if `hints` is set.
+#[cfg(feature = "fadvise")]
+fn bench_files(paths: &[std::path::PathBuf], hints: bool) -> io::Result<time::Duration> {
+    let start = time::Instant::now();
+    let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new().with_access_hints(hints);
+    for path in paths {
+        heap.add_file(path)?;
+    }
+    for line in heap {
+        hint::black_box(line?);
+    }
+    Ok(start.elapsed())
+}
+
+fn main() -> io::Result<()> {
+    for &count in &[2, 16, 128, 512] {
+        let inputs = inputs(count);
+        for &strategy in &[Strategy::Heap, Strategy::LoserTree] {
+            let elapsed = bench(strategy, &inputs)?;
+            let lines = (count * LINES_PER_INPUT) as f64;
+            println!(
+                "{:>4} inputs {:<12} {:>8.2?} {:>8.1} ns/line",
+                count,
+                format!("{:?}", strategy),
+                elapsed,
+                elapsed.as_nanos() as f64 / lines
+            );
+        }
+    }
+    #[cfg(feature = "fadvise")]
+    {
+        let dir = std::env::temp_dir();
+        let paths: Vec<_> = inputs(128)
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let path = dir.join(format!("merge-sorted-files-rs-bench-{}", i));
+                std::fs::write(&path, input).map(|_| path)
+            })
+            .collect::<io::Result<_>>()?;
+        for &hints in &[false, true] {
+            let elapsed = bench_files(&paths, hints)?;
+            println!(" 128 files  hints={:<5}  {:>8.2?}", hints, elapsed);
+        }
+        for path in paths {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,570 @@
+//! Merging the members of ZIP and TAR archives, e.g. of sorted shards bundled into one file,
+//! without unpacking them.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::path;
+use std::sync;
+
+use crate::{input, Heap};
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END: u32 = 0x0605_4b50;
+/// The size of the end of central directory record of a ZIP archive, without its comment.
+const ZIP_END_LEN: usize = 22;
+
+const TAR_BLOCK: u64 = 512;
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A member stored in an archive file, read through a handle shared with the other members, so
+/// that merging thousands of them doesn't take thousands of file descriptors.
+struct Member {
+    file: sync::Arc<sync::Mutex<fs::File>>,
+    pos: u64,
+    end: u64,
+}
+
+impl io::Read for Member {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = usize::try_from(self.end - self.pos).unwrap_or(usize::MAX);
+        let len = buf.len().min(left);
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        file.seek(io::SeekFrom::Start(self.pos))?;
+        let read = file.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Archive ends within a member",
+            ));
+        }
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// A member of a ZIP archive, as its central directory lists it.
+struct ZipEntry {
+    name: String,
+    flags: u16,
+    method: u16,
+    compressed_size: u64,
+    header_offset: u64,
+}
+
+/// The files in the ZIP archive `file`, in the order of its central directory.
+fn zip_entries(file: &mut fs::File) -> io::Result<Vec<ZipEntry>> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    // The end record is followed by a comment of up to 64 KiB.
+    let tail_len = len.min((ZIP_END_LEN + 0xffff) as u64);
+    file.seek(io::SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = (0..=tail.len().saturating_sub(ZIP_END_LEN))
+        .rev()
+        .find(|&at| tail.len() >= at + ZIP_END_LEN && u32_at(&tail, at) == ZIP_END)
+        .ok_or_else(|| invalid_data("No end of central directory in ZIP archive".to_string()))?;
+    let count = u16_at(&tail, end + 10);
+    let directory_len = u32_at(&tail, end + 12);
+    let directory_offset = u32_at(&tail, end + 16);
+    if count == u16::MAX || directory_offset == u32::MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ZIP64 archives are not supported",
+        ));
+    }
+    file.seek(io::SeekFrom::Start(u64::from(directory_offset)))?;
+    let mut directory = vec![0; directory_len as usize];
+    file.read_exact(&mut directory)?;
+    let mut entries = Vec::with_capacity(usize::from(count));
+    let mut at = 0;
+    for _ in 0..count {
+        if directory.len() < at + 46 || u32_at(&directory, at) != ZIP_CENTRAL_HEADER {
+            return Err(invalid_data(
+                "Invalid central directory in ZIP archive".to_string(),
+            ));
+        }
+        let name_len = usize::from(u16_at(&directory, at + 28));
+        let extra_len = usize::from(u16_at(&directory, at + 30));
+        let comment_len = usize::from(u16_at(&directory, at + 32));
+        let name = directory
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid_data("Invalid central directory in ZIP archive".to_string()))?;
+        let entry = ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            flags: u16_at(&directory, at + 8),
+            method: u16_at(&directory, at + 10),
+            compressed_size: u64::from(u32_at(&directory, at + 20)),
+            header_offset: u64::from(u32_at(&directory, at + 42)),
+        };
+        at += 46 + name_len + extra_len + comment_len;
+        if !entry.name.ends_with('/') {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Open `entry` of the ZIP archive `file`, inflating it if it is deflated.
+fn open_zip_entry(
+    file: &sync::Arc<sync::Mutex<fs::File>>,
+    entry: &ZipEntry,
+) -> io::Result<Box<dyn io::Read + Send>> {
+    if entry.flags & 1 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Encrypted ZIP members are not supported",
+        ));
+    }
+    if entry.compressed_size == u64::from(u32::MAX) || entry.header_offset == u64::from(u32::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ZIP64 archives are not supported",
+        ));
+    }
+    // The local header's name and extra field can differ in length from the central directory's.
+    let mut header = [0; 30];
+    {
+        let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+        file.seek(io::SeekFrom::Start(entry.header_offset))?;
+        file.read_exact(&mut header)?;
+    }
+    if u32_at(&header, 0) != ZIP_LOCAL_HEADER {
+        return Err(invalid_data(
+            "Invalid local header in ZIP archive".to_string(),
+        ));
+    }
+    let pos =
+        entry.header_offset + 30 + u64::from(u16_at(&header, 26)) + u64::from(u16_at(&header, 28));
+    let member = Member {
+        file: file.clone(),
+        pos,
+        end: pos + entry.compressed_size,
+    };
+    match entry.method {
+        0 => Ok(Box::new(member)),
+        8 => Ok(Box::new(flate2::read::DeflateDecoder::new(member))),
+        method => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("ZIP compression method {} is not supported", method),
+        )),
+    }
+}
+
+/// Read the next block of a TAR archive, returning false at the end of `r`.
+fn read_block<R: io::Read>(r: &mut R, block: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match r.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "TAR archive ends within a header",
+                ))
+            }
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+/// The octal number in a field of a TAR header, or the big-endian one if its top bit is set, as
+/// GNU tar writes sizes of 8 GiB and more.
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..].iter().try_fold(0u64, |n, byte| {
+            n.checked_mul(256)?.checked_add(u64::from(*byte))
+        });
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// The text of a NUL-terminated field of a TAR header.
+fn tar_text(field: &[u8]) -> String {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// The `path` in the records of a pax extended header, if it has one.
+fn pax_path(mut records: &[u8]) -> Option<String> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|byte| *byte == b' ')?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+/// Walk the members of the TAR archive `r`, calling `visit` with the name, offset and size of
+/// each regular file. `visit` returns how much of the file it read, and `skip` skips that much
+/// less than the rest of the file and its padding.
+fn walk_tar<R, S, V>(r: &mut R, mut skip: S, mut visit: V) -> io::Result<()>
+where
+    R: io::Read,
+    S: FnMut(&mut R, u64) -> io::Result<()>,
+    V: FnMut(&mut R, String, u64, u64) -> io::Result<u64>,
+{
+    let mut block = [0; TAR_BLOCK as usize];
+    let mut pos = 0;
+    // The name given to the next member by a GNU long name or pax extended header.
+    let mut next_name = None;
+    while read_block(r, &mut block)? {
+        let header = pos;
+        pos += TAR_BLOCK;
+        if block.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let checksum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u64::from(*byte)
+                }
+            })
+            .sum();
+        let size = match (tar_number(&block[148..156]), tar_number(&block[124..136])) {
+            (Some(expected), Some(size)) if expected == checksum => size,
+            _ => {
+                return Err(invalid_data(format!(
+                    "Invalid TAR header at byte {}",
+                    header
+                )))
+            }
+        };
+        let read = match block[156] {
+            kind @ (b'L' | b'x') => {
+                let mut data = Vec::new();
+                r.take(size).read_to_end(&mut data)?;
+                if data.len() as u64 != size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "TAR archive ends within a member",
+                    ));
+                }
+                next_name = if kind == b'L' {
+                    Some(tar_text(&data))
+                } else {
+                    pax_path(&data).or(next_name)
+                };
+                size
+            }
+            b'0' | b'7' | 0 => {
+                let name = next_name.take().unwrap_or_else(|| {
+                    let name = tar_text(&block[..100]);
+                    let prefix = tar_text(&block[345..500]);
+                    if &block[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{}/{}", prefix, name)
+                    } else {
+                        name
+                    }
+                });
+                visit(r, name, pos, size)?
+            }
+            _ => {
+                next_name = None;
+                0
+            }
+        };
+        let padded = size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        skip(r, padded - read)?;
+        pos += padded;
+    }
+    Ok(())
+}
+
+/// Skip `len` bytes of `r`, which can't seek.
+fn skip_stream<R: io::Read>(r: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "TAR archive ends within a member",
+        ));
+    }
+    Ok(())
+}
+
+/// The members of the archive file at `path` that `members` matches, with readers over them.
+type Members = Vec<(String, Box<dyn io::Read + Send>)>;
+
+/// Open the members of the archive at `path` that `members` matches, returning an error opening
+/// one alongside its name.
+fn open_members(
+    path: &path::Path,
+    members: Option<&glob::Pattern>,
+) -> io::Result<(Members, Vec<(String, io::Error)>)> {
+    let matches = |name: &str| members.is_none_or(|pattern| pattern.matches(name));
+    let mut file = fs::File::open(path)?;
+    let mut magic = [0; 4];
+    let is_zip = match file.read_exact(&mut magic) {
+        Ok(()) => magic == ZIP_LOCAL_HEADER.to_le_bytes() || magic == ZIP_END.to_le_bytes(),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err),
+    };
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut opened = Vec::new();
+    let mut failed = Vec::new();
+    if is_zip {
+        let entries = zip_entries(&mut file)?;
+        let file = sync::Arc::new(sync::Mutex::new(file));
+        for entry in entries.iter().filter(|entry| matches(&entry.name)) {
+            match open_zip_entry(&file, entry) {
+                Ok(reader) => opened.push((entry.name.clone(), reader)),
+                Err(err) => failed.push((entry.name.clone(), err)),
+            }
+        }
+    } else if input::is_compressed(&mut file)? {
+        // A compressed archive can only be read from the start, so its members are read into
+        // memory as it is.
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let mut reader = input::Input::detect(file)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let mut reader = input::Input::plain(file);
+        walk_tar(&mut reader, skip_stream, |r, name, _, size| {
+            if !matches(&name) {
+                return Ok(0);
+            }
+            let mut data = Vec::with_capacity(usize::try_from(size).unwrap_or(0));
+            r.take(size).read_to_end(&mut data)?;
+            let read = data.len() as u64;
+            opened.push((
+                name,
+                Box::new(io::Cursor::new(data)) as Box<dyn io::Read + Send>,
+            ));
+            Ok(read)
+        })?;
+    } else {
+        let shared = sync::Arc::new(sync::Mutex::new(file.try_clone()?));
+        let mut r = io::BufReader::new(file);
+        walk_tar(
+            &mut r,
+            |r, len| r.seek_relative(i64::try_from(len).unwrap_or(i64::MAX)),
+            |_, name, pos, size| {
+                if matches(&name) {
+                    let member = Member {
+                        file: shared.clone(),
+                        pos,
+                        end: pos + size,
+                    };
+                    opened.push((name, Box::new(member)));
+                }
+                Ok(0)
+            },
+        )?;
+    }
+    Ok((opened, failed))
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Add each member of the ZIP or TAR archive at `path` whose name `members` matches, or every
+    /// file in it, in the order they are in the archive, returning how many were added. Members
+    /// are named `archive:member`, and are decompressed if compression support is enabled, as is
+    /// a compressed TAR archive, e.g. a `.tar.gz`. The members of an uncompressed archive are
+    /// streamed from it as they are merged, while those of a compressed TAR archive are read into
+    /// memory up front. ZIP members must be stored or deflated. An error opening the archive, or
+    /// a member, is handled as for `add_path`.
+    pub fn add_archive(
+        &mut self,
+        path: &path::Path,
+        members: Option<&glob::Pattern>,
+    ) -> io::Result<usize> {
+        let archive = path.display().to_string();
+        let (opened, failed) = match open_members(path, members) {
+            Ok(members) => members,
+            Err(err) => return self.merge.failed_to_add(&archive, err).map(|()| 0),
+        };
+        for (member, err) in failed {
+            self.merge
+                .failed_to_add(&format!("{}:{}", archive, member), err)?;
+        }
+        let mut added = 0;
+        for (member, reader) in opened {
+            let name = format!("{}:{}", archive, member);
+            match self.add_detected(name.clone(), reader, 0) {
+                Ok(()) => added += 1,
+                Err(err) => self.merge.failed_to_add(&name, err)?,
+            }
+        }
+        Ok(added)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A TAR archive of `files`, the first with a name too long for its header.
+    fn tar(files: &[(&str, &str)]) -> Vec<u8> {
+        fn header(name: &str, kind: u8, size: usize) -> [u8; 512] {
+            let mut block = [0; 512];
+            block[..name.len()].copy_from_slice(name.as_bytes());
+            block[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+            block[156] = kind;
+            block[148..156].fill(b' ');
+            let checksum: u32 = block.iter().map(|byte| u32::from(*byte)).sum();
+            block[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+            block
+        }
+        let mut archive = Vec::new();
+        let mut add = |name: &str, kind: u8, data: &[u8]| {
+            archive.extend_from_slice(&header(name, kind, data.len()));
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().div_ceil(512) * 512, 0);
+        };
+        add("dir/", b'5', b"");
+        for (i, (name, contents)) in files.iter().enumerate() {
+            if i == 0 {
+                add("././@LongLink", b'L', format!("{}\0", name).as_bytes());
+                add("truncated", b'0', contents.as_bytes());
+            } else {
+                add(name, b'0', contents.as_bytes());
+            }
+        }
+        archive.extend_from_slice(&[0; 1024]);
+        archive
+    }
+
+    /// A ZIP archive of `files`, deflating every other one.
+    fn zip(files: &[(&str, &str)]) -> io::Result<Vec<u8>> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (i, (name, contents)) in files.iter().enumerate() {
+            let method: u16 = if i % 2 == 1 { 8 } else { 0 };
+            let data = if method == 8 {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(contents.as_bytes())?;
+                encoder.finish()?
+            } else {
+                contents.as_bytes().to_vec()
+            };
+            let offset = archive.len() as u32;
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 8]); // Time, date and CRC.
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+            archive.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+            archive.extend_from_slice(&[20, 0, 0, 0]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&data);
+            directory.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]); // Comment length, disk and attributes.
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&ZIP_END.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&offset.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        Ok(archive)
+    }
+
+    #[test]
+    fn test_add_archive() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-archive-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir)?;
+        let long = format!("shards/{}", "long-".repeat(30));
+        let files = [
+            (long.as_str(), "a\nd\n"),
+            ("shards/1.txt", "b\ne\n"),
+            ("shards/2.txt", "c\nf\n"),
+            ("README", "not sorted\n"),
+        ];
+        let pattern = glob::Pattern::new("shards/*").unwrap();
+        for (name, archive) in [("shards.tar", tar(&files)), ("shards.zip", zip(&files)?)] {
+            let path = dir.join(name);
+            fs::write(&path, archive)?;
+            let mut heap = Heap::new();
+            assert_eq!(heap.add_archive(&path, Some(&pattern))?, 3);
+            let lines = heap.collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(lines, vec!["a", "b", "c", "d", "e", "f"], "{}", name);
+
+            let mut heap = Heap::new();
+            assert_eq!(heap.add_archive(&path, None)?, 4);
+            assert_eq!(heap.count(), 7);
+        }
+
+        let err = Heap::new()
+            .add_archive(&dir.join("missing.zip"), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("missing.zip]"), "{}", err);
+        fs::write(dir.join("text.txt"), "a\n".repeat(300))?;
+        let err = Heap::new()
+            .add_archive(&dir.join("text.txt"), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid TAR header"), "{}", err);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_add_compressed_archive() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-archive-{}.tar.gz",
+            std::process::id()
+        ));
+        let mut encoder =
+            flate2::write::GzEncoder::new(fs::File::create(&path)?, flate2::Compression::default());
+        encoder.write_all(&tar(&[("0", "a\nc\n"), ("1", "b\n")]))?;
+        encoder.finish()?;
+        let mut heap = Heap::new();
+        assert_eq!(heap.add_archive(&path, None)?, 2);
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c"]);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
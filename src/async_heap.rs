@@ -0,0 +1,207 @@
+//! An asynchronous counterpart of `Heap` over tokio readers, for merging sorted data as it
+//! arrives without blocking a thread per input.
+
+use std::cmp;
+use std::collections;
+use std::io;
+use std::sync;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
+
+use crate::error::{in_file, out_of_order_error};
+use crate::Order;
+
+/// Merges the lines of sorted async readers. Lines are compared lexicographically, in the
+/// direction set with `with_order`, and ties are broken by the order readers were added in.
+pub struct AsyncHeap<T> {
+    heap: collections::BinaryHeap<AsyncHead<T>>,
+    order: Order,
+    next_index: usize,
+}
+
+impl<T> Default for AsyncHeap<T>
+where
+    T: AsyncBufRead + Unpin,
+{
+    fn default() -> AsyncHeap<T> {
+        AsyncHeap::new()
+    }
+}
+
+impl<T> AsyncHeap<T>
+where
+    T: AsyncBufRead + Unpin,
+{
+    pub fn new() -> AsyncHeap<T> {
+        AsyncHeap {
+            heap: collections::BinaryHeap::new(),
+            order: Order::Asc,
+            next_index: 0,
+        }
+    }
+
+    /// Set the direction the inputs are sorted in.
+    pub fn with_order(mut self, order: Order) -> AsyncHeap<T> {
+        self.order = order;
+        let heads = self.heap.drain().collect::<Vec<_>>();
+        for mut head in heads {
+            head.order = order;
+            self.heap.push(head);
+        }
+        self
+    }
+
+    /// Add a reader to the merge, waiting for its first line. `filename` identifies it in error
+    /// messages.
+    pub async fn add_reader(&mut self, filename: String, mut reader: T) -> io::Result<()> {
+        let mut line = String::new();
+        let index = self.next_index;
+        self.next_index += 1;
+        let first = read_line(&mut reader, &mut line).await;
+        if first.map_err(|err| in_file(err, &filename))? {
+            self.heap.push(AsyncHead {
+                name: sync::Arc::from(filename),
+                reader,
+                line,
+                line_no: 1,
+                index,
+                order: self.order,
+            });
+        }
+        Ok(())
+    }
+
+    /// Wait for the next merged line, or return `None` once every reader is exhausted.
+    pub async fn next(&mut self) -> Option<io::Result<String>> {
+        let mut head = self.heap.pop()?;
+        let mut next = String::new();
+        match read_line(&mut head.reader, &mut next).await {
+            Ok(true) => {}
+            Ok(false) => return Some(Ok(head.line)),
+            Err(err) => return Some(Err(in_file(err, &head.name))),
+        }
+        head.line_no += 1;
+        let line = std::mem::replace(&mut head.line, next);
+        let out_of_order = head.compare(&head.line, &line) == cmp::Ordering::Less;
+        let err = if out_of_order {
+            Some(out_of_order_error(
+                &head.name,
+                head.line_no,
+                Some(line.clone()),
+                Some(head.line.clone()),
+            ))
+        } else {
+            None
+        };
+        self.heap.push(head);
+        match err {
+            Some(err) => Some(Err(err)),
+            None => Some(Ok(line)),
+        }
+    }
+}
+
+/// Read a line into `line` without its trailing whitespace, returning false at EOF.
+async fn read_line<T>(reader: &mut T, line: &mut String) -> io::Result<bool>
+where
+    T: AsyncBufRead + Unpin,
+{
+    let n = reader.read_line(line).await?;
+    let len = line.trim_end().len();
+    line.truncate(len);
+    Ok(n > 0)
+}
+
+struct AsyncHead<T> {
+    name: sync::Arc<str>,
+    reader: T,
+    line: String,
+    /// The position of `line` in the reader.
+    line_no: u64,
+    index: usize,
+    order: Order,
+}
+
+impl<T> AsyncHead<T> {
+    fn compare(&self, a: &str, b: &str) -> cmp::Ordering {
+        match self.order {
+            Order::Asc => a.cmp(b),
+            Order::Desc => b.cmp(a),
+        }
+    }
+}
+
+impl<T> PartialEq for AsyncHead<T> {
+    fn eq(&self, other: &AsyncHead<T>) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for AsyncHead<T> {}
+
+impl<T> PartialOrd for AsyncHead<T> {
+    fn partial_cmp(&self, other: &AsyncHead<T>) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for AsyncHead<T> {
+    fn cmp(&self, other: &AsyncHead<T>) -> cmp::Ordering {
+        self.compare(&self.line, &other.line)
+            .then_with(|| self.index.cmp(&other.index))
+            .reverse()
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect<T>(mut heap: AsyncHeap<T>) -> io::Result<Vec<String>>
+    where
+        T: AsyncBufRead + Unpin,
+    {
+        let mut lines = Vec::new();
+        while let Some(line) = heap.next().await {
+            lines.push(line?);
+        }
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn test_async_merge() -> Result<(), io::Error> {
+        let mut heap = AsyncHeap::new();
+        heap.add_reader("file1".to_string(), "a\nc\ne".as_bytes())
+            .await?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())
+            .await?;
+        heap.add_reader("file3".to_string(), "".as_bytes()).await?;
+        assert_eq!(collect(heap).await?, vec!["a", "b", "c", "d", "e"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_descending() -> Result<(), io::Error> {
+        let mut heap = AsyncHeap::new().with_order(Order::Desc);
+        heap.add_reader("file1".to_string(), "c\na".as_bytes())
+            .await?;
+        heap.add_reader("file2".to_string(), "b".as_bytes()).await?;
+        assert_eq!(collect(heap).await?, vec!["c", "b", "a"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_ooo() -> Result<(), io::Error> {
+        let mut heap = AsyncHeap::new();
+        heap.add_reader("file1".to_string(), "b\na".as_bytes())
+            .await?;
+        let err = collect(heap).await.expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 2!"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Writing merged records in large batches, flushed once the buffer fills or, for streaming,
+//! every so many records.
+
+use std::io;
+
+use crate::Heap;
+
+/// A buffered writer of records ending in a delimiter, which writes to its inner writer once
+/// `BatchWriter::CAPACITY` bytes have been buffered and, with `with_flush_every`, flushes it after
+/// every so many records.
+pub struct BatchWriter<W: io::Write> {
+    inner: io::BufWriter<W>,
+    delimiter: u8,
+    flush_every: Option<u64>,
+    /// The number of records written since the last flush.
+    pending: u64,
+}
+
+impl<W: io::Write> BatchWriter<W> {
+    /// How many bytes are buffered before they are written to the inner writer.
+    pub const CAPACITY: usize = 256 * 1024;
+
+    /// Buffer the records, ending in `delimiter`, written to `inner`.
+    pub fn new(inner: W, delimiter: u8) -> BatchWriter<W> {
+        BatchWriter {
+            inner: io::BufWriter::with_capacity(Self::CAPACITY, inner),
+            delimiter,
+            flush_every: None,
+            pending: 0,
+        }
+    }
+
+    /// Flush the inner writer once every `records` records, at the latest, so readers of the
+    /// output don't wait on a full buffer.
+    pub fn with_flush_every(mut self, records: u64) -> BatchWriter<W> {
+        self.flush_every = Some(records.max(1));
+        self
+    }
+}
+
+impl<W: io::Write> io::Write for BatchWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(every) = self.flush_every {
+            self.pending += buf[..written]
+                .iter()
+                .filter(|&&byte| byte == self.delimiter)
+                .count() as u64;
+            if self.pending >= every {
+                self.flush()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.inner.flush()
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Have `write_sorted_lines` flush its output every `lines` merged lines, rather than only
+    /// once its buffer fills, for readers that process the output as it is merged.
+    pub fn with_flush_every(mut self, lines: u64) -> Heap<T, K> {
+        self.flush_every = Some(lines);
+        self
+    }
+
+    /// A writer batching the records written to `w`, flushed as `with_flush_every` says.
+    pub(crate) fn batch_writer<W: io::Write>(&self, w: W) -> BatchWriter<W> {
+        let w = BatchWriter::new(w, self.format.delimiter);
+        match self.flush_every {
+            Some(lines) => w.with_flush_every(lines),
+            None => w,
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell;
+    use std::io::Write;
+    use std::rc;
+
+    /// A writer recording what had been written to it at each flush.
+    #[derive(Clone, Default)]
+    struct Flushes(rc::Rc<cell::RefCell<(Vec<u8>, Vec<String>)>>);
+
+    impl io::Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut flushes = self.0.borrow_mut();
+            let written = String::from_utf8_lossy(&flushes.0).into_owned();
+            flushes.1.push(written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_writer() -> Result<(), io::Error> {
+        let flushes = Flushes::default();
+        let mut w = BatchWriter::new(flushes.clone(), b'\n').with_flush_every(2);
+        w.write_all(b"a\nb")?;
+        w.write_all(b"\nc\n")?;
+        w.write_all(b"d\n")?;
+        assert_eq!(flushes.0.borrow().1, vec!["a\nb\nc\n"]);
+        w.flush()?;
+        assert_eq!(flushes.0.borrow().1, vec!["a\nb\nc\n", "a\nb\nc\nd\n"]);
+
+        let flushes = Flushes::default();
+        let mut w = BatchWriter::new(flushes.clone(), b'\n');
+        w.write_all("x\n".repeat(1000).as_bytes())?;
+        assert!(flushes.0.borrow().0.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_flush_every() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_flush_every(2);
+        heap.add_reader("file1".to_string(), "a\nc\ne\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())?;
+        let flushes = Flushes::default();
+        assert_eq!(heap.write_sorted_lines(flushes.clone())?, 5);
+        let flushed = flushes.0.borrow().1.clone();
+        assert_eq!(
+            flushed,
+            vec![
+                "a
+b
+",
+                "a
+b
+c
+d
+",
+                "a
+b
+c
+d
+e
+"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+//! Writing BGZF, the blocked gzip of `bgzip`: a series of gzip members of at most 64 KiB each,
+//! whose compressed sizes are recorded in their headers, ending with an empty member. Readers of
+//! gzip read it as they would any gzip file, and tabix and the like can seek to any of its blocks.
+//!
+//! A position in the decompressed data is given by a virtual offset: the offset of the start of
+//! its block in the file, shifted left 16 bits, plus its offset within the data of the block.
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+
+/// The most data `bgzip` puts in a block, leaving room for incompressible data to grow.
+const BLOCK_DATA: usize = 0xff00;
+
+/// The empty block that marks the end of a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0,
+    0, 0,
+];
+
+/// Where the blocks of a BGZF file start, in the file and in the data it decompresses to, which is
+/// what a `.gzi` index, as written by `bgzip --index`, lists.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BgzfIndex {
+    /// The compressed and uncompressed offsets of each block after the first, which starts at 0.
+    blocks: Vec<(u64, u64)>,
+}
+
+impl BgzfIndex {
+    /// The number of blocks listed, all but the first.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The virtual offset of the byte at `offset` in the decompressed data.
+    pub fn virtual_offset(&self, offset: u64) -> u64 {
+        let after = self.blocks.partition_point(|&(_, start)| start <= offset);
+        let (compressed, start) = match after.checked_sub(1) {
+            Some(block) => self.blocks[block],
+            None => (0, 0),
+        };
+        compressed << 16 | (offset - start)
+    }
+
+    /// Write the index as a `.gzi`: the number of blocks listed, then the compressed and
+    /// uncompressed offsets of each, all as little-endian `u64`s.
+    pub fn write_gzi<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        let mut encoded = Vec::with_capacity(8 + self.blocks.len() * 16);
+        encoded.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+        for (compressed, uncompressed) in &self.blocks {
+            encoded.extend_from_slice(&compressed.to_le_bytes());
+            encoded.extend_from_slice(&uncompressed.to_le_bytes());
+        }
+        w.write_all(&encoded)?;
+        w.flush()
+    }
+
+    /// Read an index as `write_gzi` writes it.
+    pub fn read_gzi<R: io::Read>(mut r: R) -> io::Result<BgzfIndex> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid BGZF index");
+        let mut encoded = Vec::new();
+        r.read_to_end(&mut encoded)?;
+        if encoded.len() < 8 || encoded.len() % 16 != 8 {
+            return Err(invalid());
+        }
+        let mut words = encoded
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(<[u8; 8]>::try_from(word).expect("chunks of 8")));
+        let len = words.next().expect("at least one word");
+        let words: Vec<u64> = words.collect();
+        if words.len() as u64 != len.saturating_mul(2) {
+            return Err(invalid());
+        }
+        let blocks = words
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        Ok(BgzfIndex { blocks })
+    }
+}
+
+/// Compresses what is written to it into BGZF blocks, writing each to the inner writer once it is
+/// full, or when flushed. `finish` must be called to write the last block and the end marker.
+pub struct BgzfWriter<W: io::Write> {
+    inner: W,
+    data: Vec<u8>,
+    level: flate2::Compression,
+    /// The bytes written to `inner`, and the data compressed into them.
+    compressed: u64,
+    uncompressed: u64,
+    index: BgzfIndex,
+}
+
+impl<W: io::Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner,
+            data: Vec::with_capacity(BLOCK_DATA),
+            level: flate2::Compression::default(),
+            compressed: 0,
+            uncompressed: 0,
+            index: BgzfIndex::default(),
+        }
+    }
+
+    pub fn with_level(mut self, level: flate2::Compression) -> BgzfWriter<W> {
+        self.level = level;
+        self
+    }
+
+    /// Compress the data written since the last block into a block of its own.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(&self.data)?;
+        let compressed = encoder.finish()?;
+        let mut crc = flate2::Crc::new();
+        crc.update(&self.data);
+        let size = u16::try_from(18 + compressed.len() + 8 - 1)
+            .map_err(|_| io::Error::other("BGZF block too large"))?;
+        let mut block = Vec::with_capacity(usize::from(size) + 1);
+        block.extend_from_slice(&[
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ]);
+        block.extend_from_slice(&size.to_le_bytes());
+        block.extend_from_slice(&compressed);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        self.inner.write_all(&block)?;
+        if self.compressed > 0 {
+            self.index.blocks.push((self.compressed, self.uncompressed));
+        }
+        self.compressed += block.len() as u64;
+        self.uncompressed += self.data.len() as u64;
+        self.data.clear();
+        Ok(())
+    }
+
+    /// The virtual offset of the next byte written.
+    pub fn virtual_offset(&self) -> u64 {
+        self.compressed << 16 | self.data.len() as u64
+    }
+
+    /// Write the last block and the end marker, returning the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_indexed().map(|(inner, _)| inner)
+    }
+
+    /// Like `finish`, also returning the index of the blocks written.
+    pub fn finish_indexed(mut self) -> io::Result<(W, BgzfIndex)> {
+        self.write_block()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok((self.inner, self.index))
+    }
+}
+
+impl<W: io::Write> io::Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data.len() == BLOCK_DATA {
+            self.write_block()?;
+        }
+        let n = buf.len().min(BLOCK_DATA - self.data.len());
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_bgzf() -> Result<(), io::Error> {
+        let text: String = (0..20000).map(|i| format!("chr1\t{}\n", i)).collect();
+        let mut w = BgzfWriter::new(Vec::new());
+        w.write_all(text.as_bytes())?;
+        let (bgzf, index) = w.finish_indexed()?;
+        assert!(bgzf.ends_with(&EOF_BLOCK));
+
+        // Each block records its own size, so they can be walked without decompressing them.
+        let mut starts = Vec::new();
+        let mut at = 0;
+        while at < bgzf.len() {
+            assert_eq!(&bgzf[at + 12..at + 14], b"BC");
+            starts.push(at as u64);
+            at += usize::from(u16::from_le_bytes([bgzf[at + 16], bgzf[at + 17]])) + 1;
+        }
+        assert_eq!(at, bgzf.len());
+        assert_eq!(starts.len(), 1 + text.len().div_ceil(BLOCK_DATA));
+        // The index lists every block but the first and the end marker.
+        assert_eq!(index.len(), starts.len() - 2);
+
+        let mut decompressed = String::new();
+        flate2::read::MultiGzDecoder::new(&bgzf[..]).read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, text);
+
+        // Decompressing the block a virtual offset points into finds the byte at it.
+        let offset = text.find("chr1\t15000\n").unwrap() as u64;
+        let virtual_offset = index.virtual_offset(offset);
+        let (block, within) = (virtual_offset >> 16, (virtual_offset & 0xffff) as usize);
+        assert_eq!(block, starts[(offset / BLOCK_DATA as u64) as usize]);
+        let mut data = String::new();
+        flate2::read::GzDecoder::new(&bgzf[block as usize..]).read_to_string(&mut data)?;
+        assert!(data[within..].starts_with("chr1\t15000\n"));
+
+        let mut gzi = Vec::new();
+        index.write_gzi(&mut gzi)?;
+        assert_eq!(gzi.len(), 8 + 16 * index.len());
+        assert_eq!(BgzfIndex::read_gzi(&gzi[..])?, index);
+        assert!(BgzfIndex::read_gzi(&gzi[..gzi.len() - 16]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_virtual_offset_at_block_boundary() -> Result<(), io::Error> {
+        // Two full blocks exactly, so the second starts at a known offset in the data.
+        let data = vec![b'x'; 2 * BLOCK_DATA];
+        let mut w = BgzfWriter::new(Vec::new());
+        w.write_all(&data[..BLOCK_DATA])?;
+        assert_eq!(w.virtual_offset(), BLOCK_DATA as u64);
+        w.write_all(&data[BLOCK_DATA..])?;
+        let (bgzf, index) = w.finish_indexed()?;
+        let second = u64::from(u16::from_le_bytes([bgzf[16], bgzf[17]])) + 1;
+        assert_eq!(
+            index,
+            BgzfIndex {
+                blocks: vec![(second, BLOCK_DATA as u64)]
+            }
+        );
+        assert_eq!(index.virtual_offset(0), 0);
+        assert_eq!(index.virtual_offset(BLOCK_DATA as u64 - 1), 0xfeff);
+        assert_eq!(index.virtual_offset(BLOCK_DATA as u64), second << 16);
+        assert_eq!(
+            index.virtual_offset(BLOCK_DATA as u64 + 1),
+            second << 16 | 1
+        );
+
+        // The `.gzi` of the index, as `bgzip --index` writes it, reads back to the same index.
+        let mut gzi = Vec::new();
+        index.write_gzi(&mut gzi)?;
+        let mut expected = 1u64.to_le_bytes().to_vec();
+        expected.extend_from_slice(&second.to_le_bytes());
+        expected.extend_from_slice(&(BLOCK_DATA as u64).to_le_bytes());
+        assert_eq!(gzi, expected);
+        assert_eq!(BgzfIndex::read_gzi(&gzi[..])?, index);
+        assert_eq!(
+            BgzfIndex::read_gzi(&0u64.to_le_bytes()[..])?,
+            BgzfIndex::default()
+        );
+        assert!(BgzfIndex::read_gzi(&gzi[..7]).is_err());
+        assert!(BgzfIndex::read_gzi(&2u64.to_le_bytes()[..]).is_err());
+        Ok(())
+    }
+}
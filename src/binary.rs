@@ -0,0 +1,250 @@
+//! Merging binary records, each a blob of bytes prefixed with its length as a `u32`, such as the
+//! sorted runs of an external sorter, rather than lines of text.
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+use std::ops;
+
+use crate::input::Input;
+use crate::merge::{KWayMerge, SortedSource};
+
+/// The byte order of the length prefixes of binary records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    fn read(self, prefix: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => u32::from_le_bytes(prefix),
+            ByteOrder::BigEndian => u32::from_be_bytes(prefix),
+        }
+    }
+
+    fn write(self, len: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::LittleEndian => len.to_le_bytes(),
+            ByteOrder::BigEndian => len.to_be_bytes(),
+        }
+    }
+}
+
+/// The length-prefixed records of a reader, as a `SortedSource` of byte vectors. Compressed
+/// readers are decompressed if compression support is enabled. Merge them with a comparator,
+/// e.g. `binary_key_range`, or by their bytes with `KWayMerge::new`.
+pub struct BinaryRecordReader<R>
+where
+    R: io::Read,
+{
+    reader: Input<R>,
+    order: ByteOrder,
+}
+
+impl<R> BinaryRecordReader<R>
+where
+    R: io::Read,
+{
+    /// Read the records of `reader`, with little-endian length prefixes.
+    pub fn new(reader: R) -> io::Result<BinaryRecordReader<R>> {
+        BinaryRecordReader::with_byte_order(reader, ByteOrder::default())
+    }
+
+    /// Read the records of `reader`, with length prefixes in `order`.
+    pub fn with_byte_order(reader: R, order: ByteOrder) -> io::Result<BinaryRecordReader<R>> {
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let reader = Input::detect(reader)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let reader = Input::plain(reader);
+        Ok(BinaryRecordReader { reader, order })
+    }
+
+    /// Read the next length prefix, or `None` at the end of the reader.
+    fn read_len(&mut self) -> io::Result<Option<u32>> {
+        let mut prefix = [0; 4];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match self.reader.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Input ends within the length of a record",
+                    ))
+                }
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Some(self.order.read(prefix)))
+    }
+}
+
+impl<R> SortedSource for BinaryRecordReader<R>
+where
+    R: io::Read,
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut record = Vec::new();
+        Ok(self.next_into(&mut record)?.then_some(record))
+    }
+
+    fn next_into(&mut self, record: &mut Vec<u8>) -> io::Result<bool> {
+        record.clear();
+        let len = match self.read_len()? {
+            Some(len) => u64::from(len),
+            None => return Ok(false),
+        };
+        // The length isn't trusted to allocate up front, in case the input is corrupt.
+        let read = (&mut self.reader).take(len).read_to_end(record)?;
+        if read as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Input ends {} bytes into a record of {}", read, len),
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Records are rendered with their bytes escaped, up to the first 64.
+    fn describe(&self, record: &Vec<u8>) -> Option<String> {
+        let shown = &record[..record.len().min(64)];
+        let ellipsis = if shown.len() < record.len() {
+            "..."
+        } else {
+            ""
+        };
+        Some(format!("{}{}", shown.escape_ascii(), ellipsis))
+    }
+}
+
+/// Write `record` to `w`, prefixed with its length in `order`, as `BinaryRecordReader` reads it.
+pub fn write_binary_record<W>(w: &mut W, record: &[u8], order: ByteOrder) -> io::Result<()>
+where
+    W: io::Write + ?Sized,
+{
+    let len = u32::try_from(record.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Record of {} bytes is too long for its length",
+                record.len()
+            ),
+        )
+    })?;
+    w.write_all(&order.write(len))?;
+    w.write_all(record)
+}
+
+/// Write the records `merge` yields to `w`, as `write_binary_record` does, returning how many
+/// there were.
+pub fn write_binary_records<S, W>(
+    merge: &mut KWayMerge<S>,
+    w: W,
+    order: ByteOrder,
+) -> io::Result<u64>
+where
+    S: SortedSource<Item = Vec<u8>>,
+    W: io::Write,
+{
+    let mut w = io::BufWriter::new(w);
+    let mut count = 0;
+    merge.for_each_item(|record| {
+        count += 1;
+        write_binary_record(&mut w, record, order)
+    })?;
+    io::Write::flush(&mut w)?;
+    Ok(count)
+}
+
+/// A comparator for `KWayMerge::with_comparator` that orders binary records by the bytes of
+/// `range` within them, e.g. `0..8` for a leading 8-byte big-endian key. Records too short for
+/// the range are compared by what they have of it.
+pub fn binary_key_range(
+    range: ops::Range<usize>,
+) -> impl Fn(&Vec<u8>, &Vec<u8>) -> cmp::Ordering + Clone + Send + Sync {
+    move |a, b| key_bytes(a, &range).cmp(key_bytes(b, &range))
+}
+
+/// The bytes of `range` within `record`, or what it has of them.
+fn key_bytes<'a>(record: &'a [u8], range: &ops::Range<usize>) -> &'a [u8] {
+    let end = range.end.min(record.len());
+    &record[range.start.min(end)..end]
+}
+
+/// A comparator for `KWayMerge::with_comparator` that orders binary records by the keys `key`
+/// extracts from them.
+pub fn binary_key<F, K>(key: F) -> impl Fn(&Vec<u8>, &Vec<u8>) -> cmp::Ordering + Send + Sync
+where
+    F: Fn(&[u8]) -> K + Send + Sync,
+    K: Ord,
+{
+    move |a, b| key(a).cmp(&key(b))
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(records: &[&[u8]], order: ByteOrder) -> io::Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        for record in records {
+            write_binary_record(&mut encoded, record, order)?;
+        }
+        Ok(encoded)
+    }
+
+    #[test]
+    fn test_binary_records() -> Result<(), io::Error> {
+        // Keyed by the big-endian u16 after a one-byte tag.
+        let a = records(
+            &[b"\x00", b"z\x00\x01a", b"a\x00\x03"],
+            ByteOrder::BigEndian,
+        )?;
+        let b = records(&[b"y\x00\x02b\xff"], ByteOrder::BigEndian)?;
+        let mut merge = KWayMerge::with_comparator(binary_key_range(1..3));
+        for (name, input) in [("a", &a), ("b", &b)] {
+            let reader = BinaryRecordReader::with_byte_order(&input[..], ByteOrder::BigEndian)?;
+            merge.add_source(name.to_string(), reader)?;
+        }
+        let mut out = Vec::new();
+        assert_eq!(
+            write_binary_records(&mut merge, &mut out, ByteOrder::BigEndian)?,
+            4
+        );
+        let mut reader = BinaryRecordReader::with_byte_order(&out[..], ByteOrder::BigEndian)?;
+        let mut merged = Vec::new();
+        while let Some(record) = reader.next()? {
+            merged.push(record);
+        }
+        // A record too short for the key compares as though its key were empty.
+        let expected: [&[u8]; 4] = [b"\x00", b"z\x00\x01a", b"y\x00\x02b\xff", b"a\x00\x03"];
+        assert_eq!(merged, expected);
+
+        let mut merge = KWayMerge::with_comparator(binary_key(|record: &[u8]| record.len()));
+        merge.add_source(
+            "a".to_string(),
+            BinaryRecordReader::new(&b"\x02\0\0\0ab"[..])?,
+        )?;
+        merge.add_source(
+            "b".to_string(),
+            BinaryRecordReader::new(&b"\x01\0\0\0c"[..])?,
+        )?;
+        let merged = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(merged, [b"c".to_vec(), b"ab".to_vec()]);
+
+        let mut truncated = BinaryRecordReader::new(&b"\x05\0\0\0abc"[..])?;
+        let err = truncated.next().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(BinaryRecordReader::new(&b"\x05\0"[..])?.next().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! Building a Bloom filter of the keys of the merged lines as they are emitted, to write next to
+//! the output so that lookups can rule out the keys it doesn't have without reading it.
+//!
+//! Keys are hashed with 64-bit FNV-1a, finished with the SplitMix64 mix, and the bits of a key
+//! are found by double hashing: the `i`th is `(h + i * d) % bits`, where `d` is `h` rotated right
+//! by 17 bits with its lowest bit set. The filter is written as `MSFBLM01`, the number of hashes
+//! as a little-endian `u32`, the number of bits as a `u64`, then the bits as `u64`s.
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync;
+
+use crate::{Heap, Line};
+
+const MAGIC: &[u8; 8] = b"MSFBLM01";
+
+/// The hash of `key` the bits of a filter are derived from.
+fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        h ^= u64::from(byte);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// A Bloom filter of keys, which may claim to contain keys that weren't inserted but never
+/// denies one that was.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    words: Vec<u64>,
+    bits: u64,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter of `bits` bits, rounded up to a multiple of 64, setting `hashes` bits per
+    /// key.
+    pub fn new(bits: u64, hashes: u32) -> BloomFilter {
+        let words = bits.max(1).div_ceil(64);
+        BloomFilter {
+            words: vec![0; words as usize],
+            bits: words * 64,
+            hashes: hashes.max(1),
+        }
+    }
+
+    /// The number of hashes that gives the fewest false positives for `bits_per_key` bits per
+    /// key: `bits_per_key` times ln 2, rounded.
+    pub fn optimal_hashes(bits_per_key: u32) -> u32 {
+        ((f64::from(bits_per_key) * std::f64::consts::LN_2).round() as u32).max(1)
+    }
+
+    fn insert_hash(&mut self, mut h: u64) {
+        let delta = h.rotate_right(17) | 1;
+        for _ in 0..self.hashes {
+            let bit = h % self.bits;
+            self.words[(bit / 64) as usize] |= 1 << (bit % 64);
+            h = h.wrapping_add(delta);
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash(key));
+    }
+
+    /// Whether `key` may have been inserted.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let mut h = hash(key);
+        let delta = h.rotate_right(17) | 1;
+        (0..self.hashes).all(|_| {
+            let bit = h % self.bits;
+            h = h.wrapping_add(delta);
+            self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn write_to<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        let mut encoded = Vec::with_capacity(20 + self.words.len() * 8);
+        encoded.extend_from_slice(MAGIC);
+        encoded.extend_from_slice(&self.hashes.to_le_bytes());
+        encoded.extend_from_slice(&self.bits.to_le_bytes());
+        for word in &self.words {
+            encoded.extend_from_slice(&word.to_le_bytes());
+        }
+        w.write_all(&encoded)?;
+        w.flush()
+    }
+
+    /// Read a filter as `write_to` writes it.
+    pub fn read_from<R: io::Read>(mut r: R) -> io::Result<BloomFilter> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid Bloom filter");
+        let mut header = [0; 20];
+        r.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid());
+        }
+        let hashes =
+            u32::from_le_bytes(<[u8; 4]>::try_from(&header[8..12]).map_err(|_| invalid())?);
+        let bits = u64::from_le_bytes(<[u8; 8]>::try_from(&header[12..]).map_err(|_| invalid())?);
+        if hashes == 0 || bits == 0 || bits % 64 != 0 {
+            return Err(invalid());
+        }
+        let mut encoded = Vec::new();
+        r.read_to_end(&mut encoded)?;
+        if encoded.len() as u64 != bits / 8 {
+            return Err(invalid());
+        }
+        let words = encoded
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(<[u8; 8]>::try_from(word).expect("chunks of 8")))
+            .collect();
+        Ok(BloomFilter {
+            words,
+            bits,
+            hashes,
+        })
+    }
+}
+
+/// Gathers the keys of a merge, with `Heap::with_bloom_filter`, into a filter sized for them
+/// once it ends. Clones share the keys gathered, so one can be kept to build the filter from.
+/// Only the hash of each key is held until then, 8 bytes a key, and the merge hands them over
+/// once, when it is dropped.
+#[derive(Clone)]
+pub struct BloomBuilder {
+    hashes: sync::Arc<sync::Mutex<Vec<u64>>>,
+    bits_per_key: u32,
+    hash_count: u32,
+}
+
+impl BloomBuilder {
+    /// Build a filter of `bits_per_key` bits per key gathered, setting `hashes` bits per key.
+    pub fn new(bits_per_key: u32, hashes: u32) -> BloomBuilder {
+        BloomBuilder {
+            hashes: sync::Arc::default(),
+            bits_per_key: bits_per_key.max(1),
+            hash_count: hashes,
+        }
+    }
+
+    /// The number of keys gathered from merges that have been dropped.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Build the filter of the keys gathered so far.
+    pub fn build(&self) -> BloomFilter {
+        let hashes = self.lock();
+        let bits = (hashes.len() as u64).saturating_mul(u64::from(self.bits_per_key));
+        let mut filter = BloomFilter::new(bits, self.hash_count);
+        for &h in hashes.iter() {
+            filter.insert_hash(h);
+        }
+        filter
+    }
+
+    fn lock(&self) -> sync::MutexGuard<'_, Vec<u64>> {
+        self.hashes.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The hashes of the keys of one merge, which only it inserts into, given to `builder` when it
+/// is dropped.
+struct Gatherer {
+    builder: BloomBuilder,
+    hashes: Vec<u64>,
+}
+
+impl Gatherer {
+    /// Keys arrive in order, so a key repeated on consecutive lines is only hashed in once.
+    fn insert(&mut self, key: &[u8]) {
+        let h = hash(key);
+        if self.hashes.last() != Some(&h) {
+            self.hashes.push(h);
+        }
+    }
+}
+
+impl Drop for Gatherer {
+    fn drop(&mut self) {
+        self.builder.lock().append(&mut self.hashes);
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Insert the key `key` returns for each merged line into `bloom` as it is emitted, after
+    /// any output filters and maps added before it, so add it last. The keys reach `bloom` once
+    /// the heap is dropped.
+    pub fn with_bloom_filter<F>(mut self, bloom: BloomBuilder, key: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> &str + Send + 'static,
+    {
+        let mut gatherer = Gatherer {
+            builder: bloom,
+            hashes: Vec::new(),
+        };
+        self.merge = self.merge.with_output(move |line: &mut Line<K>| {
+            gatherer.insert(key(&line.text).as_bytes());
+            true
+        });
+        self
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() -> Result<(), io::Error> {
+        let a: String = (0..500).map(|i| format!("a{:04}\tx\n", i)).collect();
+        let bloom = BloomBuilder::new(10, BloomFilter::optimal_hashes(10));
+        let mut heap = Heap::new()
+            .with_output_filter(|line| !line.ends_with("skip"))
+            .with_bloom_filter(bloom.clone(), |line| {
+                line.split('\t').next().unwrap_or(line)
+            });
+        // A key on consecutive lines is gathered once.
+        let b = "b0000\tskip\nb0001\ty\nb0001\tz\n";
+        heap.add_reader("a".to_string(), a.as_bytes())?;
+        heap.add_reader("b".to_string(), b.as_bytes())?;
+        assert_eq!(heap.count(), 502);
+        assert_eq!(bloom.len(), 501);
+
+        let filter = bloom.build();
+        assert_eq!(filter.hashes(), 7);
+        assert_eq!(filter.bits(), 5056);
+        assert!((0..500).all(|i| filter.contains(format!("a{:04}", i).as_bytes())));
+        assert!(filter.contains(b"b0001"));
+        let false_positives = (0..10000)
+            .filter(|i| filter.contains(format!("c{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let mut encoded = Vec::new();
+        filter.write_to(&mut encoded)?;
+        assert_eq!(encoded.len(), 20 + 5056 / 8);
+        assert_eq!(BloomFilter::read_from(&encoded[..])?, filter);
+        assert!(BloomFilter::read_from(&encoded[..encoded.len() - 1]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Building heaps of files and readers fluently, with every setting in place before any input is
+//! added.
+
+use std::cmp;
+use std::io;
+use std::path;
+use std::sync;
+
+use crate::{compare_human_numeric, compare_numeric, compare_version, Heap, Order};
+
+type Reader = Box<dyn io::Read + Send>;
+
+/// How the heap a `HeapBuilder` builds compares lines.
+type LineComparator = sync::Arc<dyn Fn(&str, &str) -> cmp::Ordering + Send + Sync>;
+
+/// A setting to apply to the heap a `HeapBuilder` builds.
+type Setting = Box<dyn FnOnce(Heap<Reader>) -> Heap<Reader> + Send>;
+
+/// An input for a `HeapBuilder` to add.
+enum Source {
+    Path(path::PathBuf),
+    File(path::PathBuf, i64),
+    Reader(String, Reader),
+    Stdin,
+}
+
+/// Configures a heap of files and readers in one chain, e.g.
+/// `Heap::builder().numeric().descending().dedup().add_path(path).build()?`. Unlike with the
+/// heap's own settings, some of which only apply to inputs added after them, every setting
+/// applies to every input, whatever order they are given in: nothing is opened until `build`
+/// creates the heap, applies the settings and then adds the inputs, in the order they were given.
+pub struct HeapBuilder {
+    cmp: Option<LineComparator>,
+    order: Order,
+    settings: Vec<Setting>,
+    sources: Vec<Source>,
+}
+
+impl Default for HeapBuilder {
+    fn default() -> HeapBuilder {
+        HeapBuilder::new()
+    }
+}
+
+impl Heap<Reader> {
+    /// Start configuring a heap with a `HeapBuilder`.
+    pub fn builder() -> HeapBuilder {
+        HeapBuilder::new()
+    }
+}
+
+impl HeapBuilder {
+    /// A builder of a heap that compares lines as `Heap::new` does.
+    pub fn new() -> HeapBuilder {
+        HeapBuilder {
+            cmp: None,
+            order: Order::Asc,
+            settings: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Compare lines with `cmp`, as for `Heap::with_comparator`.
+    pub fn comparator<F>(mut self, cmp: F) -> HeapBuilder
+    where
+        F: Fn(&str, &str) -> cmp::Ordering + Send + Sync + 'static,
+    {
+        self.cmp = Some(sync::Arc::new(cmp));
+        self
+    }
+
+    /// Compare the leading numbers of lines, like `sort -n`.
+    pub fn numeric(self) -> HeapBuilder {
+        self.comparator(compare_numeric)
+    }
+
+    /// Compare the leading numbers of lines along with their SI suffixes, like `sort -h`.
+    pub fn human_numeric(self) -> HeapBuilder {
+        self.comparator(compare_human_numeric)
+    }
+
+    /// Compare lines as version strings, like `sort -V`.
+    pub fn version(self) -> HeapBuilder {
+        self.comparator(compare_version)
+    }
+
+    /// Expect the inputs to be sorted in descending order of whatever the lines are compared by.
+    pub fn descending(mut self) -> HeapBuilder {
+        self.order = Order::Desc;
+        self
+    }
+
+    /// Drop lines equal to the line before them, as for `Heap::dedup`.
+    pub fn dedup(self) -> HeapBuilder {
+        self.configure(|heap| heap.dedup(true))
+    }
+
+    /// Buffer up to `bytes` of each input at a time, as for `Heap::with_buffer_capacity`.
+    pub fn buffer_capacity(self, bytes: usize) -> HeapBuilder {
+        self.configure(move |heap| heap.with_buffer_capacity(bytes))
+    }
+
+    /// Apply any other of the heap's settings, e.g. `|heap| heap.with_headers(true)`. Settings are
+    /// applied in the order they were given, before any input is added.
+    pub fn configure<F>(mut self, setting: F) -> HeapBuilder
+    where
+        F: FnOnce(Heap<Reader>) -> Heap<Reader> + Send + 'static,
+    {
+        self.settings.push(Box::new(setting));
+        self
+    }
+
+    /// Add the input named `path`, as for `Heap::add_path`.
+    pub fn add_path<P: Into<path::PathBuf>>(mut self, path: P) -> HeapBuilder {
+        self.sources.push(Source::Path(path.into()));
+        self
+    }
+
+    /// Add the file at `path` with `priority` for `DuplicatePolicy::HighestPriority`, as for
+    /// `Heap::add_file_with_priority`.
+    pub fn add_file_with_priority<P: Into<path::PathBuf>>(
+        mut self,
+        path: P,
+        priority: i64,
+    ) -> HeapBuilder {
+        self.sources.push(Source::File(path.into(), priority));
+        self
+    }
+
+    /// Add `reader` under the name `name`, as for `Heap::add_reader`.
+    pub fn add_reader<R>(mut self, name: String, reader: R) -> HeapBuilder
+    where
+        R: io::Read + Send + 'static,
+    {
+        self.sources.push(Source::Reader(name, Box::new(reader)));
+        self
+    }
+
+    /// Add standard input, as for `Heap::add_stdin`.
+    pub fn add_stdin(mut self) -> HeapBuilder {
+        self.sources.push(Source::Stdin);
+        self
+    }
+
+    /// Create the heap, apply the settings to it and add the inputs, failing at the first input
+    /// that can't be added.
+    pub fn build(self) -> io::Result<Heap<Reader>> {
+        let heap = match self.cmp {
+            Some(cmp) => Heap::with_comparator(move |a, b| cmp(a, b)),
+            None => Heap::new(),
+        };
+        let heap = heap.with_order(self.order);
+        let mut heap = self
+            .settings
+            .into_iter()
+            .fold(heap, |heap, setting| setting(heap));
+        for source in self.sources {
+            match source {
+                Source::Path(path) => heap.add_path(&path)?,
+                Source::File(path, priority) => heap.add_file_with_priority(&path, priority)?,
+                Source::Reader(name, reader) => heap.add_reader(name, reader)?,
+                Source::Stdin => heap.add_stdin()?,
+            }
+        }
+        Ok(heap)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_builder() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-builder-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "10\n9\n2\n")?;
+        let heap = Heap::builder()
+            .add_reader("file1".to_string(), "11\n10\n1\n".as_bytes())
+            .add_path(&path)
+            .numeric()
+            .descending()
+            .dedup()
+            .buffer_capacity(2)
+            .build()?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["11", "10", "9", "2", "1"]);
+
+        // Settings given after the inputs apply to them too.
+        let heap = Heap::builder()
+            .add_reader("file1".to_string(), "a,c,".as_bytes())
+            .add_reader("file2".to_string(), "b,".as_bytes())
+            .configure(|heap| heap.with_delimiter(b','))
+            .build()?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c"]);
+
+        fs::remove_file(&path)?;
+        assert!(Heap::builder().add_path(&path).build().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Stopping a merge partway through at the request of another thread.
+
+use std::io;
+use std::io::Write;
+use std::sync;
+use std::sync::atomic;
+
+use crate::{write_record, Heap};
+
+/// How far `Heap::write_sorted_lines_until` got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeOutcome {
+    /// The number of merged lines written.
+    pub lines: u64,
+    /// Whether it stopped because it was cancelled, rather than because every line was written.
+    pub cancelled: bool,
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Stop merging once `cancel` is set, so that iterating the heap, or writing it out, fails
+    /// with `MergeError::Cancelled` instead of merging the next line. The heap is left as it was,
+    /// so it can carry on if `cancel` is cleared.
+    pub fn with_cancellation(mut self, cancel: sync::Arc<atomic::AtomicBool>) -> Heap<T, K> {
+        self.merge = self.merge.with_cancellation(cancel);
+        self
+    }
+
+    /// Like `write_sorted_lines`, but stop before the next line once `cancel` is set, flushing
+    /// what was written so far. The heap can carry on from there afterwards, e.g. by iterating it.
+    pub fn write_sorted_lines_until<W: io::Write>(
+        &mut self,
+        w: W,
+        cancel: &atomic::AtomicBool,
+    ) -> io::Result<MergeOutcome> {
+        let mut w = io::BufWriter::new(w);
+        let delimiter = self.format.delimiter;
+        if self.emit_header {
+            for header in &self.header {
+                write_record(&mut w, header, delimiter, self.eol)?;
+            }
+        }
+        let mut outcome = MergeOutcome {
+            lines: 0,
+            cancelled: false,
+        };
+        loop {
+            if cancel.load(atomic::Ordering::Relaxed) {
+                outcome.cancelled = true;
+                break;
+            }
+            match self.merge.next() {
+                Some(line) => write_record(&mut w, &line?, delimiter, self.eol)?,
+                None => break,
+            }
+            outcome.lines += 1;
+        }
+        w.flush()?;
+        Ok(outcome)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MergeError;
+
+    fn heap() -> io::Result<Heap<&'static [u8]>> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())?;
+        Ok(heap)
+    }
+
+    #[test]
+    fn test_write_sorted_lines_until() -> Result<(), io::Error> {
+        let cancel = atomic::AtomicBool::new(false);
+        let mut out = Vec::new();
+        let mut heap = heap()?;
+        heap.next().transpose()?;
+        cancel.store(true, atomic::Ordering::Relaxed);
+        let outcome = heap.write_sorted_lines_until(&mut out, &cancel)?;
+        assert_eq!(
+            outcome,
+            MergeOutcome {
+                lines: 0,
+                cancelled: true
+            }
+        );
+        cancel.store(false, atomic::Ordering::Relaxed);
+        let outcome = heap.write_sorted_lines_until(&mut out, &cancel)?;
+        assert_eq!((outcome.lines, outcome.cancelled), (3, false));
+        assert_eq!(out, b"b\nc\nd\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_cancellation() -> Result<(), io::Error> {
+        let cancel = sync::Arc::new(atomic::AtomicBool::new(false));
+        let mut heap = heap()?.with_cancellation(cancel.clone());
+        assert_eq!(heap.next().transpose()?, Some("a".to_string()));
+        cancel.store(true, atomic::Ordering::Relaxed);
+        let err = heap.next().unwrap().unwrap_err();
+        assert!(matches!(
+            MergeError::from_io(&err),
+            Some(MergeError::Cancelled)
+        ));
+        assert!(heap.for_each_line(|_| Ok(())).is_err());
+        cancel.store(false, atomic::Ordering::Relaxed);
+        assert_eq!(heap.collect::<io::Result<Vec<_>>>()?, vec!["b", "c", "d"]);
+        Ok(())
+    }
+}
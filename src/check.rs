@@ -0,0 +1,133 @@
+//! Checking that an input is sorted without merging it.
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::input::{Input, RecordFormat};
+use crate::Order;
+
+/// The first line of an input that sorts before the line preceding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsortedAt {
+    /// The 1-based number of the offending line.
+    pub line_no: u64,
+    pub line: String,
+    pub previous: String,
+}
+
+impl fmt::Display for UnsortedAt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} is out of order: {}", self.line_no, self.line)
+    }
+}
+
+impl error::Error for UnsortedAt {}
+
+/// Read `reader` to the end, or to its first line that sorts before its predecessor, comparing
+/// lines the same way `Heap::new` does and decompressing them if compression support is enabled.
+/// Errors reading the input are returned as the outer error.
+pub fn verify_sorted<R>(reader: R, order: Order) -> io::Result<Result<(), UnsortedAt>>
+where
+    R: io::Read,
+{
+    match order {
+        Order::Asc => verify_sorted_by(reader, |a, b| a.cmp(b)),
+        Order::Desc => verify_sorted_by(reader, |a, b| b.cmp(a)),
+    }
+}
+
+/// Like `verify_sorted`, but with lines ordered by `cmp`, as for `Heap::with_comparator`.
+pub fn verify_sorted_by<R, F>(reader: R, cmp: F) -> io::Result<Result<(), UnsortedAt>>
+where
+    R: io::Read,
+    F: Fn(&str, &str) -> cmp::Ordering,
+{
+    verify_records_sorted_by(reader, RecordFormat::default(), cmp)
+}
+
+/// Like `verify_sorted_by`, but for records split and trimmed according to `format`, as for
+/// `Heap::with_delimiter` and `Heap::with_trim_whitespace`.
+pub fn verify_records_sorted_by<R, F>(
+    reader: R,
+    format: RecordFormat,
+    cmp: F,
+) -> io::Result<Result<(), UnsortedAt>>
+where
+    R: io::Read,
+    F: Fn(&str, &str) -> cmp::Ordering,
+{
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    let mut reader = Input::detect(reader)?;
+    #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+    let mut reader = Input::plain(reader);
+    let mut previous = String::new();
+    let mut line = String::new();
+    let mut line_no = 0;
+    loop {
+        line.clear();
+        if reader.read_record(format.delimiter, &mut line)? == 0 {
+            return Ok(Ok(()));
+        }
+        line_no += 1;
+        format.trim(&mut line);
+        if line_no > 1 && cmp(&line, &previous) == cmp::Ordering::Less {
+            return Ok(Err(UnsortedAt {
+                line_no,
+                line,
+                previous,
+            }));
+        }
+        std::mem::swap(&mut previous, &mut line);
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_sorted() -> Result<(), io::Error> {
+        assert_eq!(verify_sorted("a\nb\nb\nc".as_bytes(), Order::Asc)?, Ok(()));
+        assert_eq!(verify_sorted("".as_bytes(), Order::Asc)?, Ok(()));
+        assert_eq!(verify_sorted("c\nb\na\n".as_bytes(), Order::Desc)?, Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_unsorted() -> Result<(), io::Error> {
+        let unsorted = verify_sorted("a\nc\nb\na".as_bytes(), Order::Asc)?;
+        assert_eq!(
+            unsorted,
+            Err(UnsortedAt {
+                line_no: 3,
+                line: "b".to_string(),
+                previous: "c".to_string(),
+            })
+        );
+        assert_eq!(
+            format!("{}", unsorted.unwrap_err()),
+            "line 3 is out of order: b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_records_sorted_by() -> Result<(), io::Error> {
+        let cmp = |a: &str, b: &str| a.cmp(b);
+        let format = RecordFormat {
+            delimiter: b'\0',
+            ..RecordFormat::default()
+        };
+        let records = "b\na\0c\0".as_bytes();
+        assert_eq!(verify_records_sorted_by(records, format, cmp)?, Ok(()));
+        let unsorted = verify_records_sorted_by("b\0a\nc\0".as_bytes(), format, cmp)?;
+        assert_eq!(
+            unsorted.map_err(|unsorted| unsorted.line),
+            Err("a\nc".to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Checkpoints of a merge in progress, from which it can be resumed.
+
+use std::io;
+use std::io::Write;
+use std::path;
+use std::sync;
+
+use crate::{registered, write_record, Counters, Heap};
+
+/// Where a checkpointed merge had got to in one of its inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceCheckpoint {
+    /// The name the input was added under, which is its path for files.
+    pub name: String,
+    /// The offset of the first line of the input that hadn't been merged, or of its end if they
+    /// all had.
+    pub offset: u64,
+}
+
+/// Where a merge written by `Heap::write_checkpointed_lines` had got to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The number of merged lines written.
+    pub lines: u64,
+    /// The number of bytes written, including any header.
+    pub output_offset: u64,
+    /// Every input of the merge, in the order they were added.
+    pub sources: Vec<SourceCheckpoint>,
+}
+
+impl Checkpoint {
+    fn of(stats: &[(sync::Arc<str>, sync::Arc<Counters>)], lines: u64, offset: u64) -> Checkpoint {
+        Checkpoint {
+            lines,
+            output_offset: offset,
+            sources: stats
+                .iter()
+                .map(|(name, counters)| SourceCheckpoint {
+                    name: name.to_string(),
+                    offset: counters.next_offset(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the checkpoint as a JSON object, with its sources as an array of objects under
+    /// `sources`.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: io::Write>(&self, w: W) -> io::Result<()> {
+        let sources: Vec<serde_json::Value> = self
+            .sources
+            .iter()
+            .map(|source| serde_json::json!({ "name": source.name, "offset": source.offset }))
+            .collect();
+        let checkpoint = serde_json::json!({
+            "lines": self.lines,
+            "output_offset": self.output_offset,
+            "sources": sources,
+        });
+        serde_json::to_writer(w, &checkpoint)?;
+        Ok(())
+    }
+
+    /// Read a checkpoint written by `write_json`.
+    #[cfg(feature = "json")]
+    pub fn read_json<R: io::Read>(r: R) -> io::Result<Checkpoint> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid checkpoint");
+        let value: serde_json::Value = serde_json::from_reader(r)?;
+        let sources = value["sources"].as_array().ok_or_else(invalid)?;
+        let sources = sources
+            .iter()
+            .map(|source| {
+                Some(SourceCheckpoint {
+                    name: source["name"].as_str()?.to_string(),
+                    offset: source["offset"].as_u64()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(Checkpoint {
+            lines: value["lines"].as_u64().ok_or_else(invalid)?,
+            output_offset: value["output_offset"].as_u64().ok_or_else(invalid)?,
+            sources,
+        })
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Like `write_sorted_lines`, also calling `checkpoint` every `every` lines, and once all
+    /// have been written, with a checkpoint of the merge so far. `w` is flushed before each call,
+    /// so the output up to the checkpoint's offset has been written to it; making that durable,
+    /// e.g. with `File::sync_data`, before saving the checkpoint is up to `checkpoint`. Fails for
+    /// heaps with a reorder window or `Strategy::Parallel`, which read their inputs further ahead
+    /// than they merge them. Returns the number of merged lines written, not counting any written
+    /// before the checkpoint the heap was resumed from.
+    pub fn write_checkpointed_lines<W, F>(
+        &mut self,
+        w: W,
+        every: u64,
+        mut checkpoint: F,
+    ) -> io::Result<u64>
+    where
+        W: io::Write,
+        F: FnMut(&Checkpoint) -> io::Result<()>,
+    {
+        if self.merge.reads_ahead() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Merges with a reorder window or in parallel cannot be checkpointed",
+            ));
+        }
+        let mut w = io::BufWriter::new(w);
+        let delimiter = self.format.delimiter;
+        let mut record = Vec::new();
+        if self.resumed.is_none() && self.emit_header {
+            for header in &self.header {
+                write_record(&mut record, header, delimiter, self.eol)?;
+            }
+        }
+        w.write_all(&record)?;
+        let (resumed_lines, resumed_offset) = self.resumed.unwrap_or((0, 0));
+        let (mut lines, mut offset) = (resumed_lines, resumed_offset + record.len() as u64);
+        for line in self.merge.by_ref() {
+            record.clear();
+            write_record(&mut record, &line?, delimiter, self.eol)?;
+            w.write_all(&record)?;
+            lines += 1;
+            offset += record.len() as u64;
+            if (lines - resumed_lines) % every.max(1) == 0 {
+                w.flush()?;
+                checkpoint(&Checkpoint::of(&registered(&self.stats), lines, offset))?;
+            }
+        }
+        w.flush()?;
+        checkpoint(&Checkpoint::of(&registered(&self.stats), lines, offset))?;
+        Ok(lines - resumed_lines)
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K>
+where
+    K: 'static,
+{
+    /// Add the inputs of `checkpoint`, which must all be files, or with the `http` feature URLs, from where it had got to in each
+    /// of them, for `write_checkpointed_lines` to carry on from there without writing the header
+    /// again. The output must be truncated to the checkpoint's offset to be appended to. Lines
+    /// are numbered from where each input resumes, and neither `dedup` nor `with_limit` take the
+    /// lines written before the checkpoint into account.
+    pub fn resume(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        self.resumed = Some((checkpoint.lines, checkpoint.output_offset));
+        for source in &checkpoint.sources {
+            let path = path::Path::new(&source.name);
+            #[cfg(feature = "http")]
+            if let Some(added) = self.add_if_url(path, Some(source.offset)) {
+                added?;
+                continue;
+            }
+            self.add_file_at(path, source.offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_checkpoint_and_resume() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let paths: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                dir.join(format!(
+                    "merge-sorted-files-rs-test-resume-{}-{}",
+                    pid, name
+                ))
+            })
+            .collect();
+        fs::write(&paths[0], "h\n1\n# skipped\n3\n5\n")?;
+        fs::write(&paths[1], "h\n2\n4\n")?;
+        let heap = || -> Heap<Box<dyn io::Read + Send>> {
+            Heap::new().with_headers(true).with_comment_prefix("#")
+        };
+        let mut full = heap();
+        for path in &paths {
+            full.add_file(path)?;
+        }
+        let (mut out, mut checkpoints) = (Vec::new(), Vec::new());
+        let lines = full.write_checkpointed_lines(&mut out, 2, |checkpoint| {
+            checkpoints.push(checkpoint.clone());
+            Ok(())
+        })?;
+        assert_eq!((lines, out.as_slice()), (5, b"h\n1\n2\n3\n4\n5\n".as_ref()));
+        assert_eq!(checkpoints.len(), 3);
+        let second = &checkpoints[1];
+        assert_eq!((second.lines, second.output_offset), (4, 10));
+        let offsets: Vec<u64> = second.sources.iter().map(|source| source.offset).collect();
+        assert_eq!(offsets, vec![16, 6]);
+
+        let mut resumed = heap();
+        resumed.resume(second)?;
+        out.truncate(second.output_offset as usize);
+        let lines = resumed.write_checkpointed_lines(&mut out, 2, |_| Ok(()))?;
+        assert_eq!((lines, out.as_slice()), (1, b"h\n1\n2\n3\n4\n5\n".as_ref()));
+        for path in &paths {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_checkpoint_json() -> Result<(), io::Error> {
+        let checkpoint = Checkpoint {
+            lines: 4,
+            output_offset: 10,
+            sources: vec![SourceCheckpoint {
+                name: "a".to_string(),
+                offset: 16,
+            }],
+        };
+        let mut json = Vec::new();
+        checkpoint.write_json(&mut json)?;
+        assert_eq!(Checkpoint::read_json(&json[..])?, checkpoint);
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+//! Digests of the bytes of the merged output, so that whoever reads it can check it arrived whole
+//! and a re-run of a merge can tell whether it wrote the same output. CRC-32C, XXH3 (64-bit,
+//! unseeded) and SHA-256 are implemented here, matching their reference implementations.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// Which digest `Checksum` computes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    Xxh3,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// The name the algorithm is parsed from.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Xxh3 => "xxh3",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// The error returned when a checksum algorithm isn't recognized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseChecksumError(String);
+
+impl fmt::Display for ParseChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid checksum algorithm [{}]", self.0)
+    }
+}
+
+impl error::Error for ParseChecksumError {}
+
+impl str::FromStr for ChecksumAlgorithm {
+    type Err = ParseChecksumError;
+
+    /// Parse `crc32c`, `xxh3` or `sha256`.
+    fn from_str(s: &str) -> Result<ChecksumAlgorithm, ParseChecksumError> {
+        match s {
+            "crc32c" => Ok(ChecksumAlgorithm::Crc32c),
+            "xxh3" => Ok(ChecksumAlgorithm::Xxh3),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            _ => Err(ParseChecksumError(s.to_string())),
+        }
+    }
+}
+
+/// A digest being computed over bytes fed to it in any number of pieces.
+#[derive(Clone)]
+pub struct Checksum(State);
+
+#[derive(Clone)]
+enum State {
+    Crc32c(u32),
+    Xxh3(Box<Xxh3>),
+    Sha256(Box<Sha256>),
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Checksum {
+        Checksum(match algorithm {
+            ChecksumAlgorithm::Crc32c => State::Crc32c(!0),
+            ChecksumAlgorithm::Xxh3 => State::Xxh3(Box::new(Xxh3::new())),
+            ChecksumAlgorithm::Sha256 => State::Sha256(Box::new(Sha256::new())),
+        })
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self.0 {
+            State::Crc32c(_) => ChecksumAlgorithm::Crc32c,
+            State::Xxh3(_) => ChecksumAlgorithm::Xxh3,
+            State::Sha256(_) => ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match &mut self.0 {
+            State::Crc32c(crc) => *crc = crc32c_update(*crc, bytes),
+            State::Xxh3(xxh3) => xxh3.update(bytes),
+            State::Sha256(sha256) => sha256.update(bytes),
+        }
+    }
+
+    /// The digest of the bytes fed so far, big-endian, as the reference tools print it.
+    pub fn digest(&self) -> Vec<u8> {
+        match &self.0 {
+            State::Crc32c(crc) => (!crc).to_be_bytes().to_vec(),
+            State::Xxh3(xxh3) => xxh3.digest().to_be_bytes().to_vec(),
+            State::Sha256(sha256) => sha256.clone().finish().to_vec(),
+        }
+    }
+
+    /// The digest in lowercase hex.
+    pub fn hex_digest(&self) -> String {
+        self.digest().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// A writer that passes what is written through to another, computing a checksum of it.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: io::Write> ChecksumWriter<W> {
+    pub fn new(inner: W, algorithm: ChecksumAlgorithm) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            checksum: Checksum::new(algorithm),
+        }
+    }
+
+    /// The checksum of what has been written so far.
+    pub fn checksum(&self) -> &Checksum {
+        &self.checksum
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// The table for the reflected Castagnoli polynomial.
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32c_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc = CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[..4]).expect("4 bytes"))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[..8]).expect("8 bytes"))
+}
+
+const PRIME32_1: u64 = 0x9e37_79b1;
+const PRIME32_2: u64 = 0x85eb_ca77;
+const PRIME32_3: u64 = 0xc2b2_ae3d;
+const PRIME64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME64_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME64_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+/// The default secret of XXH3.
+const XXH3_SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const STRIPE_LEN: usize = 64;
+const SECRET_CONSUME_RATE: usize = 8;
+/// Where in the secret the scramble after each block reads from.
+const SECRET_LIMIT: usize = XXH3_SECRET.len() - STRIPE_LEN;
+const STRIPES_PER_BLOCK: usize = SECRET_LIMIT / SECRET_CONSUME_RATE;
+const MIDSIZE_MAX: usize = 240;
+const BUFFER_LEN: usize = 256;
+
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = u128::from(a) * u128::from(b);
+    product as u64 ^ (product >> 64) as u64
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn xxh3_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(0x1656_6791_9e37_79f9);
+    h ^ (h >> 32)
+}
+
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(0x9fb2_1c65_1e98_df25);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(0x9fb2_1c65_1e98_df25);
+    h ^ (h >> 28)
+}
+
+fn mix16(input: &[u8], secret: &[u8]) -> u64 {
+    mul128_fold64(
+        read_u64(input) ^ read_u64(secret),
+        read_u64(&input[8..]) ^ read_u64(&secret[8..]),
+    )
+}
+
+/// XXH3 of an input of up to `MIDSIZE_MAX` bytes, all at once.
+fn xxh3_short(input: &[u8]) -> u64 {
+    let secret = &XXH3_SECRET;
+    let len = input.len();
+    let len64 = len as u64;
+    match len {
+        0 => xxh64_avalanche(read_u64(&secret[56..]) ^ read_u64(&secret[64..])),
+        1..=3 => {
+            let combined = (u32::from(input[0]) << 16)
+                | (u32::from(input[len >> 1]) << 24)
+                | u32::from(input[len - 1])
+                | ((len as u32) << 8);
+            let bitflip = u64::from(read_u32(secret) ^ read_u32(&secret[4..]));
+            xxh64_avalanche(u64::from(combined) ^ bitflip)
+        }
+        4..=8 => {
+            let bitflip = read_u64(&secret[8..]) ^ read_u64(&secret[16..]);
+            let input64 = u64::from(read_u32(&input[len - 4..]))
+                .wrapping_add(u64::from(read_u32(input)) << 32);
+            rrmxmx(input64 ^ bitflip, len64)
+        }
+        9..=16 => {
+            let lo = read_u64(input) ^ read_u64(&secret[24..]) ^ read_u64(&secret[32..]);
+            let hi =
+                read_u64(&input[len - 8..]) ^ read_u64(&secret[40..]) ^ read_u64(&secret[48..]);
+            xxh3_avalanche(
+                len64
+                    .wrapping_add(lo.swap_bytes())
+                    .wrapping_add(hi)
+                    .wrapping_add(mul128_fold64(lo, hi)),
+            )
+        }
+        17..=128 => {
+            let mut acc = len64.wrapping_mul(PRIME64_1);
+            for i in 0..(len - 1) / 32 + 1 {
+                acc = acc
+                    .wrapping_add(mix16(&input[16 * i..], &secret[32 * i..]))
+                    .wrapping_add(mix16(&input[len - 16 * (i + 1)..], &secret[32 * i + 16..]));
+            }
+            xxh3_avalanche(acc)
+        }
+        _ => {
+            let mut acc = len64.wrapping_mul(PRIME64_1);
+            for i in 0..8 {
+                acc = acc.wrapping_add(mix16(&input[16 * i..], &secret[16 * i..]));
+            }
+            let mut acc_end = mix16(&input[len - 16..], &secret[136 - 17..]);
+            acc = xxh3_avalanche(acc);
+            for i in 8..len / 16 {
+                acc_end =
+                    acc_end.wrapping_add(mix16(&input[16 * i..], &secret[16 * (i - 8) + 3..]));
+            }
+            xxh3_avalanche(acc.wrapping_add(acc_end))
+        }
+    }
+}
+
+/// The streaming state of XXH3, as `XXH3_state_t` keeps it.
+#[derive(Clone)]
+struct Xxh3 {
+    acc: [u64; 8],
+    buffer: [u8; BUFFER_LEN],
+    buffered: usize,
+    stripes: usize,
+    len: u64,
+}
+
+impl Xxh3 {
+    fn new() -> Xxh3 {
+        Xxh3 {
+            acc: [
+                PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5,
+                PRIME32_1,
+            ],
+            buffer: [0; BUFFER_LEN],
+            buffered: 0,
+            stripes: 0,
+            len: 0,
+        }
+    }
+
+    fn accumulate_stripe(acc: &mut [u64; 8], stripe: &[u8], secret: &[u8]) {
+        for lane in 0..8 {
+            let value = read_u64(&stripe[lane * 8..]);
+            let key = value ^ read_u64(&secret[lane * 8..]);
+            acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(value);
+            acc[lane] = acc[lane].wrapping_add((key & 0xffff_ffff).wrapping_mul(key >> 32));
+        }
+    }
+
+    fn scramble(acc: &mut [u64; 8]) {
+        let secret = &XXH3_SECRET[SECRET_LIMIT..];
+        for (lane, acc) in acc.iter_mut().enumerate() {
+            *acc = (*acc ^ (*acc >> 47) ^ read_u64(&secret[lane * 8..])).wrapping_mul(PRIME32_1);
+        }
+    }
+
+    /// Accumulate the whole stripes of `input`, scrambling at the end of each block.
+    fn consume_stripes(acc: &mut [u64; 8], stripes_so_far: &mut usize, input: &[u8]) {
+        for stripe in input.chunks_exact(STRIPE_LEN) {
+            let secret = &XXH3_SECRET[*stripes_so_far * SECRET_CONSUME_RATE..];
+            Xxh3::accumulate_stripe(acc, stripe, secret);
+            *stripes_so_far += 1;
+            if *stripes_so_far == STRIPES_PER_BLOCK {
+                Xxh3::scramble(acc);
+                *stripes_so_far = 0;
+            }
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        self.len += input.len() as u64;
+        if input.len() <= BUFFER_LEN - self.buffered {
+            self.buffer[self.buffered..self.buffered + input.len()].copy_from_slice(input);
+            self.buffered += input.len();
+            return;
+        }
+        if self.buffered > 0 {
+            let load = BUFFER_LEN - self.buffered;
+            self.buffer[self.buffered..].copy_from_slice(&input[..load]);
+            input = &input[load..];
+            Xxh3::consume_stripes(&mut self.acc, &mut self.stripes, &self.buffer);
+            self.buffered = 0;
+        }
+        if input.len() > BUFFER_LEN {
+            // The last stripe is always left in the buffer, for the digest to finish with.
+            let consumed = (input.len() - 1) / STRIPE_LEN * STRIPE_LEN;
+            Xxh3::consume_stripes(&mut self.acc, &mut self.stripes, &input[..consumed]);
+            self.buffer[BUFFER_LEN - STRIPE_LEN..]
+                .copy_from_slice(&input[consumed - STRIPE_LEN..consumed]);
+            input = &input[consumed..];
+        }
+        self.buffer[..input.len()].copy_from_slice(input);
+        self.buffered = input.len();
+    }
+
+    fn digest(&self) -> u64 {
+        if self.len <= MIDSIZE_MAX as u64 {
+            return xxh3_short(&self.buffer[..self.buffered]);
+        }
+        let mut acc = self.acc;
+        let mut last = [0; STRIPE_LEN];
+        if self.buffered >= STRIPE_LEN {
+            let consumed = (self.buffered - 1) / STRIPE_LEN * STRIPE_LEN;
+            let mut stripes = self.stripes;
+            Xxh3::consume_stripes(&mut acc, &mut stripes, &self.buffer[..consumed]);
+            last.copy_from_slice(&self.buffer[self.buffered - STRIPE_LEN..self.buffered]);
+        } else {
+            // The rest of the last stripe is the end of what was consumed before.
+            let catch_up = STRIPE_LEN - self.buffered;
+            last[..catch_up].copy_from_slice(&self.buffer[BUFFER_LEN - catch_up..]);
+            last[catch_up..].copy_from_slice(&self.buffer[..self.buffered]);
+        }
+        Xxh3::accumulate_stripe(&mut acc, &last, &XXH3_SECRET[SECRET_LIMIT - 7..]);
+        let secret = &XXH3_SECRET[11..];
+        let mut result = self.len.wrapping_mul(PRIME64_1);
+        for i in 0..4 {
+            result = result.wrapping_add(mul128_fold64(
+                acc[2 * i] ^ read_u64(&secret[16 * i..]),
+                acc[2 * i + 1] ^ read_u64(&secret[16 * i + 8..]),
+            ));
+        }
+        xxh3_avalanche(result)
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    buffered: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(<[u8; 4]>::try_from(word).expect("4 bytes"));
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        self.len += input.len() as u64;
+        if self.buffered > 0 {
+            let load = (64 - self.buffered).min(input.len());
+            self.block[self.buffered..self.buffered + load].copy_from_slice(&input[..load]);
+            self.buffered += load;
+            input = &input[load..];
+            if self.buffered < 64 {
+                return;
+            }
+            Sha256::compress(&mut self.state, &self.block);
+            self.buffered = 0;
+        }
+        let mut blocks = input.chunks_exact(64);
+        for block in &mut blocks {
+            Sha256::compress(&mut self.state, block);
+        }
+        let rest = blocks.remainder();
+        self.block[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len.wrapping_mul(8);
+        let padding = if self.buffered < 56 { 56 } else { 120 } - self.buffered;
+        let mut tail = vec![0; padding + 8];
+        tail[0] = 0x80;
+        tail[padding..].copy_from_slice(&bits.to_be_bytes());
+        self.update(&tail);
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn digest(algorithm: ChecksumAlgorithm, input: &[u8], piece: usize) -> String {
+        let mut checksum = Checksum::new(algorithm);
+        for piece in input.chunks(piece.max(1)) {
+            checksum.update(piece);
+        }
+        checksum.hex_digest()
+    }
+
+    #[test]
+    fn test_checksums() -> Result<(), io::Error> {
+        use ChecksumAlgorithm::*;
+        assert_eq!(digest(Crc32c, b"123456789", 4), "e3069283");
+        assert_eq!(digest(Crc32c, b"", 1), "00000000");
+        assert_eq!(
+            digest(Sha256, b"abc", 1),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            digest(Sha256, long, 7),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // From the reference implementation, of bytes `i * 31 + 7` for each index `i`.
+        let vectors: [(usize, &str); 15] = [
+            (0, "2d06800538d394c2"),
+            (1, "4c5cca45d0f4811f"),
+            (3, "15f7093b173d005c"),
+            (4, "dca012f95811b6b9"),
+            (8, "dec6a9a43575982e"),
+            (9, "cbe393399f17ffbd"),
+            (16, "7e484c18d74895d0"),
+            (17, "208bde5ee2bed407"),
+            (128, "f92b70eaa21a6288"),
+            (129, "f8f76713f2bb60fa"),
+            (240, "ccc7375172c41f03"),
+            (241, "0b3b630948ce4a00"),
+            (1024, "23bc880ebf0d29c6"),
+            (1025, "c09fdfbc398c7d82"),
+            (5000, "559fff92c2b7f8ee"),
+        ];
+        for &(len, expected) in &vectors {
+            let input: Vec<u8> = (0..len).map(|i| (i * 31 + 7) as u8).collect();
+            for &piece in &[len, 1, 63, 100, 300] {
+                assert_eq!(
+                    digest(Xxh3, &input, piece),
+                    expected,
+                    "{} by {}",
+                    len,
+                    piece
+                );
+            }
+        }
+
+        let mut w = ChecksumWriter::new(Vec::new(), "crc32c".parse().unwrap());
+        w.write_all(b"12345")?;
+        w.write_all(b"6789")?;
+        assert_eq!(w.checksum().hex_digest(), "e3069283");
+        assert_eq!(w.into_inner(), b"123456789");
+        assert!("md5".parse::<ChecksumAlgorithm>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_edges() -> Result<(), io::Error> {
+        use ChecksumAlgorithm::*;
+        // The CRC-32C vectors of RFC 3720, B.4.
+        assert_eq!(digest(Crc32c, &[0; 32], 5), "8a9136aa");
+        assert_eq!(digest(Crc32c, &[0xff; 32], 5), "62a8ab43");
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(digest(Crc32c, &ascending, 3), "46dd794e");
+
+        // SHA-256 pads to a multiple of 64 bytes, with a second block from 56 bytes on, of bytes
+        // `i * 31 + 7` as for XXH3.
+        assert_eq!(
+            digest(Sha256, b"", 1),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let vectors: [(usize, &str); 6] = [
+            (
+                55,
+                "8aa994584139d128848eeebc4e815639ba5ab6e6e39574195a63ac4f14f7c43b",
+            ),
+            (
+                56,
+                "ad574708f75c044c9b85de64cb568ee7711ff4f36448c6242f053ba8f6cc2b63",
+            ),
+            (
+                63,
+                "280ed3e8ff1df845b2e7dfe6ac6cee817bef20e783cc65abc41b818b4d2fe076",
+            ),
+            (
+                64,
+                "c6ab9724ade5b6a7a1edfffb12f3aa9181351355af8fd08c919952ad211339dd",
+            ),
+            (
+                65,
+                "788367c73c7ddf4c53f65e68cc0d943e6227ab55b0e78ba63ace822b1c6301c0",
+            ),
+            (
+                1000,
+                "5097e7d587352f5097062ae679f37bda5802d9f875aba14c8cb4d1a188ada179",
+            ),
+        ];
+        for &(len, expected) in &vectors {
+            let input: Vec<u8> = (0..len).map(|i| (i * 31 + 7) as u8).collect();
+            for &piece in &[len, 1, 55, 64, 65] {
+                assert_eq!(
+                    digest(Sha256, &input, piece),
+                    expected,
+                    "{} by {}",
+                    len,
+                    piece
+                );
+            }
+        }
+
+        // Taking the digest partway leaves the checksum to carry on.
+        for algorithm in [Crc32c, Xxh3, Sha256] {
+            let mut checksum = Checksum::new(algorithm);
+            checksum.update(b"1234");
+            let partway = checksum.hex_digest();
+            assert_eq!(partway, digest(algorithm, b"1234", 4));
+            assert_eq!(checksum.hex_digest(), partway);
+            checksum.update(b"56789");
+            assert_eq!(checksum.hex_digest(), digest(algorithm, b"123456789", 9));
+            assert_eq!(checksum.algorithm(), algorithm);
+            assert_eq!(algorithm.name().parse(), Ok(algorithm));
+        }
+        assert_eq!(
+            "CRC32C"
+                .parse::<ChecksumAlgorithm>()
+                .unwrap_err()
+                .to_string(),
+            "Invalid checksum algorithm [CRC32C]"
+        );
+        Ok(())
+    }
+}
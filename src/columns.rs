@@ -0,0 +1,126 @@
+//! Sort keys taken from the columns of CSV records.
+
+use std::io;
+use std::str;
+
+/// A CSV column, either by its 0-based position or by its name in the header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CsvColumn {
+    Index(usize),
+    Name(String),
+}
+
+impl str::FromStr for CsvColumn {
+    type Err = std::convert::Infallible;
+
+    /// Parse a 1-based column number, as on the command line, or else a column name.
+    fn from_str(column: &str) -> Result<CsvColumn, Self::Err> {
+        Ok(match column.parse::<usize>() {
+            Ok(n) if n > 0 => CsvColumn::Index(n - 1),
+            _ => CsvColumn::Name(column.to_string()),
+        })
+    }
+}
+
+/// Extracts the values of some columns from each line, parsed as a CSV record, to use as its key
+/// with `Heap::with_key`. Quoted fields may contain delimiters and escaped quotes, but not line
+/// breaks, since every line is parsed on its own.
+#[derive(Clone, Debug)]
+pub struct CsvKey {
+    columns: Vec<usize>,
+    delimiter: u8,
+}
+
+impl CsvKey {
+    /// Compare records by the columns at the 0-based positions `columns`, in turn.
+    pub fn new(columns: Vec<usize>) -> CsvKey {
+        CsvKey {
+            columns,
+            delimiter: b',',
+        }
+    }
+
+    /// Like `new`, but look up named columns in `header`.
+    pub fn with_header(columns: &[CsvColumn], header: &str, delimiter: u8) -> io::Result<CsvKey> {
+        let names = parse(header, delimiter)?;
+        let columns = columns
+            .iter()
+            .map(|column| match column {
+                CsvColumn::Index(i) => Ok(*i),
+                CsvColumn::Name(name) => names.iter().position(|n| n == name).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("No column [{}] in the header", name),
+                    )
+                }),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(CsvKey::new(columns).with_delimiter(delimiter))
+    }
+
+    /// Set the field delimiter. Defaults to a comma.
+    pub fn with_delimiter(mut self, delimiter: u8) -> CsvKey {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// The values of the key's columns in `line`. Records that fail to parse, and columns past
+    /// the end of a record, count as empty.
+    pub fn extract(&self, line: &str) -> Vec<String> {
+        let record = parse(line, self.delimiter).unwrap_or_default();
+        self.columns
+            .iter()
+            .map(|i| record.get(*i).cloned().unwrap_or_default())
+            .collect()
+    }
+}
+
+fn parse(line: &str, delimiter: u8) -> io::Result<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record).map_err(io::Error::other)?;
+    Ok(record.iter().map(str::to_string).collect())
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+
+    #[test]
+    fn test_extract() {
+        let key = CsvKey::new(vec![2, 0]);
+        assert_eq!(key.extract(r#"a,"b,c","d ""e"""#), vec!["d \"e\"", "a"]);
+        assert_eq!(key.extract("a"), vec!["", "a"]);
+    }
+
+    #[test]
+    fn test_with_header() -> Result<(), io::Error> {
+        let columns = ["time".parse().unwrap(), "1".parse().unwrap()];
+        let key = CsvKey::with_header(&columns, "name;time", b';')?;
+        assert_eq!(key.extract("x;3"), vec!["3", "x"]);
+        let missing = CsvKey::with_header(&["size".parse().unwrap()], "name,time", b',');
+        assert!(missing.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_merge() -> Result<(), io::Error> {
+        let key = CsvKey::new(vec![1]);
+        let mut heap = Heap::with_key(move |line| key.extract(line)).with_headers(true);
+        heap.add_reader("file1".to_string(), "name,id\n\"b,c\",1\nd,3".as_bytes())?;
+        heap.add_reader("file2".to_string(), "name,id\na,2".as_bytes())?;
+        let mut output = Vec::new();
+        heap.write_sorted_lines(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,id\n\"b,c\",1\na,2\nd,3\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Merging the output of commands, e.g. `zcat logs/a.gz`, as inputs.
+
+use std::io;
+use std::process;
+
+use crate::Heap;
+
+/// The standard output of a child process, which fails at its end if the process exited
+/// unsuccessfully. A process that is dropped before its output ends, e.g. once `Heap::head` has
+/// enough lines, is killed.
+struct CommandOutput {
+    child: process::Child,
+    stdout: Option<process::ChildStdout>,
+}
+
+impl io::Read for CommandOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stdout = match &mut self.stdout {
+            Some(stdout) => stdout,
+            None => return Ok(0),
+        };
+        let n = stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.stdout = None;
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("command {}", status)));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for CommandOutput {
+    fn drop(&mut self) {
+        if self.stdout.take().is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Run `command` and add its standard output, named by the command line, e.g. `zcat a.gz`,
+    /// and decompressed if compression support is enabled. Its standard input is empty, and its
+    /// standard error is the heap's. An unsuccessful exit is an error reading it, once its output
+    /// has been merged. An error running it is handled as for `add_path`.
+    pub fn add_command(&mut self, command: process::Command) -> io::Result<()> {
+        let mut name = command.get_program().to_string_lossy().into_owned();
+        for arg in command.get_args() {
+            name.push(' ');
+            name.push_str(&arg.to_string_lossy());
+        }
+        self.add_named_command(name, command)
+    }
+
+    /// Like `add_command`, with the output named `name`.
+    pub fn add_named_command(
+        &mut self,
+        name: String,
+        mut command: process::Command,
+    ) -> io::Result<()> {
+        let spawned = command
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => return self.merge.failed_to_add(&name, err),
+        };
+        let stdout = child.stdout.take();
+        let output = CommandOutput { child, stdout };
+        match self.add_detected(name.clone(), Box::new(output), 0) {
+            Ok(()) => Ok(()),
+            Err(err) => self.merge.failed_to_add(&name, err),
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_command() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        let mut command = process::Command::new("printf");
+        command.arg("a\\nc\\n");
+        heap.add_command(command)?;
+        heap.add_reader("file1".to_string(), Box::new(io::Cursor::new("b\n")))?;
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "echo d"]);
+        heap.add_named_command("echo".to_string(), command)?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d"]);
+
+        let mut heap = Heap::new();
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "echo a; exit 3"]);
+        heap.add_command(command)?;
+        let err = heap
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[sh -c echo a; exit 3]"), "{}", err);
+        assert!(err.contains("exit status: 3"), "{}", err);
+
+        let mut heap = Heap::new();
+        let err = heap
+            .add_command(process::Command::new(
+                "merge-sorted-files-rs-no-such-command",
+            ))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("[merge-sorted-files-rs-no-such-command]"));
+
+        // A command whose output isn't all merged is killed rather than waited for.
+        let mut heap = Heap::new();
+        heap.add_command(process::Command::new("yes"))?;
+        let lines = heap.take(3).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["y", "y", "y"]);
+        Ok(())
+    }
+}
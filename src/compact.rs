@@ -0,0 +1,139 @@
+//! Compacting sorted runs into one, as the levels of an LSM tree are.
+
+use std::io;
+use std::path;
+
+use crate::{DuplicatePolicy, Heap};
+
+/// A compaction of sorted runs into a single run, in which each key appears once, with the
+/// record from the run with the highest priority. Keys are compared however the heap it is
+/// created from compares lines, e.g. by the key given to `Heap::with_key`.
+pub struct Compaction<T, K = ()>
+where
+    T: io::Read,
+{
+    heap: Heap<T, K>,
+    runs: i64,
+}
+
+impl<T, K> Compaction<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Compact the runs added to `heap` from now on, with its settings for splitting and
+    /// comparing records. Its duplicate policy is replaced by `DuplicatePolicy::HighestPriority`.
+    pub fn new(heap: Heap<T, K>) -> Compaction<T, K> {
+        Compaction {
+            heap: heap.with_duplicate_policy(DuplicatePolicy::HighestPriority),
+            runs: 0,
+        }
+    }
+
+    /// Drop the records for which `is_tombstone` returns true from the output, once they have
+    /// shadowed the older records with their key, as when compacting into the last level. Without
+    /// this, tombstones are kept like any other record.
+    pub fn drop_tombstones<F>(mut self, is_tombstone: F) -> Compaction<T, K>
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.heap = self
+            .heap
+            .with_output_filter(move |line| !is_tombstone(line));
+        self
+    }
+
+    /// Add a run whose records shadow those of the runs added before it. Its priority is the
+    /// number of runs added before it.
+    pub fn add_run_reader(&mut self, name: String, reader: T) -> io::Result<()> {
+        self.add_run_reader_with_priority(name, reader, self.runs)
+    }
+
+    /// Add a run whose records shadow those of runs with a lower priority and, for runs with the
+    /// same priority, those of the runs added before it.
+    pub fn add_run_reader_with_priority(
+        &mut self,
+        name: String,
+        reader: T,
+        priority: i64,
+    ) -> io::Result<()> {
+        self.runs += 1;
+        self.heap.add_reader_with_priority(name, reader, priority)
+    }
+
+    /// The compacted records, in order.
+    pub fn records(self) -> impl Iterator<Item = io::Result<String>> {
+        self.heap
+    }
+
+    /// Write the compacted records to `w` as `Heap::write_sorted_lines` does. Returns the number
+    /// of records written.
+    pub fn write_to<W: io::Write>(mut self, w: W) -> io::Result<u64> {
+        self.heap.write_sorted_lines(w)
+    }
+}
+
+impl<K> Compaction<Box<dyn io::Read + Send>, K>
+where
+    K: 'static,
+{
+    /// Like `add_run_reader`, for the run file at `path`, which is decompressed if compression
+    /// support is enabled.
+    pub fn add_run(&mut self, path: &path::Path) -> io::Result<()> {
+        self.add_run_with_priority(path, self.runs)
+    }
+
+    /// Like `add_run_reader_with_priority`, for the run file at `path`.
+    pub fn add_run_with_priority(&mut self, path: &path::Path, priority: i64) -> io::Result<()> {
+        self.runs += 1;
+        self.heap.add_file_with_priority(path, priority)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(line: &str) -> String {
+        line.split('=').next().unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn test_compaction() -> Result<(), io::Error> {
+        let mut compaction = Compaction::new(Heap::with_key(key));
+        compaction.add_run_reader("level1".to_string(), "a=1\nb=1\nc=1\n".as_bytes())?;
+        compaction.add_run_reader("level0".to_string(), "a=2\nb=\nb=3\nd=3\n".as_bytes())?;
+        assert_eq!(
+            compaction.records().collect::<io::Result<Vec<_>>>()?,
+            vec!["a=2", "b=3", "c=1", "d=3"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_tombstones() -> Result<(), io::Error> {
+        let compaction = || -> io::Result<Compaction<&[u8], String>> {
+            let mut compaction = Compaction::new(Heap::with_key(key));
+            compaction.add_run_reader_with_priority(
+                "level0".to_string(),
+                "b=\nc=2\n".as_bytes(),
+                1,
+            )?;
+            compaction.add_run_reader_with_priority(
+                "level1".to_string(),
+                "a=1\nb=1\nc=1\n".as_bytes(),
+                0,
+            )?;
+            Ok(compaction)
+        };
+        let mut out = Vec::new();
+        assert_eq!(compaction()?.write_to(&mut out)?, 3);
+        assert_eq!(out, b"a=1\nb=\nc=2\n");
+        let mut out = Vec::new();
+        let compaction = compaction()?.drop_tombstones(|line| line.ends_with('='));
+        assert_eq!(compaction.write_to(&mut out)?, 2);
+        assert_eq!(out, b"a=1\nc=2\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! The errors a merge can fail with.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Why a merge failed. The merge APIs return `io::Error`s so they compose with the readers and
+/// writers around them; `MergeError::from_io` recovers the structured error from one of those.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MergeError {
+    /// Reading an input failed.
+    Io { source: io::Error, file: String },
+    /// An input yielded an item that sorts before its predecessor. `line_no` is the 1-based
+    /// position of the offending item, and `prev` and `next` are the two items, if the input knows
+    /// how to describe them.
+    OutOfOrder {
+        file: String,
+        line_no: u64,
+        prev: Option<String>,
+        next: Option<String>,
+    },
+    /// A line of an input was not valid UTF-8.
+    InvalidUtf8 { file: String, line_no: u64 },
+    /// The merge was cancelled with the flag given to `with_cancellation`.
+    Cancelled,
+}
+
+impl MergeError {
+    /// The `MergeError` wrapped by `err`, if it is one.
+    pub fn from_io(err: &io::Error) -> Option<&MergeError> {
+        err.get_ref().and_then(|err| err.downcast_ref())
+    }
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Io { source, file } => {
+                write!(f, "Error reading file [{}]: {}", file, source)
+            }
+            MergeError::OutOfOrder { file, line_no, .. } => write!(
+                f,
+                "Input lines in file [{}] out of order at line {}!",
+                file, line_no
+            ),
+            MergeError::InvalidUtf8 { file, line_no } => {
+                write!(f, "Line {} of file [{}] is not valid UTF-8", line_no, file)
+            }
+            MergeError::Cancelled => write!(f, "Merge cancelled"),
+        }
+    }
+}
+
+impl error::Error for MergeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MergeError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<MergeError> for io::Error {
+    fn from(err: MergeError) -> io::Error {
+        let kind = match &err {
+            MergeError::Io { source, .. } => source.kind(),
+            MergeError::OutOfOrder { .. } => io::ErrorKind::Other,
+            MergeError::InvalidUtf8 { .. } => io::ErrorKind::InvalidData,
+            // Not `Interrupted`, which `io::copy` and the like retry on.
+            MergeError::Cancelled => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+pub(crate) fn out_of_order_error(
+    file: &str,
+    line_no: u64,
+    prev: Option<String>,
+    next: Option<String>,
+) -> io::Error {
+    MergeError::OutOfOrder {
+        file: file.to_string(),
+        line_no,
+        prev,
+        next,
+    }
+    .into()
+}
+
+/// Attribute an error reading `file` to it, unless it already says where it came from.
+pub(crate) fn in_file(err: io::Error, file: &str) -> io::Error {
+    if MergeError::from_io(&err).is_some() {
+        return err;
+    }
+    MergeError::Io {
+        source: err,
+        file: file.to_string(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io() {
+        let err = out_of_order_error("a", 3, Some("c".to_string()), Some("b".to_string()));
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        match MergeError::from_io(&err) {
+            Some(MergeError::OutOfOrder {
+                file,
+                line_no,
+                prev,
+                next,
+            }) => {
+                assert_eq!(file, "a");
+                assert_eq!(*line_no, 3);
+                assert_eq!(prev.as_deref(), Some("c"));
+                assert_eq!(next.as_deref(), Some("b"));
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert!(MergeError::from_io(&io::Error::other("oops")).is_none());
+    }
+
+    #[test]
+    fn test_in_file() {
+        let err = in_file(io::Error::new(io::ErrorKind::NotFound, "gone"), "a");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(format!("{}", err), "Error reading file [a]: gone");
+        let err = in_file(err, "b");
+        assert_eq!(format!("{}", err), "Error reading file [a]: gone");
+    }
+}
@@ -0,0 +1,77 @@
+//! Telling the kernel how the files added to a heap are read, so it reads ahead of the merge.
+
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use crate::Heap;
+
+/// How many bytes from where a file is first read the kernel is asked to read ahead of it.
+pub(crate) const WILL_NEED: u64 = 1 << 20;
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Whether to advise the kernel that each file added from now on is read sequentially, from
+    /// where the merge starts reading it, with the first MiB read soon, on Linux. The kernel then
+    /// reads further ahead of the merge. Elsewhere, the files are read without advice.
+    pub fn with_access_hints(mut self, hints: bool) -> Heap<T, K> {
+        self.access_hints = hints;
+        self
+    }
+
+    /// Advise the kernel, if asked to, that `f` is read sequentially from `offset`.
+    pub(crate) fn advise(&self, f: &fs::File, offset: u64) {
+        if !self.access_hints {
+            return;
+        }
+        // Advice is only advice, so the merge goes on without it if it can't be given.
+        #[cfg(target_os = "linux")]
+        unsafe {
+            let fd = f.as_raw_fd();
+            libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+            libc::posix_fadvise(
+                fd,
+                offset as libc::off_t,
+                WILL_NEED as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            );
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (f, offset);
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_access_hints() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir();
+        let path1 = dir.join(format!(
+            "merge-sorted-files-rs-test-fadvise1-{}",
+            std::process::id()
+        ));
+        let path2 = dir.join(format!(
+            "merge-sorted-files-rs-test-fadvise2-{}",
+            std::process::id()
+        ));
+        fs::write(&path1, "h\na\nc\ne\n")?;
+        fs::write(&path2, "h\nb\nd\n")?;
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new()
+            .with_access_hints(true)
+            .with_headers(true)
+            .with_start_line("b");
+        heap.add_file(&path1)?;
+        heap.add_file(&path2)?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["b", "c", "d", "e"]);
+        fs::remove_file(&path1)?;
+        fs::remove_file(&path2)?;
+        Ok(())
+    }
+}
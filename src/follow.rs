@@ -0,0 +1,149 @@
+//! Following files as they grow, like `tail -f`, merging lines appended to them as they arrive.
+
+use std::io;
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time;
+
+use crate::{Heap, Line, Original};
+
+/// A reader that, rather than ending, waits at its end for more to be written to it, checking
+/// every `interval`, until `cancel` is set.
+pub(crate) struct Follow<R> {
+    reader: R,
+    interval: time::Duration,
+    cancel: Option<sync::Arc<atomic::AtomicBool>>,
+}
+
+impl<R> Follow<R> {
+    pub(crate) fn new(
+        reader: R,
+        interval: time::Duration,
+        cancel: Option<sync::Arc<atomic::AtomicBool>>,
+    ) -> Follow<R> {
+        Follow {
+            reader,
+            interval,
+            cancel,
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        match &self.cancel {
+            Some(cancel) => cancel.load(atomic::Ordering::Relaxed),
+            None => false,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.reader.read(buf)?;
+            if n > 0 || buf.is_empty() || self.cancelled() {
+                return Ok(n);
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Follow the files added from now on, like `tail -f`: at the end of a file, wait for lines to
+    /// be appended to it, checking every `interval`, rather than ending it. Since a line is only
+    /// merged once every input has a line after it, or has ended, the merge keeps up with the
+    /// slowest file. It goes on until cancelled with `with_cancellation`, which must come first,
+    /// after which the files end where they are. Files that are truncated or replaced, e.g.
+    /// rotated, aren't reopened.
+    ///
+    /// Each line is written before the next one is read from its file, so the last line of a
+    /// file isn't held back until another is appended to it.
+    pub fn with_follow(mut self, interval: time::Duration) -> Self
+    where
+        K: 'static,
+    {
+        self.follow = Some(interval);
+        let key = self.key.clone();
+        self.merge = self
+            .merge
+            .with_lazy_refill(sync::Arc::new(move |line: &Line<K>| Line {
+                text: line.text.clone(),
+                key: key(&line.text),
+                prefix: line.prefix,
+                original: Original::default(),
+            }));
+        self
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_with_follow() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-follow-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "a\nc\n")?;
+        let cancel = sync::Arc::new(atomic::AtomicBool::new(false));
+        let mut heap = Heap::new()
+            .with_cancellation(cancel.clone())
+            .with_follow(time::Duration::from_millis(5));
+        heap.add_path(&path)?;
+        heap.add_reader("file2".to_string(), Box::new("b\n".as_bytes()))?;
+        assert_eq!(heap.size_hint().1, None);
+        let appender = {
+            let path = path.clone();
+            thread::spawn(move || -> io::Result<()> {
+                thread::sleep(time::Duration::from_millis(50));
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(&path)?
+                    .write_all(b"d\n")?;
+                thread::sleep(time::Duration::from_millis(50));
+                cancel.store(true, atomic::Ordering::Relaxed);
+                Ok(())
+            })
+        };
+        let lines = heap.by_ref().take(4).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d"]);
+        appender.join().unwrap()?;
+        assert!(heap.next().is_none());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_follow_emits_last_line() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-follow-last-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "x1\nx2\n")?;
+        let cancel = sync::Arc::new(atomic::AtomicBool::new(false));
+        let mut heap = Heap::new()
+            .with_cancellation(cancel.clone())
+            .with_follow(time::Duration::from_millis(5));
+        heap.add_path(&path)?;
+        // Nothing is appended, so if a line waited on the next one it would only come out once
+        // the merge is cancelled.
+        let started = time::Instant::now();
+        let canceller = thread::spawn(move || {
+            thread::sleep(time::Duration::from_secs(2));
+            cancel.store(true, atomic::Ordering::Relaxed);
+        });
+        let lines = heap.by_ref().take(2).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["x1", "x2"]);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        canceller.join().unwrap();
+        assert!(heap.next().is_none());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
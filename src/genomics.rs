@@ -0,0 +1,427 @@
+//! Merging VCF and BED files the way bcftools and bedtools sort them: by contig, in the order the
+//! reference lists its contigs rather than by their names, then by position. The `#` header
+//! lines of the inputs are merged into one header rather than taken from the first input alone.
+
+use std::collections;
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+use std::sync;
+
+use crate::error::in_file;
+use crate::{Heap, Line, LineSource, Original};
+
+/// Which kind of genomic intervals the lines of the inputs are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenomicFormat {
+    /// VCF records, with the contig and the 1-based position in their first two columns.
+    Vcf,
+    /// BED intervals, with the contig, the 0-based start and the end in their first three
+    /// columns.
+    Bed,
+}
+
+impl GenomicFormat {
+    /// What the header lines of the format start with.
+    fn header_prefixes(self) -> &'static [&'static str] {
+        match self {
+            GenomicFormat::Vcf => &["#"],
+            GenomicFormat::Bed => &["#", "track", "browser"],
+        }
+    }
+}
+
+/// The error returned when a genomic format isn't recognized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseGenomicFormatError(String);
+
+impl fmt::Display for ParseGenomicFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid genomic format [{}]", self.0)
+    }
+}
+
+impl error::Error for ParseGenomicFormatError {}
+
+impl str::FromStr for GenomicFormat {
+    type Err = ParseGenomicFormatError;
+
+    /// Parse `vcf` or `bed`.
+    fn from_str(s: &str) -> Result<GenomicFormat, ParseGenomicFormatError> {
+        match s {
+            "vcf" => Ok(GenomicFormat::Vcf),
+            "bed" => Ok(GenomicFormat::Bed),
+            _ => Err(ParseGenomicFormatError(s.to_string())),
+        }
+    }
+}
+
+/// The order of the contigs of a reference, as listed by its `.fai` index, its `.dict` sequence
+/// dictionary or the `##contig` lines of a VCF header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContigOrder {
+    ranks: collections::HashMap<String, usize>,
+}
+
+impl ContigOrder {
+    /// The contigs `names`, in order. A name listed twice keeps its first place.
+    pub fn new<I, S>(names: I) -> ContigOrder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut ranks = collections::HashMap::new();
+        for name in names {
+            let rank = ranks.len();
+            ranks.entry(name.into()).or_insert(rank);
+        }
+        ContigOrder { ranks }
+    }
+
+    /// Read the contigs of a `.fai` index, the first column of each of its lines.
+    pub fn from_fai<R: io::BufRead>(r: R) -> io::Result<ContigOrder> {
+        let lines = r.lines().collect::<io::Result<Vec<_>>>()?;
+        Ok(ContigOrder::new(
+            lines
+                .iter()
+                .filter(|line| !line.is_empty())
+                .map(|line| line.split('\t').next().unwrap_or_default()),
+        ))
+    }
+
+    /// Read the contigs of a `.dict` sequence dictionary, the `SN` of each of its `@SQ` lines.
+    pub fn from_dict<R: io::BufRead>(r: R) -> io::Result<ContigOrder> {
+        let lines = r.lines().collect::<io::Result<Vec<_>>>()?;
+        Ok(ContigOrder::new(lines.iter().filter_map(|line| {
+            let mut fields = line.split('\t');
+            if fields.next() != Some("@SQ") {
+                return None;
+            }
+            fields.find_map(|field| field.strip_prefix("SN:"))
+        })))
+    }
+
+    /// Read the contigs of a `.dict` if it starts with a SAM header line, else of a `.fai`.
+    pub fn read<R: io::BufRead>(mut r: R) -> io::Result<ContigOrder> {
+        if r.fill_buf()?.starts_with(b"@") {
+            ContigOrder::from_dict(r)
+        } else {
+            ContigOrder::from_fai(r)
+        }
+    }
+
+    /// The contigs of the `##contig=<ID=...>` lines of a VCF header.
+    pub fn from_vcf_header<'a, I>(lines: I) -> ContigOrder
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        ContigOrder::new(lines.into_iter().filter_map(|line| {
+            let fields = line.strip_prefix("##contig=<")?.strip_suffix('>')?;
+            fields
+                .split(',')
+                .find_map(|field| field.strip_prefix("ID="))
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranks.is_empty()
+    }
+
+    /// Where `contig` is in the order, if it is in it.
+    pub fn rank(&self, contig: &str) -> Option<usize> {
+        self.ranks.get(contig).copied()
+    }
+}
+
+/// The contig of a `GenomicPosition`: contigs in the `ContigOrder` sort in its order, before those
+/// that aren't, which sort by name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Contig {
+    Ranked(usize),
+    Unranked(String),
+}
+
+/// Where a record lies on the reference, which orders records by contig, then start, then end.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GenomicPosition {
+    pub contig: Contig,
+    /// The position of a VCF record, or the start of a BED interval.
+    pub start: u64,
+    /// The end of a BED interval, or the position of a VCF record.
+    pub end: u64,
+}
+
+/// Parses the contig and position of each line of a VCF or BED file, to use as its key with
+/// `Heap::with_key`. Lines without them have a key of `None`, which sorts first.
+#[derive(Clone, Debug)]
+pub struct GenomicKey {
+    format: GenomicFormat,
+    contigs: sync::Arc<ContigOrder>,
+}
+
+impl GenomicKey {
+    pub fn new(format: GenomicFormat, contigs: ContigOrder) -> GenomicKey {
+        GenomicKey {
+            format,
+            contigs: sync::Arc::new(contigs),
+        }
+    }
+
+    pub fn format(&self) -> GenomicFormat {
+        self.format
+    }
+
+    pub fn extract(&self, line: &str) -> Option<GenomicPosition> {
+        let mut fields = line.split('\t');
+        let contig = fields.next()?;
+        let start = fields.next()?.parse().ok()?;
+        let end = match self.format {
+            GenomicFormat::Vcf => start,
+            GenomicFormat::Bed => fields.next()?.parse().ok()?,
+        };
+        let contig = match self.contigs.rank(contig) {
+            Some(rank) => Contig::Ranked(rank),
+            None => Contig::Unranked(contig.to_string()),
+        };
+        Some(GenomicPosition { contig, start, end })
+    }
+}
+
+/// Whether `line` is the column header line of a header, e.g. VCF's `#CHROM`, rather than
+/// meta-information such as `##` lines or BED's `track` lines.
+fn is_column_header(line: &str) -> bool {
+    line.starts_with('#') && !line.starts_with("##")
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Treat the leading lines of each input that are headers in `format`, however many there
+    /// are, as its header, and write the header of all the inputs merged before the merged lines.
+    /// That is the meta-information lines of the inputs, each once, in the order they first
+    /// appear, followed by the column header line, which must be the same in every input that
+    /// has one.
+    pub fn with_genomic_header(mut self, format: GenomicFormat) -> Heap<T, K> {
+        self.header_prefixes = format.header_prefixes();
+        self.with_emit_header(true)
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Consume the header lines of `source`, those starting with one of the header prefixes, and
+    /// merge them into the header.
+    pub(crate) fn read_prefixed_header<R: io::Read>(
+        &mut self,
+        source: &mut LineSource<R, K>,
+    ) -> io::Result<()> {
+        let name = source.name.clone();
+        let mut header = Vec::new();
+        loop {
+            let next =
+                io::BufRead::fill_buf(&mut source.reader).map_err(|err| in_file(err, &name))?;
+            if !self
+                .header_prefixes
+                .iter()
+                .any(|prefix| next.starts_with(prefix.as_bytes()))
+            {
+                break;
+            }
+            let mut line = Line {
+                text: String::new(),
+                key: (),
+                prefix: 0,
+                original: Original::default(),
+            };
+            source
+                .read_line(&mut line.text, &mut line.original)
+                .map_err(|err| in_file(err, &name))?;
+            source.trim(&mut line.text, &mut line.original);
+            header.push(line);
+        }
+        let merged = &mut self.header;
+        let columns = merged.iter().position(|line| is_column_header(&line.text));
+        let mut meta: collections::HashSet<String> = merged
+            .iter()
+            .filter(|line| !is_column_header(&line.text))
+            .map(|line| line.text.clone())
+            .collect();
+        let mut insert_at = columns.unwrap_or(merged.len());
+        for line in header {
+            if is_column_header(&line.text) {
+                match merged.iter().find(|merged| is_column_header(&merged.text)) {
+                    Some(existing) if existing.text != line.text => {
+                        return Err(in_file(
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "Header columns [{}] differ from [{}]",
+                                    line.text, existing.text
+                                ),
+                            ),
+                            &name,
+                        ));
+                    }
+                    Some(_) => {}
+                    None => merged.push(line),
+                }
+            } else if meta.insert(line.text.clone()) {
+                merged.insert(insert_at, line);
+                insert_at += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genomic_merge() -> Result<(), io::Error> {
+        let contigs = ContigOrder::from_vcf_header(vec![
+            "##fileformat=VCFv4.2",
+            "##contig=<ID=chr2,length=100>",
+            "##contig=<ID=chr10,length=100>",
+            "##contig=<ID=chr1,length=100>",
+        ]);
+        assert_eq!((contigs.len(), contigs.rank("chr1")), (3, Some(2)));
+        let key = GenomicKey::new(GenomicFormat::Vcf, contigs);
+        let mut heap =
+            Heap::with_key(move |line| key.extract(line)).with_genomic_header(GenomicFormat::Vcf);
+        let a = "##fileformat=VCFv4.2\n##source=a\n#CHROM\tPOS\n\
+                 chr2\t5\nchr10\t3\nchr1\t200\nchrUn\t1\n";
+        let b = "##fileformat=VCFv4.2\n##source=b\n#CHROM\tPOS\n\
+                 chr2\t40\nchr10\t1\nchr1\t7\n";
+        heap.add_reader("a".to_string(), a.as_bytes())?;
+        heap.add_reader("b".to_string(), b.as_bytes())?;
+        let mut out = Vec::new();
+        heap.write_sorted_lines(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "##fileformat=VCFv4.2\n##source=a\n##source=b\n#CHROM\tPOS\n\
+             chr2\t5\nchr2\t40\nchr10\t1\nchr10\t3\nchr1\t7\nchr1\t200\nchrUn\t1\n"
+        );
+
+        let mut heap = Heap::new().with_genomic_header(GenomicFormat::Vcf);
+        heap.add_reader("a".to_string(), "#CHROM\tPOS\tA\n".as_bytes())?;
+        assert!(heap
+            .add_reader("b".to_string(), "#CHROM\tPOS\tB\n".as_bytes())
+            .is_err());
+
+        let fai = "chrX\t100\t6\t60\t61\nchr1\t100\t200\t60\t61\n";
+        let dict = "@HD\tVN:1.6\n@SQ\tSN:chrX\tLN:100\n@SQ\tSN:chr1\tLN:100\n";
+        let contigs = ContigOrder::read(fai.as_bytes())?;
+        assert_eq!(ContigOrder::read(dict.as_bytes())?, contigs);
+        let key = GenomicKey::new(GenomicFormat::Bed, contigs);
+        let mut heap =
+            Heap::with_key(move |line| key.extract(line)).with_genomic_header(GenomicFormat::Bed);
+        heap.add_reader(
+            "a".to_string(),
+            "track name=a\nchrX\t5\t9\nchr1\t5\t6\n".as_bytes(),
+        )?;
+        heap.add_reader("b".to_string(), "chrX\t5\t7\nchr1\t1\t2\n".as_bytes())?;
+        let mut out = Vec::new();
+        heap.write_sorted_lines(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "track name=a\nchrX\t5\t7\nchrX\t5\t9\nchr1\t1\t2\nchr1\t5\t6\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_contig_fallback() -> Result<(), io::Error> {
+        let merged = |contigs: ContigOrder| -> io::Result<Vec<String>> {
+            let key = GenomicKey::new(GenomicFormat::Vcf, contigs);
+            let mut heap = Heap::with_key(move |line| key.extract(line));
+            for contig in ["chr2", "chr10", "chr1", "chrM"] {
+                let line = format!("{}\t1\n", contig).into_bytes();
+                heap.add_reader(contig.to_string(), io::Cursor::new(line))?;
+            }
+            heap.collect()
+        };
+        // Without `##contig` lines, contigs sort by name.
+        let contigs = ContigOrder::from_vcf_header(vec!["##fileformat=VCFv4.2", "#CHROM\tPOS"]);
+        assert!(contigs.is_empty());
+        assert_eq!(
+            merged(contigs)?,
+            ["chr1\t1", "chr10\t1", "chr2\t1", "chrM\t1"]
+        );
+        // Those it has come first, in its order, and the rest after them by name.
+        let contigs = ContigOrder::from_vcf_header(vec!["##contig=<ID=chrM>"]);
+        assert_eq!(
+            merged(contigs)?,
+            ["chrM\t1", "chr1\t1", "chr10\t1", "chr2\t1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_genomic_headers() -> Result<(), io::Error> {
+        // Meta-information lines are kept once each, in the order they first appear, before the
+        // column header, whether or not an input has one.
+        let mut heap = Heap::new().with_genomic_header(GenomicFormat::Vcf);
+        heap.add_reader(
+            "a".to_string(),
+            "##fileformat=VCFv4.2\n##a\n#CHROM\tPOS\nchr1\t1\n".as_bytes(),
+        )?;
+        heap.add_reader(
+            "b".to_string(),
+            "##fileformat=VCFv4.2\n##b\nchr1\t2\n".as_bytes(),
+        )?;
+        heap.add_reader(
+            "c".to_string(),
+            "##a\n##c\n#CHROM\tPOS\nchr1\t3\n".as_bytes(),
+        )?;
+        heap.add_reader("d".to_string(), "chr1\t4\n".as_bytes())?;
+        let mut out = Vec::new();
+        heap.write_sorted_lines(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "##fileformat=VCFv4.2\n##a\n##b\n##c\n#CHROM\tPOS\n\
+             chr1\t1\nchr1\t2\nchr1\t3\nchr1\t4\n"
+        );
+
+        // An input whose columns differ is named.
+        let mut heap = Heap::new().with_genomic_header(GenomicFormat::Vcf);
+        heap.add_reader("a".to_string(), "##a\n#CHROM\tPOS\n".as_bytes())?;
+        let err = heap
+            .add_reader("b".to_string(), "##b\n#CHROM\tPOS\tID\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("[b]"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bed_ends() -> Result<(), io::Error> {
+        // Intervals with the same start sort by their ends, within an input and across them.
+        let key = GenomicKey::new(GenomicFormat::Bed, ContigOrder::default());
+        let mut heap = Heap::with_key(move |line| key.extract(line));
+        heap.add_reader("a".to_string(), "chr1\t5\t6\nchr1\t5\t9\n".as_bytes())?;
+        heap.add_reader("b".to_string(), "chr1\t5\t7\nchr1\t5\t8\n".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            ["chr1\t5\t6", "chr1\t5\t7", "chr1\t5\t8", "chr1\t5\t9"]
+        );
+        let key = GenomicKey::new(GenomicFormat::Bed, ContigOrder::default());
+        assert!(key.extract("chr1\t5").is_none());
+        assert!(key.extract("chr1\t5\tx").is_none());
+        let (short, long) = (key.extract("chr1\t5\t6"), key.extract("chr1\t5\t60"));
+        assert!(short < long);
+        Ok(())
+    }
+}
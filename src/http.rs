@@ -0,0 +1,488 @@
+//! Reading inputs over HTTP, streaming response bodies, and resuming them partway with range
+//! requests. Only plain HTTP is supported: `https://` URLs are rejected, as they would need a TLS
+//! implementation, so HTTPS sources have to be fetched some other way, e.g. piped in as `-`.
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::net;
+use std::path;
+use std::sync::atomic;
+
+use crate::input::Input;
+use crate::Heap;
+
+/// How many redirects are followed before giving up on a URL.
+const MAX_REDIRECTS: usize = 5;
+
+/// Whether `name` is a URL for `Heap::add_url`, rather than a path.
+pub(crate) fn is_url(name: &str) -> bool {
+    name.starts_with("http://") || name.starts_with("https://")
+}
+
+fn invalid_url(url: &str, why: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid URL [{}]: {}", url, why),
+    )
+}
+
+/// Where a URL points: the server to connect to, and the path to request of it.
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> io::Result<Url> {
+        if url.starts_with("https://") {
+            return Err(invalid_url(url, "HTTPS isn't supported, only plain HTTP"));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid_url(url, "not an HTTP URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse().map_err(|_| invalid_url(url, "bad port"))?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid_url(url, "no host"));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// The `Host` header for the URL: the host, and the port unless it is the default.
+    fn authority(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+
+    /// The URL a redirect to `location` from this one leads to.
+    fn redirect(&self, location: &str) -> String {
+        if is_url(location) {
+            location.to_string()
+        } else {
+            format!("http://{}:{}{}", self.host, self.port, location)
+        }
+    }
+}
+
+/// How the end of a response body is found.
+enum Framing {
+    /// After this many more bytes.
+    Length(u64),
+    /// After the chunk of this many more bytes, and the chunks after it, up to an empty one.
+    Chunked(u64),
+    /// When the server closes the connection.
+    Close,
+}
+
+/// The body of a response, streamed from the connection it arrives on.
+pub(crate) struct Body {
+    conn: io::BufReader<net::TcpStream>,
+    framing: Framing,
+    /// The length of the body, if the response says and it is the whole of what was requested.
+    len: Option<u64>,
+}
+
+/// The status, headers and body of a response.
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Body,
+}
+
+impl Response {
+    /// The value of the header `name`, if the response has one.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Read the rest of the body, failing unless the status is one of `ok`.
+    #[cfg(feature = "object-store")]
+    pub(crate) fn text(mut self, ok: &[u16]) -> io::Result<String> {
+        let mut text = String::new();
+        io::Read::read_to_string(&mut self.body, &mut text)?;
+        if !ok.contains(&self.status) {
+            return Err(io::Error::other(format!("HTTP status {}", self.status)));
+        }
+        Ok(text)
+    }
+}
+
+/// Send a request to `url` with `method`, the header lines `headers`, and `body`, if it isn't
+/// empty, returning the response as soon as its headers arrive.
+pub(crate) fn send(url: &str, method: &str, headers: &str, body: &[u8]) -> io::Result<Response> {
+    let target = Url::parse(url)?;
+    let conn = net::TcpStream::connect((target.host.as_str(), target.port))?;
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: merge-sorted-files-rs\r\n\
+         Accept-Encoding: identity\r\nConnection: close\r\n{}",
+        method,
+        target.path,
+        target.authority(),
+        headers
+    );
+    if !body.is_empty() || method != "GET" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    (&conn).write_all(request.as_bytes())?;
+    (&conn).write_all(body)?;
+    let mut conn = io::BufReader::new(conn);
+    let (status, headers) = read_head(&mut conn)?;
+    let mut response = Response {
+        status,
+        headers,
+        body: Body {
+            conn,
+            framing: Framing::Close,
+            len: None,
+        },
+    };
+    response.body.framing = if response
+        .header("transfer-encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        Framing::Chunked(0)
+    } else if method == "HEAD" || status == 204 || status == 304 {
+        Framing::Length(0)
+    } else {
+        match response.header("content-length") {
+            Some(len) => Framing::Length(len.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length")
+            })?),
+            None => Framing::Close,
+        }
+    };
+    Ok(response)
+}
+
+impl Body {
+    /// Request `url` from `offset` on, following redirects. A server that doesn't support range
+    /// requests sends the whole body, whose first `offset` bytes are then skipped.
+    pub(crate) fn get(url: &str, offset: u64) -> io::Result<Body> {
+        let mut url = url.to_string();
+        let range = if offset > 0 {
+            format!("Range: bytes={}-\r\n", offset)
+        } else {
+            String::new()
+        };
+        for _ in 0..=MAX_REDIRECTS {
+            let response = send(&url, "GET", &range, &[])?;
+            if let (301 | 302 | 303 | 307 | 308, Some(location)) =
+                (response.status, response.header("location"))
+            {
+                url = Url::parse(&url)?.redirect(location);
+                continue;
+            }
+            let Response {
+                status, mut body, ..
+            } = response;
+            match status {
+                200 => {
+                    if let Framing::Length(len) = body.framing {
+                        body.len = Some(len.saturating_sub(offset));
+                    }
+                    io::copy(&mut io::Read::take(&mut body, offset), &mut io::sink())?;
+                }
+                206 => {
+                    if let Framing::Length(len) = body.framing {
+                        body.len = Some(len);
+                    }
+                }
+                // The offset is at or past the end of the body.
+                416 if offset > 0 => {
+                    body.framing = Framing::Length(0);
+                    body.len = Some(0);
+                }
+                _ => {
+                    return Err(io::Error::other(format!("HTTP status {}", status)));
+                }
+            }
+            return Ok(body);
+        }
+        Err(io::Error::other("Too many redirects"))
+    }
+}
+
+/// Read the status line and headers of a response.
+fn read_head<R: BufRead>(conn: &mut R) -> io::Result<(u16, Vec<(String, String)>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed HTTP response");
+    let mut line = String::new();
+    conn.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(malformed)?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if conn.read_line(&mut line)? == 0 {
+            return Err(malformed());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(malformed)?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok((status, headers))
+}
+
+impl io::Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = match &mut self.framing {
+            Framing::Length(left) => *left,
+            Framing::Chunked(0) => {
+                let mut line = String::new();
+                // The delimiter after the chunk before, other than before the first.
+                if self.conn.read_line(&mut line)? > 0 && line.trim().is_empty() {
+                    line.clear();
+                    self.conn.read_line(&mut line)?;
+                }
+                let size = line.trim().split(';').next().unwrap_or("");
+                let size = u64::from_str_radix(size, 16).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Malformed HTTP chunk")
+                })?;
+                if size == 0 {
+                    self.framing = Framing::Length(0);
+                    return Ok(0);
+                }
+                self.framing = Framing::Chunked(size);
+                size
+            }
+            Framing::Chunked(left) => *left,
+            Framing::Close => u64::MAX,
+        };
+        let len = buf.len().min(usize::try_from(limit).unwrap_or(usize::MAX));
+        let n = self.conn.read(&mut buf[..len])?;
+        match &mut self.framing {
+            Framing::Length(left) | Framing::Chunked(left) => {
+                if n == 0 && len > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "HTTP response ended early",
+                    ));
+                }
+                *left -= n as u64;
+            }
+            Framing::Close => {}
+        }
+        Ok(n)
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Add the body of the response to a GET request for `url`, an `http://` URL, streamed as it
+    /// arrives and decompressed if compression support is enabled. Unlike a file, it isn't
+    /// bisected to skip to the start line. `add_path` adds URLs this way too.
+    pub fn add_url(&mut self, url: &str) -> io::Result<()> {
+        let body = Body::get(url, 0)?;
+        let len = body.len;
+        self.add_detected(url.to_string(), Box::new(body), 0)?;
+        if let (Some(len), Some((_, counters))) = (len, crate::registered(&self.stats).last()) {
+            let _ = counters.size.set(len);
+        }
+        Ok(())
+    }
+
+    /// Like `add_url`, reading the body from `offset` on, past its header, as for resuming from a
+    /// `Checkpoint`, with a range request. The body isn't decompressed.
+    pub fn add_url_at(&mut self, url: &str, offset: u64) -> io::Result<()> {
+        let mut source = self.source(url, Input::plain(Body::get(url, 0)?));
+        self.read_header(&mut source)?;
+        let (name, counters) = (source.name, source.counters);
+        let header_end = counters.bytes.load(atomic::Ordering::Relaxed);
+        let offset = offset.max(header_end);
+        let body = Body::get(url, offset)?;
+        counters
+            .skipped
+            .store(offset - header_end, atomic::Ordering::Relaxed);
+        if let Some(len) = body.len {
+            let _ = counters.size.set(len);
+        }
+        let reader: Box<dyn io::Read + Send> = Box::new(body);
+        let reader = Input::buffered(self.buffer_capacity, reader);
+        let reader = self.read_ahead(url, reader)?;
+        let source = crate::LineSource {
+            counters,
+            ..self.untracked_source(name, reader)
+        };
+        self.merge.add_source(url.to_string(), source)
+    }
+
+    /// `add_url` or `add_url_at` for `path`, if it is a URL.
+    pub(crate) fn add_if_url(
+        &mut self,
+        path: &path::Path,
+        offset: Option<u64>,
+    ) -> Option<io::Result<()>> {
+        let url = path.to_str().filter(|path| is_url(path))?;
+        Some(match offset {
+            Some(offset) => self.add_url_at(url, offset),
+            None => self.add_url(url),
+        })
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::thread;
+
+    /// Serve `requests` requests on a local port, answering each with what `respond` returns for
+    /// its path and `Range` header, and return the URL of the server.
+    fn serve<F>(requests: usize, respond: F) -> io::Result<String>
+    where
+        F: Fn(&str, Option<&str>) -> String + Send + 'static,
+    {
+        let listener = net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        thread::spawn(move || -> io::Result<()> {
+            for _ in 0..requests {
+                let (conn, _) = listener.accept()?;
+                let mut reader = io::BufReader::new(&conn);
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+                let mut range = None;
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line)?;
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: ") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+                (&conn).write_all(respond(&path, range.as_deref()).as_bytes())?;
+            }
+            Ok(())
+        });
+        Ok(url)
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn test_add_url() -> Result<(), io::Error> {
+        let base = serve(4, |path, _| match path {
+            "/a" => ok("1\n3\n"),
+            "/b" => "HTTP/1.1 302 Found\r\nLocation: /c\r\nContent-Length: 0\r\n\r\n".to_string(),
+            "/c" => "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     2\r\n2\n\r\n4\r\n4\n5\n\r\n0\r\n\r\n"
+                .to_string(),
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+        })?;
+        let mut heap = Heap::new();
+        heap.add_path(format!("{}/a", base))?;
+        heap.add_url(&format!("{}/b", base))?;
+        assert_eq!(heap.size_hint().1, None);
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["1", "2", "3", "4", "5"]);
+
+        let err = Heap::new()
+            .add_path(format!("{}/missing", base))
+            .unwrap_err();
+        assert!(err.to_string().contains("HTTP status 404"), "{}", err);
+        let err = Heap::new().add_url("https://example.com/a").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("HTTPS isn't supported"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_header() -> Result<(), io::Error> {
+        // The body of each response is the `Host` header of its request.
+        let listener = net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        thread::spawn(move || -> io::Result<()> {
+            loop {
+                let (conn, _) = listener.accept()?;
+                let mut reader = io::BufReader::new(&conn);
+                let mut host = String::new();
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line)?;
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Host: ") {
+                        host = value.trim().to_string();
+                    }
+                }
+                (&conn).write_all(ok(&format!("{}\n", host)).as_bytes())?;
+            }
+        });
+        let mut heap = Heap::new();
+        heap.add_url(&format!("http://localhost:{}/a", port))?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec![format!("localhost:{}", port)]);
+        assert_eq!(Url::parse("http://localhost/a")?.authority(), "localhost");
+        assert_eq!(
+            Url::parse("http://localhost:8080")?.authority(),
+            "localhost:8080"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_url_at() -> Result<(), io::Error> {
+        let body = "h\n1\n2\n3\n";
+        let base = serve(2, move |_, range| {
+            match range.and_then(|range| range.strip_prefix("bytes=")) {
+                Some(from) => {
+                    let from: usize = from.trim_end_matches('-').parse().unwrap();
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len() - from,
+                        &body[from..]
+                    )
+                }
+                None => ok(body),
+            }
+        })?;
+        let mut heap = Heap::new().with_headers(true);
+        heap.add_url_at(&format!("{}/a", base), 4)?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["2", "3"]);
+
+        // A server that ignores the range sends all of the body, which is skipped to the offset.
+        let base = serve(1, move |_, _| ok(body))?;
+        let mut rest = String::new();
+        Body::get(&format!("{}/a", base), 6)?.read_to_string(&mut rest)?;
+        assert_eq!(rest, "3\n");
+        Ok(())
+    }
+}
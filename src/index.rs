@@ -0,0 +1,198 @@
+//! Sparse indexes of merged output, recording where some of its lines start.
+
+use std::io;
+use std::io::Write;
+
+use crate::{write_record, Heap};
+
+/// How often `Heap::write_indexed_lines` records a line in the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexInterval {
+    /// Every this many lines, starting with the first.
+    Lines(u64),
+    /// For the first line starting at least this many bytes after the last one recorded,
+    /// starting with the first.
+    Bytes(u64),
+}
+
+/// A line recorded in a `SparseIndex`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The 1-based number of the line among the merged lines.
+    pub line_no: u64,
+    /// The offset of the start of the line in the output, counting any header.
+    pub offset: u64,
+    /// The line itself, which sorts after every line before it.
+    pub line: String,
+}
+
+/// Some of the lines of a sorted file with their offsets, in the order they appear in it, so
+/// lines in it can be found without reading it all.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SparseIndex {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl SparseIndex {
+    /// The offset of the last entry for which `before` returns true, or 0 if there is none. Every
+    /// line for which it is false starts at or after that offset.
+    pub(crate) fn offset_before<F: Fn(&str) -> bool>(&self, before: F) -> u64 {
+        let after = self.entries.partition_point(|entry| before(&entry.line));
+        match after.checked_sub(1) {
+            Some(last) => self.entries[last].offset,
+            None => 0,
+        }
+    }
+
+    /// Write the index as a JSON object, with its entries as an array of objects under
+    /// `entries`.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: io::Write>(&self, w: W) -> io::Result<()> {
+        let entries: Vec<serde_json::Value> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "line_no": entry.line_no,
+                    "offset": entry.offset,
+                    "line": entry.line,
+                })
+            })
+            .collect();
+        serde_json::to_writer(w, &serde_json::json!({ "entries": entries }))?;
+        Ok(())
+    }
+
+    /// Read an index written by `write_json`.
+    #[cfg(feature = "json")]
+    pub fn read_json<R: io::Read>(r: R) -> io::Result<SparseIndex> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid index");
+        let value: serde_json::Value = serde_json::from_reader(r)?;
+        let entries = value["entries"].as_array().ok_or_else(invalid)?;
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                Some(IndexEntry {
+                    line_no: entry["line_no"].as_u64()?,
+                    offset: entry["offset"].as_u64()?,
+                    line: entry["line"].as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(SparseIndex { entries })
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Like `write_sorted_lines`, also recording lines at `interval` in an index of the output,
+    /// which `add_indexed_file` can use to skip to a start line when the output is read back with
+    /// the same settings. Returns the number of merged lines written with the index.
+    pub fn write_indexed_lines<W: io::Write>(
+        &mut self,
+        w: W,
+        interval: IndexInterval,
+    ) -> io::Result<(u64, SparseIndex)> {
+        let mut w = io::BufWriter::new(w);
+        let delimiter = self.format.delimiter;
+        let mut record = Vec::new();
+        if self.emit_header {
+            for header in &self.header {
+                write_record(&mut record, header, delimiter, self.eol)?;
+            }
+        }
+        w.write_all(&record)?;
+        let mut offset = record.len() as u64;
+        let mut index = SparseIndex::default();
+        let mut count = 0;
+        for line in self.merge.by_ref() {
+            let line = line?;
+            let indexed = match (interval, index.entries.last()) {
+                (_, None) => true,
+                (IndexInterval::Lines(lines), Some(_)) => count % lines.max(1) == 0,
+                (IndexInterval::Bytes(bytes), Some(last)) => offset - last.offset >= bytes,
+            };
+            count += 1;
+            if indexed {
+                index.entries.push(IndexEntry {
+                    line_no: count,
+                    offset,
+                    line: line.text.clone(),
+                });
+            }
+            record.clear();
+            write_record(&mut record, &line, delimiter, self.eol)?;
+            w.write_all(&record)?;
+            offset += record.len() as u64;
+        }
+        w.flush()?;
+        Ok((count, index))
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line_no: u64, offset: u64, line: &str) -> IndexEntry {
+        IndexEntry {
+            line_no,
+            offset,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_write_indexed_lines() -> Result<(), io::Error> {
+        let heap = || -> io::Result<Heap<&[u8]>> {
+            let mut heap = Heap::new().with_headers(true);
+            heap.add_reader("file1".to_string(), "h\na\ncc\ne\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "h\nbb\nd\n".as_bytes())?;
+            Ok(heap)
+        };
+        let mut out = Vec::new();
+        let (count, index) = heap()?.write_indexed_lines(&mut out, IndexInterval::Lines(2))?;
+        assert_eq!(
+            (count, out.as_slice()),
+            (5, b"h\na\nbb\ncc\nd\ne\n".as_ref())
+        );
+        assert_eq!(
+            index.entries,
+            vec![entry(1, 2, "a"), entry(3, 7, "cc"), entry(5, 12, "e")]
+        );
+        let (_, index) = heap()?.write_indexed_lines(io::sink(), IndexInterval::Bytes(4))?;
+        assert_eq!(
+            index.entries,
+            vec![entry(1, 2, "a"), entry(3, 7, "cc"), entry(5, 12, "e")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_offset_before() {
+        let index = SparseIndex {
+            entries: vec![entry(1, 0, "a"), entry(3, 4, "c"), entry(5, 8, "e")],
+        };
+        assert_eq!(index.offset_before(|line| line < "a"), 0);
+        assert_eq!(index.offset_before(|line| line < "d"), 4);
+        assert_eq!(index.offset_before(|line| line < "z"), 8);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_index_json() -> Result<(), io::Error> {
+        let index = SparseIndex {
+            entries: vec![entry(1, 0, "a\"b"), entry(3, 4, "c")],
+        };
+        let mut json = Vec::new();
+        index.write_json(&mut json)?;
+        assert_eq!(SparseIndex::read_json(&json[..])?, index);
+        assert!(SparseIndex::read_json("{}".as_bytes()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+//! Buffered readers over a single input, transparently decompressing it when needed.
+
+#[cfg(feature = "encoding")]
+use std::error;
+#[cfg(feature = "encoding")]
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::mem;
+#[cfg(feature = "encoding")]
+use std::str;
+
+use crate::merge::SortedSource;
+use crate::read_ahead::ReadAhead;
+
+#[cfg(feature = "gzip")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How many bytes of an input are buffered at a time, as by `io::BufReader::new`, unless the heap
+/// says otherwise.
+pub(crate) const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// A buffered reader over the (decompressed) contents of one input.
+pub(crate) enum Input<T>
+where
+    T: io::Read,
+{
+    Plain(io::BufReader<T>),
+    #[cfg(feature = "gzip")]
+    Gzip(io::BufReader<flate2::bufread::MultiGzDecoder<io::BufReader<T>>>),
+    #[cfg(feature = "zstd")]
+    Zstd(io::BufReader<zstd::stream::read::Decoder<'static, io::BufReader<T>>>),
+    /// Another input, transcoded to UTF-8.
+    #[cfg(feature = "encoding")]
+    Decoded(io::BufReader<encoding_rs_io::DecodeReaderBytes<Box<Input<T>>, Vec<u8>>>),
+    /// Another input, read ahead of the merge by a thread of its own.
+    ReadAhead(ReadAhead),
+}
+
+/// The text encoding of an input, which is transcoded to UTF-8 as it is read.
+#[cfg(feature = "encoding")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    /// Read the input as UTF-8, as it is.
+    #[default]
+    Utf8,
+    /// Look for a UTF-8 or UTF-16 byte order mark, stripping it and decoding accordingly, and
+    /// read the input as UTF-8 if there isn't one.
+    Bom,
+    /// Decode from this encoding, e.g. `encoding_rs::UTF_16LE` or `encoding_rs::WINDOWS_1252`
+    /// for Latin-1, unless a byte order mark says otherwise. Undecodable bytes become U+FFFD.
+    Encoding(&'static encoding_rs::Encoding),
+}
+
+/// The error returned when an encoding label isn't recognized.
+#[cfg(feature = "encoding")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseEncodingError(String);
+
+#[cfg(feature = "encoding")]
+impl fmt::Display for ParseEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown encoding [{}]", self.0)
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl error::Error for ParseEncodingError {}
+
+#[cfg(feature = "encoding")]
+impl str::FromStr for InputEncoding {
+    type Err = ParseEncodingError;
+
+    /// Parse `auto` for `Bom`, or else a WHATWG encoding label such as `utf-16le` or `latin1`.
+    fn from_str(label: &str) -> Result<InputEncoding, ParseEncodingError> {
+        if label == "auto" {
+            return Ok(InputEncoding::Bom);
+        }
+        match encoding_rs::Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding == encoding_rs::UTF_8 => Ok(InputEncoding::Bom),
+            Some(encoding) => Ok(InputEncoding::Encoding(encoding)),
+            None => Err(ParseEncodingError(label.to_string())),
+        }
+    }
+}
+
+impl<T> Input<T>
+where
+    T: io::Read,
+{
+    pub(crate) fn plain(reader: T) -> Input<T> {
+        Input::buffered(DEFAULT_CAPACITY, reader)
+    }
+
+    /// Like `plain`, buffering up to `capacity` bytes of `reader` at a time.
+    pub(crate) fn buffered(capacity: usize, reader: T) -> Input<T> {
+        Input::Plain(io::BufReader::with_capacity(capacity, reader))
+    }
+
+    /// Sniff the leading bytes of `reader` for a known compression format and wrap it in the
+    /// matching decoder, falling back to reading it as-is.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) fn detect(reader: T) -> io::Result<Input<T>> {
+        Input::detect_buffered(DEFAULT_CAPACITY, reader)
+    }
+
+    /// Like `detect`, buffering up to `capacity` bytes of `reader`, and of what it decompresses
+    /// to, at a time.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) fn detect_buffered(capacity: usize, reader: T) -> io::Result<Input<T>> {
+        let mut reader = io::BufReader::with_capacity(capacity, reader);
+        let magic = io::BufRead::fill_buf(&mut reader)?;
+        #[cfg(feature = "gzip")]
+        {
+            if magic.starts_with(&GZIP_MAGIC) {
+                let decoder = flate2::bufread::MultiGzDecoder::new(reader);
+                return Ok(Input::Gzip(io::BufReader::with_capacity(capacity, decoder)));
+            }
+        }
+        #[cfg(feature = "zstd")]
+        {
+            if magic.starts_with(&ZSTD_MAGIC) {
+                let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+                return Ok(Input::Zstd(io::BufReader::with_capacity(capacity, decoder)));
+            }
+        }
+        Ok(Input::Plain(reader))
+    }
+
+    /// Transcode this input from `encoding` to UTF-8.
+    #[cfg(feature = "encoding")]
+    pub(crate) fn decode(self, encoding: InputEncoding) -> Input<T> {
+        let encoding = match encoding {
+            InputEncoding::Utf8 => return self,
+            InputEncoding::Bom => None,
+            InputEncoding::Encoding(encoding) => Some(encoding),
+        };
+        // UTF-8 is passed through as-is, BOM or not, so invalid sequences in it are left to the
+        // heap's `InvalidUtf8Policy`.
+        let decoder = encoding_rs_io::DecodeReaderBytesBuilder::new()
+            .encoding(encoding)
+            .bom_override(true)
+            .strip_bom(true)
+            .utf8_passthru(true)
+            .build(Box::new(self));
+        Input::Decoded(io::BufReader::new(decoder))
+    }
+
+    /// Append the next record, up to and including `delimiter`, to `text`, like `read_line` does
+    /// for newlines. Returns the number of bytes read, which is 0 at EOF.
+    pub(crate) fn read_record(&mut self, delimiter: u8, text: &mut String) -> io::Result<usize> {
+        if delimiter == b'\n' {
+            return self.read_line(text);
+        }
+        let mut bytes = mem::take(text).into_bytes();
+        let start = bytes.len();
+        let read = self.read_until(delimiter, &mut bytes);
+        match String::from_utf8(bytes) {
+            Ok(record) => {
+                *text = record;
+                read
+            }
+            Err(err) => {
+                let mut bytes = err.into_bytes();
+                bytes.truncate(start);
+                *text = String::from_utf8(bytes).expect("Prefix was valid UTF-8");
+                read.and(Err(io::Error::from(io::ErrorKind::InvalidData)))
+            }
+        }
+    }
+}
+
+/// Whether the leading bytes of `reader` are those of a known compression format, leaving it
+/// rewound to the start. Without compression support, nothing is.
+#[cfg(not(any(feature = "gzip", feature = "zstd")))]
+pub(crate) fn is_compressed<R: io::Read + io::Seek>(_: &mut R) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Whether the leading bytes of `reader` are those of a known compression format, leaving it
+/// rewound to the start.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub(crate) fn is_compressed<R: io::Read + io::Seek>(reader: &mut R) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(4);
+    io::Read::read_to_end(&mut io::Read::take(&mut *reader, 4), &mut magic)?;
+    reader.seek(io::SeekFrom::Start(0))?;
+    #[cfg(feature = "gzip")]
+    {
+        if magic.starts_with(&GZIP_MAGIC) {
+            return Ok(true);
+        }
+    }
+    #[cfg(feature = "zstd")]
+    {
+        if magic.starts_with(&ZSTD_MAGIC) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// How the inputs of a merge are split into records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordFormat {
+    /// The ASCII byte that terminates each record. It is stripped from records as they are read
+    /// and written after each record of the output.
+    pub delimiter: u8,
+    /// Also strip trailing whitespace, including any `\r`, from each record. Otherwise records
+    /// are kept exactly as they are, apart from their delimiter.
+    pub trim_whitespace: bool,
+    /// Treat a `\r` before a `\n` delimiter as part of the delimiter, so lines from Windows and
+    /// Unix files compare alike.
+    pub strip_cr: bool,
+}
+
+impl Default for RecordFormat {
+    fn default() -> RecordFormat {
+        RecordFormat {
+            delimiter: b'\n',
+            trim_whitespace: false,
+            strip_cr: false,
+        }
+    }
+}
+
+impl RecordFormat {
+    /// Strip the end of a record read by `read_record`, as the format asks. Returns whether the
+    /// record ended in `\r\n` and had its `\r` stripped.
+    pub(crate) fn trim(&self, text: &mut String) -> bool {
+        let mut len = text
+            .strip_suffix(char::from(self.delimiter))
+            .map_or(text.len(), str::len);
+        let cr = self.strip_cr
+            && self.delimiter == b'\n'
+            && len < text.len()
+            && text[..len].ends_with('\r');
+        if cr {
+            len -= 1;
+        }
+        if self.trim_whitespace {
+            len = text[..len].trim_end().len();
+        }
+        text.truncate(len);
+        cr
+    }
+
+    /// Like `trim`, for records that need not be UTF-8, trimming only ASCII whitespace.
+    pub(crate) fn trim_bytes(&self, line: &mut Vec<u8>) {
+        let mut len = line
+            .strip_suffix(&[self.delimiter])
+            .map_or(line.len(), <[u8]>::len);
+        if self.strip_cr
+            && self.delimiter == b'\n'
+            && len < line.len()
+            && line[..len].ends_with(b"\r")
+        {
+            len -= 1;
+        }
+        if self.trim_whitespace {
+            len = line[..len].trim_ascii_end().len();
+        }
+        line.truncate(len);
+    }
+}
+
+/// The records of a reader, e.g. the lines of a file, as a `SortedSource` of strings, so they can
+/// be merged with sources that aren't readers at all, e.g. in a
+/// `KWayMerge<Box<dyn SortedSource<Item = String>>>`. Compressed readers are decompressed if
+/// compression support is enabled.
+pub struct RecordReader<R>
+where
+    R: io::Read,
+{
+    reader: Input<R>,
+    format: RecordFormat,
+}
+
+impl<R> RecordReader<R>
+where
+    R: io::Read,
+{
+    /// Read the lines of `reader`.
+    pub fn new(reader: R) -> io::Result<RecordReader<R>> {
+        RecordReader::with_format(reader, RecordFormat::default())
+    }
+
+    /// Read the records of `reader`, split and trimmed according to `format`.
+    pub fn with_format(reader: R, format: RecordFormat) -> io::Result<RecordReader<R>> {
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let reader = Input::detect(reader)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let reader = Input::plain(reader);
+        Ok(RecordReader { reader, format })
+    }
+}
+
+impl<R> SortedSource for RecordReader<R>
+where
+    R: io::Read,
+{
+    type Item = String;
+
+    fn next(&mut self) -> io::Result<Option<String>> {
+        let mut record = String::new();
+        Ok(self.next_into(&mut record)?.then_some(record))
+    }
+
+    fn next_into(&mut self, record: &mut String) -> io::Result<bool> {
+        record.clear();
+        if self.reader.read_record(self.format.delimiter, record)? == 0 {
+            return Ok(false);
+        }
+        self.format.trim(record);
+        Ok(true)
+    }
+
+    fn describe(&self, record: &String) -> Option<String> {
+        Some(record.clone())
+    }
+}
+
+impl<T> io::Read for Input<T>
+where
+    T: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Plain(reader) => reader.read(buf),
+            #[cfg(feature = "gzip")]
+            Input::Gzip(reader) => reader.read(buf),
+            #[cfg(feature = "zstd")]
+            Input::Zstd(reader) => reader.read(buf),
+            #[cfg(feature = "encoding")]
+            Input::Decoded(reader) => reader.read(buf),
+            Input::ReadAhead(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<T> io::BufRead for Input<T>
+where
+    T: io::Read,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Input::Plain(reader) => io::BufRead::fill_buf(reader),
+            #[cfg(feature = "gzip")]
+            Input::Gzip(reader) => io::BufRead::fill_buf(reader),
+            #[cfg(feature = "zstd")]
+            Input::Zstd(reader) => io::BufRead::fill_buf(reader),
+            #[cfg(feature = "encoding")]
+            Input::Decoded(reader) => io::BufRead::fill_buf(reader),
+            Input::ReadAhead(reader) => io::BufRead::fill_buf(reader),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Input::Plain(reader) => io::BufRead::consume(reader, amt),
+            #[cfg(feature = "gzip")]
+            Input::Gzip(reader) => io::BufRead::consume(reader, amt),
+            #[cfg(feature = "zstd")]
+            Input::Zstd(reader) => io::BufRead::consume(reader, amt),
+            #[cfg(feature = "encoding")]
+            Input::Decoded(reader) => io::BufRead::consume(reader, amt),
+            Input::ReadAhead(reader) => io::BufRead::consume(reader, amt),
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(all(test, any(feature = "gzip", feature = "zstd")))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_detect_plain() -> Result<(), io::Error> {
+        let mut contents = String::new();
+        Input::detect("a\nb\n".as_bytes())?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "a\nb\n");
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_detect_gzip() -> Result<(), io::Error> {
+        let mut compressed = Vec::new();
+        for member in &["a\n", "b\n"] {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::default());
+            io::Write::write_all(&mut encoder, member.as_bytes())?;
+            encoder.finish()?;
+        }
+        let mut contents = String::new();
+        Input::detect(&compressed[..])?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "a\nb\n");
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_detect_zstd() -> Result<(), io::Error> {
+        let compressed = zstd::encode_all("a\nb\n".as_bytes(), 0)?;
+        let mut contents = String::new();
+        Input::detect(&compressed[..])?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "a\nb\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Joins of sorted inputs, matching the items of each source on the key they are sorted by.
+
+use std::io;
+use std::iter;
+
+use crate::merge::{KWayMerge, SortedSource};
+
+/// Which keys a join emits, like the variants of SQL joins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Keys found in every source.
+    Inner,
+    /// Keys found in the first source, whether or not the others have them.
+    Left,
+    /// Keys found in any source.
+    Outer,
+}
+
+impl<S> KWayMerge<S>
+where
+    S: SortedSource,
+{
+    /// Iterate over the keys `kind` emits, in sorted order, with the items having each key grouped
+    /// by source: the `i`th group holds the items of the `i`th source added, and is empty if it
+    /// has none. Items have the same key if they compare equal. Counts towards the limit per key;
+    /// output functions are not applied.
+    pub fn join(mut self, kind: JoinKind) -> impl Iterator<Item = io::Result<Vec<Vec<S::Item>>>> {
+        iter::from_fn(move || loop {
+            let group = match self.next_group()? {
+                Ok(group) => group,
+                Err(err) => return Some(Err(err)),
+            };
+            let mut by_source: Vec<Vec<S::Item>> =
+                iter::repeat_with(Vec::new).take(self.sources()).collect();
+            for (index, _, item) in group {
+                by_source[index].push(item);
+            }
+            let keep = match kind {
+                JoinKind::Inner => by_source.iter().all(|items| !items.is_empty()),
+                JoinKind::Left => !by_source[0].is_empty(),
+                JoinKind::Outer => true,
+            };
+            if keep {
+                self.count_emitted();
+                return Some(Ok(by_source));
+            }
+        })
+    }
+}
+
+/// Every combination of one item from each group, in order, with `None` standing in for the
+/// items of empty groups.
+pub(crate) fn combinations<I: Clone>(groups: &[Vec<I>]) -> Vec<Vec<Option<I>>> {
+    let mut rows = vec![Vec::with_capacity(groups.len())];
+    for group in groups {
+        rows = match group.len() {
+            0 => rows
+                .into_iter()
+                .map(|mut row| {
+                    row.push(None);
+                    row
+                })
+                .collect(),
+            _ => rows
+                .iter()
+                .flat_map(|row| {
+                    group.iter().map(move |item| {
+                        let mut row = row.clone();
+                        row.push(Some(item.clone()));
+                        row
+                    })
+                })
+                .collect(),
+        };
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct PairSource(std::vec::IntoIter<(u32, &'static str)>);
+
+    impl SortedSource for PairSource {
+        type Item = (u32, &'static str);
+
+        fn next(&mut self) -> io::Result<Option<(u32, &'static str)>> {
+            Ok(self.0.next())
+        }
+    }
+
+    fn join(kind: JoinKind) -> io::Result<Vec<Vec<Vec<&'static str>>>> {
+        let mut merge =
+            KWayMerge::with_comparator(|a: &(u32, &str), b: &(u32, &str)| a.0.cmp(&b.0));
+        let sources = vec![
+            vec![(1, "a1"), (2, "a2"), (2, "a2'")],
+            vec![(2, "b2"), (3, "b3")],
+        ];
+        for (i, items) in sources.into_iter().enumerate() {
+            merge.add_source(format!("source{}", i), PairSource(items.into_iter()))?;
+        }
+        merge
+            .join(kind)
+            .map(|groups| {
+                groups.map(|groups| {
+                    groups
+                        .into_iter()
+                        .map(|items| items.into_iter().map(|(_, item)| item).collect())
+                        .collect()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_join() -> Result<(), io::Error> {
+        assert_eq!(
+            join(JoinKind::Inner)?,
+            vec![vec![vec!["a2", "a2'"], vec!["b2"]]]
+        );
+        assert_eq!(
+            join(JoinKind::Left)?,
+            vec![
+                vec![vec!["a1"], vec![]],
+                vec![vec!["a2", "a2'"], vec!["b2"]]
+            ]
+        );
+        assert_eq!(join(JoinKind::Outer)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_combinations() {
+        let groups = vec![vec!["a", "b"], vec![], vec!["c", "d"]];
+        assert_eq!(
+            combinations(&groups),
+            vec![
+                vec![Some("a"), None, Some("c")],
+                vec![Some("a"), None, Some("d")],
+                vec![Some("b"), None, Some("c")],
+                vec![Some("b"), None, Some("d")],
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Sort keys taken from fields of JSON Lines records.
+
+use std::cmp;
+use std::io;
+
+/// A JSON value with a total order, so it can be used as a sort key: missing values first, then
+/// `null`, booleans, numbers, strings, and finally arrays and objects by their JSON text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderedJson {
+    Missing,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Other(String),
+}
+
+impl OrderedJson {
+    fn rank(&self) -> u8 {
+        match self {
+            OrderedJson::Missing => 0,
+            OrderedJson::Null => 1,
+            OrderedJson::Bool(_) => 2,
+            OrderedJson::Number(_) => 3,
+            OrderedJson::String(_) => 4,
+            OrderedJson::Other(_) => 5,
+        }
+    }
+}
+
+impl From<&serde_json::Value> for OrderedJson {
+    fn from(value: &serde_json::Value) -> OrderedJson {
+        match value {
+            serde_json::Value::Null => OrderedJson::Null,
+            serde_json::Value::Bool(b) => OrderedJson::Bool(*b),
+            serde_json::Value::Number(n) => OrderedJson::Number(n.as_f64().unwrap_or(f64::NAN)),
+            serde_json::Value::String(s) => OrderedJson::String(s.clone()),
+            other => OrderedJson::Other(other.to_string()),
+        }
+    }
+}
+
+impl Eq for OrderedJson {}
+
+impl PartialOrd for OrderedJson {
+    fn partial_cmp(&self, other: &OrderedJson) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedJson {
+    fn cmp(&self, other: &OrderedJson) -> cmp::Ordering {
+        match (self, other) {
+            (OrderedJson::Bool(a), OrderedJson::Bool(b)) => a.cmp(b),
+            (OrderedJson::Number(a), OrderedJson::Number(b)) => a.total_cmp(b),
+            (OrderedJson::String(a), OrderedJson::String(b))
+            | (OrderedJson::Other(a), OrderedJson::Other(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+/// Extracts the values at some JSON pointers, such as `/timestamp` or `/user/id`, from each line,
+/// parsed as a JSON document, to use as its key with `Heap::with_key`. Lines that aren't valid
+/// JSON have every value missing.
+#[derive(Clone, Debug)]
+pub struct JsonKey {
+    pointers: Vec<String>,
+}
+
+impl JsonKey {
+    /// Compare records by the values at `pointers`, in turn.
+    pub fn new(pointers: Vec<String>) -> io::Result<JsonKey> {
+        if let Some(pointer) = pointers
+            .iter()
+            .find(|p| !p.is_empty() && !p.starts_with('/'))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid JSON pointer [{}]", pointer),
+            ));
+        }
+        Ok(JsonKey { pointers })
+    }
+
+    /// The values at the key's pointers in `line`.
+    pub fn extract(&self, line: &str) -> Vec<OrderedJson> {
+        let value = serde_json::from_str::<serde_json::Value>(line).ok();
+        self.pointers
+            .iter()
+            .map(|pointer| {
+                value
+                    .as_ref()
+                    .and_then(|value| value.pointer(pointer))
+                    .map_or(OrderedJson::Missing, OrderedJson::from)
+            })
+            .collect()
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+
+    #[test]
+    fn test_extract() -> Result<(), io::Error> {
+        let key = JsonKey::new(vec!["/a/b".to_string(), "/c".to_string()])?;
+        assert_eq!(
+            key.extract(r#"{"a": {"b": 1.5}, "c": "x"}"#),
+            vec![
+                OrderedJson::Number(1.5),
+                OrderedJson::String("x".to_string())
+            ]
+        );
+        assert_eq!(
+            key.extract("not json"),
+            vec![OrderedJson::Missing, OrderedJson::Missing]
+        );
+        assert!(JsonKey::new(vec!["a".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_order() {
+        let mut values = vec![
+            OrderedJson::String("a".to_string()),
+            OrderedJson::Number(10.0),
+            OrderedJson::Null,
+            OrderedJson::Number(9.0),
+            OrderedJson::Missing,
+            OrderedJson::Bool(true),
+        ];
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                OrderedJson::Missing,
+                OrderedJson::Null,
+                OrderedJson::Bool(true),
+                OrderedJson::Number(9.0),
+                OrderedJson::Number(10.0),
+                OrderedJson::String("a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_merge() -> Result<(), io::Error> {
+        let key = JsonKey::new(vec!["/t".to_string()])?;
+        let mut heap = Heap::with_key(move |line| key.extract(line));
+        heap.add_reader("file1".to_string(), "{\"t\": 2}\n{\"t\": 10}".as_bytes())?;
+        heap.add_reader("file2".to_string(), "{\"t\": 9, \"x\": 1}".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            vec!["{\"t\": 2}", "{\"t\": 9, \"x\": 1}", "{\"t\": 10}"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+//! Sort keys made of fields and character ranges of a line, as in `sort -k`.
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::str;
+use std::sync;
+
+use crate::Comparator;
+
+/// How the text of a key is compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// Compare a leading number, like `sort -n`, rather than the text itself.
+    pub numeric: bool,
+    /// Compare a leading number with an optional SI suffix such as `2K` or `1.5G`, like `sort -h`.
+    pub human_numeric: bool,
+    /// Compare embedded numbers by value, like `sort -V`, so that `v1.9` sorts before `v1.10`.
+    pub version: bool,
+    /// Compare lowercase letters as if they were uppercase, like `sort -f`.
+    pub fold_case: bool,
+    /// Ignore blanks at the start of the key, like `sort -b`.
+    pub ignore_blanks: bool,
+    /// Compare only blanks and alphanumeric characters, like `sort -d`.
+    pub dictionary: bool,
+    /// Reverse the result of the comparison.
+    pub reverse: bool,
+}
+
+/// One end of a key: a 1-based field and a 1-based character within it. A character of 0 at the
+/// end of a key stands for the end of the field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyPosition {
+    pub field: usize,
+    pub char: usize,
+}
+
+/// A sort key in the syntax of `sort -k`: `F[.C][OPTS][,F[.C][OPTS]]`, e.g. `3,3n` for the third
+/// field compared numerically or `2.3` for everything from the third character of the second
+/// field on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeySpec {
+    pub start: KeyPosition,
+    /// Where the key ends, inclusive, or `None` for the end of the line.
+    pub end: Option<KeyPosition>,
+    /// The key's own options, or `None` to use the defaults given to `key_comparator`.
+    pub options: Option<KeyOptions>,
+}
+
+/// The error returned when a key spec can't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseKeySpecError(String);
+
+impl fmt::Display for ParseKeySpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid key [{}]", self.0)
+    }
+}
+
+impl error::Error for ParseKeySpecError {}
+
+impl str::FromStr for KeySpec {
+    type Err = ParseKeySpecError;
+
+    fn from_str(spec: &str) -> Result<KeySpec, ParseKeySpecError> {
+        let invalid = || ParseKeySpecError(spec.to_string());
+        let mut options = None;
+        let (start, end) = match spec.find(',') {
+            Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+            None => (spec, None),
+        };
+        let start = parse_position(start, 1, &mut options).ok_or_else(invalid)?;
+        if start.char == 0 {
+            return Err(invalid());
+        }
+        let end = match end {
+            Some(end) => Some(parse_position(end, 0, &mut options).ok_or_else(invalid)?),
+            None => None,
+        };
+        Ok(KeySpec {
+            start,
+            end,
+            options,
+        })
+    }
+}
+
+/// Parse `F[.C][OPTS]`, adding any options to `options`. `C` defaults to `default_char`.
+fn parse_position(
+    position: &str,
+    default_char: usize,
+    options: &mut Option<KeyOptions>,
+) -> Option<KeyPosition> {
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let n = digits(position);
+    let field = position[..n].parse().ok().filter(|field| *field > 0)?;
+    let mut rest = &position[n..];
+    let mut char = default_char;
+    if let Some(after) = rest.strip_prefix('.') {
+        let n = digits(after);
+        char = after[..n].parse().ok()?;
+        rest = &after[n..];
+    }
+    for option in rest.chars() {
+        let options = options.get_or_insert_with(KeyOptions::default);
+        match option {
+            'n' => options.numeric = true,
+            'h' => options.human_numeric = true,
+            'V' => options.version = true,
+            'f' => options.fold_case = true,
+            'b' => options.ignore_blanks = true,
+            'd' => options.dictionary = true,
+            'r' => options.reverse = true,
+            _ => return None,
+        }
+    }
+    Some(KeyPosition { field, char })
+}
+
+impl KeySpec {
+    /// The part of `line` covered by the key. Fields are delimited by `separator`, or else each
+    /// is a run of blanks followed by a run of non-blanks.
+    pub fn extract<'a>(&self, line: &'a str, separator: Option<char>) -> &'a str {
+        let (field_start, field_end) = field(line, separator, self.start.field - 1);
+        let start = advance(line, field_start, field_end, self.start.char - 1);
+        let end = match self.end {
+            None => line.len(),
+            Some(KeyPosition { field: f, char: 0 }) => field(line, separator, f - 1).1,
+            Some(KeyPosition { field: f, char: c }) => {
+                let (field_start, field_end) = field(line, separator, f - 1);
+                advance(line, field_start, field_end, c)
+            }
+        };
+        &line[start..cmp::max(start, end)]
+    }
+}
+
+/// The byte range of the 0-based field `n` of `line`, which is empty at the end of the line if
+/// there are too few fields.
+fn field(line: &str, separator: Option<char>, n: usize) -> (usize, usize) {
+    match separator {
+        Some(separator) => {
+            let mut start = 0;
+            for _ in 0..n {
+                match line[start..].find(separator) {
+                    Some(i) => start += i + separator.len_utf8(),
+                    None => return (line.len(), line.len()),
+                }
+            }
+            let end = line[start..]
+                .find(separator)
+                .map_or(line.len(), |i| start + i);
+            (start, end)
+        }
+        None => {
+            let mut start = 0;
+            for _ in 0..n {
+                start = skip_field(line, start);
+            }
+            (start, skip_field(line, start))
+        }
+    }
+}
+
+/// The offset just past the blank-delimited field starting at `from`.
+fn skip_field(line: &str, from: usize) -> usize {
+    let rest = &line[from..];
+    let word = rest.trim_start_matches(is_blank);
+    let word_len = word.find(is_blank).unwrap_or(word.len());
+    from + (rest.len() - word.len()) + word_len
+}
+
+/// The offset `chars` characters after `from`, but no further than `limit`.
+fn advance(line: &str, from: usize, limit: usize, chars: usize) -> usize {
+    line[from..limit]
+        .char_indices()
+        .nth(chars)
+        .map_or(limit, |(i, _)| from + i)
+}
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Compare the leading numbers of `a` and `b` like `sort -n`: optional blanks, an optional minus
+/// sign, digits and an optional fraction. Text without a leading number counts as zero.
+pub fn compare_numeric(a: &str, b: &str) -> cmp::Ordering {
+    let (a_negative, a_int, a_frac, _) = numeric_parts(a);
+    let (b_negative, b_int, b_frac, _) = numeric_parts(b);
+    let magnitude = a_int
+        .len()
+        .cmp(&b_int.len())
+        .then_with(|| a_int.cmp(b_int))
+        .then_with(|| a_frac.cmp(b_frac));
+    match (a_negative, b_negative) {
+        (false, false) => magnitude,
+        (true, true) => magnitude.reverse(),
+        (true, false) => cmp::Ordering::Less,
+        (false, true) => cmp::Ordering::Greater,
+    }
+}
+
+/// Compare the leading numbers of `a` and `b` like `sort -h`: first by sign, then by SI suffix
+/// (none, `K` or `k`, `M`, `G`, `T`, `P`, `E`, `Z`, `Y`), then like `compare_numeric`. The
+/// suffixes are only compared, not applied, so `1500K` sorts before `1M`.
+pub fn compare_human_numeric(a: &str, b: &str) -> cmp::Ordering {
+    suffix_order(a)
+        .cmp(&suffix_order(b))
+        .then_with(|| compare_numeric(a, b))
+}
+
+/// The sign of the leading number of `s` times the rank of its suffix, or 0 for zero.
+fn suffix_order(s: &str) -> i32 {
+    let (negative, int, frac, rest) = numeric_parts(s);
+    if int.is_empty() && frac.is_empty() {
+        return 0;
+    }
+    let rank = match rest.chars().next() {
+        Some('K') | Some('k') => 1,
+        Some('M') => 2,
+        Some('G') => 3,
+        Some('T') => 4,
+        Some('P') => 5,
+        Some('E') => 6,
+        Some('Z') => 7,
+        Some('Y') => 8,
+        _ => 0,
+    };
+    if negative {
+        -rank
+    } else {
+        rank
+    }
+}
+
+/// Compare `a` and `b` as version strings, like `sort -V`: runs of digits are compared by their
+/// value and the text between them character by character, with letters sorting before other
+/// characters and `~` before anything, even the end of the text, so `1.0~rc1` sorts before `1.0`.
+pub fn compare_version(a: &str, b: &str) -> cmp::Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        loop {
+            let a_text = a.first().filter(|c| !c.is_ascii_digit());
+            let b_text = b.first().filter(|c| !c.is_ascii_digit());
+            if a_text.is_none() && b_text.is_none() {
+                break;
+            }
+            let ordering = version_rank(a_text).cmp(&version_rank(b_text));
+            if ordering != cmp::Ordering::Equal {
+                return ordering;
+            }
+            a = &a[1..];
+            b = &b[1..];
+        }
+        let (a_digits, a_rest) = version_number(a);
+        let (b_digits, b_rest) = version_number(b);
+        let ordering = a_digits
+            .len()
+            .cmp(&b_digits.len())
+            .then_with(|| a_digits.cmp(b_digits));
+        if ordering != cmp::Ordering::Equal {
+            return ordering;
+        }
+        a = a_rest;
+        b = b_rest;
+    }
+    cmp::Ordering::Equal
+}
+
+/// Where a character between the numbers of a version sorts, where `None` is the end of the text
+/// or the start of a number.
+fn version_rank(c: Option<&u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+        Some(c) => i32::from(*c) + 256,
+    }
+}
+
+/// Split the digits at the start of `s`, without leading zeros, from the rest of it.
+fn version_number(s: &[u8]) -> (&[u8], &[u8]) {
+    let len = s.iter().take_while(|c| c.is_ascii_digit()).count();
+    let digits = &s[..len];
+    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+    (&digits[zeros..], &s[len..])
+}
+
+/// Split a leading number into its sign, its integer digits without leading zeros, its fraction
+/// digits without trailing zeros and the text after it.
+fn numeric_parts(s: &str) -> (bool, &str, &str, &str) {
+    let s = s.trim_start_matches(is_blank);
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let int_len = s.bytes().take_while(u8::is_ascii_digit).count();
+    let int = s[..int_len].trim_start_matches('0');
+    let (frac, rest) = match s[int_len..].strip_prefix('.') {
+        Some(frac) => {
+            let frac_len = frac.bytes().take_while(u8::is_ascii_digit).count();
+            (frac[..frac_len].trim_end_matches('0'), &frac[frac_len..])
+        }
+        None => ("", &s[int_len..]),
+    };
+    let negative = negative && !(int.is_empty() && frac.is_empty());
+    (negative, int, frac, rest)
+}
+
+/// Compare `a` and `b` as text, folding case and skipping non-dictionary characters as
+/// `options` ask.
+fn compare_text(a: &str, b: &str, options: KeyOptions) -> cmp::Ordering {
+    if !options.fold_case && !options.dictionary {
+        return a.cmp(b);
+    }
+    let chars = |s| compared_chars(s, options.dictionary);
+    if options.fold_case {
+        let upper = |s| chars(s).flat_map(char::to_uppercase);
+        upper(a).cmp(upper(b))
+    } else {
+        chars(a).cmp(chars(b))
+    }
+}
+
+/// The characters of `s`, keeping only blanks and alphanumerics if `dictionary` is set.
+fn compared_chars(s: &str, dictionary: bool) -> impl Iterator<Item = char> + '_ {
+    s.chars()
+        .filter(move |c| !dictionary || c.is_alphanumeric() || is_blank(*c))
+}
+
+/// Build a comparator that compares lines by each of `keys` in turn, like `sort -k`, using
+/// `defaults` for keys without options of their own. Lines whose keys are all equal compare
+/// equal, as with `sort -s`, so the merge keeps them in input order.
+pub fn key_comparator(
+    keys: Vec<KeySpec>,
+    separator: Option<char>,
+    defaults: KeyOptions,
+) -> Comparator {
+    sync::Arc::new(move |a: &str, b: &str| {
+        for key in &keys {
+            let options = key.options.unwrap_or(defaults);
+            let (mut a, mut b) = (key.extract(a, separator), key.extract(b, separator));
+            if options.ignore_blanks {
+                a = a.trim_start_matches(is_blank);
+                b = b.trim_start_matches(is_blank);
+            }
+            let ordering = if options.version {
+                compare_version(a, b)
+            } else if options.human_numeric {
+                compare_human_numeric(a, b)
+            } else if options.numeric {
+                compare_numeric(a, b)
+            } else {
+                compare_text(a, b, options)
+            };
+            let ordering = if options.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering != cmp::Ordering::Equal {
+                return ordering;
+            }
+        }
+        cmp::Ordering::Equal
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(spec: &str) -> KeySpec {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            key("3,3n"),
+            KeySpec {
+                start: KeyPosition { field: 3, char: 1 },
+                end: Some(KeyPosition { field: 3, char: 0 }),
+                options: Some(KeyOptions {
+                    numeric: true,
+                    ..KeyOptions::default()
+                }),
+            }
+        );
+        assert_eq!(
+            key("2.3r"),
+            KeySpec {
+                start: KeyPosition { field: 2, char: 3 },
+                end: None,
+                options: Some(KeyOptions {
+                    reverse: true,
+                    ..KeyOptions::default()
+                }),
+            }
+        );
+        assert_eq!(
+            key("1bf,1d").options,
+            Some(KeyOptions {
+                fold_case: true,
+                ignore_blanks: true,
+                dictionary: true,
+                ..KeyOptions::default()
+            })
+        );
+        for spec in &["", "0", "1.0", "a", "1,x", "2z"] {
+            assert_eq!(
+                spec.parse::<KeySpec>(),
+                Err(ParseKeySpecError(spec.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(key("2,2").extract("a,bc,d", Some(',')), "bc");
+        assert_eq!(key("2").extract("a,bc,d", Some(',')), "bc,d");
+        assert_eq!(key("4,4").extract("a,bc,d", Some(',')), "");
+        assert_eq!(key("2,2").extract("a  bc d", None), "  bc");
+        assert_eq!(key("1.2,2.1").extract("abc  de", None), "bc ");
+        assert_eq!(key("2.9,2").extract("a,bc,d", Some(',')), "");
+    }
+
+    #[test]
+    fn test_compare_numeric() {
+        assert_eq!(compare_numeric("10", "9"), cmp::Ordering::Greater);
+        assert_eq!(compare_numeric(" 007", "7.0"), cmp::Ordering::Equal);
+        assert_eq!(compare_numeric("-3", "2"), cmp::Ordering::Less);
+        assert_eq!(compare_numeric("-3", "-20"), cmp::Ordering::Greater);
+        assert_eq!(compare_numeric("1.5", "1.25"), cmp::Ordering::Greater);
+        assert_eq!(compare_numeric("-0", "abc"), cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_compare_version() {
+        assert_eq!(compare_version("v1.9", "v1.10"), cmp::Ordering::Less);
+        assert_eq!(
+            compare_version("file2.txt", "file10.txt"),
+            cmp::Ordering::Less
+        );
+        assert_eq!(compare_version("1.01", "1.1"), cmp::Ordering::Equal);
+        assert_eq!(compare_version("1.0~rc1", "1.0"), cmp::Ordering::Less);
+        assert_eq!(compare_version("1.0a", "1.0+"), cmp::Ordering::Less);
+        assert_eq!(compare_version("1.0", "1.0.1"), cmp::Ordering::Less);
+        assert_eq!(compare_version("a", "b"), cmp::Ordering::Less);
+    }
+
+    #[test]
+    fn test_compare_human_numeric() {
+        assert_eq!(compare_human_numeric("2K", "1.5G"), cmp::Ordering::Less);
+        assert_eq!(compare_human_numeric("900", "1k"), cmp::Ordering::Less);
+        assert_eq!(compare_human_numeric("10M", "9M"), cmp::Ordering::Greater);
+        assert_eq!(compare_human_numeric("1500K", "1M"), cmp::Ordering::Less);
+        assert_eq!(compare_human_numeric("-1G", "-1K"), cmp::Ordering::Less);
+        assert_eq!(compare_human_numeric("0G", "-1"), cmp::Ordering::Greater);
+        assert_eq!(
+            compare_human_numeric("4.0K\tdir", "4K"),
+            cmp::Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_key_comparator() {
+        let cmp = key_comparator(
+            vec![key("2,2n"), key("1,1r")],
+            Some(','),
+            KeyOptions::default(),
+        );
+        assert_eq!(cmp("a,10", "b,9"), cmp::Ordering::Greater);
+        assert_eq!(cmp("a,10", "b,10"), cmp::Ordering::Greater);
+        assert_eq!(cmp("a,10", "a,10.0"), cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_text_options() {
+        let compare = |spec: &str, a: &str, b: &str| {
+            key_comparator(vec![key(spec)], None, KeyOptions::default())(a, b)
+        };
+        assert_eq!(compare("1", "apple", "Banana"), cmp::Ordering::Greater);
+        assert_eq!(compare("1f", "apple", "Banana"), cmp::Ordering::Less);
+        assert_eq!(compare("1f", "straße", "STRASSE"), cmp::Ordering::Equal);
+        assert_eq!(compare("1", "  b", "a"), cmp::Ordering::Less);
+        assert_eq!(compare("1b", "  b", "a"), cmp::Ordering::Greater);
+        assert_eq!(compare("2b,2", "x   b", "y a"), cmp::Ordering::Greater);
+        assert_eq!(compare("1d", "a-c", "ab"), cmp::Ordering::Greater);
+        assert_eq!(compare("1df", "A-b", "ab"), cmp::Ordering::Equal);
+    }
+}
@@ -1,216 +1,2619 @@
 use std::cmp;
-use std::collections;
+use std::convert::TryFrom;
+use std::fs;
 use std::io;
+use std::io::Seek;
+use std::io::Write;
+use std::iter;
+use std::mem;
+use std::path;
+use std::sync;
+use std::sync::atomic;
+use std::time;
 
+#[cfg(feature = "archive")]
+mod archive;
+#[cfg(feature = "tokio")]
+mod async_heap;
+mod batch;
+#[cfg(feature = "gzip")]
+mod bgzf;
+mod binary;
+mod bloom;
+mod builder;
+mod cancel;
+mod check;
+mod checkpoint;
+mod checksum;
+#[cfg(feature = "csv")]
+mod columns;
+mod command;
+mod compact;
+mod error;
+#[cfg(feature = "fadvise")]
+mod fadvise;
+mod follow;
+mod genomics;
+#[cfg(feature = "http")]
+mod http;
+mod index;
+mod input;
+mod join;
+#[cfg(feature = "json")]
+mod json_key;
+mod key;
+mod manifest;
+pub mod merge;
+#[cfg(feature = "unicode")]
+mod normalize;
+#[cfg(feature = "object-store")]
+mod object_store;
+pub mod parallel;
+mod progress;
+mod read_ahead;
+mod reader;
+mod reduce;
+#[cfg(feature = "regex")]
+mod regex_key;
+#[cfg(feature = "serde")]
+mod serde_source;
+mod setops;
+mod shard;
+mod sort;
+mod sstable;
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "time")]
+mod time_key;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "object-store")]
+mod upload;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod watermark;
+
+#[cfg(feature = "tokio")]
+pub use async_heap::AsyncHeap;
+pub use batch::BatchWriter;
+#[cfg(feature = "gzip")]
+pub use bgzf::{BgzfIndex, BgzfWriter};
+pub use binary::{
+    binary_key, binary_key_range, write_binary_record, write_binary_records, BinaryRecordReader,
+    ByteOrder,
+};
+pub use bloom::{BloomBuilder, BloomFilter};
+pub use builder::HeapBuilder;
+pub use cancel::MergeOutcome;
+pub use check::{verify_records_sorted_by, verify_sorted, verify_sorted_by, UnsortedAt};
+pub use checkpoint::{Checkpoint, SourceCheckpoint};
+pub use checksum::{Checksum, ChecksumAlgorithm, ChecksumWriter, ParseChecksumError};
+#[cfg(feature = "csv")]
+pub use columns::{CsvColumn, CsvKey};
+pub use compact::Compaction;
+pub use error::MergeError;
+pub use genomics::{
+    Contig, ContigOrder, GenomicFormat, GenomicKey, GenomicPosition, ParseGenomicFormatError,
+};
+pub use index::{IndexEntry, IndexInterval, SparseIndex};
+use input::Input;
+#[cfg(feature = "encoding")]
+pub use input::{InputEncoding, ParseEncodingError};
+pub use input::{RecordFormat, RecordReader};
+pub use join::JoinKind;
+#[cfg(feature = "json")]
+pub use json_key::{JsonKey, OrderedJson};
+pub use key::{
+    compare_human_numeric, compare_numeric, compare_version, key_comparator, KeyOptions,
+    KeyPosition, KeySpec, ParseKeySpecError,
+};
+pub use manifest::{ManifestBuilder, ManifestWriter, RunManifest};
+use merge::Last;
+pub use merge::{
+    merge_iters, DuplicatePolicy, Interleave, IterSource, KWayMerge, Order, OutOfOrderPolicy,
+    ReorderWindow, SortedSource, SourceErrorPolicy, Strategy,
+};
+#[cfg(feature = "unicode")]
+pub use normalize::{Normalization, ParseNormalizationError};
+#[cfg(feature = "object-store")]
+pub use object_store::{LocalStore, ObjectStore, UnsignedS3Store};
+pub use progress::{MergeStats, ProgressInterval};
+pub use reader::MergedReader;
+pub use reduce::{Aggregate, ParseAggregateError};
+#[cfg(feature = "regex")]
+pub use regex_key::{RegexKey, UnmatchedPolicy};
+#[cfg(all(feature = "serde", feature = "json"))]
+pub use serde_source::JsonLines;
+#[cfg(feature = "serde")]
+pub use serde_source::{Bincode, SerdeFormat, SerdeSource, SerdeWriter};
+pub use setops::SetOperation;
+pub use shard::{Shard, ShardSplit};
+pub use sort::ExternalSort;
+pub use sstable::{BlockCompression, Scan, SsTableReader, SsTableWriter};
+#[cfg(feature = "time")]
+pub use time_key::{TimeFormat, TimeKey};
+#[cfg(feature = "object-store")]
+pub use upload::{MultipartUpload, MultipartWriter};
+pub use watermark::WatermarkMerge;
+
+/// A function used to order lines, in place of the default lexicographic `str::cmp`.
+pub type Comparator = sync::Arc<dyn Fn(&str, &str) -> cmp::Ordering + Send + Sync>;
+
+type KeyExtractor<K> = sync::Arc<dyn Fn(&str) -> K + Send + Sync>;
+
+/// A function deciding whether a line is merged, or failing the merge.
+pub type LineFilter = sync::Arc<dyn Fn(&str) -> io::Result<bool> + Send + Sync>;
+
+/// A line read from one of the inputs, along with its cached sort key.
+struct Line<K> {
+    text: String,
+    key: K,
+    /// The first bytes of the line, as `prefix` packs them.
+    prefix: u64,
+    original: Original,
+}
+
+impl<K> Line<K> {
+    /// Pack the first 8 of `bytes` into an integer, big-endian and padded with zeros, so that
+    /// comparing the integers of two lines orders them as comparing their bytes does, unless the
+    /// integers are equal.
+    fn prefix(bytes: &[u8]) -> u64 {
+        let mut prefix = [0; 8];
+        let n = bytes.len().min(8);
+        prefix[..n].copy_from_slice(&bytes[..n]);
+        u64::from_be_bytes(prefix)
+    }
+
+    /// Pack the first bytes of the line again, after changing it.
+    fn cache_prefix(&mut self) {
+        self.prefix = Line::<K>::prefix(self.bytes());
+    }
+
+    /// The bytes the line was read as, which differ from its text if it wasn't valid UTF-8.
+    fn bytes(&self) -> &[u8] {
+        self.original
+            .bytes
+            .as_deref()
+            .unwrap_or(self.text.as_bytes())
+    }
+}
+
+/// What reading a line changed about it, so that it can be written back out as it was read.
 #[derive(Default)]
-pub struct Heap<T>
+struct Original {
+    /// Whether a `\r` was stripped from the end of the line, for `LineEnding::Preserve`.
+    cr: bool,
+    /// The bytes of a line that wasn't valid UTF-8, under `InvalidUtf8Policy::Passthrough`.
+    bytes: Option<Vec<u8>>,
+}
+
+/// What to do with a line that isn't valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUtf8Policy {
+    /// Fail with a `MergeError::InvalidUtf8` naming the file and line.
+    #[default]
+    Error,
+    /// Replace each invalid sequence with U+FFFD and merge the result.
+    Lossy,
+    /// Like `Lossy` for keys and the lines yielded by the iterators, but write the line's
+    /// original bytes from `write_sorted_lines`. `Heap::new` compares such lines byte-wise.
+    Passthrough,
+}
+
+/// What to do with blank lines, which are empty or contain only whitespace.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlankLinePolicy {
+    /// Merge them like any other line, so they are expected to sort first.
+    #[default]
+    Merge,
+    /// Drop them as they are read, wherever they appear in an input.
+    Skip,
+}
+
+/// The line endings written after merged lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Write `\n`.
+    Lf,
+    /// Write `\r\n`.
+    Crlf,
+    /// Write `\r\n` after lines read with a `\r\n` ending that was stripped, and `\n` otherwise.
+    #[default]
+    Preserve,
+}
+
+/// Moves an input, by the name given, to a thread of its own to be read ahead of the merge.
+type ReadAheadSpawner<T> = sync::Arc<dyn Fn(&str, Input<T>) -> io::Result<Input<T>> + Send + Sync>;
+
+/// The lines of a reader, trimmed according to its record format.
+struct LineSource<T, K>
 where
     T: io::Read,
 {
-    heap: collections::BinaryHeap<Entry<T>>,
+    reader: Input<T>,
+    key: KeyExtractor<K>,
+    filter: Option<LineFilter>,
+    comment_prefix: Option<sync::Arc<str>>,
+    blank_lines: BlankLinePolicy,
+    format: RecordFormat,
+    invalid_utf8: InvalidUtf8Policy,
+    name: sync::Arc<str>,
+    counters: sync::Arc<Counters>,
+    #[cfg(feature = "tracing")]
+    trace: trace::SourceTrace,
 }
 
-/// Implementation of a heap specialized to keep a list of files sorted on the contents of the first
-/// line.
-impl<T> Heap<T>
+/// How much of an input has been read, shared between its `LineSource` and its `Heap`.
+#[derive(Default)]
+struct Counters {
+    lines: atomic::AtomicU64,
+    bytes: atomic::AtomicU64,
+    /// The bytes skipped by seeking past them, which aren't counted as read.
+    skipped: atomic::AtomicU64,
+    /// The bytes read before the line read last, which the merge holds until it is merged, or all
+    /// of those read once the input is exhausted.
+    merged: atomic::AtomicU64,
+    /// The number of bytes there are to read, if the input is a file whose size says.
+    size: sync::OnceLock<u64>,
+}
+
+/// The inputs of a heap with their counters, shared with its progress hook.
+type Registry = sync::Arc<sync::Mutex<Vec<(sync::Arc<str>, sync::Arc<Counters>)>>>;
+
+/// The inputs in `registry`, which a panicking progress hook leaves usable.
+fn registered(
+    registry: &Registry,
+) -> sync::MutexGuard<'_, Vec<(sync::Arc<str>, sync::Arc<Counters>)>> {
+    registry.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// How much of each input in `registry` has been read.
+fn source_stats(registry: &Registry) -> Vec<SourceStats> {
+    registered(registry)
+        .iter()
+        .map(|(name, counters)| SourceStats {
+            name: name.to_string(),
+            lines: counters.lines.load(atomic::Ordering::Relaxed),
+            bytes: counters.bytes.load(atomic::Ordering::Relaxed),
+            size: counters.size.get().copied(),
+        })
+        .collect()
+}
+
+impl Counters {
+    /// The offset in the input of the first line that hasn't been merged.
+    fn next_offset(&self) -> u64 {
+        self.skipped.load(atomic::Ordering::Relaxed) + self.merged.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// How much of one input a `Heap` has read so far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceStats {
+    pub name: String,
+    pub lines: u64,
+    /// Bytes read, after decompression.
+    pub bytes: u64,
+    /// The number of bytes `bytes` counts up to, if known: the size of an uncompressed file, less
+    /// what was skipped by seeking past it.
+    pub size: Option<u64>,
+}
+
+impl<T, K> SortedSource for LineSource<T, K>
 where
     T: io::Read,
 {
-    pub fn new() -> Heap<T> {
-        let heap = collections::BinaryHeap::new();
-        Heap { heap }
-    }
-
-    pub fn add_reader(&mut self, filename: String, reader: T) -> io::Result<()> {
-        let buf_reader = io::BufReader::new(reader);
-        self.readd_reader(filename, buf_reader).map(|_| ())
-    }
+    type Item = Line<K>;
 
-    fn readd_reader(
-        &mut self,
-        filename: String,
-        mut buf_reader: io::BufReader<T>,
-    ) -> io::Result<Option<String>> {
-        let mut first_line = String::new();
-        let n = io::BufRead::read_line(&mut buf_reader, &mut first_line)?;
-        if n > 0 {
-            let first_line = first_line.trim_end().to_string();
-            self.heap.push(Entry {
-                filename,
-                reader: buf_reader,
-                first_line: first_line.clone(),
-            });
-            Ok(Some(first_line))
+    fn next(&mut self) -> io::Result<Option<Line<K>>> {
+        let mut text = String::new();
+        let mut original = Original::default();
+        if self.read_text(&mut text, &mut original)? {
+            let key = (self.key)(&text);
+            let mut line = Line {
+                text,
+                key,
+                prefix: 0,
+                original,
+            };
+            line.cache_prefix();
+            Ok(Some(line))
         } else {
             Ok(None)
         }
     }
 
-    pub fn print_sorted_lines(&mut self) -> io::Result<()> {
-        for line in self {
-            if let Ok(contents) = line {
-                println!("{}", contents);
-            } else {
-                line?;
-            }
+    fn next_into(&mut self, line: &mut Line<K>) -> io::Result<bool> {
+        if self.read_text(&mut line.text, &mut line.original)? {
+            line.key = (self.key)(&line.text);
+            line.cache_prefix();
+            Ok(true)
+        } else {
+            Ok(false)
         }
-        Ok(())
+    }
+
+    fn describe(&self, line: &Line<K>) -> Option<String> {
+        Some(line.text.clone())
+    }
+
+    fn line_no(&self) -> Option<u64> {
+        Some(self.counters.lines.load(atomic::Ordering::Relaxed))
     }
 }
 
-impl<T> Iterator for Heap<T>
+impl<T, K> LineSource<T, K>
 where
     T: io::Read,
 {
-    type Item = io::Result<String>;
+    /// Replace `text` with the next line that isn't a comment or a skipped blank line and passes
+    /// the filter, if any, trimmed according to the record format, and `original` with what was
+    /// changed about it. Returns false at EOF.
+    #[cfg(not(feature = "tracing"))]
+    fn read_text(&mut self, text: &mut String, original: &mut Original) -> io::Result<bool> {
+        self.read_filtered(text, original)
+    }
 
-    fn next(&mut self) -> Option<io::Result<String>> {
-        if let Some(Entry {
-            filename,
-            reader,
-            first_line,
-        }) = self.heap.pop()
-        {
-            let next_line_result = self.readd_reader(filename.clone(), reader);
-            match next_line_result {
-                Ok(next_line) => {
-                    if next_line.is_some() && next_line.unwrap() < first_line {
-                        Some(Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Input lines in file [{}] out of order!", filename),
-                        )))
-                    } else {
-                        Some(Ok(first_line))
-                    }
+    /// Like the untraced `read_text`, in the span of the input and timing how long it takes.
+    #[cfg(feature = "tracing")]
+    fn read_text(&mut self, text: &mut String, original: &mut Original) -> io::Result<bool> {
+        let span = self.trace.span().clone();
+        let _entered = span.enter();
+        let started = time::Instant::now();
+        let read = self.read_filtered(text, original);
+        self.trace.read(started, &self.counters, &read);
+        read
+    }
+
+    fn read_filtered(&mut self, text: &mut String, original: &mut Original) -> io::Result<bool> {
+        loop {
+            text.clear();
+            let before = self.counters.bytes.load(atomic::Ordering::Relaxed);
+            if self.read_line(text, original)? == 0 {
+                self.counters
+                    .merged
+                    .store(before, atomic::Ordering::Relaxed);
+                return Ok(false);
+            }
+            self.trim(text, original);
+            if self.skips(text) {
+                continue;
+            }
+            match &self.filter {
+                Some(filter) if !filter(text)? => continue,
+                _ => {
+                    self.counters
+                        .merged
+                        .store(before, atomic::Ordering::Relaxed);
+                    return Ok(true);
                 }
-                Err(err) => Some(Err(err)),
             }
-        } else {
-            None
+        }
+    }
+
+    /// Whether `text` is a comment, or a blank line that the policy drops.
+    fn skips(&self, text: &str) -> bool {
+        let comment = match &self.comment_prefix {
+            Some(prefix) => text.starts_with(&**prefix),
+            None => false,
+        };
+        comment || (self.blank_lines == BlankLinePolicy::Skip && text.trim().is_empty())
+    }
+
+    fn read_line(&mut self, text: &mut String, original: &mut Original) -> io::Result<usize> {
+        let read = match self.invalid_utf8 {
+            InvalidUtf8Policy::Error => self.reader.read_record(self.format.delimiter, text),
+            _ => self.read_lossy(text, original),
+        };
+        match read {
+            Ok(n) => {
+                if n > 0 {
+                    self.counters.lines.fetch_add(1, atomic::Ordering::Relaxed);
+                    self.counters
+                        .bytes
+                        .fetch_add(n as u64, atomic::Ordering::Relaxed);
+                }
+                Ok(n)
+            }
+            // `read_line` reports invalid UTF-8 as a bare `InvalidData` error, whereas decoders
+            // attach the underlying error to theirs.
+            Err(err) if err.kind() == io::ErrorKind::InvalidData && err.get_ref().is_none() => {
+                Err(MergeError::InvalidUtf8 {
+                    file: self.name.to_string(),
+                    line_no: self.counters.lines.load(atomic::Ordering::Relaxed) + 1,
+                }
+                .into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn trim(&self, text: &mut String, original: &mut Original) {
+        original.cr = self.format.trim(text);
+        if let Some(bytes) = &mut original.bytes {
+            self.format.trim_bytes(bytes);
+        }
+    }
+
+    /// Append the next line to `text`, replacing invalid UTF-8 with U+FFFD, and keep its original
+    /// bytes in `original` if they were invalid and the policy asks for them.
+    fn read_lossy(&mut self, text: &mut String, original: &mut Original) -> io::Result<usize> {
+        let mut bytes = original.bytes.take().unwrap_or_default();
+        bytes.clear();
+        let n = io::BufRead::read_until(&mut self.reader, self.format.delimiter, &mut bytes)?;
+        match str::from_utf8(&bytes) {
+            Ok(valid) => text.push_str(valid),
+            Err(_) => {
+                text.push_str(&String::from_utf8_lossy(&bytes));
+                if self.invalid_utf8 == InvalidUtf8Policy::Passthrough {
+                    original.bytes = Some(bytes);
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// Write out the rest of the last source of a merge without going through the heap, reusing
+    /// two line buffers rather than allocating for every line. Returns the number of lines
+    /// written and of duplicates dropped.
+    fn write_remaining<W: io::Write>(
+        last: Last<LineSource<T, K>>,
+        eol: LineEnding,
+        w: &mut W,
+    ) -> io::Result<(u64, u64)> {
+        let Last {
+            name,
+            mut source,
+            item: mut prev,
+            cmp,
+            policy,
+            dedup,
+        } = last;
+        let delimiter = source.format.delimiter;
+        write_record(w, &prev, delimiter, eol)?;
+        let (mut count, mut duplicates) = (1, 0);
+        let mut line = match source.next().map_err(|err| error::in_file(err, &name))? {
+            Some(line) => line,
+            None => return Ok((count, duplicates)),
+        };
+        loop {
+            let line_no = source.counters.lines.load(atomic::Ordering::Relaxed);
+            let ordering = cmp(&line, &prev);
+            let out_of_order = || {
+                error::out_of_order_error(
+                    &name,
+                    line_no,
+                    Some(prev.text.clone()),
+                    Some(line.text.clone()),
+                )
+            };
+            #[cfg(feature = "tracing")]
+            if ordering == cmp::Ordering::Less {
+                trace::out_of_order(&name, line_no, policy);
+            }
+            let emit = match (ordering, policy) {
+                (cmp::Ordering::Less, OutOfOrderPolicy::Error) => return Err(out_of_order()),
+                (cmp::Ordering::Less, OutOfOrderPolicy::Skip) => false,
+                (cmp::Ordering::Less, OutOfOrderPolicy::WarnAndContinue) => {
+                    eprintln!("warning: {}", out_of_order());
+                    true
+                }
+                (cmp::Ordering::Equal, _) if dedup => {
+                    duplicates += 1;
+                    false
+                }
+                _ => true,
+            };
+            if emit {
+                write_record(w, &line, delimiter, eol)?;
+                count += 1;
+            }
+            if emit || ordering != cmp::Ordering::Less {
+                mem::swap(&mut prev, &mut line);
+            }
+            if !source
+                .next_into(&mut line)
+                .map_err(|err| error::in_file(err, &name))?
+            {
+                return Ok((count, duplicates));
+            }
         }
     }
 }
 
-#[derive(Debug)]
-struct Entry<T>
+pub struct Heap<T, K = ()>
 where
     T: io::Read,
 {
-    filename: String,
-    reader: io::BufReader<T>,
-    first_line: String,
+    merge: KWayMerge<LineSource<T, K>>,
+    key: KeyExtractor<K>,
+    stats: Registry,
+    /// When the heap was created, which progress reports count the time elapsed from.
+    started: time::Instant,
+    filter: Option<LineFilter>,
+    comment_prefix: Option<sync::Arc<str>>,
+    blank_lines: BlankLinePolicy,
+    format: RecordFormat,
+    invalid_utf8: InvalidUtf8Policy,
+    #[cfg(feature = "encoding")]
+    encoding: InputEncoding,
+    eol: LineEnding,
+    header_lines: usize,
+    emit_header: bool,
+    /// What the header lines of each input start with, if its header is however many leading
+    /// lines start with one of them rather than `header_lines` lines.
+    header_prefixes: &'static [&'static str],
+    header: Vec<Line<()>>,
+    /// The number of lines and bytes written before the checkpoint the heap was resumed from.
+    resumed: Option<(u64, u64)>,
+    read_ahead: Option<ReadAheadSpawner<T>>,
+    /// How many bytes of each input are buffered at a time.
+    buffer_capacity: usize,
+    /// How many merged lines apart `write_sorted_lines` flushes its output, if it does.
+    flush_every: Option<u64>,
+    /// How often the files added are checked for appended lines at their end, if they are
+    /// followed.
+    follow: Option<time::Duration>,
+    /// The number of reads of each file to keep in flight through io_uring, if it is used.
+    #[cfg(feature = "io-uring")]
+    io_uring: Option<u32>,
+    /// Whether the kernel is advised how the files added are read.
+    #[cfg(feature = "fadvise")]
+    access_hints: bool,
 }
 
-impl<T> PartialEq for Entry<T>
+impl<T> Default for Heap<T>
 where
     T: io::Read,
 {
-    fn eq(&self, other: &Self) -> bool {
-        self.filename == other.filename
+    fn default() -> Heap<T> {
+        Heap::new()
     }
 }
 
-impl<T> Eq for Entry<T> where T: io::Read {}
+/// Implementation of a heap specialized to keep a list of files sorted on the contents of the first
+/// line.
+impl<T> Heap<T>
+where
+    T: io::Read,
+{
+    pub fn new() -> Heap<T> {
+        // Comparing bytes orders valid UTF-8 the same way `str::cmp` does, and also orders lines
+        // kept as bytes under `InvalidUtf8Policy::Passthrough`. Most lines of a wide merge differ
+        // in their first bytes, so comparing their prefixes first seldom has to read the lines.
+        let merge = KWayMerge::with_comparator(|a: &Line<()>, b: &Line<()>| {
+            a.prefix
+                .cmp(&b.prefix)
+                .then_with(|| a.bytes().cmp(b.bytes()))
+        });
+        Heap::from_merge(merge, sync::Arc::new(|_: &str| ()))
+    }
+
+    /// Create a heap for inputs sorted in descending lexicographic order.
+    pub fn descending() -> Heap<T> {
+        Heap::new().with_order(Order::Desc)
+    }
+
+    /// Create a heap that orders lines with `cmp`. Every input is expected to be sorted according
+    /// to the same comparator; it is also used for the out-of-order check.
+    pub fn with_comparator<F>(cmp: F) -> Heap<T>
+    where
+        F: Fn(&str, &str) -> cmp::Ordering + Send + Sync + 'static,
+    {
+        let merge =
+            KWayMerge::with_comparator(move |a: &Line<()>, b: &Line<()>| cmp(&a.text, &b.text));
+        Heap::from_merge(merge, sync::Arc::new(|_: &str| ()))
+    }
+}
 
-impl<T> Ord for Entry<T>
+impl<T, K> Heap<T, K>
 where
     T: io::Read,
+    K: Ord + 'static,
 {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        if self == other {
-            cmp::Ordering::Equal
-        } else {
-            cmp::Ordering::reverse(self.first_line.cmp(&other.first_line))
-        }
+    /// Create a heap that orders lines by the key `key` extracts from them, e.g. a timestamp or a
+    /// numeric field. The key is computed once per line and cached alongside it.
+    pub fn with_key<F>(key: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> K + Send + Sync + 'static,
+    {
+        let merge = KWayMerge::with_comparator(|a: &Line<K>, b: &Line<K>| a.key.cmp(&b.key));
+        Heap::from_merge(merge, sync::Arc::new(key))
     }
 }
 
-impl<T> PartialOrd for Entry<T>
+impl<T, K> Heap<T, K>
 where
     T: io::Read,
+    K: 'static,
 {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        Some(self.cmp(other))
+    fn from_merge(merge: KWayMerge<LineSource<T, K>>, key: KeyExtractor<K>) -> Heap<T, K> {
+        Heap {
+            merge,
+            key,
+            stats: Registry::default(),
+            started: time::Instant::now(),
+            filter: None,
+            comment_prefix: None,
+            blank_lines: BlankLinePolicy::default(),
+            format: RecordFormat::default(),
+            invalid_utf8: InvalidUtf8Policy::default(),
+            #[cfg(feature = "encoding")]
+            encoding: InputEncoding::default(),
+            eol: LineEnding::default(),
+            header_lines: 0,
+            emit_header: false,
+            header_prefixes: &[],
+            header: Vec::new(),
+            resumed: None,
+            read_ahead: None,
+            buffer_capacity: input::DEFAULT_CAPACITY,
+            flush_every: None,
+            follow: None,
+            #[cfg(feature = "io-uring")]
+            io_uring: None,
+            #[cfg(feature = "fadvise")]
+            access_hints: false,
+        }
+    }
+
+    /// Set the direction the inputs are sorted in. `Order::Desc` flips both the merge order and the
+    /// out-of-order check of whatever comparator or key the heap was created with.
+    pub fn with_order(mut self, order: Order) -> Heap<T, K> {
+        self.merge = self.merge.with_order(order);
+        self
+    }
+
+    /// Tolerate inputs whose lines may each arrive up to `lines` positions late, re-sorting them
+    /// within that window instead of treating them as out of order.
+    pub fn with_reorder_window(mut self, lines: usize) -> Heap<T, K> {
+        self.merge = self.merge.with_reorder_window(ReorderWindow::Items(lines));
+        self
+    }
+
+    /// Like `with_reorder_window`, but bounded by key rather than by line count: a buffered line is
+    /// released once `settled(key, newest)` returns true for the key of a line read after it from
+    /// the same input, e.g. `|a, b| b - a > 60` for timestamps that are at most a minute late.
+    pub fn with_reorder_window_by_key<F>(mut self, settled: F) -> Heap<T, K>
+    where
+        F: Fn(&K, &K) -> bool + Send + Sync + 'static,
+    {
+        let settled = move |a: &Line<K>, b: &Line<K>| settled(&a.key, &b.key);
+        self.merge = self
+            .merge
+            .with_reorder_window(ReorderWindow::Settled(sync::Arc::new(settled)));
+        self
     }
 }
 
-#[allow(clippy::string_lit_as_bytes)]
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Set the data structure used to pick the next line. `Strategy::LoserTree` does fewer
+    /// comparisons per line for merges of many inputs. Heaps merge in parallel only with
+    /// `with_parallel_strategy`, since that needs inputs that can be moved to other threads.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Heap<T, K> {
+        self.merge = self.merge.with_strategy(strategy);
+        self
+    }
 
-    #[test]
-    fn test_single() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "bar\nfoo".as_bytes())?;
-        assert_eq!(heap.next().unwrap()?, "bar");
-        assert_eq!(heap.next().unwrap()?, "foo");
-        assert!(heap.next().is_none());
-        Ok(())
+    /// Take lines from the inputs in the order `interleave` gives, e.g. one from each in turn
+    /// with `Interleave::RoundRobin`, rather than merging them, so they needn't be sorted.
+    pub fn with_interleave(mut self, interleave: Interleave) -> Heap<T, K> {
+        self.merge = self.merge.with_interleave(interleave);
+        self
     }
 
-    #[test]
-    fn test_single_ooo() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "foo\nbar".as_bytes())?;
-        let err = heap.next().unwrap().expect_err("Expected an error");
-        assert_eq!(err.kind(), io::ErrorKind::Other);
-        assert_eq!(
-            format!("{}", err),
-            "Input lines in file [file1] out of order!"
-        );
-        Ok(())
+    /// Skip the lines that sort before `line`, compared the same way merged lines are, e.g. by
+    /// the key the heap extracts from each of them.
+    pub fn with_start_line(mut self, line: &str) -> Heap<T, K> {
+        let line = self.bound(line);
+        self.merge = self.merge.with_start(line);
+        self
     }
 
-    #[test]
-    fn test_multiple() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
-        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
-        heap.add_reader("file3".to_string(), "".as_bytes())?;
-        assert_eq!(heap.next().unwrap()?, "a");
-        assert_eq!(heap.next().unwrap()?, "b");
-        assert_eq!(heap.next().unwrap()?, "c");
-        assert_eq!(heap.next().unwrap()?, "d");
-        assert!(heap.next().is_none());
-        Ok(())
+    /// Stop merging at the first line that sorts after `line`, without reading the rest of the
+    /// inputs.
+    pub fn with_end_line(mut self, line: &str) -> Heap<T, K> {
+        let line = self.bound(line);
+        self.merge = self.merge.with_end(line);
+        self
     }
 
-    #[test]
-    fn test_multiple_with_dupes() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
-        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
-        heap.add_reader("file3".to_string(), "b\nc".as_bytes())?;
-        assert_eq!(heap.next().unwrap()?, "a");
-        assert_eq!(heap.next().unwrap()?, "b");
-        assert_eq!(heap.next().unwrap()?, "b");
-        assert_eq!(heap.next().unwrap()?, "c");
-        assert_eq!(heap.next().unwrap()?, "c");
-        assert_eq!(heap.next().unwrap()?, "d");
-        assert!(heap.next().is_none());
-        Ok(())
+    /// Stop merging once `lines` lines have been merged, without reading the rest of the inputs.
+    pub fn with_limit(mut self, lines: u64) -> Heap<T, K> {
+        self.merge = self.merge.with_limit(lines);
+        self
     }
 
-    #[test]
-    fn test_multiple_with_repeated_names() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
-        heap.add_reader("file1".to_string(), "b\nd".as_bytes())?;
-        assert_eq!(heap.next().unwrap()?, "a");
-        assert_eq!(heap.next().unwrap()?, "b");
-        assert_eq!(heap.next().unwrap()?, "c");
-        assert_eq!(heap.next().unwrap()?, "d");
-        assert!(heap.next().is_none());
+    /// Keep only the merged lines for which `filter` returns true. Unlike `with_filter`, this
+    /// applies to the output of the merge, after `dedup` and any `with_output_map` added before
+    /// it, and dropped lines don't count towards `with_limit`.
+    pub fn with_output_filter<F>(mut self, filter: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+        K: 'static,
+    {
+        self.merge = self
+            .merge
+            .with_output(move |line: &mut Line<K>| filter(&line.text));
+        self
+    }
+
+    /// Replace each merged line with what `map` returns for it, after any output filters and maps
+    /// added before it. Lines kept as bytes under `InvalidUtf8Policy::Passthrough` are mapped as
+    /// text, and written as what `map` returns.
+    pub fn with_output_map<F>(mut self, map: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+        K: 'static,
+    {
+        self.merge = self.merge.with_output(move |line: &mut Line<K>| {
+            line.text = map(&line.text);
+            line.original.bytes = None;
+            line.cache_prefix();
+            true
+        });
+        self
+    }
+
+    /// Set which of the lines that compare equal, such as those sharing a key, are merged.
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Heap<T, K> {
+        self.merge = self.merge.with_duplicate_policy(policy);
+        self
+    }
+
+    /// Order lines that compare equal, such as those sharing a key, by their bytes rather than by
+    /// the order their inputs were added, so that the output is the same whichever order the
+    /// inputs are added in. Of lines with the same bytes, those read without a stripped `\r` come
+    /// first, as `LineEnding::Preserve` writes them differently.
+    pub fn with_deterministic_ties(mut self) -> Heap<T, K>
+    where
+        K: 'static,
+    {
+        self.merge = self.merge.with_tie_breaker(|a: &Line<K>, b: &Line<K>| {
+            a.bytes()
+                .cmp(b.bytes())
+                .then(a.original.cr.cmp(&b.original.cr))
+        });
+        self
+    }
+
+    /// Replace each run of lines that compare equal with what `merge` returns for them, in the
+    /// order their inputs were added, or by their bytes with `with_deterministic_ties`, overriding
+    /// the duplicate policy.
+    pub fn with_duplicate_merger<F>(mut self, merge: F) -> Heap<T, K>
+    where
+        F: Fn(Vec<String>) -> String + Send + Sync + 'static,
+        K: 'static,
+    {
+        let key = self.key.clone();
+        self.merge = self
+            .merge
+            .with_duplicate_merger(move |lines: Vec<Line<K>>| {
+                let text = merge(lines.into_iter().map(|line| line.text).collect());
+                Line {
+                    key: key(&text),
+                    prefix: Line::<K>::prefix(text.as_bytes()),
+                    text,
+                    original: Original::default(),
+                }
+            });
+        self
+    }
+
+    fn bound(&self, line: &str) -> Line<K> {
+        Line {
+            text: line.to_string(),
+            key: (self.key)(line),
+            prefix: Line::<K>::prefix(line.as_bytes()),
+            original: Original::default(),
+        }
+    }
+
+    /// Suppress lines equal to the line emitted just before them, like `sort -mu`.
+    pub fn dedup(mut self, dedup: bool) -> Heap<T, K> {
+        self.merge = self.merge.dedup(dedup);
+        self
+    }
+
+    /// Set what happens when an input's lines turn out not to be sorted.
+    pub fn with_out_of_order_policy(mut self, policy: OutOfOrderPolicy) -> Heap<T, K> {
+        self.merge = self.merge.with_out_of_order_policy(policy);
+        self
+    }
+
+    /// Set what happens when reading an input fails partway through.
+    pub fn with_source_error_policy(mut self, policy: SourceErrorPolicy) -> Heap<T, K> {
+        self.merge = self.merge.with_source_error_policy(policy);
+        self
+    }
+
+    /// The inputs dropped under `SourceErrorPolicy::DropSource`, with the errors that dropped them.
+    pub fn failed_sources(&self) -> &[(sync::Arc<str>, io::Error)] {
+        self.merge.failed_sources()
+    }
+
+    /// Stop merging the first input added as `name` that is left to merge, e.g. a shard found
+    /// to be bad partway through, discarding the rest of it. Its statistics are kept. Returns
+    /// whether there was such an input. Inputs can be added once merging has started too; see
+    /// `KWayMerge::add_source_with_priority`.
+    pub fn remove_source(&mut self, name: &str) -> bool {
+        self.merge.remove_source(name).is_some()
+    }
+
+    /// How many inputs are left to merge, excluding those that are exhausted or have been dropped.
+    pub fn len_sources(&self) -> usize {
+        self.merge.len_sources()
+    }
+
+    /// Whether there is nothing left to merge, neither inputs nor an error to return.
+    pub fn is_empty(&self) -> bool {
+        self.merge.is_empty()
+    }
+
+    /// At most how many lines are left to merge, if the size of every input is known: the lines
+    /// the merge holds, and one for every byte left to read.
+    fn lines_left(&self) -> Option<usize> {
+        if self.merge.merges_in_parallel() || self.follow.is_some() {
+            return None;
+        }
+        let mut left = self.merge.held() as u64;
+        for (_, counters) in registered(&self.stats).iter() {
+            let size = *counters.size.get()?;
+            left += size.saturating_sub(counters.bytes.load(atomic::Ordering::Relaxed));
+        }
+        usize::try_from(left).ok()
+    }
+
+    pub fn add_reader(&mut self, filename: String, reader: T) -> io::Result<()> {
+        let reader = Input::buffered(self.buffer_capacity, reader);
+        self.add_input(filename, reader, 0)
+    }
+
+    /// Like `add_reader`, with `priority` for `DuplicatePolicy::HighestPriority`, so that e.g. the
+    /// lines of an input added with priority 1 override equal ones from inputs added with the
+    /// default of 0.
+    pub fn add_reader_with_priority(
+        &mut self,
+        filename: String,
+        reader: T,
+        priority: i64,
+    ) -> io::Result<()> {
+        let reader = Input::buffered(self.buffer_capacity, reader);
+        self.add_input(filename, reader, priority)
+    }
+
+    /// Add a reader whose contents may be compressed. The format is detected from the leading
+    /// magic bytes and decompressed on the fly; uncompressed input is read as-is.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub fn add_compressed_reader(&mut self, filename: String, reader: T) -> io::Result<()> {
+        let reader = Input::detect_buffered(self.buffer_capacity, reader)?;
+        self.add_input(filename, reader, 0)
+    }
+
+    fn add_input(&mut self, filename: String, reader: Input<T>, priority: i64) -> io::Result<()> {
+        #[cfg(feature = "encoding")]
+        let reader = reader.decode(self.encoding);
+        let reader = self.read_ahead(&filename, reader)?;
+        let mut source = self.source(filename.as_str(), reader);
+        self.read_header(&mut source)?;
+        self.merge
+            .add_source_with_priority(filename, source, priority)
+    }
+
+    /// Move `reader` to a thread of its own if the heap reads ahead.
+    fn read_ahead(&self, name: &str, reader: Input<T>) -> io::Result<Input<T>> {
+        match &self.read_ahead {
+            Some(spawn) => spawn(name, reader),
+            None => Ok(reader),
+        }
+    }
+
+    /// Create the source of the input `name`, with its statistics tracked by the heap.
+    fn source<R: io::Read>(&mut self, name: &str, reader: Input<R>) -> LineSource<R, K> {
+        let source = self.untracked_source(sync::Arc::from(name), reader);
+        registered(&self.stats).push((source.name.clone(), source.counters.clone()));
+        source
+    }
+
+    fn untracked_source<R: io::Read>(
+        &self,
+        name: sync::Arc<str>,
+        reader: Input<R>,
+    ) -> LineSource<R, K> {
+        LineSource {
+            reader,
+            key: self.key.clone(),
+            filter: self.filter.clone(),
+            comment_prefix: self.comment_prefix.clone(),
+            blank_lines: self.blank_lines,
+            format: self.format,
+            invalid_utf8: self.invalid_utf8,
+            #[cfg(feature = "tracing")]
+            trace: trace::SourceTrace::new(&name),
+            name,
+            counters: sync::Arc::new(Counters::default()),
+        }
+    }
+
+    /// Consume the header lines of `source`, keeping them if they are the first.
+    fn read_header<R: io::Read>(&mut self, source: &mut LineSource<R, K>) -> io::Result<()> {
+        if !self.header_prefixes.is_empty() {
+            return self.read_prefixed_header(source);
+        }
+        let mut header = Vec::with_capacity(self.header_lines);
+        for _ in 0..self.header_lines {
+            let mut line = Line {
+                text: String::new(),
+                key: (),
+                prefix: 0,
+                original: Original::default(),
+            };
+            let n = source
+                .read_line(&mut line.text, &mut line.original)
+                .map_err(|err| error::in_file(err, &source.name))?;
+            if n == 0 {
+                break;
+            }
+            source.trim(&mut line.text, &mut line.original);
+            header.push(line);
+        }
+        if self.header.is_empty() {
+            self.header = header;
+        }
         Ok(())
     }
 
-    #[test]
-    fn test_multiple_empty() -> Result<(), io::Error> {
-        let mut heap = Heap::new();
-        heap.add_reader("file1".to_string(), "".as_bytes())?;
-        heap.add_reader("file1".to_string(), "".as_bytes())?;
-        assert!(heap.next().is_none());
+    /// Open `path` and add it as `wrap(file)`, reading it from after its header on, or from
+    /// where `start` says. If the heap has a start line and the file is a regular, uncompressed
+    /// one, it is first bisected, or looked up in an index, to skip to near the first line at or
+    /// after the start, rather than reading every line before it.
+    fn open_path<F>(
+        &mut self,
+        path: &path::Path,
+        wrap: F,
+        priority: i64,
+        start: StartAt<'_>,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(fs::File) -> T,
+    {
+        let filename = path.display().to_string();
+        let mut f = fs::File::open(path)?;
+        #[cfg(feature = "encoding")]
+        let decoded = self.encoding != InputEncoding::Utf8;
+        #[cfg(not(feature = "encoding"))]
+        let decoded = false;
+        let metadata = f.metadata()?;
+        // The size of the file is what is read of it only if it is read as it is.
+        let size = if decoded || !metadata.is_file() || input::is_compressed(&mut f)? {
+            None
+        } else {
+            Some(metadata.len())
+        };
+        let seeks = match start {
+            StartAt::Offset(_) => true,
+            StartAt::Bisect | StartAt::Index(_) => self.merge.seeks_start(),
+        };
+        if !seeks || size.is_none() {
+            if let StartAt::Offset(_) = start {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Cannot resume [{}], which isn't a regular, uncompressed UTF-8 file",
+                        filename
+                    ),
+                ));
+            }
+            #[cfg(feature = "fadvise")]
+            self.advise(&f, 0);
+            self.add_detected(filename, wrap(f), priority)?;
+            if let (Some(size), Some((_, counters))) = (size, registered(&self.stats).last()) {
+                let _ = counters.size.set(size);
+            }
+            return Ok(());
+        }
+        let mut source = self.source(filename.as_str(), Input::plain(&mut f));
+        self.read_header(&mut source)?;
+        let (name, counters) = (source.name, source.counters);
+        let header_end = counters.bytes.load(atomic::Ordering::Relaxed);
+        let offset = match start {
+            StartAt::Offset(offset) => offset.max(header_end),
+            StartAt::Index(index) => index
+                .offset_before(|line| self.merge.before_start(&self.bound(line)))
+                .max(header_end),
+            // If bisecting fails, e.g. on a line that isn't valid UTF-8, read the file from the
+            // start instead, so the error is reported where it occurs.
+            StartAt::Bisect => self.seek_start(&mut f, header_end).unwrap_or(header_end),
+        };
+        f.seek(io::SeekFrom::Start(offset))?;
+        #[cfg(feature = "fadvise")]
+        self.advise(&f, offset);
+        counters
+            .skipped
+            .store(offset - header_end, atomic::Ordering::Relaxed);
+        let _ = counters.size.set(metadata.len() - (offset - header_end));
+        let reader = Input::buffered(self.buffer_capacity, wrap(f));
+        let reader = self.read_ahead(&filename, reader)?;
+        let source = LineSource {
+            counters,
+            ..self.untracked_source(name, reader)
+        };
+        self.merge
+            .add_source_with_priority(filename, source, priority)
+    }
+
+    /// Bisect the lines of `f` after the offset `first` for the offset of a line that is at or
+    /// before the first line at or after the start line. Like the merge itself, this relies on
+    /// `f` being sorted.
+    fn seek_start(&self, f: &mut fs::File, first: u64) -> io::Result<u64> {
+        // Below this many bytes, it's as quick to read through what's left.
+        const SCAN: u64 = 16 * 1024;
+        let (mut lo, mut hi) = (first, f.seek(io::SeekFrom::End(0))?);
+        while hi - lo > SCAN {
+            let mid = lo + (hi - lo) / 2;
+            match self.line_after(f, mid, first)? {
+                (_, Some(line)) if self.merge.before_start(&line) => lo = mid,
+                _ => hi = mid,
+            }
+        }
+        Ok(self.line_after(f, lo, first)?.0)
+    }
+
+    /// Seek to `offset` and return the offset of the first line starting there or after it, along
+    /// with the first line from there on that would be merged, if any. `first` is the offset of
+    /// the first line, which needs no resyncing.
+    fn line_after(
+        &self,
+        f: &mut fs::File,
+        offset: u64,
+        first: u64,
+    ) -> io::Result<(u64, Option<Line<K>>)> {
+        f.seek(io::SeekFrom::Start(offset))?;
+        let mut probe = self.untracked_source(sync::Arc::from(""), Input::plain(&mut *f));
+        let mut start = offset;
+        if offset > first {
+            // Skip the rest of the line `offset` falls in, which may not be valid UTF-8 on its own.
+            let mut partial = Vec::new();
+            start +=
+                io::BufRead::read_until(&mut probe.reader, self.format.delimiter, &mut partial)?
+                    as u64;
+        }
+        Ok((start, probe.next()?))
+    }
+
+    /// Merge only the lines for which `filter` returns true, dropping the rest as they are read.
+    /// Only inputs added afterwards are filtered.
+    pub fn with_filter<F>(self, filter: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.with_try_filter(move |line| Ok(filter(line)))
+    }
+
+    /// Like `with_filter`, but `filter` may also fail the merge, with the error attributed to the
+    /// input the line came from.
+    pub fn with_try_filter<F>(mut self, filter: F) -> Heap<T, K>
+    where
+        F: Fn(&str) -> io::Result<bool> + Send + Sync + 'static,
+    {
+        self.filter = Some(sync::Arc::new(filter));
+        self
+    }
+
+    /// Drop lines starting with `prefix`, e.g. `#`, from inputs added afterwards, so comments
+    /// needn't be sorted along with the lines around them. Header lines are kept regardless.
+    pub fn with_comment_prefix(mut self, prefix: &str) -> Heap<T, K> {
+        self.comment_prefix = Some(sync::Arc::from(prefix));
+        self
+    }
+
+    /// Set what happens to blank lines in inputs added afterwards.
+    pub fn with_blank_line_policy(mut self, policy: BlankLinePolicy) -> Heap<T, K> {
+        self.blank_lines = policy;
+        self
+    }
+
+    /// Buffer up to `bytes` of each input added afterwards at a time, rather than 8 KiB, so inputs
+    /// for which each read is slow, e.g. on a network file system, are read in fewer reads.
+    pub fn with_buffer_capacity(mut self, bytes: usize) -> Heap<T, K> {
+        self.buffer_capacity = bytes.max(1);
+        self
+    }
+
+    /// Split inputs into records terminated by the ASCII byte `delimiter` rather than by
+    /// newlines, e.g. `b'\0'` for the output of `find -print0`, and terminate written records with
+    /// it too. Such records may contain newlines. Only inputs added afterwards are split this way.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Heap<T, K> {
+        self.format.delimiter = delimiter;
+        self
+    }
+
+    /// Strip trailing whitespace from each line of inputs added afterwards, rather than keeping
+    /// lines exactly as they are apart from their delimiter.
+    pub fn with_trim_whitespace(mut self, trim: bool) -> Heap<T, K> {
+        self.format.trim_whitespace = trim;
+        self
+    }
+
+    /// Treat a `\r\n` line ending like a `\n` one in inputs added afterwards, stripping the `\r`
+    /// before lines are compared. Whether each line had one is remembered for
+    /// `LineEnding::Preserve`.
+    pub fn with_crlf(mut self, crlf: bool) -> Heap<T, K> {
+        self.format.strip_cr = crlf;
+        self
+    }
+
+    /// Set what happens to lines that aren't valid UTF-8 in inputs added afterwards.
+    pub fn with_invalid_utf8_policy(mut self, policy: InvalidUtf8Policy) -> Heap<T, K> {
+        self.invalid_utf8 = policy;
+        self
+    }
+
+    /// Transcode inputs added afterwards from `encoding` to UTF-8, after any decompression. Merged
+    /// lines are always UTF-8.
+    #[cfg(feature = "encoding")]
+    pub fn with_input_encoding(mut self, encoding: InputEncoding) -> Heap<T, K> {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set the line endings `write_sorted_lines` writes when lines are delimited by newlines.
+    pub fn with_line_ending(mut self, eol: LineEnding) -> Heap<T, K> {
+        self.eol = eol;
+        self
+    }
+
+    /// Treat the first line of every input as a header rather than merging it. The header of the
+    /// first input is kept for `header` and written out first by `write_sorted_lines`.
+    pub fn with_headers(self, headers: bool) -> Heap<T, K> {
+        self.with_skip_header(usize::from(headers))
+            .with_emit_header(headers)
+    }
+
+    /// Consume the first `lines` lines of every input added afterwards rather than merging them,
+    /// e.g. the header row of a CSV or TSV export. Those of the first non-empty input are kept for
+    /// `header_lines`.
+    pub fn with_skip_header(mut self, lines: usize) -> Heap<T, K> {
+        self.header_lines = lines;
+        self
+    }
+
+    /// Have `write_sorted_lines` copy the skipped header lines of the first non-empty input to the
+    /// output, as they were read, before the merged lines.
+    pub fn with_emit_header(mut self, emit: bool) -> Heap<T, K> {
+        self.emit_header = emit;
+        self
+    }
+
+    /// The first header line of the first non-empty input, if the heap was created `with_headers`
+    /// or `with_skip_header`.
+    pub fn header(&self) -> Option<&str> {
+        self.header.first().map(|header| header.text.as_str())
+    }
+
+    /// All the header lines skipped from the first non-empty input, which may be fewer than were
+    /// asked for if it was shorter.
+    pub fn header_lines(&self) -> impl Iterator<Item = &str> {
+        self.header.iter().map(|header| header.text.as_str())
+    }
+
+    /// Add `reader` with `priority`, decompressing it if compression support is enabled.
+    fn add_detected(&mut self, filename: String, reader: T, priority: i64) -> io::Result<()> {
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let reader = Input::detect_buffered(self.buffer_capacity, reader)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let reader = Input::buffered(self.buffer_capacity, reader);
+        self.add_input(filename, reader, priority)
+    }
+
+    /// Collapse each run of equal merged lines into one, paired with the number of times it
+    /// occurred, like `sort -m | uniq -c`.
+    pub fn counted(self) -> impl Iterator<Item = io::Result<(u64, String)>> {
+        self.merge
+            .counted()
+            .map(|line| line.map(|(count, Line { text, .. })| (count, text)))
+    }
+
+    /// Iterate over the lines `op` keeps, treating each input as a set of lines. Lines are the same
+    /// element if they compare equal, in which case the first of them is yielded.
+    pub fn set_operation(self, op: SetOperation) -> impl Iterator<Item = io::Result<String>> {
+        self.merge
+            .set_operation(op)
+            .map(|line| line.map(|Line { text, .. }| text))
+    }
+
+    /// Join the inputs on the key they are sorted by, like `join(1)` for any number of inputs.
+    /// Lines have the same key if they compare equal. Each row holds one line from each input,
+    /// in the order they were added, or `None` for an input without the key; a key with several
+    /// lines in some inputs yields a row for every combination of them.
+    pub fn join(self, kind: JoinKind) -> impl Iterator<Item = io::Result<Vec<Option<String>>>> {
+        self.merge.join(kind).flat_map(|groups| {
+            let rows = groups.map(|groups| {
+                let texts: Vec<Vec<String>> = groups
+                    .into_iter()
+                    .map(|lines| lines.into_iter().map(|line| line.text).collect())
+                    .collect();
+                join::combinations(&texts)
+            });
+            match rows {
+                Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            }
+        })
+    }
+
+    /// Call `f` with each merged line in turn, stopping at the first error. Line buffers are
+    /// recycled from one line to the next, so this avoids the allocations `next` makes per line.
+    pub fn for_each_line<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+    {
+        self.merge.for_each_item(|line| f(&line.text))
+    }
+
+    /// Iterate over the merged lines, each paired with the name of the input it came from.
+    pub fn iter_with_source(
+        &mut self,
+    ) -> impl Iterator<Item = io::Result<(sync::Arc<str>, String)>> + '_ {
+        iter::from_fn(move || {
+            self.merge
+                .next_with_source()
+                .map(|next| next.map(|(name, Line { text, .. })| (name, text)))
+        })
+    }
+
+    /// Like `iter_with_source`, but also pair each line with its line number in its input.
+    pub fn iter_with_position(
+        &mut self,
+    ) -> impl Iterator<Item = io::Result<merge::Positioned<String>>> + '_ {
+        iter::from_fn(move || {
+            self.merge
+                .next_with_position()
+                .map(|next| next.map(|(name, line_no, Line { text, .. })| (name, line_no, text)))
+        })
+    }
+
+    /// The line the merge yields next, whichever method it is merged by, without consuming it.
+    /// An error is returned by whichever of `peek` and the merge comes across it, only once.
+    pub fn peek(&mut self) -> Option<io::Result<&str>> {
+        self.merge
+            .peek()
+            .map(|line| line.map(|line| line.text.as_str()))
+    }
+
+    /// How many lines and bytes have been read from each input, in the order they were added.
+    /// Lines read ahead of the merge, such as each input's next line, are included.
+    pub fn stats(&self) -> Vec<SourceStats> {
+        source_stats(&self.stats)
+    }
+
+    pub fn print_sorted_lines(&mut self) -> io::Result<()> {
+        let stdout = io::stdout();
+        self.write_sorted_lines(stdout.lock()).map(|_| ())
+    }
+
+    /// Write the merged lines, each terminated by the delimiter, to `w` through a `BatchWriter`,
+    /// after the header if it is emitted. Returns the number of merged lines written. Once only one
+    /// input is left, its remaining lines are copied straight through.
+    pub fn write_sorted_lines<W: io::Write>(&mut self, w: W) -> io::Result<u64> {
+        let mut w = self.batch_writer(w);
+        let delimiter = self.format.delimiter;
+        if self.emit_header {
+            for header in &self.header {
+                write_record(&mut w, header, delimiter, self.eol)?;
+            }
+        }
+        let mut count = 0;
+        loop {
+            if let Some(last) = self.merge.take_last() {
+                let (lines, duplicates) = LineSource::write_remaining(last, self.eol, &mut w)?;
+                self.merge.count_drained(lines, duplicates);
+                count += lines;
+                break;
+            }
+            match self.merge.next() {
+                Some(line) => write_record(&mut w, &line?, delimiter, self.eol)?,
+                None => break,
+            }
+            count += 1;
+        }
+        w.flush()?;
+        Ok(count)
+    }
+}
+
+/// Write `line` followed by `delimiter`, and by a `\r` before a `\n` delimiter if `eol` calls for
+/// one.
+fn write_record<W: io::Write, K>(
+    w: &mut W,
+    line: &Line<K>,
+    delimiter: u8,
+    eol: LineEnding,
+) -> io::Result<()> {
+    w.write_all(line.bytes())?;
+    let crlf = match eol {
+        LineEnding::Lf => false,
+        LineEnding::Crlf => true,
+        LineEnding::Preserve => line.original.cr,
+    };
+    if crlf && delimiter == b'\n' {
+        w.write_all(b"\r")?;
+    }
+    w.write_all(&[delimiter])
+}
+
+/// Where `Heap::open_path` starts reading a file.
+enum StartAt<'a> {
+    /// After the header, or close to the start line if there is one, found by bisecting.
+    Bisect,
+    /// After the header, or close to the start line if there is one, found in the index.
+    Index(&'a SparseIndex),
+    /// At this offset, or after the header if that is further.
+    Offset(u64),
+}
+
+impl<K> Heap<fs::File, K> {
+    /// Open `path` and add it, decompressing it if compression support is enabled.
+    pub(crate) fn add_file(&mut self, path: &path::Path) -> io::Result<()> {
+        self.open_path(path, |f| f, 0, StartAt::Bisect)
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Read the files added from now on through io_uring on Linux, keeping `depth` reads of 64 KiB
+    /// of each in flight ahead of the merge, which saves system calls and hides the latency of
+    /// e.g. network filesystems. Elsewhere, or where io_uring can't be set up, files are read as
+    /// usual.
+    #[cfg(feature = "io-uring")]
+    pub fn with_io_uring(mut self, depth: u32) -> Self {
+        self.io_uring = Some(depth);
+        self
+    }
+
+    /// How the files added are read.
+    fn file_reader(&self) -> impl FnOnce(fs::File) -> Box<dyn io::Read + Send> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let ring = self.io_uring.and_then(|depth| uring::Ring::new(depth).ok());
+        let follow = self.follow;
+        let cancel = self.merge.cancellation().cloned();
+        move |f| {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            if let Some(ring) = ring {
+                let reader = uring::UringFile::new(ring, f);
+                return match follow {
+                    Some(interval) => Box::new(follow::Follow::new(reader, interval, cancel)),
+                    None => Box::new(reader),
+                };
+            }
+            match follow {
+                Some(interval) => Box::new(follow::Follow::new(f, interval, cancel)),
+                None => Box::new(f),
+            }
+        }
+    }
+
+    /// Open `path` and add it, decompressing it if compression support is enabled. With
+    /// `with_start_line`, the lines of a regular, uncompressed file before the start line are
+    /// mostly skipped by bisecting the file rather than read.
+    pub fn add_file(&mut self, path: &path::Path) -> io::Result<()> {
+        let reader = self.file_reader();
+        self.open_path(path, reader, 0, StartAt::Bisect)
+    }
+
+    /// Like `add_file`, with `priority` for `DuplicatePolicy::HighestPriority`.
+    pub fn add_file_with_priority(&mut self, path: &path::Path, priority: i64) -> io::Result<()> {
+        let reader = self.file_reader();
+        self.open_path(path, reader, priority, StartAt::Bisect)
+    }
+
+    /// Like `add_file`, for a file indexed by `index`, which is used instead of bisecting the file
+    /// to skip to the start line.
+    pub fn add_indexed_file(&mut self, path: &path::Path, index: &SparseIndex) -> io::Result<()> {
+        let reader = self.file_reader();
+        self.open_path(path, reader, 0, StartAt::Index(index))
+    }
+
+    /// Like `add_file`, reading the file from `offset` on, past its header, as for resuming from
+    /// a `Checkpoint`. Fails unless it is a regular, uncompressed UTF-8 file.
+    pub fn add_file_at(&mut self, path: &path::Path, offset: u64) -> io::Result<()> {
+        let reader = self.file_reader();
+        self.open_path(path, reader, 0, StartAt::Offset(offset))
+    }
+
+    /// Add standard input under the name `-`, decompressing it if compression support is enabled.
+    /// There is only one standard input, so anything added after it is empty.
+    pub fn add_stdin(&mut self) -> io::Result<()> {
+        self.add_detected("-".to_string(), Box::new(io::stdin()), 0)
+    }
+
+    /// Add the input named `path` on the command line of tools like `sort`: standard input if it
+    /// is `-`, with the `http` feature the body at it if it is an `http://` URL, as for `add_url`,
+    /// and otherwise the file there, as for `add_file`. An error adding it names it, and
+    /// under `SourceErrorPolicy::DropSource` is recorded in `failed_sources` instead.
+    pub fn add_path<P: AsRef<path::Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        #[cfg(feature = "http")]
+        let url = self.add_if_url(path, None);
+        #[cfg(not(feature = "http"))]
+        let url = None;
+        let added = match url {
+            Some(added) => added,
+            None if path == path::Path::new("-") => self.add_stdin(),
+            None => self.add_file(path),
+        };
+        match added {
+            Ok(()) => Ok(()),
+            Err(err) => self.merge.failed_to_add(&path.display().to_string(), err),
+        }
+    }
+
+    /// Add each of `paths` in turn with `add_path`, stopping at the first that can't be added.
+    pub fn add_paths<I>(&mut self, paths: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<path::Path>,
+    {
+        paths.into_iter().try_for_each(|path| self.add_path(path))
+    }
+}
+
+/// Adds each path with `Heap::add_path`. Since `extend` can't fail, an error adding a path is
+/// returned by the merge instead, before any line.
+impl<K, P> Extend<P> for Heap<Box<dyn io::Read + Send>, K>
+where
+    P: AsRef<path::Path>,
+{
+    fn extend<I: IntoIterator<Item = P>>(&mut self, paths: I) {
+        for path in paths {
+            if let Err(err) = self.add_path(path) {
+                self.merge.defer(err);
+            }
+        }
+    }
+}
+
+impl<T, K> Iterator for Heap<T, K>
+where
+    T: io::Read,
+{
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        self.merge
+            .next()
+            .map(|line| line.map(|Line { text, .. }| text))
+    }
+
+    /// Like `KWayMerge::size_hint`, bounded by `lines_left` too.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.merge.size_hint();
+        let upper = match (upper, self.lines_left()) {
+            (Some(upper), Some(left)) => Some(upper.min(left)),
+            (upper, left) => upper.or(left),
+        };
+        (lower, upper)
+    }
+}
+
+/// The byte lines of a reader, trimmed according to its record format.
+struct ByteLineSource<T>
+where
+    T: io::Read,
+{
+    reader: io::BufReader<T>,
+    format: RecordFormat,
+}
+
+impl<T> SortedSource for ByteLineSource<T>
+where
+    T: io::Read,
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        if self.next_into(&mut line)? {
+            Ok(Some(line))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_into(&mut self, line: &mut Vec<u8>) -> io::Result<bool> {
+        line.clear();
+        let n = io::BufRead::read_until(&mut self.reader, self.format.delimiter, line)?;
+        self.format.trim_bytes(line);
+        Ok(n > 0)
+    }
+
+    fn describe(&self, line: &Vec<u8>) -> Option<String> {
+        Some(String::from_utf8_lossy(line).into_owned())
+    }
+}
+
+/// A counterpart to `Heap` that merges raw byte lines, split on `b'\n'` unless set otherwise,
+/// without requiring them to be valid UTF-8. Lines are compared as byte strings.
+pub struct ByteHeap<T>
+where
+    T: io::Read,
+{
+    merge: KWayMerge<ByteLineSource<T>>,
+    format: RecordFormat,
+}
+
+impl<T> Default for ByteHeap<T>
+where
+    T: io::Read,
+{
+    fn default() -> ByteHeap<T> {
+        ByteHeap::new()
+    }
+}
+
+impl<T> ByteHeap<T>
+where
+    T: io::Read,
+{
+    pub fn new() -> ByteHeap<T> {
+        ByteHeap {
+            merge: KWayMerge::new(),
+            format: RecordFormat::default(),
+        }
+    }
+
+    /// Set the direction the inputs are sorted in.
+    pub fn with_order(mut self, order: Order) -> ByteHeap<T> {
+        self.merge = self.merge.with_order(order);
+        self
+    }
+
+    /// Split inputs added afterwards on `delimiter` rather than on newlines, as for
+    /// `Heap::with_delimiter`.
+    pub fn with_delimiter(mut self, delimiter: u8) -> ByteHeap<T> {
+        self.format.delimiter = delimiter;
+        self
+    }
+
+    /// Strip trailing ASCII whitespace from each line of inputs added afterwards.
+    pub fn with_trim_whitespace(mut self, trim: bool) -> ByteHeap<T> {
+        self.format.trim_whitespace = trim;
+        self
+    }
+
+    /// Strip the `\r` of `\r\n` line endings from inputs added afterwards.
+    pub fn with_crlf(mut self, crlf: bool) -> ByteHeap<T> {
+        self.format.strip_cr = crlf;
+        self
+    }
+
+    pub fn add_reader(&mut self, filename: String, reader: T) -> io::Result<()> {
+        let source = ByteLineSource {
+            reader: io::BufReader::new(reader),
+            format: self.format,
+        };
+        self.merge.add_source(filename, source)
+    }
+}
+
+impl<T> Iterator for ByteHeap<T>
+where
+    T: io::Read,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.merge.next()
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "bar\nfoo".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "bar");
+        assert_eq!(heap.next().unwrap()?, "foo");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_ooo() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "foo\nbar".as_bytes())?;
+        let err = heap.next().unwrap().expect_err("Expected an error");
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
+        heap.add_reader("file3".to_string(), "".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_prefixes() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader(
+            "file1".to_string(),
+            "\nab\nabcdefgh\nabcdefgh2\n".as_bytes(),
+        )?;
+        heap.add_reader("file2".to_string(), "a\nab\0\nabcdefgh1\nb\n".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            vec![
+                "",
+                "a",
+                "ab",
+                "ab\0",
+                "abcdefgh",
+                "abcdefgh1",
+                "abcdefgh2",
+                "b"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_with_dupes() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
+        heap.add_reader("file3".to_string(), "b\nc".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_with_repeated_names() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file1".to_string(), "b\nd".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_empty() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "".as_bytes())?;
+        heap.add_reader("file1".to_string(), "".as_bytes())?;
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_comparator() -> Result<(), io::Error> {
+        let mut heap = Heap::with_comparator(|a: &str, b: &str| b.cmp(a));
+        heap.add_reader("file1".to_string(), "c\na".as_bytes())?;
+        heap.add_reader("file2".to_string(), "d\nb".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_comparator_ooo() -> Result<(), io::Error> {
+        let mut heap = Heap::with_comparator(|a: &str, b: &str| b.cmp(a));
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        let err = heap.next().unwrap().expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_key() -> Result<(), io::Error> {
+        let mut heap = Heap::with_key(|line: &str| line[2..].parse::<u32>().unwrap());
+        heap.add_reader("file1".to_string(), "a 2\nb 10".as_bytes())?;
+        heap.add_reader("file2".to_string(), "c 9\nd 11".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a 2");
+        assert_eq!(heap.next().unwrap()?, "c 9");
+        assert_eq!(heap.next().unwrap()?, "b 10");
+        assert_eq!(heap.next().unwrap()?, "d 11");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_key_computed_once_per_line() -> Result<(), io::Error> {
+        let calls = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut heap = Heap::with_key(move |line: &str| {
+            counter.fetch_add(1, sync::atomic::Ordering::SeqCst);
+            line.len()
+        });
+        heap.add_reader("file1".to_string(), "a\nbb\nccc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "dd\neeee".as_bytes())?;
+        assert_eq!(heap.by_ref().count(), 5);
+        assert_eq!(calls.load(sync::atomic::Ordering::SeqCst), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_descending() -> Result<(), io::Error> {
+        let mut heap = Heap::descending();
+        heap.add_reader("file1".to_string(), "c\na".as_bytes())?;
+        heap.add_reader("file2".to_string(), "d\nb".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_descending_ooo() -> Result<(), io::Error> {
+        let mut heap = Heap::descending();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        let err = heap.next().unwrap().expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_descending_with_key() -> Result<(), io::Error> {
+        let mut heap = Heap::with_key(|line: &str| line.len()).with_order(Order::Desc);
+        heap.add_reader("file1".to_string(), "aaa\na".as_bytes())?;
+        heap.add_reader("file2".to_string(), "bb".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "aaa");
+        assert_eq!(heap.next().unwrap()?, "bb");
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_heap() -> Result<(), io::Error> {
+        let mut heap = ByteHeap::new();
+        heap.add_reader("file1".to_string(), &b"a\n\xff\xfe"[..])?;
+        heap.add_reader("file2".to_string(), &b"b\xc3\x28\n"[..])?;
+        assert_eq!(heap.next().unwrap()?, b"a");
+        assert_eq!(heap.next().unwrap()?, b"b\xc3\x28");
+        assert_eq!(heap.next().unwrap()?, b"\xff\xfe");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_heap_ooo() -> Result<(), io::Error> {
+        let mut heap = ByteHeap::new();
+        heap.add_reader("file1".to_string(), &b"\xff\na"[..])?;
+        let err = heap.next().unwrap().expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_heap_descending() -> Result<(), io::Error> {
+        let mut heap = ByteHeap::new().with_order(Order::Desc);
+        heap.add_reader("file1".to_string(), &b"\xff\na"[..])?;
+        heap.add_reader("file2".to_string(), &b"b"[..])?;
+        assert_eq!(heap.next().unwrap()?, b"\xff");
+        assert_eq!(heap.next().unwrap()?, b"b");
+        assert_eq!(heap.next().unwrap()?, b"a");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_sorted_lines() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 3);
+        assert_eq!(out, b"a\nb\nc\n");
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_add_compressed_reader() -> Result<(), io::Error> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"a\nc\n")?;
+        let compressed = encoder.finish()?;
+        let mut heap = Heap::new();
+        heap.add_compressed_reader("file1.gz".to_string(), &compressed[..])?;
+        heap.add_compressed_reader("file2".to_string(), "b\nd".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_policy() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_out_of_order_policy(OutOfOrderPolicy::Skip);
+        heap.add_reader("file1".to_string(), "b\na\nc".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorder_window() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_reorder_window(1);
+        heap.add_reader("file1".to_string(), "b\na\nd\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "bb".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "bb");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorder_window_by_key() -> Result<(), io::Error> {
+        let mut heap = Heap::with_key(|line: &str| line.parse::<u32>().unwrap())
+            .with_reorder_window_by_key(|a, b| *b > a + 60);
+        heap.add_reader("file1".to_string(), "100\n90\n150\n200".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "90");
+        assert_eq!(heap.next().unwrap()?, "100");
+        assert_eq!(heap.next().unwrap()?, "150");
+        assert_eq!(heap.next().unwrap()?, "200");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() -> Result<(), io::Error> {
+        let mut heap = Heap::new().dedup(true);
+        heap.add_reader("file1".to_string(), "a\nb\nb\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "a\nc\nd".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "c");
+        assert_eq!(heap.next().unwrap()?, "d");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_counted() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nb\nb".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nc".as_bytes())?;
+        let mut counted = heap.counted();
+        assert_eq!(counted.next().unwrap()?, (1, "a".to_string()));
+        assert_eq!(counted.next().unwrap()?, (3, "b".to_string()));
+        assert_eq!(counted.next().unwrap()?, (1, "c".to_string()));
+        assert!(counted.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_with_source() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b".as_bytes())?;
+        let lines = heap.iter_with_source().collect::<io::Result<Vec<_>>>()?;
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|(name, line)| (&**name, &**line))
+            .collect();
+        assert_eq!(lines, vec![("file1", "a"), ("file2", "b"), ("file1", "c")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ties_broken_by_insertion_order() -> Result<(), io::Error> {
+        let mut heap = Heap::with_key(|line: &str| line.len());
+        heap.add_reader("file2".to_string(), "b\nbb".as_bytes())?;
+        heap.add_reader("file1".to_string(), "a\naa".as_bytes())?;
+        assert_eq!(heap.next().unwrap()?, "b");
+        assert_eq!(heap.next().unwrap()?, "a");
+        assert_eq!(heap.next().unwrap()?, "bb");
+        assert_eq!(heap.next().unwrap()?, "aa");
+        assert!(heap.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic_ties() -> Result<(), io::Error> {
+        let inputs = [
+            ("file1", "1 b\n2 a\n2 c\n3 z\n"),
+            ("file2", "1 a\n2 b\n3 z\n"),
+            ("file3", "1 c\n2 a\n4 a\n"),
+        ];
+        let orders: [[usize; 3]; 3] = [[0, 1, 2], [2, 1, 0], [1, 2, 0]];
+        let mut outputs = Vec::new();
+        for order in &orders {
+            for strategy in &[Strategy::Heap, Strategy::LoserTree] {
+                for &(capacity, threads) in &[(1, 1), (64 * 1024, 1), (64 * 1024, 2), (3, 3)] {
+                    let heap = Heap::with_key(|line: &str| line[..1].to_string())
+                        .with_strategy(*strategy)
+                        .with_buffer_capacity(capacity)
+                        .with_deterministic_ties();
+                    let mut heap = match threads {
+                        1 => heap,
+                        threads => heap.with_parallel_strategy(threads),
+                    };
+                    for &i in order {
+                        let (name, lines) = inputs[i];
+                        heap.add_reader(name.to_string(), io::Cursor::new(lines.as_bytes()))?;
+                    }
+                    let mut out = Vec::new();
+                    heap.write_sorted_lines(&mut out)?;
+                    outputs.push(String::from_utf8(out).unwrap());
+                }
+            }
+        }
+        let expected = "1 a\n1 b\n1 c\n2 a\n2 a\n2 b\n2 c\n3 z\n3 z\n4 a\n";
+        assert!(outputs.iter().all(|out| out == expected), "{:?}", outputs);
+
+        // Which line of a run is kept still follows the order of the inputs.
+        let mut heap = Heap::with_key(|line: &str| line[..1].to_string())
+            .with_deterministic_ties()
+            .with_duplicate_policy(DuplicatePolicy::LastSource);
+        heap.add_reader("file1".to_string(), io::Cursor::new("1 b\n".as_bytes()))?;
+        heap.add_reader("file2".to_string(), io::Cursor::new("1 a\n".as_bytes()))?;
+        assert_eq!(heap.collect::<io::Result<Vec<_>>>()?, ["1 a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_sorted_lines_last_source() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_trim_whitespace(true).dedup(true);
+        heap.add_reader("file1".to_string(), "a\nb  \nb\nd\ne".as_bytes())?;
+        heap.add_reader("file2".to_string(), "c".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 5);
+        assert_eq!(out, b"a\nb\nc\nd\ne\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_sorted_lines_last_source_ooo() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\nb".as_bytes())?;
+        let err = heap
+            .write_sorted_lines(Vec::new())
+            .expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 3!"
+        );
+
+        let mut heap = Heap::new().with_out_of_order_policy(OutOfOrderPolicy::Skip);
+        heap.add_reader("file1".to_string(), "b\na\nc".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 2);
+        assert_eq!(out, b"b\nc\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_line() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_trim_whitespace(true);
+        heap.add_reader("file1".to_string(), "a\nc  \ne".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
+        let mut lines = Vec::new();
+        heap.for_each_line(|line| {
+            lines.push(line.to_string());
+            Ok(())
+        })?;
+        assert_eq!(lines, vec!["a", "b", "c", "d", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_context() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\nb".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b".as_bytes())?;
+        let err = heap
+            .collect::<io::Result<Vec<_>>>()
+            .expect_err("Expected an error");
+        match MergeError::from_io(&err) {
+            Some(MergeError::OutOfOrder {
+                file,
+                line_no,
+                prev,
+                next,
+            }) => {
+                assert_eq!(file, "file1");
+                assert_eq!(*line_no, 3);
+                assert_eq!(prev.as_deref(), Some("c"));
+                assert_eq!(next.as_deref(), Some("b"));
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_line_numbers() -> Result<(), io::Error> {
+        // The line an error names counts the header and comments skipped before it, whether the
+        // line is merged through the heap, copied through as the last input, or re-sorted.
+        let line_no =
+            |heap: Heap<&'static [u8]>, text: &'static str, write: bool| -> io::Result<u64> {
+                let mut heap = heap.with_skip_header(1).with_comment_prefix("#");
+                heap.add_reader("file1".to_string(), text.as_bytes())?;
+                heap.add_reader("file2".to_string(), "h\nb\n".as_bytes())?;
+                let err = if write {
+                    heap.write_sorted_lines(io::sink()).unwrap_err()
+                } else {
+                    heap.collect::<io::Result<Vec<_>>>().unwrap_err()
+                };
+                match MergeError::from_io(&err) {
+                    Some(MergeError::OutOfOrder { line_no, .. }) => Ok(*line_no),
+                    other => panic!("Unexpected error {:?}", other),
+                }
+            };
+        let text = "h\na\n# c\nc\nb\n";
+        assert_eq!(line_no(Heap::new(), text, false)?, 5);
+        assert_eq!(line_no(Heap::new(), text, true)?, 5);
+        let heap = Heap::new().with_reorder_window(1);
+        assert_eq!(line_no(heap, "h\nc\n# c\nd\na\n", false)?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), &b"a\n\xff\n"[..])?;
+        let err = heap
+            .collect::<io::Result<Vec<_>>>()
+            .expect_err("Expected an error");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            format!("{}", err),
+            "Line 2 of file [file1] is not valid UTF-8"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8_policy() -> Result<(), io::Error> {
+        let inputs = |heap: &mut Heap<&'static [u8]>| -> io::Result<()> {
+            heap.add_reader("file1".to_string(), &b"a\nb\xff\n"[..])?;
+            heap.add_reader("file2".to_string(), &b"b\nc\n"[..])
+        };
+        let mut heap = Heap::new().with_invalid_utf8_policy(InvalidUtf8Policy::Lossy);
+        inputs(&mut heap)?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "b\u{fffd}", "c"]);
+        let mut heap = Heap::new().with_invalid_utf8_policy(InvalidUtf8Policy::Passthrough);
+        inputs(&mut heap)?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 4);
+        assert_eq!(out, b"a\nb\nb\xff\nc\n");
+        Ok(())
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn test_input_encoding() -> Result<(), io::Error> {
+        let utf16: Vec<u8> = "\u{feff}b\nd\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let mut heap = Heap::new().with_input_encoding(InputEncoding::Bom);
+        heap.add_reader("utf16".to_string(), &utf16[..])?;
+        heap.add_reader("utf8".to_string(), "\u{feff}a\nc\n".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d"]);
+        let encoding = "latin1".parse().unwrap();
+        let mut heap = Heap::new().with_input_encoding(encoding);
+        heap.add_reader("latin1".to_string(), &b"caf\xe9\n"[..])?;
+        assert_eq!(heap.next().unwrap()?, "caf\u{e9}");
+        assert!("klingon".parse::<InputEncoding>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_with_position() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b".as_bytes())?;
+        let lines = heap
+            .iter_with_position()
+            .map(|line| line.map(|(name, line_no, line)| format!("{}:{}:{}", name, line_no, line)))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["file1:1:a", "file2:1:b", "file1:2:c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nccc\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b".as_bytes())?;
+        assert_eq!(heap.stats()[0].lines, 1);
+        heap.by_ref().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            heap.stats(),
+            vec![
+                SourceStats {
+                    name: "file1".to_string(),
+                    lines: 2,
+                    bytes: 6,
+                    size: None,
+                },
+                SourceStats {
+                    name: "file2".to_string(),
+                    lines: 1,
+                    bytes: 1,
+                    size: None,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    /// A reader that yields `contents` and then fails.
+    struct Failing<'a>(&'a [u8]);
+
+    impl io::Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated"));
+            }
+            io::Read::read(&mut self.0, buf)
+        }
+    }
+
+    #[test]
+    fn test_source_error_policy() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_source_error_policy(SourceErrorPolicy::DropSource);
+        heap.add_reader("file1".to_string(), Failing("a\nc\n".as_bytes()))?;
+        heap.add_reader("file2".to_string(), Failing("b\nd\ne\n".as_bytes()))?;
+        heap.add_reader("file3".to_string(), Failing("".as_bytes()))?;
+        let lines = heap.by_ref().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d", "e"]);
+        let failed = heap
+            .failed_sources()
+            .iter()
+            .map(|(name, err)| format!("{}: {}", name, err))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            vec![
+                "file3: Error reading file [file3]: truncated",
+                "file1: Error reading file [file1]: truncated",
+                "file2: Error reading file [file2]: truncated",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_error_fails_by_default() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), Failing("a\nb\n".as_bytes()))?;
+        let err = heap
+            .collect::<io::Result<Vec<_>>>()
+            .expect_err("Expected an error");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delimiter() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_delimiter(b'\0');
+        heap.add_reader("file1".to_string(), "a\nb \0c\0".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\0d".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 4);
+        assert_eq!(out, b"a\nb \0b\0c\0d\0");
+        let mut heap = Heap::new().with_delimiter(b'\0');
+        heap.add_reader("file1".to_string(), &b"a\0\xff\0"[..])?;
+        let err = heap
+            .collect::<io::Result<Vec<_>>>()
+            .expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Line 2 of file [file1] is not valid UTF-8"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_whitespace() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a \r\nb\t\n".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a \r", "b\t"]);
+        let mut heap = Heap::new().with_delimiter(b'\0').with_trim_whitespace(true);
+        heap.add_reader("file1".to_string(), "a \r\n\0b\t".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crlf() -> Result<(), io::Error> {
+        let write = |eol: LineEnding| -> io::Result<Vec<u8>> {
+            let mut heap = Heap::new().with_crlf(true).with_line_ending(eol);
+            heap.add_reader("unix".to_string(), "a\nc\n".as_bytes())?;
+            heap.add_reader("windows".to_string(), "b\r\nc\r\nd\r\n".as_bytes())?;
+            let mut out = Vec::new();
+            heap.write_sorted_lines(&mut out)?;
+            Ok(out)
+        };
+        assert_eq!(write(LineEnding::Preserve)?, b"a\nb\r\nc\nc\r\nd\r\n");
+        assert_eq!(write(LineEnding::Lf)?, b"a\nb\nc\nc\nd\n");
+        assert_eq!(write(LineEnding::Crlf)?, b"a\r\nb\r\nc\r\nc\r\nd\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> Result<(), io::Error> {
+        let merge = |heap: Heap<&[u8]>| -> io::Result<Vec<String>> {
+            let mut heap = heap;
+            heap.add_reader("file1".to_string(), "a\nc\ne\ng\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "b\nd\nf\nz\n".as_bytes())?;
+            heap.collect()
+        };
+        let heap = Heap::new().with_start_line("b").with_end_line("e");
+        assert_eq!(merge(heap)?, vec!["b", "c", "d", "e"]);
+        let heap = Heap::new().with_start_line("c").with_limit(2);
+        assert_eq!(merge(heap)?, vec!["c", "d"]);
+
+        let mut heap = Heap::new().with_end_line("bb");
+        heap.add_reader("file1".to_string(), "a\nc\nb\n".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 1);
+        assert_eq!(out, b"a\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_start() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-seek-{}",
+            std::process::id()
+        ));
+        let mut contents = "header\n".to_string();
+        for i in 0..100_000 {
+            contents.push_str(&format!("{:06}\n", i * 2));
+        }
+        fs::write(&path, contents)?;
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new()
+            .with_headers(true)
+            .with_start_line("100001")
+            .with_limit(2);
+        heap.add_file(&path)?;
+        heap.add_reader("other".to_string(), Box::new("header\n100003\n".as_bytes()))?;
+        let mut out = Vec::new();
+        heap.write_sorted_lines(&mut out)?;
+        assert_eq!(out, b"header\n100002\n100003\n");
+        assert!(heap.stats()[0].lines < 10_000);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_hint() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-size-hint-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "a\nc\ne\n")?;
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new();
+        assert!(heap.is_empty());
+        heap.add_file(&path)?;
+        assert_eq!(heap.size_hint(), (1, Some(5)));
+        heap.add_reader("other".to_string(), Box::new("b\n".as_bytes()))?;
+        assert_eq!((heap.len_sources(), heap.size_hint()), (2, (2, None)));
+        heap.by_ref().take(2).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!((heap.len_sources(), heap.size_hint()), (1, (1, None)));
+        assert_eq!(heap.by_ref().count(), 2);
+        assert!(heap.is_empty());
+        assert_eq!(heap.size_hint(), (0, Some(0)));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\nd\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())?;
+        assert_eq!(heap.peek().transpose()?, Some("a"));
+        assert_eq!(heap.peek().transpose()?, Some("a"));
+        assert_eq!(heap.size_hint().0, 3);
+        assert_eq!(heap.next().transpose()?.as_deref(), Some("a"));
+        assert_eq!(heap.peek().transpose()?, Some("b"));
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 4);
+        assert_eq!(out, b"b\nc\nd\nd\n");
+        assert!(heap.peek().is_none());
+
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nb\nb\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\n".as_bytes())?;
+        assert_eq!(heap.peek().transpose()?, Some("a"));
+        heap.next();
+        assert_eq!(heap.peek().transpose()?, Some("b"));
+        let counted = heap.counted().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(counted, vec![(3, "b".to_string())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_and_remove_sources() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\ne\ng\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\nf\n".as_bytes())?;
+        assert_eq!(
+            heap.by_ref().take(2).collect::<io::Result<Vec<_>>>()?,
+            vec!["a", "b"]
+        );
+        heap.add_reader("late".to_string(), "a\nd\nh\n".as_bytes())?;
+        assert!(heap.remove_source("file2"));
+        assert!(!heap.remove_source("file2"));
+        assert_eq!(heap.len_sources(), 2);
+        let lines = heap.by_ref().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "c", "d", "e", "g", "h"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_paths() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            dir.join(format!(
+                "merge-sorted-files-rs-test-paths-{}-{}",
+                name,
+                std::process::id()
+            ))
+        };
+        fs::write(path("a"), "a\nc\n")?;
+        fs::write(path("b"), "b\n")?;
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new();
+        heap.add_paths([path("a"), path("b")])?;
+        assert_eq!(heap.collect::<io::Result<Vec<_>>>()?, vec!["a", "b", "c"]);
+
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new();
+        let err = heap
+            .add_paths([path("a"), path("missing"), path("b")])
+            .expect_err("Expected an error");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        match MergeError::from_io(&err) {
+            Some(MergeError::Io { file, .. }) => {
+                assert_eq!(*file, path("missing").display().to_string())
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert_eq!(heap.len_sources(), 1);
+
+        let mut heap: Heap<Box<dyn io::Read + Send>> =
+            Heap::new().with_source_error_policy(SourceErrorPolicy::DropSource);
+        heap.add_paths([path("a"), path("missing"), path("b")])?;
+        assert_eq!(heap.failed_sources().len(), 1);
+        assert_eq!(heap.collect::<io::Result<Vec<_>>>()?, vec!["a", "b", "c"]);
+
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new();
+        heap.extend([path("a"), path("missing"), path("b")]);
+        assert!(heap.next().expect("An error").is_err());
+        assert_eq!(heap.collect::<io::Result<Vec<_>>>()?, vec!["a", "b", "c"]);
+        fs::remove_file(path("a"))?;
+        fs::remove_file(path("b"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexed_file() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-index-{}",
+            std::process::id()
+        ));
+        let mut heap = Heap::new().with_headers(true);
+        heap.add_reader(
+            "evens".to_string(),
+            "header\n000000\n000002\n000004\n".as_bytes(),
+        )?;
+        heap.add_reader("odds".to_string(), "header\n000001\n000003\n".as_bytes())?;
+        let (count, index) =
+            heap.write_indexed_lines(fs::File::create(&path)?, IndexInterval::Lines(2))?;
+        assert_eq!((count, index.entries.len()), (5, 3));
+        let mut heap: Heap<Box<dyn io::Read + Send>> =
+            Heap::new().with_headers(true).with_start_line("000003");
+        heap.add_indexed_file(&path, &index)?;
+        assert_eq!(
+            heap.by_ref().collect::<io::Result<Vec<_>>>()?,
+            vec!["000003", "000004"]
+        );
+        // The header, then the lines from the indexed 000002 on.
+        assert_eq!(heap.stats()[0].lines, 4);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_hooks() -> Result<(), io::Error> {
+        let heap = || -> io::Result<Heap<&[u8]>> {
+            let mut heap = Heap::new()
+                .with_output_filter(|line| !line.starts_with('b'))
+                .with_output_map(|line| line.to_uppercase())
+                .with_output_filter(|line| line != "D")
+                .dedup(true)
+                .with_limit(3);
+            heap.add_reader("file1".to_string(), "a\nb\nc\ne\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "a\nb\nd\nf\n".as_bytes())?;
+            Ok(heap)
+        };
+        assert_eq!(
+            heap()?.collect::<io::Result<Vec<_>>>()?,
+            vec!["A", "C", "E"]
+        );
+        let mut out = Vec::new();
+        assert_eq!(heap()?.write_sorted_lines(&mut out)?, 3);
+        assert_eq!(out, b"A\nC\nE\n");
+        let mut lines = Vec::new();
+        heap()?.for_each_line(|line| {
+            lines.push(line.to_string());
+            Ok(())
+        })?;
+        assert_eq!(lines, vec!["A", "C", "E"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_operation() -> Result<(), io::Error> {
+        let mut heap =
+            Heap::with_comparator(|a: &str, b: &str| a.to_lowercase().cmp(&b.to_lowercase()));
+        heap.add_reader("file1".to_string(), "a\nB\nc\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nc\nd\n".as_bytes())?;
+        let lines = heap.set_operation(SetOperation::Intersection);
+        assert_eq!(lines.collect::<io::Result<Vec<_>>>()?, vec!["B", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleave() -> Result<(), io::Error> {
+        for strategy in [Strategy::Heap, Strategy::LoserTree] {
+            let mut heap = Heap::new()
+                .with_strategy(strategy)
+                .with_interleave(Interleave::RoundRobin);
+            heap.add_reader("file1".to_string(), "c\na\nb\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "z\n".as_bytes())?;
+            heap.add_reader("file3".to_string(), "y\nx\n".as_bytes())?;
+            assert_eq!(
+                heap.collect::<io::Result<Vec<_>>>()?,
+                vec!["c", "z", "y", "a", "x", "b"]
+            );
+        }
+        let mut heap = Heap::new().with_interleave(Interleave::Concat);
+        heap.add_reader("file1".to_string(), "c\na\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\na\n".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 4);
+        assert_eq!(out, b"c\na\nb\na\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_policy() -> Result<(), io::Error> {
+        let key = |line: &str| line.split('=').next().unwrap_or("").to_string();
+        let mut heap = Heap::with_key(key).with_duplicate_policy(DuplicatePolicy::HighestPriority);
+        heap.add_reader_with_priority("new".to_string(), "a=2\nc=2\n".as_bytes(), 1)?;
+        heap.add_reader("old".to_string(), "a=1\nb=1\nc=1\n".as_bytes())?;
+        assert_eq!(
+            heap.collect::<io::Result<Vec<_>>>()?,
+            vec!["a=2", "b=1", "c=2"]
+        );
+        let mut heap = Heap::with_key(key).with_duplicate_merger(|lines| lines.join(";"));
+        heap.add_reader("file1".to_string(), "a=1\nb=1\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "a=2\n".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 2);
+        assert_eq!(out, b"a=1;a=2\nb=1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_join() -> Result<(), io::Error> {
+        let key = |line: &str| line.split(',').next().unwrap_or("").to_string();
+        let mut heap = Heap::with_key(key);
+        heap.add_reader("names".to_string(), "1,ann\n2,bob\n".as_bytes())?;
+        heap.add_reader("ages".to_string(), "2,40\n2,41\n3,50\n".as_bytes())?;
+        let rows = heap.join(JoinKind::Outer).collect::<io::Result<Vec<_>>>()?;
+        let some = |line: &str| Some(line.to_string());
+        assert_eq!(
+            rows,
+            vec![
+                vec![some("1,ann"), None],
+                vec![some("2,bob"), some("2,40")],
+                vec![some("2,bob"), some("2,41")],
+                vec![None, some("3,50")],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() -> Result<(), io::Error> {
+        let merge = |policy: BlankLinePolicy| -> io::Result<Vec<String>> {
+            let mut heap = Heap::new()
+                .with_comment_prefix("#")
+                .with_blank_line_policy(policy);
+            heap.add_reader("file1".to_string(), "\n# zzz\na\n#\nc\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "b\n  \n# a\nd\n".as_bytes())?;
+            heap.collect()
+        };
+        assert_eq!(merge(BlankLinePolicy::Skip)?, vec!["a", "b", "c", "d"]);
+        assert!(merge(BlankLinePolicy::Merge).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_header() -> Result<(), io::Error> {
+        let write = |emit: bool| -> io::Result<(Vec<u8>, Vec<String>)> {
+            let mut heap = Heap::new().with_skip_header(2).with_emit_header(emit);
+            heap.add_reader("empty".to_string(), "".as_bytes())?;
+            heap.add_reader("file1".to_string(), "name\r\n--\na\nc\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "other\n--\nb\n".as_bytes())?;
+            let header = heap.header_lines().map(str::to_string).collect();
+            let mut out = Vec::new();
+            heap.write_sorted_lines(&mut out)?;
+            Ok((out, header))
+        };
+        let (out, header) = write(true)?;
+        assert_eq!(out, b"name\r\n--\na\nb\nc\n");
+        assert_eq!(header, vec!["name\r", "--"]);
+        assert_eq!(write(false)?.0, b"a\nb\nc\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_heap_delimiter() -> Result<(), io::Error> {
+        let mut heap = ByteHeap::new().with_delimiter(b'\0');
+        heap.add_reader("file1".to_string(), &b"a\n\0c\0"[..])?;
+        heap.add_reader("file2".to_string(), &b"b\xff\0"[..])?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec![&b"a\n"[..], &b"b\xff"[..], &b"c"[..]]);
         Ok(())
     }
 }
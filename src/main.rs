@@ -1,20 +1,3536 @@
+use std::collections;
+use std::convert::TryFrom;
 use std::env;
 use std::fs;
 use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::iter;
+use std::path;
+use std::process;
+use std::sync;
+use std::time;
 
 use merge_sorted_files_rs::*;
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let mut heap = Heap::new();
-    for filename in &args[1..] {
-        add_file_to_heap(&mut heap, filename.to_string())?;
+/// The readers merged by the heap, which may be files or standard input.
+type Reader = Box<dyn io::Read + Send>;
+
+/// What the binary exits with when an input is out of order or otherwise invalid, e.g. isn't
+/// UTF-8, and when `--check` finds an input unsorted.
+const EXIT_DATA_ERROR: i32 = 1;
+/// What the binary exits with when it is given invalid arguments.
+const EXIT_USAGE_ERROR: i32 = 2;
+/// What the binary exits with when reading the inputs or writing the output fails.
+const EXIT_IO_ERROR: i32 = 3;
+/// What the binary exits with when interrupted, which is what shells report for SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// What `--help` prints. Options that need a feature the binary wasn't built with are listed all
+/// the same, and say so when given.
+const USAGE: &str = "\
+Usage: merge-sorted-files-rs [COMMAND] [OPTION]... [FILE]...
+Merge sorted FILEs, or standard input as -, into one sorted output.
+
+Commands, given first:
+  union, intersect, diff    the set operation of the inputs, as sets of lines
+  join                      join the lines of the inputs on their keys
+
+Ordering:
+  -r, --reverse             merge inputs sorted in descending order
+  -k, --key=KEYDEF          compare by a key, as sort -k does, e.g. 2,2n
+  -t, --field-separator=C   split fields on C rather than runs of blanks
+  -n, -h, -V, -f, -b, -d    compare numerically, by human sizes, by versions, ignoring case,
+                            ignoring leading blanks, or dictionary order, as sort does
+      --csv=COLUMNS         compare CSV records by the comma-separated COLUMNS
+      --json-key=PATH       compare JSON lines by the value at PATH
+      --time-key=FORMAT     compare by a timestamp, e.g. rfc3339, with --time-capture=REGEX
+      --format=syslog|clf   compare syslog or common log format lines by their timestamps
+      --key-regex=REGEX     compare by the first capture of REGEX, with --unmatched=error|empty|skip
+      --genomic=vcf|bed     compare records by contig and position, with --contigs=FILE
+      --normalize=nfc|nfkc  compare Unicode text normalized
+      --deterministic       break ties by line bytes rather than by input
+
+Reading:
+  -z, --zero-terminated     lines end with NUL rather than newline
+      --record-delimiter=C  lines end with C, or \\0, \\n or \\t
+      --trim-trailing-whitespace, --output-eol=lf|crlf|preserve
+      --invalid-utf8=error|lossy|passthrough, --input-encoding=ENCODING
+      --skip-header=N, --emit-header, --comment-prefix=PREFIX, --blank-lines=merge|skip
+      --out-of-order=error|skip|emit|warn, --on-source-error=fail|drop
+      --reorder-window=N    re-sort lines up to N out of place
+      --read-ahead=N, --flush-every=N, --io-uring=DEPTH, --fadvise
+      --follow, --sleep-interval=SECONDS    keep reading lines appended to the inputs
+      --watermark=SECONDS   with --follow and --time-key, emit lines once they are settled
+      --files-from=FILE, -0, --null, -R, --recursive, --include=GLOB, --exclude=GLOB
+      --cmd=COMMAND         merge the output of a shell command
+      --archive=FILE, --zip=FILE, --members=GLOB
+      --object-store-endpoint=URL   where object://BUCKET/KEY inputs and -o outputs are,
+                            an S3-compatible server that needs no credentials
+      --part-size=SIZE, --upload-concurrency=N
+      --binary, --key-bytes=START-END, --big-endian
+      --auto-sort           sort inputs that turn out not to be sorted, reading each twice
+
+Merging:
+      --mode=sorted|round-robin|concat
+  -u, --unique, --duplicates=keep|first|last, --aggregate=count|first|last|concat|sum:N
+      --join-type=inner|left|outer, --fill=VALUE
+      --head=N, --top=N, --from-key=KEY, --to-key=KEY, --grep=REGEX, --sed=s/REGEX/TEXT/
+      --parallel=THREADS, --strategy=heap|loser-tree, --max-fan-in=N
+      --check               check that each input is sorted rather than merging them
+
+Writing:
+  -o, --output=FILE         write to FILE, replacing it only once the merge succeeds
+  -c, --count               prefix each distinct line with how many times it occurs
+  -H, --tag-source          prefix each line with the input it came from
+      --color[=auto|always|never]
+      --compress-output=none|bgzf|zstd, --bgzf-index=FILE
+      --split-size=SIZE, --split-lines=N, --split-keys=FILE
+      --write-index=FILE, --index-every=N, --index-every-bytes=SIZE, --read-index=FILE=INDEX
+      --checkpoint=FILE, --checkpoint-every=N, --resume=FILE
+      --sstable, --block-size=SIZE, --block-compression=none|deflate|zstd
+      --bloom-filter=FILE, --bloom-bits-per-key=N, --bloom-hashes=N
+      --checksum=crc32c|xxh3|sha256, --checksum-file=FILE, --manifest=FILE
+      --progress, --stats=text|json, --error-format=text|json
+      --help                print this and exit
+
+Exit status is 0 on success, 1 if an input is out of order or invalid, 2 for invalid arguments,
+3 if reading or writing fails, and 130 if interrupted.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = Options::parse(args.iter().cloned()).and_then(|options| {
+        if options.help {
+            return io::stdout().write_all(USAGE.as_bytes());
+        }
+        run(&options)?;
+        write_bloom_filter(&options)?;
+        write_bgzf_index(&options)
+    });
+    let err = match result {
+        Ok(()) => return,
+        Err(err) => err,
+    };
+    // Whatever was reading the output, e.g. `head`, has had all it wanted from it.
+    if err.kind() == io::ErrorKind::BrokenPipe {
+        return;
+    }
+    let (_, code) = classify(&err);
+    ReportFormat::from_args(&args).report_error(&err);
+    process::exit(code);
+}
+
+/// The kind of failure `err` is, as reported by `--error-format json`, with the exit code for it.
+fn classify(err: &io::Error) -> (&'static str, i32) {
+    if err.get_ref().is_some_and(|err| err.is::<Interrupted>()) {
+        return ("interrupted", EXIT_INTERRUPTED);
+    }
+    match MergeError::from_io(err) {
+        Some(MergeError::Cancelled) => ("interrupted", EXIT_INTERRUPTED),
+        Some(MergeError::OutOfOrder { .. }) => ("out_of_order", EXIT_DATA_ERROR),
+        Some(MergeError::InvalidUtf8 { .. }) => ("invalid_utf8", EXIT_DATA_ERROR),
+        _ if err.kind() == io::ErrorKind::InvalidData => ("invalid_data", EXIT_DATA_ERROR),
+        _ if err.kind() == io::ErrorKind::InvalidInput => ("usage", EXIT_USAGE_ERROR),
+        _ => ("io", EXIT_IO_ERROR),
+    }
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    if options.check {
+        if !check_sorted(options)? {
+            process::exit(EXIT_DATA_ERROR);
+        }
+        return Ok(());
+    }
+    if options.binary {
+        return merge_binary(options);
+    }
+    if let Some(format) = options.genomic {
+        let key = genomic_key(options, format)?;
+        return merge(options, GenomicHeaps(sync::Arc::new(key), options.order));
+    }
+    #[cfg(feature = "csv")]
+    {
+        if let Some(columns) = &options.csv_columns {
+            let key = csv_key(options, columns)?;
+            let mut heaps = KeyedHeaps::new(move |line| key.extract(line), options);
+            heaps.headers = true;
+            return merge(options, heaps);
+        }
+    }
+    #[cfg(feature = "json")]
+    {
+        if !options.json_keys.is_empty() {
+            let key = JsonKey::new(options.json_keys.clone())?;
+            return merge(
+                options,
+                KeyedHeaps::new(move |line| key.extract(line), options),
+            );
+        }
+    }
+    #[cfg(feature = "time")]
+    {
+        if let Some(key) = options.time_key() {
+            let key = key?;
+            if let Some(lateness) = options.watermark {
+                return merge_watermarked(options, key, lateness);
+            }
+            return merge(
+                options,
+                KeyedHeaps::new(move |line| key.extract(line), options),
+            );
+        }
+    }
+    #[cfg(feature = "regex")]
+    {
+        if let Some(pattern) = &options.key_regex {
+            let key = RegexKey::new(pattern)?.with_unmatched(options.unmatched);
+            let filter = key.clone();
+            let mut heaps = KeyedHeaps::new(move |line| key.extract(line), options);
+            heaps.filter = Some(sync::Arc::new(move |line| filter.filter(line)));
+            return merge(options, heaps);
+        }
+    }
+    merge(options, LineHeaps(options))
+}
+
+/// Merge the inputs named by `options` with heaps created by `heaps`.
+fn merge<H: NewHeap>(options: &Options, heaps: H) -> io::Result<()> {
+    interrupt::install();
+    if let Some(max_fan_in) = options.max_fan_in {
+        let inputs = options.filenames.iter().map(path::PathBuf::from).collect();
+        let plan = merge::plan(inputs, max_fan_in);
+        return write_output(options, |w| {
+            plan.run(|| configure(heaps.new_heap(), options), w)
+        });
+    }
+    let mut heap = configure(heaps.new_heap::<Reader>(), options);
+    if let Some(depth) = options.read_ahead {
+        heap = heap.with_read_ahead(depth);
+    }
+    if options.follow {
+        heap = heap.with_follow(options.sleep_interval);
+    }
+    if let Some(lines) = options.records_per_flush() {
+        heap = heap.with_flush_every(lines);
+    }
+    #[cfg(feature = "io-uring")]
+    if let Some(depth) = options.io_uring {
+        heap = heap.with_io_uring(depth);
+    }
+    match &options.resume {
+        Some((_, checkpoint)) => heap.resume(checkpoint)?,
+        None => add_inputs(&mut heap, options)?,
+    }
+    if options.progress || options.stats.is_some() {
+        let bar = options
+            .progress
+            .then(|| sync::Mutex::new(ProgressBar::default()));
+        let every = if options.progress {
+            PROGRESS_EVERY
+        } else {
+            u64::MAX
+        };
+        let format = options.stats;
+        heap = heap.on_progress(ProgressInterval::Lines(every), move |stats| {
+            if let Some(bar) = &bar {
+                bar.lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .draw(stats);
+            }
+            if let (true, Some(format)) = (stats.finished, format) {
+                format.report_stats(stats);
+            }
+        });
+    }
+    if options.stats.is_some() {
+        heap = heap.count_comparisons();
+    }
+    match options.command {
+        Some(Command::SetOperation(op)) => {
+            return write_output(options, |w| write_set_operation(heap, op, w, options))
+        }
+        Some(Command::Join) => return write_output(options, |w| write_joined(heap, w, options)),
+        None => {}
+    }
+    if let (Some(split), Some(output)) = (&options.split, &options.output) {
+        return write_shards(&mut heap, split, output, options);
+    }
+    #[cfg(feature = "json")]
+    {
+        if let Some(index_path) = &options.write_index {
+            return write_indexed(&mut heap, path::Path::new(index_path), options);
+        }
+        let state = options.checkpoint.as_ref();
+        if let Some(state) = state.or(options.resume.as_ref().map(|(state, _)| state)) {
+            return write_checkpointed(&mut heap, path::Path::new(state), options);
+        }
+    }
+    if options.sstable {
+        return write_output(options, |w| {
+            let table = SsTableWriter::new(w)
+                .with_block_size(options.block_size)
+                .with_compression(options.block_compression);
+            heap.write_sstable(table)
+        });
+    }
+    if let Some(aggregate) = &options.aggregate {
+        return write_output(options, |w| write_aggregated(heap, aggregate, w, options));
+    }
+    if options.count {
+        return write_output(options, |w| write_counted_lines(heap, w, options));
+    }
+    if options.tag_source || options.color {
+        return write_output(options, |w| write_tagged_lines(&mut heap, w, options));
+    }
+    write_output(options, |w| heap.write_sorted_lines(w))
+}
+
+/// Merge the length-prefixed binary records of the inputs named by `options`, by their bytes or
+/// those of `--key-bytes`.
+fn merge_binary(options: &Options) -> io::Result<()> {
+    interrupt::install();
+    let key = binary_key_range(options.key_bytes.clone().unwrap_or(0..usize::MAX));
+    let mut merge = match options.order {
+        Order::Asc => KWayMerge::with_comparator(key),
+        Order::Desc => KWayMerge::with_comparator(move |a, b| key(b, a)),
+    }
+    .with_out_of_order_policy(options.out_of_order)
+    .with_source_error_policy(options.source_errors)
+    .dedup(options.unique)
+    .with_cancellation(interrupt::flag());
+    if options.deterministic {
+        merge = merge.with_tie_breaker(|a: &Vec<u8>, b: &Vec<u8>| a.cmp(b));
+    }
+    if let Some(head) = options.head {
+        merge = merge.with_limit(head);
+    }
+    for filename in &options.filenames {
+        let reader: io::Result<Reader> = if filename == "-" {
+            Ok(Box::new(io::stdin()))
+        } else {
+            fs::File::open(filename).map(|f| Box::new(f) as Reader)
+        };
+        // `add_source` names the file in the errors it returns, but opening it doesn't.
+        let added = reader
+            .and_then(|reader| BinaryRecordReader::with_byte_order(reader, options.byte_order))
+            .map_err(|err| {
+                io::Error::from(MergeError::Io {
+                    source: err,
+                    file: filename.clone(),
+                })
+            })
+            .and_then(|source| merge.add_source(filename.clone(), source));
+        match added {
+            Err(err) if options.source_errors == SourceErrorPolicy::DropSource => {
+                eprintln!("warning: dropping [{}]: {}", filename, err)
+            }
+            result => result?,
+        }
+    }
+    write_output(options, |w| {
+        write_binary_records(&mut merge, w, options.byte_order)
+    })
+}
+
+/// Merge the inputs named by `options` by the timestamps `key` reads, as far as `--watermark`
+/// allows, rather than waiting on each input in turn.
+#[cfg(feature = "time")]
+fn merge_watermarked(options: &Options, key: TimeKey, lateness: time::Duration) -> io::Result<()> {
+    interrupt::install();
+    let heaps = KeyedHeaps::new(move |line| key.extract(line), options);
+    let mut heap = configure(heaps.new_heap::<Reader>(), options);
+    if options.follow {
+        heap = heap.with_follow(options.sleep_interval);
+    }
+    add_inputs(&mut heap, options)?;
+    let mut merged = heap.into_watermarked(lateness)?;
+    write_output(options, |w| merged.write_lines(w))
+}
+
+/// How many merged lines apart `--progress` is reported, and so considered for redrawing.
+const PROGRESS_EVERY: u64 = 4096;
+
+/// The progress `--progress` draws on stderr: a line for each input and one for the whole merge,
+/// redrawn in place if stderr is a terminal, or otherwise the latter less often.
+#[derive(Default)]
+struct ProgressBar {
+    /// When the progress was last drawn, and how many lines it took.
+    drawn: Option<(time::Instant, usize)>,
+}
+
+impl ProgressBar {
+    fn draw(&mut self, stats: &MergeStats) {
+        let terminal = io::stderr().is_terminal();
+        let every = if terminal {
+            time::Duration::from_millis(200)
+        } else {
+            time::Duration::from_secs(10)
+        };
+        if let Some((at, _)) = self.drawn {
+            if !stats.finished && at.elapsed() < every {
+                return;
+            }
+        }
+        let mut lines = Vec::new();
+        if terminal {
+            for source in &stats.sources {
+                lines.push(format!(
+                    "{}: {}",
+                    source.name,
+                    progress_of(source.bytes, source.size)
+                ));
+            }
+        }
+        let read = progress_of(stats.bytes(), stats.size());
+        let mut total = format!(
+            "{} lines merged, {}, {} elapsed",
+            stats.lines,
+            read,
+            format_duration(stats.elapsed)
+        );
+        if let (false, Some(size)) = (stats.finished, stats.size()) {
+            let bytes = stats.bytes();
+            if bytes > 0 && bytes <= size {
+                let remaining = stats.elapsed.mul_f64((size - bytes) as f64 / bytes as f64);
+                total.push_str(&format!(", ETA {}", format_duration(remaining)));
+            }
+        }
+        lines.push(total);
+        let mut stderr = io::stderr().lock();
+        if terminal {
+            if let Some((_, drawn)) = self.drawn {
+                let _ = write!(stderr, "\x1b[{}A", drawn);
+            }
+            for line in &lines {
+                let _ = writeln!(stderr, "\x1b[2K{}", line);
+            }
+        } else {
+            let _ = writeln!(stderr, "{}", lines.last().expect("There is a total line"));
+        }
+        self.drawn = Some((time::Instant::now(), lines.len()));
+    }
+}
+
+/// How many of `size` bytes have been read, if it is known.
+fn progress_of(bytes: u64, size: Option<u64>) -> String {
+    match size {
+        Some(size) if size > 0 => format!(
+            "{} of {} read ({:.1}%)",
+            format_size(bytes),
+            format_size(size),
+            100.0 * bytes.min(size) as f64 / size as f64
+        ),
+        _ => format!("{} read", format_size(bytes)),
+    }
+}
+
+/// `bytes` with the same suffixes `parse_size` takes.
+fn format_size(bytes: u64) -> String {
+    let units = [
+        (1 << 40, "T"),
+        (1 << 30, "G"),
+        (1 << 20, "M"),
+        (1 << 10, "K"),
+    ];
+    match units.iter().find(|(unit, _)| bytes >= *unit) {
+        Some((unit, suffix)) => format!("{:.1}{}", bytes as f64 / *unit as f64, suffix),
+        None => format!("{}B", bytes),
+    }
+}
+
+fn format_duration(duration: time::Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The path of the object in its bucket that `name` is the URI of, e.g. `bucket/key` for
+/// `object://bucket/key`, if it is one. The object is requested of `--object-store-endpoint`, an
+/// S3-compatible server that needs no credentials, not of any cloud provider's store.
+fn object_path(name: &str) -> Option<&str> {
+    name.strip_prefix("object://")
+}
+
+/// Add the object at the URI `name`, from `--object-store-endpoint`.
+fn add_object<K>(heap: &mut Heap<Reader, K>, name: &str, options: &Options) -> io::Result<()> {
+    #[cfg(feature = "object-store")]
+    {
+        if let (Some(endpoint), Some(path)) = (&options.object_store_endpoint, object_path(name)) {
+            return heap.add_object_store(&UnsignedS3Store::new(endpoint), path);
+        }
+        Err(invalid_input(format!(
+            "Merging [{}] requires --object-store-endpoint",
+            name
+        )))
+    }
+    #[cfg(not(feature = "object-store"))]
+    {
+        let _ = (heap, options);
+        Err(invalid_input(format!(
+            "Merging [{}] requires the object-store feature",
+            name
+        )))
+    }
+}
+
+/// Add the file `filename` for `--auto-sort`, sorting it first if it turns out not to be sorted.
+fn add_sorted<K>(heap: &mut Heap<Reader, K>, filename: &str) -> io::Result<()> {
+    if heap.add_path_sorted(path::Path::new(filename), &ExternalSort::new())? {
+        eprintln!("warning: sorting [{}], which is not sorted", filename);
+    }
+    Ok(())
+}
+
+/// Add the inputs named by `options` to `heap`, with their indexes if they have any.
+fn add_inputs<K>(heap: &mut Heap<Reader, K>, options: &Options) -> io::Result<()> {
+    for filename in &options.filenames {
+        if object_path(filename).is_some() {
+            add_object(heap, filename, options)?;
+            continue;
+        }
+        let index = options
+            .read_index
+            .iter()
+            .find(|(indexed, _)| indexed == filename)
+            .map(|(_, index)| index);
+        let added = match index {
+            Some(index) => heap.add_indexed_file(path::Path::new(filename), index),
+            None if options.auto_sort => add_sorted(heap, filename),
+            None => heap.add_path(filename),
+        };
+        match added {
+            Err(err) if options.source_errors == SourceErrorPolicy::DropSource => {
+                eprintln!("warning: dropping [{}]: {}", filename, err)
+            }
+            result => result?,
+        }
+    }
+    #[cfg(feature = "archive")]
+    for archive in &options.archives {
+        heap.add_archive(path::Path::new(archive), options.members.as_ref())?;
+    }
+    for command in &options.commands {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c").arg(command);
+        heap.add_named_command(command.clone(), shell)?;
+    }
+    Ok(())
+}
+
+/// Creates the empty heaps of a merge, which order lines by keys of type `Key`.
+trait NewHeap {
+    type Key: Send + 'static;
+
+    fn new_heap<T: io::Read>(&self) -> Heap<T, Self::Key>;
+}
+
+/// Heaps that compare whole lines, or the `-k` keys of lines.
+struct LineHeaps<'a>(&'a Options);
+
+impl NewHeap for LineHeaps<'_> {
+    type Key = ();
+
+    fn new_heap<T: io::Read>(&self) -> Heap<T> {
+        match self.0.comparator() {
+            Some(cmp) => Heap::with_comparator(move |a, b| cmp(a, b)),
+            None => Heap::new().with_order(self.0.order),
+        }
+    }
+}
+
+/// Heaps that order lines by a key extracted from each of them, such as `--csv` columns.
+#[cfg(any(feature = "csv", feature = "json", feature = "regex", feature = "time"))]
+struct KeyedHeaps<K> {
+    key: sync::Arc<dyn Fn(&str) -> K + Send + Sync>,
+    order: Order,
+    headers: bool,
+    filter: Option<LineFilter>,
+}
+
+#[cfg(any(feature = "csv", feature = "json", feature = "regex", feature = "time"))]
+impl<K> KeyedHeaps<K> {
+    fn new<F>(key: F, options: &Options) -> KeyedHeaps<K>
+    where
+        F: Fn(&str) -> K + Send + Sync + 'static,
+    {
+        KeyedHeaps {
+            key: sync::Arc::new(key),
+            order: options.order,
+            headers: false,
+            filter: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "csv", feature = "json", feature = "regex", feature = "time"))]
+impl<K: Ord + Send + 'static> NewHeap for KeyedHeaps<K> {
+    type Key = K;
+
+    fn new_heap<T: io::Read>(&self) -> Heap<T, K> {
+        let key = self.key.clone();
+        let heap = Heap::with_key(move |line| key(line))
+            .with_order(self.order)
+            .with_headers(self.headers);
+        match self.filter.clone() {
+            Some(filter) => heap.with_try_filter(move |line| filter(line)),
+            None => heap,
+        }
+    }
+}
+
+/// Heaps that order VCF or BED records by position, merging the headers of the inputs.
+struct GenomicHeaps(sync::Arc<GenomicKey>, Order);
+
+impl NewHeap for GenomicHeaps {
+    type Key = Option<GenomicPosition>;
+
+    fn new_heap<T: io::Read>(&self) -> Heap<T, Self::Key> {
+        let key = self.0.clone();
+        Heap::with_key(move |line| key.extract(line))
+            .with_order(self.1)
+            .with_genomic_header(self.0.format())
+    }
+}
+
+/// Build the key for `--genomic`, ordering contigs as the `--contigs` file lists them or else, for
+/// VCF, as the `##contig` lines in the header of the first input do. Contigs missing from the
+/// order sort after those in it, by name.
+fn genomic_key(options: &Options, format: GenomicFormat) -> io::Result<GenomicKey> {
+    if let Some(contigs) = &options.contigs {
+        let contigs = ContigOrder::read(io::BufReader::new(fs::File::open(contigs)?))?;
+        return Ok(GenomicKey::new(format, contigs));
+    }
+    let first = match options.filenames.first() {
+        Some(first) if format == GenomicFormat::Vcf => first,
+        _ => return Ok(GenomicKey::new(format, ContigOrder::default())),
+    };
+    if first == "-" || object_path(first).is_some() {
+        return Err(invalid_input(
+            "Ordering contigs by the VCF header needs the first input to be a file, or --contigs"
+                .to_string(),
+        ));
+    }
+    let mut heap = configure(Heap::<Reader>::new(), options).with_genomic_header(format);
+    heap.add_path(first)?;
+    let contigs = ContigOrder::from_vcf_header(heap.header_lines());
+    Ok(GenomicKey::new(format, contigs))
+}
+
+/// Build the key for `--csv`, looking up named columns in the header of the first input.
+#[cfg(feature = "csv")]
+fn csv_key(options: &Options, columns: &[CsvColumn]) -> io::Result<CsvKey> {
+    let delimiter = match options.field_separator {
+        Some(separator) if separator.is_ascii() => separator as u8,
+        Some(_) => {
+            return Err(invalid_input(
+                "The CSV delimiter must be an ASCII character".to_string(),
+            ))
+        }
+        None => b',',
+    };
+    if columns
+        .iter()
+        .all(|column| matches!(column, CsvColumn::Index(_)))
+    {
+        let columns = columns
+            .iter()
+            .filter_map(|column| match column {
+                CsvColumn::Index(i) => Some(*i),
+                CsvColumn::Name(_) => None,
+            })
+            .collect();
+        return Ok(CsvKey::new(columns).with_delimiter(delimiter));
+    }
+    let first = match options.filenames.first() {
+        Some(first) if first != "-" => first,
+        _ => {
+            return Err(invalid_input(
+                "Named CSV columns need the first input to be a file".to_string(),
+            ))
+        }
+    };
+    let mut heap = configure(Heap::<Reader>::new(), options).with_headers(true);
+    heap.add_path(first)?;
+    CsvKey::with_header(columns, heap.header().unwrap_or(""), delimiter)
+}
+
+/// Report the first out-of-order line of each input on stderr, like `sort -c`, returning whether
+/// they were all sorted.
+fn check_sorted(options: &Options) -> io::Result<bool> {
+    let cmp = options
+        .comparator()
+        .unwrap_or_else(|| options.order_comparator());
+    let mut sorted = true;
+    for filename in &options.filenames {
+        let unsorted = if filename == "-" {
+            verify_records_sorted_by(io::stdin(), options.format, &*cmp)
+        } else {
+            fs::File::open(filename)
+                .and_then(|file| verify_records_sorted_by(file, options.format, &*cmp))
+        };
+        let unsorted = unsorted.map_err(|err| MergeError::Io {
+            source: err,
+            file: filename.clone(),
+        })?;
+        if let Err(unsorted) = unsorted {
+            options.error_format.report_disorder(filename, &unsorted);
+            sorted = false;
+        }
+    }
+    Ok(sorted)
+}
+
+/// Apply the settings in `options` that don't depend on how lines are compared to `heap`.
+fn configure<T, K>(heap: Heap<T, K>, options: &Options) -> Heap<T, K>
+where
+    T: io::Read + Send + 'static,
+    K: Send + 'static,
+{
+    let heap = heap
+        .with_out_of_order_policy(options.out_of_order)
+        .with_source_error_policy(options.source_errors)
+        .with_delimiter(options.format.delimiter)
+        .with_trim_whitespace(options.format.trim_whitespace)
+        .with_crlf(options.format.strip_cr)
+        .with_line_ending(options.eol)
+        .with_invalid_utf8_policy(options.invalid_utf8)
+        .with_blank_line_policy(options.blank_lines)
+        .with_duplicate_policy(options.duplicates)
+        .dedup(options.unique)
+        .with_cancellation(interrupt::flag());
+    let heap = if options.deterministic {
+        heap.with_deterministic_ties()
+    } else {
+        heap
+    };
+    let heap = match options.strategy {
+        Strategy::Parallel { threads } => heap.with_parallel_strategy(threads),
+        strategy => heap.with_strategy(strategy),
+    };
+    let heap = heap.with_interleave(options.interleave);
+    let heap = match &options.comment_prefix {
+        Some(prefix) => heap.with_comment_prefix(prefix),
+        None => heap,
+    };
+    let heap = match &options.from_key {
+        Some(line) => heap.with_start_line(line),
+        None => heap,
+    };
+    let heap = match &options.to_key {
+        Some(line) => heap.with_end_line(line),
+        None => heap,
+    };
+    let heap = match options.head {
+        Some(lines) => heap.with_limit(lines),
+        None => heap,
+    };
+    #[cfg(feature = "regex")]
+    let heap = options
+        .post_process
+        .iter()
+        .fold(heap, |heap, post_process| post_process.apply(heap));
+    // Only override the header settings when asked to, as `--csv` has a header of its own.
+    let heap = match options.skip_header {
+        0 => heap,
+        lines => heap.with_skip_header(lines),
+    };
+    let heap = if options.emit_header {
+        heap.with_emit_header(true)
+    } else {
+        heap
+    };
+    // The keys are gathered after the output filters and maps, from the lines emitted.
+    let heap = match (&options.bloom_filter, options.keys.first()) {
+        (Some((_, bloom)), Some(key)) => {
+            let (key, separator) = (key.clone(), options.field_separator);
+            heap.with_bloom_filter(bloom.clone(), move |line| key.extract(line, separator))
+        }
+        (Some((_, bloom)), None) => heap.with_bloom_filter(bloom.clone(), |line| line),
+        (None, _) => heap,
+    };
+    // A split output gathers the manifest of each shard as it writes it.
+    let heap = match (&options.manifest, &options.split) {
+        (Some((_, manifest)), None) => heap.with_manifest(manifest.clone()),
+        _ => heap,
+    };
+    #[cfg(feature = "encoding")]
+    let heap = heap.with_input_encoding(options.encoding);
+    #[cfg(feature = "fadvise")]
+    let heap = heap.with_access_hints(options.fadvise);
+    match options.reorder_window {
+        Some(lines) => heap.with_reorder_window(lines),
+        None => heap,
+    }
+}
+
+/// The ANSI colors `--color` gives the inputs, in turn.
+const SOURCE_COLORS: [&str; 12] = [
+    "31", "32", "33", "34", "35", "36", "91", "92", "93", "94", "95", "96",
+];
+
+/// Write each merged line prefixed with the name of the file it came from, like `grep -H`, with
+/// `--tag-source`, and with `--color` in the color of that file: the prefix with `--tag-source`
+/// and otherwise the whole line. The inputs are colored in the order they were named, and then
+/// any other sources, such as archive members, in the order their first lines are written.
+fn write_tagged_lines<K>(
+    heap: &mut Heap<Reader, K>,
+    w: &mut dyn io::Write,
+    options: &Options,
+) -> io::Result<u64> {
+    let mut w = options.output_writer(w);
+    let terminator = options.terminator();
+    let mut colors: collections::HashMap<sync::Arc<str>, usize> = collections::HashMap::new();
+    for filename in &options.filenames {
+        let next = colors.len();
+        colors
+            .entry(sync::Arc::from(filename.as_str()))
+            .or_insert(next);
+    }
+    let mut lines = 0;
+    for line in heap.iter_with_source() {
+        let (filename, line) = line?;
+        let color = options.color.then(|| {
+            let next = colors.len();
+            let color = *colors.entry(filename.clone()).or_insert(next);
+            SOURCE_COLORS[color % SOURCE_COLORS.len()]
+        });
+        match (color, options.tag_source) {
+            (Some(color), true) => write!(w, "\x1b[{}m{}\x1b[0m:{}", color, filename, line)?,
+            (Some(color), false) => write!(w, "\x1b[{}m{}\x1b[0m", color, line)?,
+            (None, _) => write!(w, "{}:{}", filename, line)?,
+        }
+        w.write_all(&terminator)?;
+        lines += 1;
+    }
+    w.flush()?;
+    Ok(lines)
+}
+
+/// Write the lines kept by `op`, after the header if `--emit-header` was given.
+fn write_set_operation<K>(
+    heap: Heap<Reader, K>,
+    op: SetOperation,
+    w: &mut dyn io::Write,
+    options: &Options,
+) -> io::Result<u64> {
+    let mut w = options.output_writer(w);
+    let terminator = options.terminator();
+    if options.emit_header {
+        for header in heap.header_lines() {
+            w.write_all(header.as_bytes())?;
+            w.write_all(&terminator)?;
+        }
+    }
+    let mut lines = 0;
+    for line in heap.set_operation(op) {
+        w.write_all(line?.as_bytes())?;
+        w.write_all(&terminator)?;
+        lines += 1;
+    }
+    w.flush()?;
+    Ok(lines)
+}
+
+/// Write the rows of a join, with the lines in each separated by the field separator
+/// or a tab, and `--fill` standing in for missing ones.
+fn write_joined<K>(
+    heap: Heap<Reader, K>,
+    w: &mut dyn io::Write,
+    options: &Options,
+) -> io::Result<u64> {
+    let mut w = options.output_writer(w);
+    let terminator = options.terminator();
+    let separator = options.field_separator.unwrap_or('\t').to_string();
+    let fill = options.fill.as_deref().unwrap_or("");
+    let mut lines = 0;
+    for row in heap.join(options.join_kind) {
+        let row = row?;
+        let row: Vec<&str> = row
+            .iter()
+            .map(|line| line.as_deref().unwrap_or(fill))
+            .collect();
+        w.write_all(row.join(&separator).as_bytes())?;
+        w.write_all(&terminator)?;
+        lines += 1;
     }
-    heap.print_sorted_lines()?;
+    w.flush()?;
+    Ok(lines)
+}
+
+/// Write one `--aggregate` record for each run of merged lines with the same `-k` keys, or that
+/// are the same line if no keys were given.
+fn write_aggregated<K>(
+    heap: Heap<Reader, K>,
+    aggregate: &Aggregate,
+    w: &mut dyn io::Write,
+    options: &Options,
+) -> io::Result<u64> {
+    let mut w = options.output_writer(w);
+    let terminator = options.terminator();
+    let separator = options.field_separator;
+    let delimiter = separator.unwrap_or('\t').to_string();
+    let key = |line: &str| options.key_text(line);
+    let records = heap.reduce_by_key(key, |key, lines| {
+        aggregate.reduce(key, lines, separator, &delimiter)
+    });
+    let mut lines = 0;
+    for record in records {
+        w.write_all(record?.as_bytes())?;
+        w.write_all(&terminator)?;
+        lines += 1;
+    }
+    w.flush()?;
+    Ok(lines)
+}
+
+/// Write the merged lines to shards named after `output` with their index, `output.00000` and so
+/// on, then list them in `output.manifest`, one per line with their number of lines and their
+/// first and last keys, separated by the field separator or a tab.
+fn write_shards<K: 'static>(
+    heap: &mut Heap<Reader, K>,
+    split: &ShardSplit,
+    output: &str,
+    options: &Options,
+) -> io::Result<()> {
+    let shard_path = |index: usize| format!("{}.{:05}", output, index);
+    let shards = match &options.manifest {
+        Some(_) => {
+            let algorithm = options.checksum.unwrap_or(ChecksumAlgorithm::Crc32c);
+            let mut manifests = Vec::new();
+            let shards = heap.write_shards(split, |index| {
+                let manifest = ManifestBuilder::new(algorithm);
+                manifests.push(manifest.clone());
+                Ok(manifest.writer(fs::File::create(shard_path(index))?))
+            })?;
+            let runs: Vec<RunManifest> = shards
+                .iter()
+                .zip(&manifests)
+                .map(|(shard, manifest)| RunManifest {
+                    path: Some(shard_path(shard.index)),
+                    lines: shard.lines,
+                    min_key: Some(options.key_text(&shard.first)),
+                    max_key: Some(options.key_text(&shard.last)),
+                    ..manifest.build(|line| options.key_text(line))
+                })
+                .collect();
+            write_manifests(&runs, options)?;
+            shards
+        }
+        None => heap.write_shards(split, |index| fs::File::create(shard_path(index)))?,
+    };
+    let separator = options.field_separator.unwrap_or('\t').to_string();
+    let manifest = path::PathBuf::from(format!("{}.manifest", output));
+    write_atomically(&manifest, |f| {
+        let mut w = io::BufWriter::new(f);
+        for shard in &shards {
+            let fields = [
+                shard_path(shard.index),
+                shard.lines.to_string(),
+                options.key_text(&shard.first),
+                options.key_text(&shard.last),
+            ];
+            writeln!(w, "{}", fields.join(&separator))?;
+        }
+        w.flush()
+    })
+}
+
+/// Write the filter of the keys of the merged lines to where `--bloom-filter` says, once the
+/// merge has written them all. The keys are those of the first `--key`, or the whole lines.
+fn write_bloom_filter(options: &Options) -> io::Result<()> {
+    match &options.bloom_filter {
+        Some((path, bloom)) => write_atomically(path::Path::new(path), |f| {
+            bloom.build().write_to(io::BufWriter::new(f))
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Write the index of the blocks of BGZF output to where `--bgzf-index` says, once the output
+/// has been written.
+#[cfg(feature = "gzip")]
+fn write_bgzf_index(options: &Options) -> io::Result<()> {
+    match (&options.bgzf_index, options.compression.bgzf_index()) {
+        (Some(path), Some(index)) => write_atomically(path::Path::new(path), |f| {
+            index.write_gzi(io::BufWriter::new(f))
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "gzip"))]
+fn write_bgzf_index(_: &Options) -> io::Result<()> {
+    Ok(())
+}
+
+/// Write the merged lines along with an index of them to `index_path`. The offsets of lines in BGZF
+/// output are their virtual offsets.
+#[cfg(feature = "json")]
+fn write_indexed<K: 'static>(
+    heap: &mut Heap<Reader, K>,
+    index_path: &path::Path,
+    options: &Options,
+) -> io::Result<()> {
+    let mut index = SparseIndex::default();
+    write_output(options, |w| {
+        let (count, written) = heap.write_indexed_lines(w, options.index_interval)?;
+        index = written;
+        Ok(count)
+    })?;
+    #[cfg(feature = "gzip")]
+    if let Some(blocks) = options.compression.bgzf_index() {
+        for entry in &mut index.entries {
+            entry.offset = blocks.virtual_offset(entry.offset);
+        }
+    }
+    write_atomically(index_path, |f| {
+        let mut w = io::BufWriter::new(f);
+        index.write_json(&mut w)?;
+        w.flush()
+    })
+}
+
+/// Write the merged lines straight to the output, saving a checkpoint to `state` every so often
+/// once they have been synced to disk. A resumed merge truncates the output to the offset of its
+/// checkpoint and appends to it from there.
+#[cfg(feature = "json")]
+fn write_checkpointed<K: 'static>(
+    heap: &mut Heap<Reader, K>,
+    state: &path::Path,
+    options: &Options,
+) -> io::Result<()> {
+    let output = path::Path::new(options.output.as_deref().unwrap_or_default());
+    let f = match &options.resume {
+        Some((_, checkpoint)) => {
+            let mut f = fs::OpenOptions::new().write(true).open(output)?;
+            f.set_len(checkpoint.output_offset)?;
+            io::Seek::seek(&mut f, io::SeekFrom::End(0))?;
+            f
+        }
+        None => fs::File::create(output)?,
+    };
+    heap.write_checkpointed_lines(&f, options.checkpoint_every, |checkpoint| {
+        f.sync_data()?;
+        write_atomically(state, |s| {
+            let mut w = io::BufWriter::new(s);
+            checkpoint.write_json(&mut w)?;
+            w.flush()
+        })
+    })?;
     Ok(())
 }
 
-fn add_file_to_heap(heap: &mut Heap<fs::File>, filename: String) -> io::Result<()> {
-    let f = fs::File::open(&filename)?;
-    heap.add_reader(filename, f)
+/// Write each distinct merged line prefixed with its number of occurrences, like `uniq -c`.
+fn write_counted_lines<K>(
+    heap: Heap<Reader, K>,
+    w: &mut dyn io::Write,
+    options: &Options,
+) -> io::Result<u64> {
+    let mut w = options.output_writer(w);
+    let terminator = options.terminator();
+    let mut lines = 0;
+    for line in heap.counted() {
+        let (count, line) = line?;
+        write!(w, "{:>7} {}", count, line)?;
+        w.write_all(&terminator)?;
+        lines += 1;
+    }
+    w.flush()?;
+    Ok(lines)
+}
+
+/// What to do with the merged inputs, instead of writing them out, chosen by the first argument.
+enum Command {
+    SetOperation(SetOperation),
+    Join,
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::SetOperation(SetOperation::Union) => "union",
+            Command::SetOperation(SetOperation::Intersection) => "intersect",
+            Command::SetOperation(SetOperation::Difference) => "diff",
+            Command::Join => "join",
+        }
+    }
+}
+
+/// Command-line options.
+struct Options {
+    order: Order,
+    keys: Vec<KeySpec>,
+    field_separator: Option<char>,
+    /// How keys without options of their own are compared: `-n`, `-h`, `-V`, `-f`, `-b` and
+    /// `-d`.
+    key_options: KeyOptions,
+    #[cfg(feature = "unicode")]
+    normalization: Option<Normalization>,
+    /// How records are delimited, on input and output, and trimmed.
+    format: RecordFormat,
+    /// The line endings chosen by `--output-eol`, which also strips `\r` from input lines.
+    eol: LineEnding,
+    invalid_utf8: InvalidUtf8Policy,
+    skip_header: usize,
+    emit_header: bool,
+    comment_prefix: Option<String>,
+    blank_lines: BlankLinePolicy,
+    /// Which of the lines that compare equal are kept, by the file they come from.
+    duplicates: DuplicatePolicy,
+    /// How many merged lines `--head`, or `--top`, stops after.
+    head: Option<u64>,
+    #[cfg(feature = "regex")]
+    post_process: Vec<PostProcess>,
+    from_key: Option<String>,
+    to_key: Option<String>,
+    #[cfg(feature = "encoding")]
+    encoding: InputEncoding,
+    #[cfg(feature = "csv")]
+    csv_columns: Option<Vec<CsvColumn>>,
+    #[cfg(feature = "json")]
+    json_keys: Vec<String>,
+    #[cfg(feature = "time")]
+    time_format: Option<TimeFormat>,
+    #[cfg(feature = "time")]
+    time_capture: Option<String>,
+    /// How far out of order `--watermark` lets the timestamps of `--time-key` be.
+    #[cfg(feature = "time")]
+    watermark: Option<time::Duration>,
+    #[cfg(feature = "regex")]
+    key_regex: Option<String>,
+    /// Whether `--genomic` orders the lines as VCF or BED records, with the contigs in the order
+    /// of the `.fai` or `.dict` file given with `--contigs`, if any.
+    genomic: Option<GenomicFormat>,
+    contigs: Option<String>,
+    #[cfg(feature = "regex")]
+    unmatched: UnmatchedPolicy,
+    out_of_order: OutOfOrderPolicy,
+    source_errors: SourceErrorPolicy,
+    reorder_window: Option<usize>,
+    /// How many blocks `--read-ahead` reads of each input ahead of the merge.
+    read_ahead: Option<usize>,
+    /// How many records apart `--flush-every` flushes the output.
+    flush_every: Option<u64>,
+    follow: bool,
+    /// How often `--follow` checks the files for appended lines.
+    sleep_interval: time::Duration,
+    /// How many reads of each file `--io-uring` keeps in flight.
+    #[cfg(feature = "io-uring")]
+    io_uring: Option<u32>,
+    /// Whether `--fadvise` advises the kernel that files are read sequentially.
+    #[cfg(feature = "fadvise")]
+    fadvise: bool,
+    strategy: Strategy,
+    /// Whether `--auto-sort` sorts the inputs that turn out not to be sorted rather than failing.
+    auto_sort: bool,
+    /// Whether `--mode` interleaves or concatenates the inputs instead of merging them.
+    interleave: Interleave,
+    unique: bool,
+    /// Whether `--deterministic` makes the output the same whatever order the inputs are given
+    /// in, breaking ties between lines by their bytes and always ending them with `\n`.
+    deterministic: bool,
+    command: Option<Command>,
+    aggregate: Option<Aggregate>,
+    join_kind: JoinKind,
+    fill: Option<String>,
+    count: bool,
+    tag_source: bool,
+    /// Whether `--color` colors each merged line, or with `--tag-source` its prefix, by its input.
+    color: bool,
+    check: bool,
+    progress: bool,
+    output: Option<String>,
+    compression: Compression,
+    /// Where `--bgzf-index` writes the `.gzi` index of the blocks of BGZF output.
+    #[cfg(feature = "gzip")]
+    bgzf_index: Option<String>,
+    error_format: ReportFormat,
+    /// How `--stats` reports the statistics of the merge once it ends.
+    stats: Option<ReportFormat>,
+    /// How `--split-size`, `--split-lines` or `--split-keys` split the output into shards.
+    split: Option<ShardSplit>,
+    /// Where `--write-index` writes an index of the output, and how often it records lines.
+    write_index: Option<String>,
+    #[cfg(feature = "json")]
+    index_interval: IndexInterval,
+    /// The indexes given with `--read-index` for the inputs they are paired with.
+    read_index: Vec<(String, SparseIndex)>,
+    /// Where `--checkpoint` saves checkpoints, and how many lines apart.
+    checkpoint: Option<String>,
+    #[cfg(feature = "json")]
+    checkpoint_every: u64,
+    /// The checkpoint given with `--resume`, along with where it was read from.
+    resume: Option<(String, Checkpoint)>,
+    max_fan_in: Option<usize>,
+    filenames: Vec<String>,
+    /// The base URL of the S3-compatible server `--object-store-endpoint` serves the buckets of
+    /// `object://` URIs from, without signing requests.
+    #[cfg(feature = "object-store")]
+    object_store_endpoint: Option<String>,
+    /// How `-o` uploads the output to an object URI: in parts of `--part-size` bytes, with
+    /// `--upload-concurrency` of them uploaded at once.
+    #[cfg(feature = "object-store")]
+    part_size: usize,
+    #[cfg(feature = "object-store")]
+    upload_concurrency: usize,
+    /// The archives given with `--archive`, whose members that `--members` matches are merged
+    /// after the files.
+    #[cfg(feature = "archive")]
+    archives: Vec<String>,
+    #[cfg(feature = "archive")]
+    members: Option<glob::Pattern>,
+    /// The shell commands given with `--cmd`, whose outputs are merged after the files.
+    commands: Vec<String>,
+    /// Whether `--binary` merges length-prefixed binary records rather than lines, by the bytes
+    /// of `--key-bytes`, with lengths in the byte order of `--big-endian`.
+    binary: bool,
+    key_bytes: Option<std::ops::Range<usize>>,
+    byte_order: ByteOrder,
+    /// Whether `--sstable` writes the output as an SSTable, in blocks of `--block-size` bytes
+    /// compressed as `--block-compression` says.
+    sstable: bool,
+    block_size: usize,
+    block_compression: BlockCompression,
+    /// Where `--bloom-filter` writes a filter of the keys of the merged lines, of
+    /// `--bloom-bits-per-key` bits per key and `--bloom-hashes` hashes, and what gathers them.
+    bloom_filter: Option<(String, BloomBuilder)>,
+    bloom_bits_per_key: u32,
+    bloom_hashes: Option<u32>,
+    /// The digest `--checksum` computes of the bytes written, and where `--checksum-file` writes
+    /// it rather than to standard error.
+    checksum: Option<ChecksumAlgorithm>,
+    checksum_file: Option<String>,
+    /// Where `--manifest` writes the manifest of the output, or of each shard, and what gathers
+    /// it.
+    manifest: Option<(String, ManifestBuilder)>,
+    /// Whether `--help` was given, in which case nothing else is read or checked.
+    help: bool,
+}
+
+/// How input names are expanded into files: glob patterns always, and directories with
+/// `--recursive`, keeping only the files matched by `--include` and not by `--exclude`.
+struct Expansion {
+    recursive: bool,
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+/// Where `--files-from` reads the list of inputs from, and how the names in it are delimited.
+struct FileList {
+    path: String,
+    delimiter: u8,
+}
+
+impl Options {
+    fn parse<I>(args: I) -> io::Result<Options>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut args = Args::new(args);
+        let mut options = Options {
+            order: Order::Asc,
+            keys: Vec::new(),
+            field_separator: None,
+            key_options: KeyOptions::default(),
+            #[cfg(feature = "unicode")]
+            normalization: None,
+            format: RecordFormat::default(),
+            eol: LineEnding::Preserve,
+            invalid_utf8: InvalidUtf8Policy::Error,
+            skip_header: 0,
+            emit_header: false,
+            comment_prefix: None,
+            blank_lines: BlankLinePolicy::Merge,
+            duplicates: DuplicatePolicy::KeepAll,
+            head: None,
+            #[cfg(feature = "regex")]
+            post_process: Vec::new(),
+            from_key: None,
+            to_key: None,
+            #[cfg(feature = "encoding")]
+            encoding: InputEncoding::Utf8,
+            #[cfg(feature = "csv")]
+            csv_columns: None,
+            #[cfg(feature = "json")]
+            json_keys: Vec::new(),
+            #[cfg(feature = "time")]
+            time_format: None,
+            #[cfg(feature = "time")]
+            time_capture: None,
+            #[cfg(feature = "time")]
+            watermark: None,
+            #[cfg(feature = "regex")]
+            key_regex: None,
+            genomic: None,
+            contigs: None,
+            #[cfg(feature = "regex")]
+            unmatched: UnmatchedPolicy::Error,
+            out_of_order: OutOfOrderPolicy::Error,
+            source_errors: SourceErrorPolicy::Fail,
+            reorder_window: None,
+            read_ahead: None,
+            flush_every: None,
+            follow: false,
+            sleep_interval: time::Duration::from_secs(1),
+            #[cfg(feature = "io-uring")]
+            io_uring: None,
+            #[cfg(feature = "fadvise")]
+            fadvise: false,
+            strategy: Strategy::Heap,
+            auto_sort: false,
+            interleave: Interleave::Sorted,
+            unique: false,
+            deterministic: false,
+            command: None,
+            aggregate: None,
+            join_kind: JoinKind::Inner,
+            fill: None,
+            count: false,
+            tag_source: false,
+            color: false,
+            progress: false,
+            check: false,
+            output: None,
+            compression: Compression::None,
+            #[cfg(feature = "gzip")]
+            bgzf_index: None,
+            error_format: ReportFormat::Text,
+            stats: None,
+            split: None,
+            write_index: None,
+            #[cfg(feature = "json")]
+            index_interval: IndexInterval::Lines(1024),
+            read_index: Vec::new(),
+            checkpoint: None,
+            #[cfg(feature = "json")]
+            checkpoint_every: 1_000_000,
+            resume: None,
+            max_fan_in: None,
+            filenames: Vec::new(),
+            #[cfg(feature = "object-store")]
+            object_store_endpoint: None,
+            #[cfg(feature = "object-store")]
+            part_size: MultipartWriter::DEFAULT_PART_SIZE,
+            #[cfg(feature = "object-store")]
+            upload_concurrency: 4,
+            #[cfg(feature = "archive")]
+            archives: Vec::new(),
+            #[cfg(feature = "archive")]
+            members: None,
+            commands: Vec::new(),
+            binary: false,
+            key_bytes: None,
+            byte_order: ByteOrder::LittleEndian,
+            sstable: false,
+            block_size: SsTableWriter::<io::Sink>::DEFAULT_BLOCK_SIZE,
+            block_compression: BlockCompression::None,
+            bloom_filter: None,
+            bloom_bits_per_key: 10,
+            bloom_hashes: None,
+            checksum: None,
+            checksum_file: None,
+            manifest: None,
+            help: false,
+        };
+        let mut files_from = None;
+        let mut null_delimited = false;
+        let mut color = ColorChoice::Never;
+        // The format `--format` names, which `--time-key` would otherwise silently override.
+        #[cfg(feature = "time")]
+        let mut log_format = None;
+        let mut expansion = Expansion {
+            recursive: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        };
+        options.command = match args.peek().map(String::as_str) {
+            Some("union") => Some(Command::SetOperation(SetOperation::Union)),
+            Some("intersect") => Some(Command::SetOperation(SetOperation::Intersection)),
+            Some("diff") => Some(Command::SetOperation(SetOperation::Difference)),
+            Some("join") => Some(Command::Join),
+            _ => None,
+        };
+        if options.command.is_some() {
+            args.next();
+        }
+        while let Some(arg) = args.next_option()? {
+            match arg.as_str() {
+                "-r" | "--reverse" => options.order = Order::Desc,
+                "-u" | "--unique" => options.unique = true,
+                "--deterministic" => options.deterministic = true,
+                "-n" | "--numeric-sort" => options.key_options.numeric = true,
+                "-h" | "--human-numeric-sort" => options.key_options.human_numeric = true,
+                "-V" | "--version-sort" => options.key_options.version = true,
+                "-f" | "--ignore-case" => options.key_options.fold_case = true,
+                "-b" | "--ignore-leading-blanks" => options.key_options.ignore_blanks = true,
+                "-d" | "--dictionary-order" => options.key_options.dictionary = true,
+                "-k" | "--key" => {
+                    let key = required_value(&arg, args.next())?;
+                    options.keys.push(
+                        key.parse()
+                            .map_err(|err: ParseKeySpecError| invalid_input(err.to_string()))?,
+                    )
+                }
+                #[cfg(feature = "csv")]
+                "--csv" => {
+                    let columns = required_value(&arg, args.next())?;
+                    options.csv_columns = Some(
+                        columns
+                            .split(',')
+                            .map(|column| column.parse().unwrap_or_else(|e| match e {}))
+                            .collect(),
+                    )
+                }
+                #[cfg(not(feature = "csv"))]
+                "--csv" => return Err(invalid_input("--csv requires the csv feature".to_string())),
+                #[cfg(feature = "json")]
+                "--json-key" => options.json_keys.push(required_value(&arg, args.next())?),
+                #[cfg(not(feature = "json"))]
+                "--json-key" => {
+                    return Err(invalid_input(
+                        "--json-key requires the json feature".to_string(),
+                    ))
+                }
+                #[cfg(feature = "time")]
+                "--time-key" => {
+                    options.time_format = Some(
+                        required_value(&arg, args.next())?
+                            .parse()
+                            .unwrap_or_else(|e| match e {}),
+                    )
+                }
+                #[cfg(feature = "time")]
+                "--format" => {
+                    let value = required_value(&arg, args.next())?;
+                    log_format = Some(match value.as_str() {
+                        "syslog" => TimeFormat::Syslog,
+                        "clf" => TimeFormat::CommonLog,
+                        _ => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}; expected syslog or clf",
+                                value, arg
+                            )))
+                        }
+                    })
+                }
+                #[cfg(feature = "time")]
+                "--time-capture" => options.time_capture = Some(required_value(&arg, args.next())?),
+                #[cfg(feature = "time")]
+                "--watermark" => options.watermark = Some(parse_seconds(&arg, args.next())?),
+                #[cfg(not(feature = "time"))]
+                "--time-key" | "--format" | "--time-capture" | "--watermark" => {
+                    return Err(invalid_input(format!("{} requires the time feature", arg)))
+                }
+                #[cfg(feature = "unicode")]
+                "--normalize" => options.normalization = Some(parse_value(&arg, args.next())?),
+                #[cfg(not(feature = "unicode"))]
+                "--normalize" => {
+                    return Err(invalid_input(
+                        "--normalize requires the unicode feature".to_string(),
+                    ))
+                }
+                #[cfg(feature = "regex")]
+                "--key-regex" => options.key_regex = Some(required_value(&arg, args.next())?),
+                #[cfg(feature = "regex")]
+                "--unmatched" => {
+                    options.unmatched = match required_value(&arg, args.next())?.as_str() {
+                        "error" => UnmatchedPolicy::Error,
+                        "empty" => UnmatchedPolicy::Empty,
+                        "skip" => UnmatchedPolicy::Skip,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                #[cfg(not(feature = "regex"))]
+                "--key-regex" | "--unmatched" => {
+                    return Err(invalid_input(format!("{} requires the regex feature", arg)))
+                }
+                "-z" | "--zero-terminated" => options.format.delimiter = b'\0',
+                "--output-eol" => {
+                    options.eol = match required_value(&arg, args.next())?.as_str() {
+                        "lf" => LineEnding::Lf,
+                        "crlf" => LineEnding::Crlf,
+                        "preserve" => LineEnding::Preserve,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    };
+                    options.format.strip_cr = true;
+                }
+                "--invalid-utf8" => {
+                    options.invalid_utf8 = match required_value(&arg, args.next())?.as_str() {
+                        "error" => InvalidUtf8Policy::Error,
+                        "lossy" => InvalidUtf8Policy::Lossy,
+                        "passthrough" => InvalidUtf8Policy::Passthrough,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                #[cfg(feature = "encoding")]
+                "--input-encoding" => options.encoding = parse_value(&arg, args.next())?,
+                #[cfg(not(feature = "encoding"))]
+                "--input-encoding" => {
+                    return Err(invalid_input(
+                        "--input-encoding requires the encoding feature".to_string(),
+                    ))
+                }
+                "--skip-header" => options.skip_header = parse_value(&arg, args.next())?,
+                "--genomic" => options.genomic = Some(parse_value(&arg, args.next())?),
+                "--contigs" => options.contigs = Some(required_value(&arg, args.next())?),
+                "--emit-header" => options.emit_header = true,
+                // The first K merged lines are the K smallest, or with `-r` the K largest.
+                "--head" | "--top" => options.head = Some(parse_value(&arg, args.next())?),
+                "--from-key" => options.from_key = Some(required_value(&arg, args.next())?),
+                "--to-key" => options.to_key = Some(required_value(&arg, args.next())?),
+                #[cfg(feature = "regex")]
+                "--grep" => {
+                    let pattern = required_value(&arg, args.next())?;
+                    options
+                        .post_process
+                        .push(PostProcess::Grep(parse_regex(&arg, &pattern)?))
+                }
+                #[cfg(feature = "regex")]
+                "--sed" => options.post_process.push(PostProcess::parse_sed(
+                    &arg,
+                    &required_value(&arg, args.next())?,
+                )?),
+                #[cfg(not(feature = "regex"))]
+                "--grep" | "--sed" => {
+                    return Err(invalid_input(format!("{} requires the regex feature", arg)))
+                }
+                "--comment-prefix" => {
+                    options.comment_prefix = Some(required_value(&arg, args.next())?)
+                }
+                "--blank-lines" => {
+                    options.blank_lines = match required_value(&arg, args.next())?.as_str() {
+                        "merge" => BlankLinePolicy::Merge,
+                        "skip" => BlankLinePolicy::Skip,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--duplicates" => {
+                    options.duplicates = match required_value(&arg, args.next())?.as_str() {
+                        "keep" => DuplicatePolicy::KeepAll,
+                        "first" => DuplicatePolicy::FirstSource,
+                        "last" => DuplicatePolicy::LastSource,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--trim-trailing-whitespace" => options.format.trim_whitespace = true,
+                "--record-delimiter" => {
+                    let delimiter = required_value(&arg, args.next())?;
+                    options.format.delimiter = match delimiter.as_str() {
+                        "\\0" => b'\0',
+                        "\\n" => b'\n',
+                        "\\t" => b'\t',
+                        _ if delimiter.len() == 1 && delimiter.is_ascii() => {
+                            delimiter.as_bytes()[0]
+                        }
+                        _ => {
+                            return Err(invalid_input(format!(
+                                "{} must be a single ASCII character, \\0, \\n or \\t",
+                                arg
+                            )))
+                        }
+                    }
+                }
+                "-t" | "--field-separator" => {
+                    let separator = required_value(&arg, args.next())?;
+                    let mut chars = separator.chars();
+                    options.field_separator = match (chars.next(), chars.next()) {
+                        (Some(separator), None) => Some(separator),
+                        _ => {
+                            return Err(invalid_input(format!(
+                                "{} requires a single character",
+                                arg
+                            )))
+                        }
+                    }
+                }
+                "--join-type" => {
+                    options.join_kind = match required_value(&arg, args.next())?.as_str() {
+                        "inner" => JoinKind::Inner,
+                        "left" => JoinKind::Left,
+                        "outer" => JoinKind::Outer,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--aggregate" => options.aggregate = Some(parse_value(&arg, args.next())?),
+                "--fill" => options.fill = Some(required_value(&arg, args.next())?),
+                "-c" | "--count" => options.count = true,
+                "-H" | "--tag-source" => options.tag_source = true,
+                "--color" => {
+                    color = match args.peek().map(String::as_str) {
+                        Some("auto") => ColorChoice::Auto,
+                        Some("always") => ColorChoice::Always,
+                        Some("never") => ColorChoice::Never,
+                        _ => {
+                            color = ColorChoice::Auto;
+                            continue;
+                        }
+                    };
+                    args.next();
+                }
+                "--progress" => options.progress = true,
+                "--check" => options.check = true,
+                "--help" => {
+                    options.help = true;
+                    return Ok(options);
+                }
+                "--out-of-order" => {
+                    options.out_of_order = match required_value(&arg, args.next())?.as_str() {
+                        "error" => OutOfOrderPolicy::Error,
+                        "skip" => OutOfOrderPolicy::Skip,
+                        "emit" => OutOfOrderPolicy::EmitAnyway,
+                        "warn" => OutOfOrderPolicy::WarnAndContinue,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--on-source-error" => {
+                    options.source_errors = match required_value(&arg, args.next())?.as_str() {
+                        "fail" => SourceErrorPolicy::Fail,
+                        "drop" => SourceErrorPolicy::DropSource,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--reorder-window" => {
+                    options.reorder_window = Some(parse_value(&arg, args.next())?)
+                }
+                "--read-ahead" => options.read_ahead = Some(parse_value(&arg, args.next())?),
+                "--flush-every" => options.flush_every = Some(parse_value(&arg, args.next())?),
+                "--follow" => options.follow = true,
+                "--sleep-interval" => options.sleep_interval = parse_seconds(&arg, args.next())?,
+                #[cfg(feature = "io-uring")]
+                "--io-uring" => options.io_uring = Some(parse_value(&arg, args.next())?),
+                #[cfg(not(feature = "io-uring"))]
+                "--io-uring" => {
+                    return Err(invalid_input(
+                        "--io-uring requires the io-uring feature".to_string(),
+                    ))
+                }
+                #[cfg(feature = "fadvise")]
+                "--fadvise" => options.fadvise = true,
+                #[cfg(not(feature = "fadvise"))]
+                "--fadvise" => {
+                    return Err(invalid_input(
+                        "--fadvise requires the fadvise feature".to_string(),
+                    ))
+                }
+                "--parallel" => {
+                    let threads = parse_value(&arg, args.next())?;
+                    options.strategy = Strategy::Parallel { threads };
+                }
+                "--strategy" => {
+                    options.strategy = match required_value(&arg, args.next())?.as_str() {
+                        "heap" => Strategy::Heap,
+                        "loser-tree" => Strategy::LoserTree,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "--auto-sort" => options.auto_sort = true,
+                "--mode" => {
+                    options.interleave = match required_value(&arg, args.next())?.as_str() {
+                        "sorted" => Interleave::Sorted,
+                        "round-robin" => Interleave::RoundRobin,
+                        "concat" => Interleave::Concat,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "Invalid value [{}] for {}",
+                                value, arg
+                            )))
+                        }
+                    }
+                }
+                "-o" | "--output" => options.output = Some(required_value(&arg, args.next())?),
+                "--max-fan-in" => {
+                    let max_fan_in = parse_value(&arg, args.next())?;
+                    if max_fan_in < 2 {
+                        return Err(invalid_input(format!("{} must be at least 2", arg)));
+                    }
+                    options.max_fan_in = Some(max_fan_in);
+                }
+                "--compress-output" => {
+                    options.compression = Compression::parse(&required_value(&arg, args.next())?)?
+                }
+                #[cfg(feature = "gzip")]
+                "--bgzf-index" => options.bgzf_index = Some(required_value(&arg, args.next())?),
+                #[cfg(not(feature = "gzip"))]
+                "--bgzf-index" => {
+                    return Err(invalid_input(
+                        "--bgzf-index requires the gzip feature".to_string(),
+                    ))
+                }
+                "--stats" => {
+                    options.stats = Some(ReportFormat::parse(
+                        &arg,
+                        &required_value(&arg, args.next())?,
+                    )?)
+                }
+                "--error-format" => {
+                    options.error_format =
+                        ReportFormat::parse(&arg, &required_value(&arg, args.next())?)?
+                }
+                "--split-size" => {
+                    let size = required_value(&arg, args.next())?;
+                    options.split = Some(ShardSplit::Bytes(parse_size(&arg, &size)?));
+                }
+                "--split-lines" => match parse_value(&arg, args.next())? {
+                    0 => return Err(invalid_input(format!("{} must be at least 1", arg))),
+                    lines => options.split = Some(ShardSplit::Lines(lines)),
+                },
+                "--split-keys" => {
+                    let path = required_value(&arg, args.next())?;
+                    let keys = fs::read_to_string(&path)?;
+                    let keys = keys.lines().map(str::to_string).collect();
+                    options.split = Some(ShardSplit::Boundaries(keys));
+                }
+                #[cfg(feature = "json")]
+                "--write-index" => options.write_index = Some(required_value(&arg, args.next())?),
+                #[cfg(feature = "json")]
+                "--index-every" => match parse_value(&arg, args.next())? {
+                    0 => return Err(invalid_input(format!("{} must be at least 1", arg))),
+                    lines => options.index_interval = IndexInterval::Lines(lines),
+                },
+                #[cfg(feature = "json")]
+                "--index-every-bytes" => {
+                    let size = required_value(&arg, args.next())?;
+                    options.index_interval = IndexInterval::Bytes(parse_size(&arg, &size)?);
+                }
+                #[cfg(feature = "json")]
+                "--read-index" => {
+                    let value = required_value(&arg, args.next())?;
+                    let (filename, index) = value.split_once('=').ok_or_else(|| {
+                        invalid_input(format!("{} takes FILE=INDEX, not [{}]", arg, value))
+                    })?;
+                    let index = SparseIndex::read_json(io::BufReader::new(fs::File::open(index)?))?;
+                    options.read_index.push((filename.to_string(), index));
+                }
+                #[cfg(feature = "json")]
+                "--checkpoint" => options.checkpoint = Some(required_value(&arg, args.next())?),
+                #[cfg(feature = "json")]
+                "--checkpoint-every" => match parse_value(&arg, args.next())? {
+                    0 => return Err(invalid_input(format!("{} must be at least 1", arg))),
+                    lines => options.checkpoint_every = lines,
+                },
+                #[cfg(feature = "json")]
+                "--resume" => {
+                    let state = required_value(&arg, args.next())?;
+                    let checkpoint =
+                        Checkpoint::read_json(io::BufReader::new(fs::File::open(&state)?))?;
+                    options.resume = Some((state, checkpoint));
+                }
+                #[cfg(feature = "json")]
+                "--manifest" => {
+                    let path = required_value(&arg, args.next())?;
+                    // The checksum it takes is settled once all the arguments are parsed.
+                    let manifest = ManifestBuilder::new(ChecksumAlgorithm::Crc32c);
+                    options.manifest = Some((path, manifest));
+                }
+                #[cfg(not(feature = "json"))]
+                "--write-index"
+                | "--index-every"
+                | "--index-every-bytes"
+                | "--read-index"
+                | "--checkpoint"
+                | "--checkpoint-every"
+                | "--resume"
+                | "--manifest" => {
+                    return Err(invalid_input(format!("{} requires the json feature", arg)))
+                }
+                #[cfg(feature = "object-store")]
+                "--object-store-endpoint" => {
+                    options.object_store_endpoint = Some(required_value(&arg, args.next())?)
+                }
+                #[cfg(feature = "object-store")]
+                "--part-size" => {
+                    let size = required_value(&arg, args.next())?;
+                    options.part_size =
+                        usize::try_from(parse_size(&arg, &size)?).map_err(|_| {
+                            invalid_input(format!("Invalid value [{}] for {}", size, arg))
+                        })?;
+                }
+                #[cfg(feature = "object-store")]
+                "--upload-concurrency" => {
+                    options.upload_concurrency = parse_value(&arg, args.next())?
+                }
+                #[cfg(not(feature = "object-store"))]
+                "--object-store-endpoint" | "--part-size" | "--upload-concurrency" => {
+                    return Err(invalid_input(format!(
+                        "{} requires the object-store feature",
+                        arg
+                    )))
+                }
+                #[cfg(feature = "archive")]
+                "--archive" | "--zip" => options.archives.push(required_value(&arg, args.next())?),
+                #[cfg(feature = "archive")]
+                "--members" => options.members = Some(parse_pattern(&arg, args.next())?),
+                #[cfg(not(feature = "archive"))]
+                "--archive" | "--zip" | "--members" => {
+                    return Err(invalid_input(format!(
+                        "{} requires the archive feature",
+                        arg
+                    )))
+                }
+                "--cmd" => options.commands.push(required_value(&arg, args.next())?),
+                "--binary" => options.binary = true,
+                "--key-bytes" => options.key_bytes = Some(parse_range(&arg, args.next())?),
+                "--big-endian" => options.byte_order = ByteOrder::BigEndian,
+                "--sstable" => options.sstable = true,
+                "--block-size" => {
+                    let size = required_value(&arg, args.next())?;
+                    options.block_size =
+                        usize::try_from(parse_size(&arg, &size)?).map_err(|_| {
+                            invalid_input(format!("Invalid value [{}] for {}", size, arg))
+                        })?;
+                }
+                "--bloom-filter" => {
+                    let path = required_value(&arg, args.next())?;
+                    // The builder is set up once all the flags are in.
+                    options.bloom_filter = Some((path, BloomBuilder::new(0, 0)));
+                }
+                "--bloom-bits-per-key" => match parse_value(&arg, args.next())? {
+                    0 => return Err(invalid_input(format!("{} must be at least 1", arg))),
+                    bits => options.bloom_bits_per_key = bits,
+                },
+                "--bloom-hashes" => match parse_value(&arg, args.next())? {
+                    0 => return Err(invalid_input(format!("{} must be at least 1", arg))),
+                    hashes => options.bloom_hashes = Some(hashes),
+                },
+                "--checksum" => options.checksum = Some(parse_value(&arg, args.next())?),
+                "--checksum-file" => {
+                    options.checksum_file = Some(required_value(&arg, args.next())?)
+                }
+                "--block-compression" => {
+                    options.block_compression =
+                        parse_block_compression(&required_value(&arg, args.next())?)?
+                }
+                "--files-from" => files_from = Some(required_value(&arg, args.next())?),
+                "-0" | "--null" => null_delimited = true,
+                "-R" | "--recursive" => expansion.recursive = true,
+                "--include" => expansion.include.push(parse_pattern(&arg, args.next())?),
+                "--exclude" => expansion.exclude.push(parse_pattern(&arg, args.next())?),
+                // Everything after `--` is an input, even if it looks like an option.
+                "--" => options.filenames.extend(args.by_ref()),
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(invalid_input(format!("Unknown option [{}]", arg)))
+                }
+                _ => options.filenames.push(arg),
+            }
+        }
+        #[cfg(feature = "time")]
+        if let Some(format) = log_format {
+            if options.time_format.is_some() {
+                return Err(invalid_input(
+                    "--format cannot be combined with --time-key".to_string(),
+                ));
+            }
+            options.time_format = Some(format);
+        }
+        if let Some(path) = files_from {
+            let list = FileList {
+                path,
+                delimiter: if null_delimited { b'\0' } else { b'\n' },
+            };
+            if list.path == "-" && options.filenames.iter().any(|f| f == "-") {
+                return Err(invalid_input(
+                    "Standard input cannot be both an input and --files-from".to_string(),
+                ));
+            }
+            options.filenames.extend(list.read()?);
+        }
+        options.filenames = expansion.expand(options.filenames)?;
+        options.validate(color)?;
+        Ok(options)
+    }
+}
+
+impl Expansion {
+    /// Replace each glob pattern in `names` with the paths it matches, and each directory with
+    /// the files beneath it, both in sorted order. Names that exist, `-`, URLs and names that are
+    /// not patterns are kept as they are.
+    fn expand(&self, names: Vec<String>) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        for name in names {
+            if object_path(&name).is_some() {
+                files.push(name);
+                continue;
+            }
+            if name.starts_with("http://") || name.starts_with("https://") {
+                if cfg!(not(feature = "http")) {
+                    return Err(invalid_input(format!(
+                        "Merging [{}] requires the http feature",
+                        name
+                    )));
+                }
+                files.push(name);
+                continue;
+            }
+            let path = path::Path::new(&name);
+            if name == "-" || path.exists() || !is_glob(&name) {
+                self.add(path::PathBuf::from(&name), &mut files)?;
+                continue;
+            }
+            let paths = glob::glob(&name)
+                .map_err(|err| invalid_input(format!("Invalid pattern [{}]: {}", name, err)))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::from)?;
+            if paths.is_empty() {
+                return Err(invalid_input(format!("No files match [{}]", name)));
+            }
+            for path in paths {
+                self.add(path, &mut files)?;
+            }
+        }
+        Ok(files)
+    }
+
+    fn add(&self, path: path::PathBuf, files: &mut Vec<String>) -> io::Result<()> {
+        if !path.is_dir() {
+            files.push(path.display().to_string());
+            return Ok(());
+        }
+        if !self.recursive {
+            return Err(invalid_input(format!(
+                "[{}] is a directory; use --recursive to merge the files in it",
+                path.display()
+            )));
+        }
+        let mut entries = fs::read_dir(&path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() {
+                self.add(entry, files)?;
+            } else if self.keeps(&entry) {
+                files.push(entry.display().to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a file found in a directory passes the `--include` and `--exclude` filters, which
+    /// match against its name.
+    fn keeps(&self, path: &path::Path) -> bool {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['].as_ref())
+}
+
+fn parse_pattern(flag: &str, value: Option<String>) -> io::Result<glob::Pattern> {
+    let value = required_value(flag, value)?;
+    glob::Pattern::new(&value)
+        .map_err(|err| invalid_input(format!("Invalid pattern [{}] for {}: {}", value, flag, err)))
+}
+
+impl FileList {
+    /// Read the names in the list, skipping empty ones. `-` reads the list from standard input.
+    fn read(&self) -> io::Result<Vec<String>> {
+        let contents = if self.path == "-" {
+            let mut contents = Vec::new();
+            io::Read::read_to_end(&mut io::stdin(), &mut contents)?;
+            contents
+        } else {
+            fs::read(&self.path)?
+        };
+        contents
+            .split(|b| *b == self.delimiter)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                String::from_utf8(name.to_vec())
+                    .map_err(|_| invalid_input(format!("Invalid file name in [{}]", self.path)))
+            })
+            .collect()
+    }
+}
+
+impl Options {
+    /// Check the options given together can be, and settle what follows from them.
+    fn validate(&mut self, color: ColorChoice) -> io::Result<()> {
+        self.check_key_modes()?;
+        self.check_merging()?;
+        #[cfg(feature = "time")]
+        self.check_watermark()?;
+        self.check_interleave()?;
+        self.check_inputs()?;
+        self.check_command()?;
+        self.check_outputs()?;
+        self.check_deterministic()?;
+        self.check_records()?;
+        self.check_fan_in()?;
+        self.color = self.use_color(color)?;
+        Ok(())
+    }
+
+    /// That at most one way of comparing lines was chosen.
+    fn check_key_modes(&self) -> io::Result<()> {
+        let mut modes = self.key_modes();
+        let key_flag = self.key_flag();
+        if self.check && modes.iter().any(|mode| Some(*mode) != key_flag) {
+            modes.insert(0, "--check");
+        }
+        if modes.len() > 1 {
+            return Err(invalid_input(format!(
+                "{} cannot be combined",
+                modes.join(", ")
+            )));
+        }
+        #[cfg(feature = "unicode")]
+        {
+            if self.normalization.is_some() {
+                if let Some(mode) = modes.iter().find(|mode| Some(**mode) != key_flag) {
+                    return Err(invalid_input(format!(
+                        "--normalize cannot be combined with {}",
+                        mode
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// That the options that shape a merge aren't given with `--check`, or with each other where
+    /// they conflict.
+    fn check_merging(&self) -> io::Result<()> {
+        if self.count && self.tag_source {
+            return Err(invalid_input(
+                "--count cannot be combined with --tag-source".to_string(),
+            ));
+        }
+        if self.count && self.duplicates != DuplicatePolicy::KeepAll {
+            return Err(invalid_input(
+                "--count cannot be combined with --duplicates".to_string(),
+            ));
+        }
+        if self.genomic.is_some() {
+            exclusive(
+                "--genomic",
+                &[
+                    (self.skip_header > 0, "--skip-header"),
+                    (self.emit_header, "--emit-header"),
+                    (self.format.delimiter != b'\n', "-z"),
+                ],
+            )?;
+        } else if self.contigs.is_some() {
+            return Err(invalid_input("--contigs requires --genomic".to_string()));
+        }
+        let merging_only = [
+            (self.head.is_some(), "--head"),
+            (self.from_key.is_some(), "--from-key"),
+            (self.to_key.is_some(), "--to-key"),
+            (self.skip_header > 0, "--skip-header"),
+            (self.comment_prefix.is_some(), "--comment-prefix"),
+            (self.blank_lines != BlankLinePolicy::Merge, "--blank-lines"),
+            (self.duplicates != DuplicatePolicy::KeepAll, "--duplicates"),
+            (self.progress, "--progress"),
+            (self.stats.is_some(), "--stats"),
+            (self.read_ahead.is_some(), "--read-ahead"),
+            (self.flush_every.is_some(), "--flush-every"),
+            #[cfg(feature = "io-uring")]
+            (self.io_uring.is_some(), "--io-uring"),
+        ];
+        if let Some((_, arg)) = merging_only.iter().find(|(set, _)| self.check && *set) {
+            return Err(invalid_input(format!(
+                "{} cannot be combined with --check",
+                arg
+            )));
+        }
+        if self.emit_header && (self.count || self.tag_source) {
+            return Err(invalid_input(
+                "--emit-header cannot be combined with --count or --tag-source".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// That `--watermark` has a time key and nothing that would hold lines back past it.
+    #[cfg(feature = "time")]
+    fn check_watermark(&self) -> io::Result<()> {
+        if self.watermark.is_some() {
+            if self.time_format.is_none() {
+                return Err(invalid_input("--watermark requires --time-key".to_string()));
+            }
+            exclusive(
+                "--watermark",
+                &[
+                    (self.order == Order::Desc, "--reverse"),
+                    (self.check, "--check"),
+                    (self.unique, "--unique"),
+                    (self.duplicates != DuplicatePolicy::KeepAll, "--duplicates"),
+                    (self.head.is_some(), "--head"),
+                    (self.from_key.is_some(), "--from-key"),
+                    (self.to_key.is_some(), "--to-key"),
+                    (self.reorder_window.is_some(), "--reorder-window"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.split.is_some(), "splitting the output"),
+                    (self.write_index.is_some(), "--write-index"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                    (self.progress, "--progress"),
+                    (self.stats.is_some(), "--stats"),
+                    (self.bloom_filter.is_some(), "--bloom-filter"),
+                    (self.deterministic, "--deterministic"),
+                    (self.manifest.is_some(), "--manifest"),
+                    (self.interleave != Interleave::Sorted, "--mode"),
+                    (self.auto_sort, "--auto-sort"),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// That `--mode round-robin` and `--mode concat` come without the options of a sorted merge.
+    fn check_interleave(&self) -> io::Result<()> {
+        // Lines that aren't merged have no order to check, deduplicate or cut at, and a key would
+        // go unused.
+        if self.interleave != Interleave::Sorted {
+            let modes = self.key_modes();
+            let mode = match self.interleave {
+                Interleave::RoundRobin => "round-robin",
+                _ => "concat",
+            };
+            let flags = [
+                (
+                    !modes.is_empty(),
+                    modes.first().copied().unwrap_or_default(),
+                ),
+                (self.order == Order::Desc, "--reverse"),
+                (self.check, "--check"),
+                (self.unique, "--unique"),
+                (self.duplicates != DuplicatePolicy::KeepAll, "--duplicates"),
+                (self.deterministic, "--deterministic"),
+                (self.from_key.is_some(), "--from-key"),
+                (self.to_key.is_some(), "--to-key"),
+                (self.reorder_window.is_some(), "--reorder-window"),
+                (self.count, "--count"),
+                (self.command.is_some(), "a command"),
+                (self.aggregate.is_some(), "--aggregate"),
+                (self.max_fan_in.is_some(), "--max-fan-in"),
+                (
+                    matches!(self.split, Some(ShardSplit::Boundaries(_))),
+                    "--split-keys",
+                ),
+                (self.checkpoint.is_some(), "--checkpoint"),
+                (self.resume.is_some(), "--resume"),
+                (self.manifest.is_some(), "--manifest"),
+                (self.follow, "--follow"),
+                (self.binary, "--binary"),
+                (self.sstable, "--sstable"),
+                (self.auto_sort, "--auto-sort"),
+            ];
+            if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+                return Err(invalid_input(format!(
+                    "--mode {} cannot be combined with {}",
+                    mode, flag
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// That the inputs `--auto-sort`, `--follow`, `--archive`, `--binary` and `--cmd` read can be
+    /// read that way.
+    fn check_inputs(&self) -> io::Result<()> {
+        // An input is read through once to check it and again to merge or sort it, so it has to
+        // be a file.
+        if self.auto_sort {
+            exclusive(
+                "--auto-sort",
+                &[
+                    (self.check, "--check"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.follow, "--follow"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                    (self.binary, "--binary"),
+                    (self.sstable, "--sstable"),
+                ],
+            )?;
+            if let Some(filename) = self
+                .filenames
+                .iter()
+                .find(|f| *f == "-" || f.contains("://"))
+            {
+                return Err(invalid_input(format!(
+                    "--auto-sort requires files, not [{}]",
+                    filename
+                )));
+            }
+        }
+        if self.follow {
+            exclusive(
+                "--follow",
+                &[
+                    (self.check, "--check"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+        }
+        #[cfg(feature = "archive")]
+        if !self.archives.is_empty() {
+            exclusive(
+                "--archive",
+                &[
+                    (self.check, "--check"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+        } else if self.members.is_some() {
+            return Err(invalid_input("--members requires --archive".to_string()));
+        }
+        if self.binary {
+            exclusive(
+                "--binary",
+                &[
+                    (self.check, "--check"),
+                    (!self.keys.is_empty(), "--key"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.skip_header > 0, "--skip-header"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.split.is_some(), "splitting the output"),
+                    (self.write_index.is_some(), "--write-index"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                    (self.follow, "--follow"),
+                    (!self.commands.is_empty(), "--cmd"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.command.is_some(), "a command"),
+                    (self.sstable, "--sstable"),
+                ],
+            )?;
+        } else if self.key_bytes.is_some() || self.byte_order != ByteOrder::LittleEndian {
+            return Err(invalid_input(
+                "--key-bytes and --big-endian require --binary".to_string(),
+            ));
+        }
+        if !self.commands.is_empty() {
+            exclusive(
+                "--cmd",
+                &[
+                    (self.check, "--check"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// That a command or `--aggregate`, and the options only they take, come with what they allow.
+    fn check_command(&self) -> io::Result<()> {
+        if let Some(command) = &self.command {
+            let flags = [
+                (self.count, "--count"),
+                (self.tag_source, "--tag-source"),
+                (self.unique, "--unique"),
+                (self.max_fan_in.is_some(), "--max-fan-in"),
+                (self.aggregate.is_some(), "--aggregate"),
+                (self.duplicates != DuplicatePolicy::KeepAll, "--duplicates"),
+            ];
+            if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+                return Err(invalid_input(format!(
+                    "{} cannot be combined with {}",
+                    command.name(),
+                    flag
+                )));
+            }
+            if matches!(command, Command::Join) && self.emit_header {
+                return Err(invalid_input(
+                    "join cannot be combined with --emit-header".to_string(),
+                ));
+            }
+        } else if self.join_kind != JoinKind::Inner || self.fill.is_some() {
+            return Err(invalid_input(
+                "--join-type and --fill require the join command".to_string(),
+            ));
+        }
+        if self.aggregate.is_some() {
+            exclusive(
+                "--aggregate",
+                &[
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.emit_header, "--emit-header"),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// That the output can be written as asked, and set up the Bloom filter written beside it.
+    fn check_outputs(&mut self) -> io::Result<()> {
+        let object_output = self.output.as_deref().and_then(object_path).is_some();
+        if object_output {
+            #[cfg(feature = "object-store")]
+            if self.object_store_endpoint.is_none() {
+                return Err(invalid_input(
+                    "Writing to an object URI requires --object-store-endpoint".to_string(),
+                ));
+            }
+            exclusive(
+                "Writing to an object URI",
+                &[
+                    (self.split.is_some(), "splitting the output"),
+                    (self.write_index.is_some(), "--write-index"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+        }
+        if self.split.is_some() {
+            if self.output.is_none() {
+                return Err(invalid_input(
+                    "Splitting the output requires --output".to_string(),
+                ));
+            }
+            exclusive(
+                "Splitting the output",
+                &[
+                    (self.check, "--check"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (
+                        !matches!(self.compression, Compression::None),
+                        "--compress-output",
+                    ),
+                ],
+            )?;
+        }
+        #[cfg(feature = "gzip")]
+        if self.bgzf_index.is_some() && !matches!(self.compression, Compression::Bgzf(_)) {
+            return Err(invalid_input(
+                "--bgzf-index requires --compress-output bgzf".to_string(),
+            ));
+        }
+        if self.write_index.is_some() {
+            exclusive(
+                "--write-index",
+                &[
+                    (self.check, "--check"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.split.is_some(), "splitting the output"),
+                    (!self.compression.is_indexable(), "--compress-output"),
+                ],
+            )?;
+        }
+        if self.sstable {
+            // Tables are scanned in byte order, so their lines must be merged in it.
+            let modes = self.key_modes();
+            exclusive(
+                "--sstable",
+                &[
+                    (
+                        !modes.is_empty(),
+                        modes.first().copied().unwrap_or_default(),
+                    ),
+                    (self.order == Order::Desc, "--reverse"),
+                    #[cfg(feature = "unicode")]
+                    (self.normalization.is_some(), "--normalize"),
+                    (self.check, "--check"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.emit_header, "--emit-header"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.split.is_some(), "splitting the output"),
+                    (self.write_index.is_some(), "--write-index"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                    (
+                        !matches!(self.compression, Compression::None),
+                        "--compress-output",
+                    ),
+                ],
+            )?;
+        } else if self.block_size != SsTableWriter::<io::Sink>::DEFAULT_BLOCK_SIZE
+            || self.block_compression != BlockCompression::None
+        {
+            return Err(invalid_input(
+                "--block-size and --block-compression require --sstable".to_string(),
+            ));
+        }
+        if let Some((_, bloom)) = &mut self.bloom_filter {
+            exclusive(
+                "--bloom-filter",
+                &[
+                    (self.check, "--check"),
+                    (self.binary, "--binary"),
+                    (self.follow, "--follow"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                ],
+            )?;
+            let bits_per_key = self.bloom_bits_per_key;
+            let hashes = self
+                .bloom_hashes
+                .unwrap_or_else(|| BloomFilter::optimal_hashes(bits_per_key));
+            *bloom = BloomBuilder::new(bits_per_key, hashes);
+        } else if self.bloom_bits_per_key != 10 || self.bloom_hashes.is_some() {
+            return Err(invalid_input(
+                "--bloom-bits-per-key and --bloom-hashes require --bloom-filter".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// That `--deterministic` can be kept, and fix the line ending it writes.
+    fn check_deterministic(&mut self) -> io::Result<()> {
+        if self.deterministic {
+            exclusive(
+                "--deterministic",
+                &[
+                    (self.tag_source, "--tag-source"),
+                    (self.follow, "--follow"),
+                    (self.duplicates != DuplicatePolicy::KeepAll, "--duplicates"),
+                    (matches!(self.command, Some(Command::Join)), "join"),
+                    (
+                        self.eol == LineEnding::Preserve && self.format.strip_cr,
+                        "--output-eol preserve",
+                    ),
+                ],
+            )?;
+            // Lines end the same whichever ending they were read with.
+            if self.eol == LineEnding::Preserve {
+                self.eol = LineEnding::Lf;
+                self.format.strip_cr = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// That the checksum, manifest and checkpoint of the output can be kept, and set up the
+    /// manifest.
+    fn check_records(&mut self) -> io::Result<()> {
+        if self.checksum.is_some() {
+            exclusive(
+                "--checksum",
+                &[
+                    (self.check, "--check"),
+                    // Each shard's checksum goes in its manifest.
+                    (
+                        self.split.is_some() && self.manifest.is_none(),
+                        "splitting the output",
+                    ),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+        } else if self.checksum_file.is_some() {
+            return Err(invalid_input(
+                "--checksum-file requires --checksum".to_string(),
+            ));
+        }
+        if let Some((_, manifest)) = &mut self.manifest {
+            exclusive(
+                "--manifest",
+                &[
+                    (self.check, "--check"),
+                    (self.count, "--count"),
+                    (self.binary, "--binary"),
+                    (self.follow, "--follow"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.checkpoint.is_some(), "--checkpoint"),
+                    (self.resume.is_some(), "--resume"),
+                ],
+            )?;
+            if self.split.is_some() && self.checksum_file.is_some() {
+                return Err(invalid_input(
+                    "--checksum-file cannot be combined with splitting the output".to_string(),
+                ));
+            }
+            *manifest = ManifestBuilder::new(self.checksum.unwrap_or(ChecksumAlgorithm::Crc32c));
+        }
+        if self.checkpoint.is_some() || self.resume.is_some() {
+            if self.output.is_none() {
+                return Err(invalid_input(
+                    "--checkpoint and --resume require --output".to_string(),
+                ));
+            }
+            if self.resume.is_some() && !self.filenames.is_empty() {
+                return Err(invalid_input(
+                    "--resume takes its inputs from the checkpoint".to_string(),
+                ));
+            }
+            exclusive(
+                "--checkpoint and --resume",
+                &[
+                    (self.check, "--check"),
+                    (self.count, "--count"),
+                    (self.tag_source, "--tag-source"),
+                    (self.unique, "--unique"),
+                    (self.head.is_some(), "--head"),
+                    (self.reorder_window.is_some(), "--reorder-window"),
+                    (self.command.is_some(), "a command"),
+                    (self.aggregate.is_some(), "--aggregate"),
+                    (self.max_fan_in.is_some(), "--max-fan-in"),
+                    (self.split.is_some(), "splitting the output"),
+                    (self.write_index.is_some(), "--write-index"),
+                    (
+                        !matches!(self.compression, Compression::None),
+                        "--compress-output",
+                    ),
+                    (self.filenames.iter().any(|f| f == "-"), "standard input"),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// That what intermediate merges can't do isn't asked of them.
+    fn check_fan_in(&self) -> io::Result<()> {
+        // Intermediate merges skip the header of each file they read back, so they must write it.
+        if self.skip_header > 0 && !self.emit_header && self.max_fan_in.is_some() {
+            return Err(invalid_input(
+                "--skip-header needs --emit-header when combined with --max-fan-in".to_string(),
+            ));
+        }
+        // Intermediate merges would rewrite lines once per pass.
+        #[cfg(feature = "regex")]
+        {
+            let substitutes = self
+                .post_process
+                .iter()
+                .any(|post_process| matches!(post_process, PostProcess::Substitute { .. }));
+            if substitutes && self.max_fan_in.is_some() {
+                return Err(invalid_input(
+                    "--sed cannot be combined with --max-fan-in".to_string(),
+                ));
+            }
+        }
+        let single_pass = [
+            (self.progress, "--progress"),
+            (self.stats.is_some(), "--stats"),
+            (self.read_ahead.is_some(), "--read-ahead"),
+            (self.flush_every.is_some(), "--flush-every"),
+            #[cfg(feature = "io-uring")]
+            (self.io_uring.is_some(), "--io-uring"),
+        ];
+        if let Some((_, flag)) = single_pass
+            .iter()
+            .find(|(set, _)| self.max_fan_in.is_some() && *set)
+        {
+            return Err(invalid_input(format!(
+                "{} cannot be combined with --max-fan-in",
+                flag
+            )));
+        }
+        if self.max_fan_in.is_some() && self.filenames.iter().any(|f| f == "-") {
+            return Err(invalid_input(
+                "Standard input cannot be combined with --max-fan-in".to_string(),
+            ));
+        }
+        if !self.read_index.is_empty() && self.max_fan_in.is_some() {
+            return Err(invalid_input(
+                "--read-index cannot be combined with --max-fan-in".to_string(),
+            ));
+        }
+        if (self.count || self.tag_source) && self.max_fan_in.is_some() {
+            return Err(invalid_input(
+                "--count and --tag-source cannot be combined with --max-fan-in".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether to color lines, which `--color always` insists on.
+    fn use_color(&self, color: ColorChoice) -> io::Result<bool> {
+        // Colored lines are written as `--tag-source` writes them.
+        let uncolored = [
+            (self.count, "--count"),
+            (self.binary, "--binary"),
+            (self.sstable, "--sstable"),
+            (self.emit_header, "--emit-header"),
+            (self.command.is_some(), "a command"),
+            (self.aggregate.is_some(), "--aggregate"),
+            (self.max_fan_in.is_some(), "--max-fan-in"),
+            (self.split.is_some(), "splitting the output"),
+            (self.write_index.is_some(), "--write-index"),
+            (self.checkpoint.is_some(), "--checkpoint"),
+            (self.resume.is_some(), "--resume"),
+            #[cfg(feature = "time")]
+            (self.watermark.is_some(), "--watermark"),
+        ];
+        let uncolored = uncolored.iter().find(|(set, _)| *set);
+        Ok(match (color, uncolored) {
+            (ColorChoice::Never, _) => false,
+            (ColorChoice::Always, Some((_, flag))) => {
+                return Err(invalid_input(format!(
+                    "--color always cannot be combined with {}",
+                    flag
+                )));
+            }
+            (ColorChoice::Always, None) => true,
+            (ColorChoice::Auto, uncolored) => {
+                uncolored.is_none() && self.output.is_none() && io::stdout().is_terminal()
+            }
+        })
+    }
+
+    /// The first of `-k` and the flags that apply to keys given, which together choose one way
+    /// of comparing lines, if any was.
+    fn key_flag(&self) -> Option<&'static str> {
+        let key = &self.key_options;
+        [
+            (!self.keys.is_empty(), "-k"),
+            (key.numeric, "-n"),
+            (key.human_numeric, "-h"),
+            (key.version, "-V"),
+            (key.fold_case, "-f"),
+            (key.ignore_blanks, "-b"),
+            (key.dictionary, "-d"),
+        ]
+        .iter()
+        .find(|(given, _)| *given)
+        .map(|(_, flag)| *flag)
+    }
+
+    /// The options in use that choose what lines are compared by.
+    fn key_modes(&self) -> Vec<&'static str> {
+        let mut modes = Vec::new();
+        if let Some(flag) = self.key_flag() {
+            modes.push(flag);
+        }
+        #[cfg(feature = "csv")]
+        {
+            if self.csv_columns.is_some() {
+                modes.push("--csv");
+            }
+        }
+        #[cfg(feature = "json")]
+        {
+            if !self.json_keys.is_empty() {
+                modes.push("--json-key");
+            }
+        }
+        #[cfg(feature = "time")]
+        {
+            if self.time_format.is_some() {
+                modes.push("--time-key");
+            }
+        }
+        #[cfg(feature = "regex")]
+        {
+            if self.key_regex.is_some() {
+                modes.push("--key-regex");
+            }
+        }
+        if self.genomic.is_some() {
+            modes.push("--genomic");
+        }
+        modes
+    }
+
+    /// What to write after each line of `--count` and `--tag-source` output, which don't track the
+    /// line ending of each line, so `--output-eol preserve` writes plain delimiters.
+    fn terminator(&self) -> Vec<u8> {
+        if self.format.delimiter == b'\n' && self.eol == LineEnding::Crlf {
+            b"\r\n".to_vec()
+        } else {
+            vec![self.format.delimiter]
+        }
+    }
+
+    /// How many records apart the output is flushed: every `--flush-every` records, and with
+    /// `--follow` by default every record, so lines are written as they are merged rather than
+    /// once enough have piled up.
+    fn records_per_flush(&self) -> Option<u64> {
+        match self.flush_every {
+            None if self.follow => Some(1),
+            flush_every => flush_every,
+        }
+    }
+
+    /// A writer batching the records written to `w`, flushed as `records_per_flush` says.
+    fn output_writer<W: io::Write>(&self, w: W) -> BatchWriter<W> {
+        let w = BatchWriter::new(w, self.format.delimiter);
+        match self.records_per_flush() {
+            Some(records) => w.with_flush_every(records),
+            None => w,
+        }
+    }
+
+    /// The `-k` fields of `line` joined by the field separator or a tab, or the whole line without
+    /// any `-k`.
+    fn key_text(&self, line: &str) -> String {
+        if self.keys.is_empty() {
+            return line.to_string();
+        }
+        let separator = self.field_separator;
+        let fields: Vec<&str> = self
+            .keys
+            .iter()
+            .map(|key| key.extract(line, separator))
+            .collect();
+        fields.join(&separator.unwrap_or('\t').to_string())
+    }
+
+    /// The key chosen by `--time-key` and `--time-capture`, if any.
+    #[cfg(feature = "time")]
+    fn time_key(&self) -> Option<io::Result<TimeKey>> {
+        let key = TimeKey::new(self.time_format.clone()?);
+        Some(match &self.time_capture {
+            Some(capture) => key.with_capture(capture),
+            None => Ok(key),
+        })
+    }
+
+    /// The comparator chosen by `-k`, `-t`, the key options and `--normalize`, or `None` to
+    /// compare whole lines. Since keys can reverse their own order, `-r` is folded into the
+    /// comparator too.
+    fn comparator(&self) -> Option<Comparator> {
+        let cmp = self.key_comparator();
+        #[cfg(feature = "unicode")]
+        {
+            if let Some(normalization) = self.normalization {
+                let cmp = cmp.unwrap_or_else(|| self.order_comparator());
+                return Some(normalization.comparator(cmp));
+            }
+        }
+        cmp
+    }
+
+    /// The comparator of whole lines in the order chosen by `-r`.
+    fn order_comparator(&self) -> Comparator {
+        match self.order {
+            Order::Asc => sync::Arc::new(|a: &str, b: &str| a.cmp(b)),
+            Order::Desc => sync::Arc::new(|a: &str, b: &str| b.cmp(a)),
+        }
+    }
+
+    fn key_comparator(&self) -> Option<Comparator> {
+        if self.keys.is_empty() && self.key_options == KeyOptions::default() {
+            return None;
+        }
+        let keys = if self.keys.is_empty() {
+            vec!["1".parse().expect("Invalid whole-line key")]
+        } else {
+            self.keys.clone()
+        };
+        let defaults = KeyOptions {
+            reverse: self.order == Order::Desc,
+            ..self.key_options
+        };
+        Some(key_comparator(keys, self.field_separator, defaults))
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Fail if any of `flags` is given, naming the first as what `what` cannot be combined with.
+fn exclusive(what: &str, flags: &[(bool, &str)]) -> io::Result<()> {
+    match flags.iter().find(|(given, _)| *given) {
+        Some((_, flag)) => Err(invalid_input(format!(
+            "{} cannot be combined with {}",
+            what, flag
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The short options that take a value, which is the rest of their argument if it goes on, as in
+/// `-k2,2n` or `-t,`.
+const SHORT_OPTIONS_WITH_VALUES: &str = "kto";
+
+/// The arguments of the command line, split as GNU `getopt_long` splits them: `--name=value` into
+/// the option and its value, and a cluster of short options like `-nr` or `-nk2,2n` into each of
+/// them, with the rest of the cluster after one that takes a value as its value. Values are taken
+/// with `next` and `peek`, and options and inputs with `next_option`.
+struct Args<I>
+where
+    I: Iterator<Item = String>,
+{
+    args: iter::Peekable<I>,
+    /// What is left of the argument the last option came from.
+    rest: Option<Rest>,
+}
+
+enum Rest {
+    /// The rest of a cluster of short options, without the leading `-`.
+    Options(String),
+    /// The value attached to `option`.
+    Value { option: String, value: String },
+}
+
+impl<I> Args<I>
+where
+    I: Iterator<Item = String>,
+{
+    fn new(args: I) -> Args<I> {
+        Args {
+            args: args.peekable(),
+            rest: None,
+        }
+    }
+
+    /// The next option or input. Fails if the option before it had a value attached that it
+    /// didn't take.
+    fn next_option(&mut self) -> io::Result<Option<String>> {
+        match self.rest.take() {
+            Some(Rest::Value { option, value }) => {
+                return Err(invalid_input(format!(
+                    "Invalid value [{}] for {}",
+                    value, option
+                )))
+            }
+            Some(Rest::Options(cluster)) => return Ok(Some(self.short_option(&cluster))),
+            None => {}
+        }
+        let arg = match self.args.next() {
+            Some(arg) => arg,
+            None => return Ok(None),
+        };
+        if let Some((name, value)) = arg.strip_prefix("--").and_then(|arg| arg.split_once('=')) {
+            let option = format!("--{}", name);
+            self.rest = Some(Rest::Value {
+                option: option.clone(),
+                value: value.to_string(),
+            });
+            return Ok(Some(option));
+        }
+        match arg.strip_prefix('-') {
+            Some(cluster) if !arg.starts_with("--") && cluster.chars().nth(1).is_some() => {
+                Ok(Some(self.short_option(cluster)))
+            }
+            _ => Ok(Some(arg)),
+        }
+    }
+
+    /// The first option of the cluster of short options `cluster`, leaving the rest of it to come
+    /// after.
+    fn short_option(&mut self, cluster: &str) -> String {
+        let mut chars = cluster.chars();
+        let name = chars.next().expect("a cluster has an option");
+        let option = format!("-{}", name);
+        let rest = chars.as_str().to_string();
+        if !rest.is_empty() {
+            self.rest = Some(if SHORT_OPTIONS_WITH_VALUES.contains(name) {
+                Rest::Value {
+                    option: option.clone(),
+                    value: rest,
+                }
+            } else {
+                Rest::Options(rest)
+            });
+        }
+        option
+    }
+
+    /// The value `next` returns next, if there is one.
+    fn peek(&mut self) -> Option<&String> {
+        match &self.rest {
+            Some(Rest::Value { value, .. }) => Some(value),
+            Some(Rest::Options(_)) => None,
+            None => self.args.peek(),
+        }
+    }
+}
+
+impl<I> Iterator for Args<I>
+where
+    I: Iterator<Item = String>,
+{
+    type Item = String;
+
+    /// The next value: the one attached to the last option, if any, or else the next argument.
+    /// Options in the same cluster as the last take no value.
+    fn next(&mut self) -> Option<String> {
+        match self.rest.take() {
+            Some(Rest::Value { value, .. }) => Some(value),
+            Some(rest) => {
+                self.rest = Some(rest);
+                None
+            }
+            None => self.args.next(),
+        }
+    }
+}
+
+fn required_value(flag: &str, value: Option<String>) -> io::Result<String> {
+    value.ok_or_else(|| invalid_input(format!("{} requires an argument", flag)))
+}
+
+/// Parse a number of bytes, optionally followed by `K`, `M`, `G` or `T` for powers of 1024.
+fn parse_size(flag: &str, value: &str) -> io::Result<u64> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) => (&value[..i], 1 << 30),
+        Some((i, 'T')) => (&value[..i], 1 << 40),
+        _ => (value, 1),
+    };
+    match digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+    {
+        Some(0) => Err(invalid_input(format!("{} must be at least 1", flag))),
+        Some(size) => Ok(size),
+        None => Err(invalid_input(format!(
+            "Invalid value [{}] for {}",
+            value, flag
+        ))),
+    }
+}
+
+fn parse_value<V: std::str::FromStr>(flag: &str, value: Option<String>) -> io::Result<V> {
+    let value = required_value(flag, value)?;
+    value
+        .parse()
+        .map_err(|_| invalid_input(format!("Invalid value [{}] for {}", value, flag)))
+}
+
+/// Parse the value of `flag` as a range of bytes, `START..END` or `START..`.
+fn parse_range(flag: &str, value: Option<String>) -> io::Result<std::ops::Range<usize>> {
+    let value = required_value(flag, value)?;
+    let range = value.split_once("..").and_then(|(start, end)| {
+        let start = start.parse().ok()?;
+        let end = if end.is_empty() {
+            usize::MAX
+        } else {
+            end.parse().ok()?
+        };
+        (start <= end).then_some(start..end)
+    });
+    range.ok_or_else(|| invalid_input(format!("Invalid value [{}] for {}", value, flag)))
+}
+
+/// Parse the value of `flag` as a number of seconds, possibly with a fraction.
+fn parse_seconds(flag: &str, value: Option<String>) -> io::Result<time::Duration> {
+    let value = required_value(flag, value)?;
+    value
+        .parse()
+        .ok()
+        .and_then(|seconds| time::Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| invalid_input(format!("Invalid value [{}] for {}", value, flag)))
+}
+
+/// Run `write` against the output chosen by `options`: compressed as requested, and either to
+/// stdout or atomically to the `-o` file. If the merge is interrupted, says how many lines were
+/// written before it was.
+fn write_output<F>(options: &Options, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn io::Write) -> io::Result<u64>,
+{
+    let mut lines = 0;
+    let counted = |w: &mut dyn io::Write| {
+        write(&mut LineCounter {
+            inner: w,
+            delimiter: options.format.delimiter,
+            lines: &mut lines,
+        })
+    };
+    let mut checksum = None;
+    // The checksum and the manifest are of the bytes as written, after any compression.
+    let checksummed = |w: &mut dyn io::Write| match options.checksum {
+        Some(algorithm) => {
+            let mut w = ChecksumWriter::new(w, algorithm);
+            options.compression.write(&mut w, counted)?;
+            checksum = Some(w.checksum().clone());
+            Ok(())
+        }
+        None => options.compression.write(w, counted),
+    };
+    let compressed = |w: &mut dyn io::Write| match &options.manifest {
+        Some((_, manifest)) => checksummed(&mut manifest.writer(w)),
+        None => checksummed(w),
+    };
+    let result = match &options.output {
+        Some(output) if object_path(output).is_some() => {
+            write_object(output, options, |w| compressed(w))
+        }
+        Some(output) => write_atomically(path::Path::new(output), |f| compressed(f)),
+        None => compressed(&mut io::stdout().lock()),
+    };
+    result.map_err(|err| match MergeError::from_io(&err) {
+        Some(MergeError::Cancelled) => io::Error::other(Interrupted {
+            lines,
+            output: options.output.clone(),
+        }),
+        _ => err,
+    })?;
+    if let Some(checksum) = checksum {
+        write_checksum(&checksum, options)?;
+    }
+    match &options.manifest {
+        Some((_, manifest)) => {
+            let run = RunManifest {
+                path: options.output.clone(),
+                ..manifest.build(|line| options.key_text(line))
+            };
+            write_manifests(&[run], options)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Write the manifest of each run, one JSON object a line, to where `--manifest` says.
+#[cfg(feature = "json")]
+fn write_manifests(runs: &[RunManifest], options: &Options) -> io::Result<()> {
+    match &options.manifest {
+        Some((path, _)) => write_atomically(path::Path::new(path), |f| {
+            let mut w = io::BufWriter::new(f);
+            for run in runs {
+                run.write_json(&mut w)?;
+            }
+            w.flush()
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn write_manifests(_: &[RunManifest], _: &Options) -> io::Result<()> {
+    Ok(())
+}
+
+/// Report the checksum of the output as `sha256sum` and the like do, with the name of the
+/// output, to `--checksum-file` or else to standard error.
+fn write_checksum(checksum: &Checksum, options: &Options) -> io::Result<()> {
+    let line = format!(
+        "{}  {}\n",
+        checksum.hex_digest(),
+        options.output.as_deref().unwrap_or("-")
+    );
+    match &options.checksum_file {
+        Some(path) => write_atomically(path::Path::new(path), |f| f.write_all(line.as_bytes())),
+        None => io::stderr().write_all(line.as_bytes()),
+    }
+}
+
+/// Run `write` against a multipart upload of the object at the URI `uri`, completing the upload
+/// only if it succeeds.
+#[cfg(feature = "object-store")]
+fn write_object<F>(uri: &str, options: &Options, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut MultipartWriter) -> io::Result<()>,
+{
+    let (Some(endpoint), Some(path)) = (&options.object_store_endpoint, object_path(uri)) else {
+        return Err(invalid_input(format!(
+            "Writing to [{}] requires --object-store-endpoint",
+            uri
+        )));
+    };
+    let upload = UnsignedS3Store::new(endpoint).put_multipart(path)?;
+    let mut w = MultipartWriter::new(upload)
+        .with_part_size(options.part_size)
+        .with_concurrency(options.upload_concurrency);
+    write(&mut w)?;
+    w.finish().map(|_| ())
+}
+
+#[cfg(not(feature = "object-store"))]
+fn write_object<F>(uri: &str, _: &Options, _: F) -> io::Result<()>
+where
+    F: FnOnce(&mut io::Sink) -> io::Result<()>,
+{
+    Err(invalid_input(format!(
+        "Writing to [{}] requires the object-store feature",
+        uri
+    )))
+}
+
+/// How far a merge got before it was interrupted, which `write_output` fails with instead of
+/// `MergeError::Cancelled`.
+#[derive(Debug)]
+struct Interrupted {
+    /// The number of lines written, or for `-o`, merged before the output was discarded.
+    lines: u64,
+    output: Option<String>,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.output {
+            Some(output) => write!(
+                f,
+                "Interrupted after merging {} lines; nothing was written to [{}]",
+                self.lines, output
+            ),
+            None => write!(f, "Interrupted after writing {} lines", self.lines),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// A writer counting the lines written through it by their delimiters.
+struct LineCounter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    delimiter: u8,
+    lines: &'a mut u64,
+}
+
+impl<W: io::Write + ?Sized> io::Write for LineCounter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        *self.lines += buf[..written]
+            .iter()
+            .filter(|&&byte| byte == self.delimiter)
+            .count() as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Stopping merges cleanly on SIGINT, so what they have written so far is flushed.
+mod interrupt {
+    use std::sync;
+    use std::sync::atomic;
+
+    static FLAG: sync::OnceLock<sync::Arc<atomic::AtomicBool>> = sync::OnceLock::new();
+
+    /// The flag set on SIGINT once `install` has been called, for `Heap::with_cancellation`.
+    pub fn flag() -> sync::Arc<atomic::AtomicBool> {
+        FLAG.get_or_init(Default::default).clone()
+    }
+
+    /// Set the flag on the next SIGINT, after which SIGINT kills the process as usual, e.g. if
+    /// it is blocked reading from a terminal.
+    #[cfg(unix)]
+    pub fn install() {
+        const SIGINT: i32 = 2;
+        const SIG_DFL: usize = 0;
+        extern "C" {
+            fn signal(signum: i32, handler: usize) -> usize;
+        }
+        extern "C" fn on_sigint(_: i32) {
+            if let Some(flag) = FLAG.get() {
+                flag.store(true, atomic::Ordering::Relaxed);
+            }
+            // SAFETY: `signal` is async-signal-safe.
+            unsafe {
+                signal(SIGINT, SIG_DFL);
+            }
+        }
+        flag();
+        // SAFETY: the handler only touches an already initialised atomic and calls `signal`.
+        unsafe {
+            signal(SIGINT, on_sigint as extern "C" fn(i32) as usize);
+        }
+    }
+
+    /// Elsewhere SIGINT kills the process as usual.
+    #[cfg(not(unix))]
+    pub fn install() {}
+}
+
+/// How errors and statistics are reported on stderr, chosen with `--error-format` and
+/// `--stats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    Text,
+    /// As one JSON object per line. For errors, with the `kind` of failure, a `message`, the
+    /// `exit_code` and, when known, the `file` and `line` it happened at.
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl ReportFormat {
+    fn parse(flag: &str, value: &str) -> io::Result<ReportFormat> {
+        match value {
+            "text" => Ok(ReportFormat::Text),
+            #[cfg(feature = "json")]
+            "json" => Ok(ReportFormat::Json),
+            #[cfg(not(feature = "json"))]
+            "json" => Err(invalid_input(format!(
+                "{} json requires the json feature",
+                flag
+            ))),
+            _ => Err(invalid_input(format!(
+                "Invalid value [{}] for {}",
+                value, flag
+            ))),
+        }
+    }
+
+    /// The format chosen by the last `--error-format` in `args`, which are looked through again
+    /// because errors parsing the rest of them are reported in it too.
+    fn from_args(args: &[String]) -> ReportFormat {
+        let mut format = ReportFormat::Text;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("--error-format") {
+                Some("") => args.next().map(String::as_str),
+                Some(attached) => attached.strip_prefix('='),
+                None => None,
+            };
+            if let Some(parsed) = value.and_then(|value| ReportFormat::parse(arg, value).ok()) {
+                format = parsed;
+            }
+        }
+        format
+    }
+
+    /// Report `err`, which the binary is about to exit with.
+    fn report_error(self, err: &io::Error) {
+        match self {
+            // Interruptions aren't errors so much as a report of how far the merge got.
+            ReportFormat::Text if classify(err).1 == EXIT_INTERRUPTED => eprintln!("{}", err),
+            ReportFormat::Text => eprintln!("Error: {}", err),
+            #[cfg(feature = "json")]
+            ReportFormat::Json => {
+                let (kind, code) = classify(err);
+                let mut object = serde_json::json!({
+                    "kind": kind,
+                    "message": err.to_string(),
+                    "exit_code": code,
+                });
+                match MergeError::from_io(err) {
+                    Some(MergeError::Io { file, .. }) => object["file"] = file.as_str().into(),
+                    Some(MergeError::OutOfOrder { file, line_no, .. })
+                    | Some(MergeError::InvalidUtf8 { file, line_no }) => {
+                        object["file"] = file.as_str().into();
+                        object["line"] = (*line_no).into();
+                    }
+                    _ => {}
+                }
+                if let Some(interrupted) = err.get_ref().and_then(|err| err.downcast_ref()) {
+                    let Interrupted { lines, .. } = interrupted;
+                    object["lines"] = (*lines).into();
+                }
+                eprintln!("{}", object);
+            }
+        }
+    }
+
+    /// Report the statistics of a merge that has ended.
+    fn report_stats(self, stats: &MergeStats) {
+        match self {
+            ReportFormat::Text => {
+                for source in &stats.sources {
+                    eprintln!(
+                        "{}: {} lines, {} bytes read",
+                        source.name, source.lines, source.bytes
+                    );
+                }
+                let comparisons = stats.comparisons.unwrap_or_default();
+                eprintln!(
+                    "{} lines merged, {} duplicates dropped, {} comparisons in {:.3}s",
+                    stats.lines,
+                    stats.duplicates,
+                    comparisons,
+                    stats.elapsed.as_secs_f64()
+                );
+            }
+            #[cfg(feature = "json")]
+            ReportFormat::Json => {
+                let mut json = Vec::new();
+                if stats.write_json(&mut json).is_ok() {
+                    eprintln!("{}", String::from_utf8_lossy(&json));
+                }
+            }
+        }
+    }
+
+    /// Report the first out-of-order line `--check` found in `filename`.
+    fn report_disorder(self, filename: &str, unsorted: &UnsortedAt) {
+        match self {
+            ReportFormat::Text => eprintln!(
+                "{}:{}: disorder: {}",
+                filename, unsorted.line_no, unsorted.line
+            ),
+            #[cfg(feature = "json")]
+            ReportFormat::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "kind": "out_of_order",
+                    "message": format!("disorder: {}", unsorted.line),
+                    "exit_code": EXIT_DATA_ERROR,
+                    "file": filename,
+                    "line": unsorted.line_no,
+                })
+            ),
+        }
+    }
+}
+
+/// Parse the compression of the blocks of an SSTable, chosen with `--block-compression`.
+fn parse_block_compression(value: &str) -> io::Result<BlockCompression> {
+    match value {
+        "none" => Ok(BlockCompression::None),
+        #[cfg(feature = "gzip")]
+        "deflate" => Ok(BlockCompression::Deflate),
+        #[cfg(feature = "zstd")]
+        "zstd" => Ok(BlockCompression::Zstd),
+        _ => Err(invalid_input(format!(
+            "Unsupported block compression [{}]",
+            value
+        ))),
+    }
+}
+
+/// When `--color` colors the output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ColorChoice {
+    /// When standard output is a terminal, as with `--color` or `--color auto`.
+    Auto,
+    Always,
+    Never,
+}
+
+/// How the merged output is compressed, chosen with `--compress-output`.
+enum Compression {
+    None,
+    /// BGZF, as `bgzip` writes it, with the index of its blocks once it has been written.
+    #[cfg(feature = "gzip")]
+    Bgzf(sync::OnceLock<BgzfIndex>),
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    fn parse(value: &str) -> io::Result<Compression> {
+        match value {
+            "none" => Ok(Compression::None),
+            #[cfg(feature = "gzip")]
+            "bgzf" => Ok(Compression::Bgzf(sync::OnceLock::new())),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(invalid_input(format!(
+                "Unsupported output compression [{}]",
+                value
+            ))),
+        }
+    }
+
+    /// Whether the offsets of lines in the output can still be found once it is compressed, as
+    /// the virtual offsets of BGZF.
+    fn is_indexable(&self) -> bool {
+        match self {
+            Compression::None => true,
+            #[cfg(feature = "gzip")]
+            Compression::Bgzf(_) => true,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => false,
+        }
+    }
+
+    /// The index of the blocks of BGZF output, once it has been written.
+    #[cfg(feature = "gzip")]
+    fn bgzf_index(&self) -> Option<&BgzfIndex> {
+        match self {
+            Compression::Bgzf(index) => index.get(),
+            _ => None,
+        }
+    }
+
+    /// Run `write` against `w`, compressing whatever it writes.
+    fn write<W, F>(&self, mut w: W, write: F) -> io::Result<()>
+    where
+        W: io::Write,
+        F: FnOnce(&mut dyn io::Write) -> io::Result<u64>,
+    {
+        match self {
+            Compression::None => write(&mut w).map(|_| ()),
+            #[cfg(feature = "gzip")]
+            Compression::Bgzf(index) => {
+                let mut encoder = BgzfWriter::new(w);
+                write(&mut encoder)?;
+                let (_, blocks) = encoder.finish_indexed()?;
+                let _ = index.set(blocks);
+                Ok(())
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(w, 0)?;
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+        }
+    }
+}
+
+/// A step applied to the merged lines, chosen with `--grep` or `--sed`.
+#[cfg(feature = "regex")]
+enum PostProcess {
+    /// Keep only the lines matching the regex.
+    Grep(regex::Regex),
+    /// Replace the first match of the regex in each line, or every match if `global`.
+    Substitute {
+        regex: regex::Regex,
+        replacement: String,
+        global: bool,
+    },
+}
+
+#[cfg(feature = "regex")]
+impl PostProcess {
+    /// Parse `s/PATTERN/REPLACEMENT/` with an optional trailing `g`. Any character may stand in
+    /// for `/`, and is escaped with a backslash. The replacement may refer to groups as `$1`.
+    fn parse_sed(flag: &str, value: &str) -> io::Result<PostProcess> {
+        let invalid = || {
+            invalid_input(format!(
+                "Invalid value [{}] for {}; expected s/PATTERN/REPLACEMENT/[g]",
+                value, flag
+            ))
+        };
+        let mut chars = value.chars();
+        if chars.next() != Some('s') {
+            return Err(invalid());
+        }
+        let delimiter = chars.next().ok_or_else(invalid)?;
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.clone().next() == Some(delimiter) => {
+                    parts
+                        .last_mut()
+                        .expect("parts is never empty")
+                        .push(delimiter);
+                    chars.next();
+                }
+                _ if c == delimiter => parts.push(String::new()),
+                _ => parts.last_mut().expect("parts is never empty").push(c),
+            }
+        }
+        let global = match parts.as_slice() {
+            [_, _, flags] if flags.is_empty() => false,
+            [_, _, flags] if flags == "g" => true,
+            _ => return Err(invalid()),
+        };
+        Ok(PostProcess::Substitute {
+            regex: parse_regex(flag, &parts[0])?,
+            replacement: parts[1].clone(),
+            global,
+        })
+    }
+
+    fn apply<T: io::Read, K: 'static>(&self, heap: Heap<T, K>) -> Heap<T, K> {
+        match self {
+            PostProcess::Grep(regex) => {
+                let regex = regex.clone();
+                heap.with_output_filter(move |line| regex.is_match(line))
+            }
+            PostProcess::Substitute {
+                regex,
+                replacement,
+                global,
+            } => {
+                let (regex, replacement) = (regex.clone(), replacement.clone());
+                let limit = if *global { 0 } else { 1 };
+                heap.with_output_map(move |line| {
+                    regex
+                        .replacen(line, limit, replacement.as_str())
+                        .into_owned()
+                })
+            }
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+fn parse_regex(flag: &str, pattern: &str) -> io::Result<regex::Regex> {
+    regex::Regex::new(pattern)
+        .map_err(|err| invalid_input(format!("Invalid regex [{}] for {}: {}", pattern, flag, err)))
+}
+
+/// Stream output into a temporary file next to `path` and rename it into place only once `write`
+/// succeeds, so a failed merge never leaves a truncated output behind.
+fn write_atomically<F>(path: &path::Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| invalid_input(format!("Invalid output path [{}]", path.display())))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_path = dir.join(tmp_name);
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .and_then(|mut f| {
+            write(&mut f)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
 }
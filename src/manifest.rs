@@ -0,0 +1,194 @@
+//! Manifests of sorted runs: the metadata a planner of later merges needs about an output, its
+//! keys, lines, bytes and checksum, gathered while it is written rather than by reading it again.
+
+use std::io;
+use std::sync;
+
+use crate::{Checksum, ChecksumAlgorithm, Heap, Line};
+
+/// The metadata of a sorted run, as written by a merge or as one shard of one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunManifest {
+    /// Where the run was written, if to a file.
+    pub path: Option<String>,
+    /// The number of merged lines in the run.
+    pub lines: u64,
+    /// The number of bytes written, counting any header and after any compression.
+    pub bytes: u64,
+    /// The key of the first merged line, which sorts first, or `None` if there are no lines.
+    pub min_key: Option<String>,
+    /// The key of the last merged line, which sorts last.
+    pub max_key: Option<String>,
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// The hex digest of the bytes written.
+    pub checksum: String,
+}
+
+impl RunManifest {
+    /// Write the manifest as a JSON object on a line of its own, so the manifests of several
+    /// runs can be written one after another as JSON Lines.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        let manifest = serde_json::json!({
+            "path": self.path,
+            "lines": self.lines,
+            "bytes": self.bytes,
+            "min_key": self.min_key,
+            "max_key": self.max_key,
+            "checksum_algorithm": self.checksum_algorithm.name(),
+            "checksum": self.checksum,
+        });
+        serde_json::to_writer(&mut w, &manifest)?;
+        w.write_all(b"\n")
+    }
+}
+
+struct Gathered {
+    lines: u64,
+    first: Option<String>,
+    last: String,
+    bytes: u64,
+    checksum: Checksum,
+}
+
+/// Gathers the manifest of a run as it is written: its lines with `Heap::with_manifest` and its
+/// bytes through the writer `writer` wraps the output in. Clones share what is gathered, so one
+/// can be kept to build the manifest from once the run is written.
+#[derive(Clone)]
+pub struct ManifestBuilder {
+    gathered: sync::Arc<sync::Mutex<Gathered>>,
+}
+
+impl ManifestBuilder {
+    pub fn new(algorithm: ChecksumAlgorithm) -> ManifestBuilder {
+        ManifestBuilder {
+            gathered: sync::Arc::new(sync::Mutex::new(Gathered {
+                lines: 0,
+                first: None,
+                last: String::new(),
+                bytes: 0,
+                checksum: Checksum::new(algorithm),
+            })),
+        }
+    }
+
+    /// Count `line` as the next line of the run.
+    pub fn insert_line(&self, line: &str) {
+        let mut gathered = self.lock();
+        gathered.lines += 1;
+        if gathered.first.is_none() {
+            gathered.first = Some(line.to_string());
+        }
+        gathered.last.clear();
+        gathered.last.push_str(line);
+    }
+
+    /// Wrap `inner` so that what is written to it is counted and digested as the bytes of the
+    /// run.
+    pub fn writer<W: io::Write>(&self, inner: W) -> ManifestWriter<W> {
+        ManifestWriter {
+            inner,
+            builder: self.clone(),
+        }
+    }
+
+    /// Build the manifest of what has been gathered so far, taking the keys of the first and
+    /// last lines with `key`.
+    pub fn build<F: Fn(&str) -> String>(&self, key: F) -> RunManifest {
+        let gathered = self.lock();
+        RunManifest {
+            path: None,
+            lines: gathered.lines,
+            bytes: gathered.bytes,
+            min_key: gathered.first.as_deref().map(&key),
+            max_key: gathered.first.as_ref().map(|_| key(&gathered.last)),
+            checksum_algorithm: gathered.checksum.algorithm(),
+            checksum: gathered.checksum.hex_digest(),
+        }
+    }
+
+    fn lock(&self) -> sync::MutexGuard<'_, Gathered> {
+        self.gathered.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// A writer that counts and digests the bytes written through it for a `ManifestBuilder`.
+pub struct ManifestWriter<W> {
+    inner: W,
+    builder: ManifestBuilder,
+}
+
+impl<W> ManifestWriter<W> {
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for ManifestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let mut gathered = self.builder.lock();
+        gathered.bytes += written as u64;
+        gathered.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Count each merged line into `manifest` as it is emitted, after any output filters and
+    /// maps added before it, so add it last.
+    pub fn with_manifest(mut self, manifest: ManifestBuilder) -> Heap<T, K> {
+        self.merge = self.merge.with_output(move |line: &mut Line<K>| {
+            manifest.insert_line(&line.text);
+            true
+        });
+        self
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() -> Result<(), io::Error> {
+        let manifest = ManifestBuilder::new(ChecksumAlgorithm::Crc32c);
+        let mut heap = Heap::new()
+            .with_output_filter(|line| !line.starts_with('z'))
+            .with_manifest(manifest.clone());
+        heap.add_reader("a".to_string(), "b\t2\nd\t4\nz\t9\n".as_bytes())?;
+        heap.add_reader("c".to_string(), "a\t1\nc\t3\n".as_bytes())?;
+        let mut output = Vec::new();
+        heap.write_sorted_lines(manifest.writer(&mut output))?;
+        assert_eq!(output, b"a\t1\nb\t2\nc\t3\nd\t4\n");
+
+        let run = manifest.build(|line| line.split('\t').next().unwrap_or(line).to_string());
+        let mut checksum = Checksum::new(ChecksumAlgorithm::Crc32c);
+        checksum.update(&output);
+        assert_eq!(
+            run,
+            RunManifest {
+                path: None,
+                lines: 4,
+                bytes: 16,
+                min_key: Some("a".to_string()),
+                max_key: Some("d".to_string()),
+                checksum_algorithm: ChecksumAlgorithm::Crc32c,
+                checksum: checksum.hex_digest(),
+            }
+        );
+
+        let empty = ManifestBuilder::new(ChecksumAlgorithm::Crc32c).build(str::to_string);
+        assert_eq!((empty.lines, empty.min_key, empty.max_key), (0, None, None));
+        Ok(())
+    }
+}
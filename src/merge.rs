@@ -0,0 +1,2335 @@
+//! A generic k-way merge over any number of already-sorted sources.
+
+use std::cmp;
+use std::collections;
+use std::env;
+use std::fs;
+use std::io;
+use std::iter;
+use std::mem;
+use std::path;
+use std::process;
+use std::sync;
+use std::sync::atomic;
+use std::time;
+
+use crate::error::{in_file, out_of_order_error, MergeError};
+use crate::Heap;
+
+mod loser_tree;
+
+use loser_tree::LoserTree;
+
+/// A function used to order the items of a merge.
+pub type ItemComparator<I> = sync::Arc<dyn Fn(&I, &I) -> cmp::Ordering + Send + Sync>;
+
+/// A function deciding something about a pair of items.
+pub type ItemPredicate<I> = sync::Arc<dyn Fn(&I, &I) -> bool + Send + Sync>;
+
+/// An item along with the index of its source in the order sources were added, which names it
+/// in `KWayMerge::names`, and its 1-based position there.
+type Indexed<I> = (usize, u64, I);
+
+/// A run of equal items, each with the index of its source.
+pub(crate) type Group<I> = Vec<Indexed<I>>;
+
+/// A source taken out of a merge: its name, the items read from it but not emitted, and itself.
+pub(crate) type SourceParts<S> = (sync::Arc<str>, Vec<<S as SortedSource>::Item>, S);
+
+/// What `KWayMerge::into_sources` takes a merge apart into.
+pub(crate) type MergeParts<S> = (Option<<S as SortedSource>::Item>, Vec<SourceParts<S>>);
+
+/// A function combining a run of equal items from different sources into one.
+pub type ItemMerger<I> = sync::Arc<dyn Fn(Vec<I>) -> I + Send + Sync>;
+
+/// A function that rewrites an item in place, returning whether to keep it. It is only called
+/// by the merge it belongs to, so it may keep state of its own.
+pub type ItemTransform<I> = Box<dyn FnMut(&mut I) -> bool + Send>;
+
+/// A function returning a copy of an item that compares the same as it.
+pub(crate) type ItemCopy<I> = sync::Arc<dyn Fn(&I) -> I + Send + Sync>;
+
+/// A function called with how far a merge has got.
+pub type ProgressHook = sync::Arc<dyn Fn(&MergeProgress) + Send + Sync>;
+
+/// Merges a group of the sources of a `Strategy::Parallel` merge on a thread of its own,
+/// returning a source of the items it merges by the name given.
+pub(crate) type Parallelize<S> = sync::Arc<dyn Fn(&str, KWayMerge<S>) -> S + Send + Sync>;
+
+/// An item along with the name of the source it came from and its 1-based position there.
+pub type Positioned<I> = (sync::Arc<str>, u64, I);
+
+/// The direction in which the inputs, and therefore the merged output, are sorted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How a `KWayMerge` keeps track of which source's head item comes next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// A binary heap, which replaces the source at its top for every item merged, sifting it down
+    /// only if its next item doesn't still come first.
+    #[default]
+    Heap,
+    /// A tournament tree of losers, which replays a single leaf-to-root path of comparisons per
+    /// item. This pays off for merges of hundreds of sources.
+    LoserTree,
+    /// Split the sources into up to `threads` groups of consecutive ones, merge each group on a
+    /// thread of its own, and merge what the groups merge on the calling thread, so very wide
+    /// merges use several cores. The sources are only split once the merge starts. This is up to
+    /// `Heap::with_parallel_strategy`; other merges, and those with
+    /// `DuplicatePolicy::HighestPriority`, use a binary heap instead.
+    Parallel { threads: usize },
+}
+
+/// The order in which a `KWayMerge` takes items from its sources.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interleave {
+    /// By their items, so sorted sources merge into sorted output.
+    #[default]
+    Sorted,
+    /// One item from each source in turn, in the order they were added, skipping those that are
+    /// exhausted.
+    RoundRobin,
+    /// All the items of each source before any of the next, in the order they were added.
+    Concat,
+}
+
+/// What to do when a source yields an item that sorts before its predecessor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutOfOrderPolicy {
+    /// Yield an error for the offending source.
+    #[default]
+    Error,
+    /// Drop items from the source until it catches back up with the last item it yielded.
+    Skip,
+    /// Merge the item as if nothing were wrong, so it is emitted out of sequence.
+    EmitAnyway,
+    /// Like `EmitAnyway`, but also print a warning to stderr.
+    WarnAndContinue,
+}
+
+/// What to do when reading from a source fails, e.g. because a file turns out to be truncated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceErrorPolicy {
+    /// Yield the error.
+    #[default]
+    Fail,
+    /// Print a warning to stderr, remove the source from the merge and keep merging the others.
+    /// The errors are collected for `failed_sources`.
+    DropSource,
+}
+
+/// How far out of order a source's items may arrive and still be merged into sequence. Items are
+/// held back in a per-source buffer and re-sorted there before they reach the merge.
+pub enum ReorderWindow<I> {
+    /// Items may arrive up to this many positions late.
+    Items(usize),
+    /// Items may arrive late until `settled(item, newest)` returns true, where `newest` is an item
+    /// read from the same source afterwards, e.g. once `newest` is more than a minute later than
+    /// `item` for logs that are out of order by at most a minute.
+    Settled(ItemPredicate<I>),
+}
+
+impl<I> Clone for ReorderWindow<I> {
+    fn clone(&self) -> ReorderWindow<I> {
+        match self {
+            ReorderWindow::Items(n) => ReorderWindow::Items(*n),
+            ReorderWindow::Settled(settled) => ReorderWindow::Settled(settled.clone()),
+        }
+    }
+}
+
+/// A source of items that are already sorted, e.g. the lines of a file or the rows of a database
+/// cursor.
+pub trait SortedSource {
+    type Item;
+
+    /// Return the next item, or `None` once the source is exhausted.
+    fn next(&mut self) -> io::Result<Option<Self::Item>>;
+
+    /// Read the next item into `item`, reusing whatever it has allocated, and return whether there
+    /// was one. Sources whose items own buffers should override this; by default it calls `next`.
+    fn next_into(&mut self, item: &mut Self::Item) -> io::Result<bool> {
+        match self.next()? {
+            Some(next) => {
+                *item = next;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Render `item` for error messages, or return `None` if it has no useful rendering.
+    fn describe(&self, _item: &Self::Item) -> Option<String> {
+        None
+    }
+
+    /// The number of the line the item returned last started on, counting the lines skipped
+    /// before it too, if the source reads lines. Out-of-order errors report it rather than the
+    /// position of the item among those returned.
+    fn line_no(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Boxed sources, so that a merge can mix sources of different types, e.g. a database cursor, a
+/// paginated API and the lines of a file, as `Box<dyn SortedSource<Item = String>>`.
+impl<S> SortedSource for Box<S>
+where
+    S: SortedSource + ?Sized,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> io::Result<Option<S::Item>> {
+        (**self).next()
+    }
+
+    fn next_into(&mut self, item: &mut S::Item) -> io::Result<bool> {
+        (**self).next_into(item)
+    }
+
+    fn describe(&self, item: &S::Item) -> Option<String> {
+        (**self).describe(item)
+    }
+
+    fn line_no(&self) -> Option<u64> {
+        (**self).line_no()
+    }
+}
+
+/// The items of an iterator as a `SortedSource`, for `merge_iters`.
+pub struct IterSource<I>(pub I);
+
+impl<I, T> SortedSource for IterSource<I>
+where
+    I: Iterator<Item = io::Result<T>>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> io::Result<Option<T>> {
+        self.0.next().transpose()
+    }
+}
+
+/// Merge the items of `sources`, each sorted in ascending order, like `itertools::kmerge` does,
+/// but with the errors they yield passed through and a check that they are sorted. Errors name
+/// each source by its 1-based position, as `iterator 2`. The merge can be configured like any
+/// other, e.g. with `KWayMerge::with_order`, though reading the first item of each source, which
+/// this does, can fail.
+pub fn merge_iters<I, T>(
+    sources: I,
+) -> io::Result<KWayMerge<IterSource<<I::Item as IntoIterator>::IntoIter>>>
+where
+    I: IntoIterator,
+    I::Item: IntoIterator<Item = io::Result<T>>,
+    T: Ord,
+{
+    let mut merge = KWayMerge::new();
+    for (i, source) in sources.into_iter().enumerate() {
+        let name = format!("iterator {}", i + 1);
+        merge.add_source(name, IterSource(source.into_iter()))?;
+    }
+    Ok(merge)
+}
+
+/// Merges any number of sorted sources into a single sorted stream, reporting an error whenever a
+/// source yields an item that sorts before its predecessor.
+pub struct KWayMerge<S>
+where
+    S: SortedSource,
+{
+    heap: Queue<Head<S>>,
+    interleave: Interleave,
+    /// The name of each source, by its index in the order sources were added.
+    names: Vec<sync::Arc<str>>,
+    strategy: Strategy,
+    /// How the groups of a `Strategy::Parallel` merge are merged, until they are split off.
+    parallelize: Option<Parallelize<S>>,
+    cmp: ItemComparator<S::Item>,
+    policy: OutOfOrderPolicy,
+    source_errors: SourceErrorPolicy,
+    failed: Vec<(sync::Arc<str>, io::Error)>,
+    window: Option<ReorderWindow<S::Item>>,
+    dedup: bool,
+    /// The next item, if `peek` has returned it, to be returned before anything else.
+    peeked: Option<Indexed<S::Item>>,
+    deferred: Option<io::Error>,
+    next_index: usize,
+    start: Option<S::Item>,
+    end: Option<S::Item>,
+    /// Whether items equal to `end` are past the end of the range too.
+    end_before: bool,
+    limit: Option<u64>,
+    emitted: u64,
+    output: Option<ItemTransform<S::Item>>,
+    cancel: Option<sync::Arc<atomic::AtomicBool>>,
+    progress: Option<ProgressReports>,
+    /// The items dropped as duplicates so far.
+    dropped: u64,
+    comparisons: Option<sync::Arc<atomic::AtomicU64>>,
+    ended: Option<time::Instant>,
+    #[cfg(feature = "tracing")]
+    trace: crate::trace::MergeTrace,
+    duplicates: DuplicatePolicy,
+    merger: Option<ItemMerger<S::Item>>,
+    priorities: Vec<i64>,
+    /// How items `cmp` finds equal are ordered before falling back to the order of their sources,
+    /// and the comparator of the heads, which breaks ties with it.
+    tie: Option<ItemComparator<S::Item>>,
+    head_cmp: ItemComparator<S::Item>,
+    /// How to copy the item at a head, if the source it came from is only read from again once
+    /// the item has been emitted.
+    lazy: Option<ItemCopy<S::Item>>,
+    /// The head popped last by a lazy merge, holding a copy of the item emitted from it to check
+    /// the next one against, until that is read.
+    unfilled: Option<Head<S>>,
+}
+
+/// Which of the items that compare equal, which are duplicates of the same key, a merge keeps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Keep them all, in the order their sources were added.
+    #[default]
+    KeepAll,
+    /// Keep only the first, from the source added first.
+    FirstSource,
+    /// Keep only the last, from the source added last, so later sources override earlier ones.
+    LastSource,
+    /// Keep only the last from the source with the highest priority, or the one of those added
+    /// last if several share it, as for the levels of an LSM tree.
+    HighestPriority,
+}
+
+/// How far a merge has got, as returned by `KWayMerge::progress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeProgress {
+    /// The number of items emitted.
+    pub emitted: u64,
+    /// The number of items dropped as duplicates of the item before them, by `dedup` or the
+    /// duplicate policy, or collapsed into it by `counted`.
+    pub duplicates: u64,
+    /// The number of comparisons between items, if `count_comparisons` was called.
+    pub comparisons: Option<u64>,
+    /// When the merge ended, if it has, either because every source is exhausted or at the end
+    /// of its range.
+    pub ended: Option<time::Instant>,
+}
+
+/// When a merge next calls the hook given to `with_progress`.
+struct ProgressReports {
+    hook: ProgressHook,
+    every: u64,
+    /// The number of emitted items at which the hook is next called.
+    next: u64,
+    /// Whether the hook has been called for the end of the merge.
+    ended: bool,
+}
+
+/// Where an item falls relative to the range a merge is limited to.
+enum Bound {
+    Before,
+    Within,
+    /// After the end of the range, or beyond the limit on the number of items.
+    After,
+    /// Not to be emitted, or even popped, because the merge has been cancelled.
+    Cancelled,
+    /// Starting to merge the groups of a parallel merge failed, though the merge can carry on.
+    Failed(io::Error),
+}
+
+impl<S> Default for KWayMerge<S>
+where
+    S: SortedSource,
+    S::Item: Ord,
+{
+    fn default() -> KWayMerge<S> {
+        KWayMerge::new()
+    }
+}
+
+impl<S> KWayMerge<S>
+where
+    S: SortedSource,
+    S::Item: Ord,
+{
+    pub fn new() -> KWayMerge<S> {
+        KWayMerge::with_comparator(|a: &S::Item, b: &S::Item| a.cmp(b))
+    }
+}
+
+impl<S> KWayMerge<S>
+where
+    S: SortedSource,
+    S::Item: 'static,
+{
+    /// Set the direction the sources are sorted in. `Order::Desc` flips both the merge order and
+    /// the out-of-order check of the comparator the merge was created with.
+    pub fn with_order(mut self, order: Order) -> KWayMerge<S> {
+        if order == Order::Desc {
+            let cmp = self.cmp.clone();
+            self.replace_comparator(sync::Arc::new(move |a: &S::Item, b: &S::Item| cmp(b, a)));
+        }
+        self
+    }
+
+    /// Count the comparisons between items from now on, for `progress` to report. This slows
+    /// down each comparison slightly.
+    pub fn count_comparisons(mut self) -> KWayMerge<S> {
+        if self.comparisons.is_some() {
+            return self;
+        }
+        let comparisons = sync::Arc::new(atomic::AtomicU64::new(0));
+        let (counted, cmp) = (comparisons.clone(), self.cmp.clone());
+        self.comparisons = Some(comparisons);
+        self.replace_comparator(sync::Arc::new(move |a: &S::Item, b: &S::Item| {
+            counted.fetch_add(1, atomic::Ordering::Relaxed);
+            cmp(a, b)
+        }));
+        self
+    }
+
+    /// Order items with `cmp` from now on, including those at the heads of the sources.
+    fn replace_comparator(&mut self, cmp: ItemComparator<S::Item>) {
+        self.cmp = cmp;
+        let cmp = self.head_comparator();
+        self.head_cmp = cmp.clone();
+        let heads = self.heap.drain();
+        for mut head in heads {
+            head.cmp = cmp.clone();
+            self.heap.push(head);
+        }
+    }
+
+    /// Order the items that the comparator finds equal by `tie` too, before the order their
+    /// sources were added, e.g. by their bytes so that the output doesn't depend on that order.
+    /// Runs of equal items reach a duplicate merger in this order as well. Whether items are in
+    /// order within a source, and which are duplicates, is still up to the comparator alone.
+    pub fn with_tie_breaker<F>(mut self, tie: F) -> KWayMerge<S>
+    where
+        F: Fn(&S::Item, &S::Item) -> cmp::Ordering + Send + Sync + 'static,
+    {
+        self.tie = Some(sync::Arc::new(tie));
+        let cmp = self.cmp.clone();
+        self.replace_comparator(cmp);
+        self
+    }
+
+    /// The comparator that orders the heads of the sources, which breaks ties with `tie`.
+    fn head_comparator(&self) -> ItemComparator<S::Item> {
+        match &self.tie {
+            Some(tie) => {
+                let (cmp, tie) = (self.cmp.clone(), tie.clone());
+                sync::Arc::new(move |a: &S::Item, b: &S::Item| cmp(a, b).then_with(|| tie(a, b)))
+            }
+            None => self.cmp.clone(),
+        }
+    }
+}
+
+impl<S> KWayMerge<S>
+where
+    S: SortedSource,
+{
+    /// Create a merge that orders items with `cmp`. Every source is expected to be sorted
+    /// according to the same comparator; it is also used for the out-of-order check.
+    pub fn with_comparator<F>(cmp: F) -> KWayMerge<S>
+    where
+        F: Fn(&S::Item, &S::Item) -> cmp::Ordering + Send + Sync + 'static,
+    {
+        KWayMerge::from_comparator(sync::Arc::new(cmp))
+    }
+
+    fn from_comparator(cmp: ItemComparator<S::Item>) -> KWayMerge<S> {
+        KWayMerge {
+            heap: Queue::new(Strategy::Heap),
+            interleave: Interleave::Sorted,
+            names: Vec::new(),
+            strategy: Strategy::Heap,
+            parallelize: None,
+            head_cmp: cmp.clone(),
+            cmp,
+            policy: OutOfOrderPolicy::Error,
+            source_errors: SourceErrorPolicy::Fail,
+            failed: Vec::new(),
+            window: None,
+            dedup: false,
+            peeked: None,
+            deferred: None,
+            next_index: 0,
+            start: None,
+            end: None,
+            end_before: false,
+            limit: None,
+            emitted: 0,
+            output: None,
+            cancel: None,
+            progress: None,
+            dropped: 0,
+            comparisons: None,
+            ended: None,
+            #[cfg(feature = "tracing")]
+            trace: crate::trace::MergeTrace::new(),
+            duplicates: DuplicatePolicy::KeepAll,
+            merger: None,
+            priorities: Vec::new(),
+            tie: None,
+            lazy: None,
+            unfilled: None,
+        }
+    }
+
+    /// Suppress items that compare equal to the item emitted just before them, like `sort -mu`.
+    pub fn dedup(mut self, dedup: bool) -> KWayMerge<S> {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Set the data structure used to pick the next item. `Strategy::Heap` is the default.
+    pub fn with_strategy(mut self, strategy: Strategy) -> KWayMerge<S> {
+        let heads = self.heap.drain();
+        self.heap = Queue::new(strategy);
+        self.strategy = strategy;
+        for head in heads {
+            self.heap.push(head);
+        }
+        self
+    }
+
+    /// Take items from the sources in the order `interleave` gives rather than by comparing them,
+    /// so the sources needn't be sorted and aren't checked for order. The items are still
+    /// compared for `dedup`, the duplicate policy and the range the merge is limited to, which
+    /// only make sense for `Interleave::Sorted`. A `Strategy::Parallel` merge that doesn't sort
+    /// uses a binary heap instead.
+    pub fn with_interleave(mut self, interleave: Interleave) -> KWayMerge<S> {
+        self.interleave = interleave;
+        let heads = self.heap.drain();
+        for mut head in heads {
+            head.interleave = interleave;
+            self.heap.push(head);
+        }
+        self
+    }
+
+    /// The out-of-order policy heads advance under, which lets anything through unless the
+    /// merge sorts.
+    fn advance_policy(&self) -> OutOfOrderPolicy {
+        match self.interleave {
+            Interleave::Sorted => self.policy,
+            Interleave::RoundRobin | Interleave::Concat => OutOfOrderPolicy::EmitAnyway,
+        }
+    }
+
+    /// Tolerate sources that are only sorted within `window`, re-sorting their items before they
+    /// are merged instead of treating them as out of order.
+    pub fn with_reorder_window(mut self, window: ReorderWindow<S::Item>) -> KWayMerge<S> {
+        self.window = Some(window);
+        self
+    }
+
+    /// Skip the items that sort before `start`. Since the sources are sorted, these are the items
+    /// at the head of each of them.
+    pub fn with_start(mut self, start: S::Item) -> KWayMerge<S> {
+        self.start = Some(start);
+        self
+    }
+
+    /// End the merge at the first item that sorts after `end`, dropping every source without
+    /// reading the rest of them.
+    pub fn with_end(mut self, end: S::Item) -> KWayMerge<S> {
+        self.end = Some(end);
+        self.end_before = false;
+        self
+    }
+
+    /// Like `with_end`, but end the merge at the first item that doesn't sort before `end`, so
+    /// the items equal to it are left out, as for ranges that are split there.
+    pub fn with_end_before(mut self, end: S::Item) -> KWayMerge<S> {
+        self.end = Some(end);
+        self.end_before = true;
+        self
+    }
+
+    /// End the merge once `limit` items have been emitted, dropping every source. With `dedup`
+    /// or `counted`, a run of equal items counts once.
+    pub fn with_limit(mut self, limit: u64) -> KWayMerge<S> {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Pass each item about to be emitted through `output`, which may rewrite it or drop it by
+    /// returning false, after `dedup` has compared it. Dropped items don't count towards the
+    /// limit. Calling this again runs `output` after the functions already added.
+    pub fn with_output<F>(mut self, mut output: F) -> KWayMerge<S>
+    where
+        F: FnMut(&mut S::Item) -> bool + Send + 'static,
+        S::Item: 'static,
+    {
+        self.output = Some(match self.output.take() {
+            Some(mut first) => Box::new(move |item: &mut S::Item| first(item) && output(item)),
+            None => Box::new(output),
+        });
+        self
+    }
+
+    /// Stop merging once `cancel` is set, failing with `MergeError::Cancelled` instead of popping
+    /// the next item. The merge is left as it was, so it can carry on if `cancel` is cleared.
+    pub fn with_cancellation(mut self, cancel: sync::Arc<atomic::AtomicBool>) -> KWayMerge<S> {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// The flag given to `with_cancellation`, if any.
+    pub(crate) fn cancellation(&self) -> Option<&sync::Arc<atomic::AtomicBool>> {
+        self.cancel.as_ref()
+    }
+
+    /// Call `hook` with the progress of the merge every `every` emitted items, and once more when
+    /// it ends.
+    pub fn with_progress(mut self, every: u64, hook: ProgressHook) -> KWayMerge<S> {
+        self.progress = Some(ProgressReports {
+            hook,
+            every: every.max(1),
+            next: self.emitted.saturating_add(every.max(1)),
+            ended: false,
+        });
+        self
+    }
+
+    /// How far the merge has got.
+    pub fn progress(&self) -> MergeProgress {
+        MergeProgress {
+            emitted: self.emitted,
+            duplicates: self.dropped,
+            comparisons: self
+                .comparisons
+                .as_ref()
+                .map(|comparisons| comparisons.load(atomic::Ordering::Relaxed)),
+            ended: self.ended,
+        }
+    }
+
+    /// Count the items of the last source that were drained without going through the merge.
+    pub(crate) fn count_drained(&mut self, emitted: u64, duplicates: u64) {
+        self.emitted += emitted;
+        self.dropped += duplicates;
+        self.end();
+    }
+
+    /// Record that the merge has ended, and call the progress hook for the last time.
+    fn end(&mut self) {
+        if self.ended.is_none() {
+            self.ended = Some(time::Instant::now());
+        }
+        self.report_progress();
+    }
+
+    /// Call the progress hook if it is due.
+    fn report_progress(&mut self) {
+        let progress = self.progress();
+        if let Some(reports) = &mut self.progress {
+            let ended = progress.ended.is_some();
+            if !reports.ended && (ended || progress.emitted >= reports.next) {
+                (reports.hook)(&progress);
+                reports.next = progress.emitted.saturating_add(reports.every);
+                reports.ended = ended;
+            }
+        }
+    }
+
+    /// Run `item` through the output functions, counting it as emitted if it is kept.
+    pub(crate) fn emit(&mut self, item: &mut S::Item) -> bool {
+        let keep = match &mut self.output {
+            Some(output) => output(item),
+            None => true,
+        };
+        if keep {
+            self.emitted += 1;
+        }
+        keep
+    }
+
+    /// Count an item, or a group of them, as emitted without running the output functions.
+    pub(crate) fn count_emitted(&mut self) {
+        self.emitted += 1;
+    }
+
+    /// Emit each item before reading the next one from its source, rather than after, keeping
+    /// a copy of it made with `copy` to check that one against. An emitted item then doesn't
+    /// wait on its source's next item, which matters if reading it blocks, e.g. while following
+    /// a file. Merges that drop duplicates, which need to see that item first, read it eagerly
+    /// regardless.
+    pub(crate) fn with_lazy_refill(mut self, copy: ItemCopy<S::Item>) -> KWayMerge<S> {
+        self.lazy = Some(copy);
+        self
+    }
+
+    /// Read the item after the one emitted from the head popped last by a lazy merge, putting
+    /// the head back in the merge unless its source is exhausted.
+    fn refill(&mut self) -> io::Result<()> {
+        let mut head = match self.unfilled.take() {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        let policy = self.advance_policy();
+        let index = head.source.index;
+        let name = self.names[index].clone();
+        match head.advance(&name, self.window.as_ref(), &self.cmp, policy, &mut None) {
+            Advanced::Replaced(_) => {
+                self.heap.push(head);
+                Ok(())
+            }
+            Advanced::Exhausted => Ok(()),
+            Advanced::Failed(err) => match self.source_failed(index, err) {
+                Some(err) => Err(err),
+                None => Ok(()),
+            },
+            Advanced::OutOfOrder(err) => {
+                self.heap.push(head);
+                Err(err)
+            }
+        }
+    }
+
+    /// Merge the groups of a `Strategy::Parallel` merge with `parallelize` once it starts.
+    pub(crate) fn with_parallelize(mut self, parallelize: Parallelize<S>) -> KWayMerge<S> {
+        self.parallelize = Some(parallelize);
+        self
+    }
+
+    /// Replace the sources with those of the groups they are split into, if the merge is a
+    /// parallel one that is starting. Returns the first error reading from a group; the others
+    /// are merged regardless.
+    fn split_into_groups(&mut self) -> io::Result<()> {
+        let (threads, parallelize) = match (self.strategy, self.parallelize.take()) {
+            (Strategy::Parallel { threads }, Some(parallelize)) => (threads, parallelize),
+            _ => return Ok(()),
+        };
+        if threads < 2
+            || self.heap.len() < 2
+            || self.duplicates == DuplicatePolicy::HighestPriority
+            || self.interleave != Interleave::Sorted
+        {
+            return Ok(());
+        }
+        let mut heads = self.heap.drain();
+        heads.sort_by_key(|head| head.source.index);
+        let per_group = heads.len().div_ceil(threads);
+        let mut groups = Vec::new();
+        let mut heads = heads.into_iter().peekable();
+        while heads.peek().is_some() {
+            let mut group = KWayMerge {
+                policy: self.policy,
+                source_errors: self.source_errors,
+                window: self.window.clone(),
+                names: self.names.clone(),
+                tie: self.tie.clone(),
+                head_cmp: self.head_cmp.clone(),
+                ..KWayMerge::from_comparator(self.cmp.clone())
+            };
+            for head in heads.by_ref().take(per_group) {
+                group.heap.push(head);
+            }
+            groups.push(group);
+        }
+        // What each group merges is sorted, or out of order only as the out-of-order policy
+        // allows, and errors reading from a group come from its sources.
+        self.policy = OutOfOrderPolicy::EmitAnyway;
+        self.source_errors = SourceErrorPolicy::Fail;
+        self.window = None;
+        let mut failed = Ok(());
+        for (i, group) in groups.into_iter().enumerate() {
+            let name = format!("group {}", i + 1);
+            let source = parallelize(&name, group);
+            failed = failed.and(self.add_source(name, source));
+        }
+        failed
+    }
+
+    /// Where the next item to be emitted falls relative to the range of the merge, checked before
+    /// it is popped so that nothing past the end of the range is read.
+    fn bound(&mut self) -> Option<Bound> {
+        if let Err(err) = self.split_into_groups() {
+            return Some(Bound::Failed(err));
+        }
+        if let Err(err) = self.refill() {
+            return Some(Bound::Failed(err));
+        }
+        if self.heap.peek().is_none() {
+            self.end();
+            return None;
+        }
+        self.report_progress();
+        #[cfg(feature = "tracing")]
+        self.trace.emitting(self.emitted);
+        let emitted = self.emitted;
+        let cancelled = match &self.cancel {
+            Some(cancel) => cancel.load(atomic::Ordering::Relaxed),
+            None => false,
+        };
+        let item = &self.heap.peek()?.item;
+        let cmp = &self.cmp;
+        if self.limit.is_some_and(|limit| emitted >= limit) {
+            return Some(Bound::After);
+        }
+        if cancelled {
+            return Some(Bound::Cancelled);
+        }
+        Some(match (&self.start, &self.end) {
+            (Some(start), _) if cmp(item, start) == cmp::Ordering::Less => Bound::Before,
+            (_, Some(end)) => match cmp(item, end) {
+                cmp::Ordering::Greater => Bound::After,
+                cmp::Ordering::Equal if self.end_before => Bound::After,
+                _ => Bound::Within,
+            },
+            _ => Bound::Within,
+        })
+    }
+
+    /// Whether sources can be skipped ahead to the start of the range by position, because there
+    /// is one and they are fully sorted.
+    pub(crate) fn seeks_start(&self) -> bool {
+        self.start.is_some() && self.window.is_none()
+    }
+
+    /// Whether sources are read further ahead than their next item, which the merge holds.
+    pub(crate) fn reads_ahead(&self) -> bool {
+        self.window.is_some() || self.merges_in_parallel()
+    }
+
+    /// Whether groups of the sources are merged on threads of their own, which hold items of
+    /// their own.
+    pub(crate) fn merges_in_parallel(&self) -> bool {
+        matches!(self.strategy, Strategy::Parallel { .. })
+    }
+
+    /// The source at the head of the merge, if any.
+    pub(crate) fn first_source(&mut self) -> Option<&S> {
+        self.heap.peek().map(|head| &head.source.source)
+    }
+
+    /// How `a` compares to `b` in the merged order.
+    pub(crate) fn compare(&self, a: &S::Item, b: &S::Item) -> cmp::Ordering {
+        (self.cmp)(a, b)
+    }
+
+    /// Whether `item` sorts before the start of the range.
+    pub(crate) fn before_start(&self, item: &S::Item) -> bool {
+        match &self.start {
+            Some(start) => (self.cmp)(item, start) == cmp::Ordering::Less,
+            None => false,
+        }
+    }
+
+    /// Drop every source once the end of the range has been reached, closing them.
+    fn finish(&mut self) {
+        self.heap.drain();
+        self.end();
+    }
+
+    /// Set which of the items that compare equal are kept.
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> KWayMerge<S> {
+        self.duplicates = policy;
+        self
+    }
+
+    /// Replace each run of items that compare equal with what `merge` returns for them, in the
+    /// order their sources were added, or of a tie breaker, overriding the duplicate policy.
+    pub fn with_duplicate_merger<F>(mut self, merge: F) -> KWayMerge<S>
+    where
+        F: Fn(Vec<S::Item>) -> S::Item + Send + Sync + 'static,
+    {
+        self.merger = Some(sync::Arc::new(merge));
+        self
+    }
+
+    /// Whether runs of equal items are resolved into one rather than all kept.
+    fn resolves_duplicates(&self) -> bool {
+        self.duplicates != DuplicatePolicy::KeepAll || self.merger.is_some()
+    }
+
+    /// The item kept out of a run of equal ones, at the position of the first of them.
+    fn resolve(&self, group: Group<S::Item>) -> Indexed<S::Item> {
+        if let Some(merger) = &self.merger {
+            let (index, line_no, _) = group[0];
+            let items = group.into_iter().map(|(_, _, item)| item).collect();
+            return (index, line_no, merger(items));
+        }
+        let priorities = &self.priorities;
+        let kept = match self.duplicates {
+            DuplicatePolicy::KeepAll => group.into_iter().next(),
+            // The group is in the order of its sources, unless a tie breaker reordered it.
+            DuplicatePolicy::FirstSource => group.into_iter().min_by_key(|(index, _, _)| *index),
+            DuplicatePolicy::LastSource => group.into_iter().max_by_key(|(index, _, _)| *index),
+            DuplicatePolicy::HighestPriority => group
+                .into_iter()
+                .max_by_key(|(index, _, _)| (priorities[*index], *index)),
+        };
+        kept.expect("Groups are never empty")
+    }
+
+    /// Set what happens when a source turns out not to be sorted.
+    pub fn with_out_of_order_policy(mut self, policy: OutOfOrderPolicy) -> KWayMerge<S> {
+        self.policy = policy;
+        self
+    }
+
+    /// Set what happens when reading from a source fails.
+    pub fn with_source_error_policy(mut self, policy: SourceErrorPolicy) -> KWayMerge<S> {
+        self.source_errors = policy;
+        self
+    }
+
+    /// The sources removed from the merge under `SourceErrorPolicy::DropSource`, along with the
+    /// errors that removed them.
+    pub fn failed_sources(&self) -> &[(sync::Arc<str>, io::Error)] {
+        &self.failed
+    }
+
+    /// Handle an error reading from the source with index `index`: under
+    /// `SourceErrorPolicy::DropSource`, record it and return `None` so the caller carries on
+    /// without the source.
+    fn source_failed(&mut self, index: usize, err: io::Error) -> Option<io::Error> {
+        self.drop_failed(self.names[index].clone(), err)
+    }
+
+    fn drop_failed(&mut self, name: sync::Arc<str>, err: io::Error) -> Option<io::Error> {
+        let err = in_file(err, &name);
+        match self.source_errors {
+            SourceErrorPolicy::Fail => Some(err),
+            SourceErrorPolicy::DropSource => {
+                eprintln!("warning: dropping [{}]: {}", name, err);
+                self.failed.push((name, err));
+                None
+            }
+        }
+    }
+
+    /// Handle an error adding the source `name`, e.g. opening it, as `source_failed` handles
+    /// errors reading one, returning it unless the source is dropped.
+    pub(crate) fn failed_to_add(&mut self, name: &str, err: io::Error) -> io::Result<()> {
+        match self.drop_failed(sync::Arc::from(name), err) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Return `err` from the next call to `next`, unless an error is already to be returned.
+    pub(crate) fn defer(&mut self, err: io::Error) {
+        self.deferred.get_or_insert(err);
+    }
+
+    /// Add a source to the merge. `name` identifies it in error messages. Items that compare equal
+    /// are emitted in the order their sources were added, so the merge is stable.
+    pub fn add_source(&mut self, name: String, source: S) -> io::Result<()> {
+        self.add_source_with_priority(name, source, 0)
+    }
+
+    /// Like `add_source`, with `priority` for `DuplicatePolicy::HighestPriority`. Sources added
+    /// with `add_source` have priority 0.
+    ///
+    /// Sources can be added once the merge has started, e.g. as late shards arrive. Their items
+    /// are merged with what is left of the others, so the output stays sorted as long as none of
+    /// them sorts before the last item emitted, or peeked at; any that do are emitted next, out
+    /// of order.
+    pub fn add_source_with_priority(
+        &mut self,
+        name: String,
+        source: S,
+        priority: i64,
+    ) -> io::Result<()> {
+        self.priorities.push(priority);
+        self.names.push(sync::Arc::from(name));
+        let mut source = Buffered {
+            index: self.next_index,
+            source,
+            pending: collections::VecDeque::new(),
+            exhausted: false,
+            released: 0,
+            line_no: 0,
+        };
+        self.next_index += 1;
+        match source.next(self.window.as_ref(), &self.cmp) {
+            Ok(Some(item)) => self.heap.push(Head {
+                source,
+                item,
+                cmp: self.head_cmp.clone(),
+                interleave: self.interleave,
+            }),
+            Ok(None) => {}
+            Err(err) => {
+                if let Some(err) = self.source_failed(source.index, err) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop merging the first source added as `name` that is left to merge, and return the items
+    /// read from it but not yet emitted, in order, along with the source to read the rest from.
+    /// An item already peeked at is emitted regardless. Returns `None` if there is no such source,
+    /// including once a `Strategy::Parallel` merge has split its sources into groups.
+    pub fn remove_source(&mut self, name: &str) -> Option<(Vec<S::Item>, S)> {
+        if let Err(err) = self.refill() {
+            self.defer(err);
+        }
+        let names = &self.names;
+        let index = self
+            .heap
+            .iter()
+            .map(|head| head.source.index)
+            .filter(|index| &*names[*index] == name)
+            .min()?;
+        let Head { source, item, .. } = self.heap.remove(|head| head.source.index == index)?;
+        let mut items = vec![item];
+        items.extend(source.pending.into_iter().map(|(_, item)| item));
+        Some((items, source.source))
+    }
+
+    /// Take apart the merge: the item `peek` returned, if any, and every source that hasn't ended,
+    /// in the order they were added, with its name and the items read from it but not emitted.
+    pub(crate) fn into_sources(mut self) -> io::Result<MergeParts<S>> {
+        self.refill()?;
+        if let Some(err) = self.deferred.take() {
+            return Err(err);
+        }
+        let mut heads = Vec::new();
+        while let Some(head) = self.heap.pop() {
+            heads.push(head);
+        }
+        heads.sort_by_key(|head| head.source.index);
+        let sources = heads
+            .into_iter()
+            .map(|Head { source, item, .. }| {
+                let mut items = vec![item];
+                items.extend(source.pending.into_iter().map(|(_, item)| item));
+                (self.names[source.index].clone(), items, source.source)
+            })
+            .collect();
+        Ok((self.peeked.map(|(_, _, item)| item), sources))
+    }
+
+    /// Discard every head equal to `item`. Since the output is sorted, these are exactly the
+    /// duplicates that would have been emitted next. An error hit along the way is deferred until
+    /// the following call to `next`, so `item` itself is not lost.
+    fn skip_duplicates_of(&mut self, item: &S::Item) -> u64 {
+        let mut skipped = 0;
+        loop {
+            let cmp = &self.cmp;
+            let duplicate = match self.heap.peek() {
+                Some(head) => cmp(&head.item, item) == cmp::Ordering::Equal,
+                None => false,
+            };
+            if !duplicate {
+                break;
+            }
+            if let Some(Err(err)) = self.pop() {
+                self.deferred = Some(err);
+                break;
+            }
+            skipped += 1;
+        }
+        self.dropped += skipped;
+        skipped
+    }
+
+    /// Collapse each run of equal items into its first item, paired with the length of the run,
+    /// like `uniq -c`.
+    pub fn counted(mut self) -> impl Iterator<Item = io::Result<(u64, S::Item)>> {
+        iter::from_fn(move || loop {
+            if let Some((_, _, item)) = self.peeked.take() {
+                let count = 1 + self.skip_duplicates_of(&item);
+                return Some(Ok((count, item)));
+            }
+            if let Some(err) = self.deferred.take() {
+                return Some(Err(err));
+            }
+            let mut item = match self.pop_in_range()? {
+                Ok((_, _, item)) => item,
+                Err(err) => return Some(Err(err)),
+            };
+            let count = 1 + self.skip_duplicates_of(&item);
+            if self.emit(&mut item) {
+                return Some(Ok((count, item)));
+            }
+        })
+    }
+
+    /// Call `f` with each merged item in turn, stopping at the first error. Unlike `next`, the
+    /// buffer of each item is reused to read the one after it, so sources that implement
+    /// `SortedSource::next_into` are merged without allocating per item.
+    pub fn for_each_item<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&S::Item) -> io::Result<()>,
+    {
+        if self.output.is_some() || self.resolves_duplicates() {
+            // The items handed to `f` may have been rewritten, so they can't be compared with the
+            // next ones as below.
+            for item in self.by_ref() {
+                f(&item?)?;
+            }
+            return Ok(());
+        }
+        // The last item handed to `f`, or a duplicate of it that was skipped.
+        let mut spare = None;
+        if let Some((_, _, item)) = self.peeked.take() {
+            f(&item)?;
+            spare = Some(item);
+        }
+        if let Some(err) = self.deferred.take() {
+            return Err(err);
+        }
+        while let Some(bound) = self.bound() {
+            match bound {
+                Bound::After => {
+                    self.finish();
+                    return Ok(());
+                }
+                Bound::Cancelled => return Err(MergeError::Cancelled.into()),
+                Bound::Failed(err) => return Err(err),
+                Bound::Before | Bound::Within => {}
+            }
+            let cmp = &self.cmp;
+            let head = self.heap.peek().expect("The heap has a head");
+            let duplicate = self.dedup
+                && spare
+                    .as_ref()
+                    .map(|last| cmp(&head.item, last) == cmp::Ordering::Equal)
+                    .unwrap_or(false);
+            if duplicate {
+                self.dropped += 1;
+            } else if let Bound::Within = bound {
+                self.emitted += 1;
+                f(&head.item)?;
+            }
+            match self.pop_reusing(&mut spare) {
+                Some(Ok((_, _, item))) => spare = Some(item),
+                Some(Err(err)) => return Err(err),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `next`, but also return the name of the source the item came from.
+    pub fn next_with_source(&mut self) -> Option<io::Result<(sync::Arc<str>, S::Item)>> {
+        self.next_with_position()
+            .map(|next| next.map(|(name, _, item)| (name, item)))
+    }
+
+    /// Like `next_with_source`, but also return the 1-based position of the item in its source,
+    /// e.g. its line number. With a `ReorderWindow`, this is its position after re-sorting.
+    pub fn next_with_position(&mut self) -> Option<io::Result<Positioned<S::Item>>> {
+        let next = self.next_indexed()?;
+        Some(next.map(|(index, line_no, item)| (self.names[index].clone(), line_no, item)))
+    }
+
+    /// Like `next_with_position`, with the index of the source rather than its name.
+    fn next_indexed(&mut self) -> Option<io::Result<Indexed<S::Item>>> {
+        if let Some(next) = self.peeked.take() {
+            return Some(Ok(next));
+        }
+        loop {
+            if let Some(err) = self.deferred.take() {
+                return Some(Err(err));
+            }
+            let (index, line_no, mut item) = if self.resolves_duplicates() {
+                match self.next_group()? {
+                    Ok(group) => {
+                        self.dropped += group.len() as u64 - 1;
+                        self.resolve(group)
+                    }
+                    Err(err) => return Some(Err(err)),
+                }
+            } else {
+                match self.pop_in_range()? {
+                    Ok(next) => next,
+                    Err(err) => return Some(Err(err)),
+                }
+            };
+            if self.dedup {
+                self.skip_duplicates_of(&item);
+            }
+            if self.emit(&mut item) {
+                return Some(Ok((index, line_no, item)));
+            }
+        }
+    }
+
+    /// The item `next` returns next, without consuming it. An error is returned by whichever of
+    /// `peek` and `next` comes across it, only once.
+    pub fn peek(&mut self) -> Option<io::Result<&S::Item>> {
+        if self.peeked.is_none() {
+            match self.next_indexed()? {
+                Ok(next) => self.peeked = Some(next),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.peeked.as_ref().map(|(_, _, item)| Ok(item))
+    }
+
+    /// If only one source is left, remove it from the merge and return it along with its head
+    /// item, so the caller can drain it directly instead of going through the heap for every item.
+    pub(crate) fn take_last(&mut self) -> Option<Last<S>> {
+        // Errors from the last source are returned rather than recorded, so it can only be taken
+        // when they would be anyway.
+        if self.heap.len() != 1
+            || self.unfilled.is_some()
+            || self.peeked.is_some()
+            || self.deferred.is_some()
+            || self.source_errors != SourceErrorPolicy::Fail
+            || self.start.is_some()
+            || self.end.is_some()
+            || self.limit.is_some()
+            || self.output.is_some()
+            || self.resolves_duplicates()
+            || self.cancel.is_some()
+            || self.progress.is_some()
+        {
+            return None;
+        }
+        if self.heap.peek().map(|head| head.source.pending.is_empty()) != Some(true) {
+            return None;
+        }
+        let Head { source, item, .. } = self.heap.pop()?;
+        Some(Last {
+            name: self.names[source.index].clone(),
+            source: source.source,
+            item,
+            cmp: self.cmp.clone(),
+            policy: self.advance_policy(),
+            dedup: self.dedup,
+        })
+    }
+
+    /// How many sources have been added, including those that were empty or have been dropped.
+    pub(crate) fn sources(&self) -> usize {
+        self.next_index
+    }
+
+    /// How many sources are left to merge, excluding those that are exhausted or have been
+    /// dropped. Once a `Strategy::Parallel` merge has started, these are its groups.
+    pub fn len_sources(&self) -> usize {
+        self.heap.len() + self.unfilled.is_some() as usize
+    }
+
+    /// Whether there is nothing left to merge, neither sources nor an error to return.
+    pub fn is_empty(&self) -> bool {
+        self.heap.len() == 0
+            && self.unfilled.is_none()
+            && self.peeked.is_none()
+            && self.deferred.is_none()
+    }
+
+    /// How many items the merge holds, at the heads of the sources and in their reorder windows,
+    /// and peeked at.
+    pub(crate) fn held(&self) -> usize {
+        let heads: usize = self
+            .heap
+            .iter()
+            .map(|head| 1 + head.source.pending.len())
+            .sum();
+        heads + self.peeked.is_some() as usize
+    }
+
+    /// Pop the next run of equal items, each paired with the index of its source in the order
+    /// sources were added. An error hit after the first item is deferred to the next call.
+    pub(crate) fn next_group(&mut self) -> Option<io::Result<Group<S::Item>>> {
+        let first = match self.peeked.take() {
+            // Whoever takes the group counts it as emitted.
+            Some(first) => {
+                self.emitted -= 1;
+                Ok(first)
+            }
+            None => {
+                if let Some(err) = self.deferred.take() {
+                    return Some(Err(err));
+                }
+                self.pop_in_range()?
+            }
+        };
+        let mut group = match first {
+            Ok(first) => vec![first],
+            Err(err) => return Some(Err(err)),
+        };
+        loop {
+            let cmp = &self.cmp;
+            let equal = match self.heap.peek() {
+                Some(head) => cmp(&head.item, &group[0].2) == cmp::Ordering::Equal,
+                None => false,
+            };
+            if !equal {
+                break;
+            }
+            match self.pop() {
+                Some(Ok(next)) => group.push(next),
+                Some(Err(err)) => {
+                    self.deferred = Some(err);
+                    break;
+                }
+                None => break,
+            }
+        }
+        Some(Ok(group))
+    }
+
+    /// Like `pop`, but skip the items before the start of the range and stop at its end or limit.
+    fn pop_in_range(&mut self) -> Option<io::Result<Indexed<S::Item>>> {
+        loop {
+            match self.bound()? {
+                Bound::Before => {
+                    if let Err(err) = self.pop()? {
+                        return Some(Err(err));
+                    }
+                }
+                Bound::Within => return self.pop(),
+                Bound::After => {
+                    self.finish();
+                    return None;
+                }
+                Bound::Cancelled => return Some(Err(MergeError::Cancelled.into())),
+                Bound::Failed(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /// Pop the next item, along with the index of its source and its position there.
+    fn pop(&mut self) -> Option<io::Result<Indexed<S::Item>>> {
+        self.pop_reusing(&mut None)
+    }
+
+    /// Like `pop`, reading the item after it from its source into the item in `spare`, if there
+    /// is one. The head of the merge is replaced in place, so while one source keeps coming next,
+    /// the binary heap of `Strategy::Heap` only compares its item with the runner-up.
+    fn pop_reusing(&mut self, spare: &mut Option<S::Item>) -> Option<io::Result<Indexed<S::Item>>> {
+        if let Err(err) = self.refill() {
+            return Some(Err(err));
+        }
+        if let Some(copy) = &self.lazy {
+            if !self.dedup && !self.resolves_duplicates() {
+                let mut head = self.heap.pop()?;
+                let (index, line_no) = (head.source.index, head.source.released);
+                let copied = copy(&head.item);
+                let item = mem::replace(&mut head.item, copied);
+                self.unfilled = Some(head);
+                return Some(Ok((index, line_no, item)));
+            }
+        }
+        let policy = self.advance_policy();
+        let (window, cmp, names) = (self.window.as_ref(), &self.cmp, &self.names);
+        let (index, line_no, advanced) = self.heap.update_top(|head| {
+            let (index, line_no) = (head.source.index, head.source.released);
+            let name = &names[index];
+            (
+                index,
+                line_no,
+                head.advance(name, window, cmp, policy, spare),
+            )
+        })?;
+        match advanced {
+            Advanced::Replaced(item) => Some(Ok((index, line_no, item))),
+            Advanced::Exhausted => {
+                let head = self.heap.pop()?;
+                Some(Ok((index, line_no, head.item)))
+            }
+            Advanced::Failed(err) => {
+                let head = self.heap.pop()?;
+                match self.source_failed(index, err) {
+                    Some(err) => Some(Err(err)),
+                    None => Some(Ok((index, line_no, head.item))),
+                }
+            }
+            Advanced::OutOfOrder(err) => Some(Err(err)),
+        }
+    }
+}
+
+impl<S> Iterator for KWayMerge<S>
+where
+    S: SortedSource,
+{
+    type Item = io::Result<S::Item>;
+
+    fn next(&mut self) -> Option<io::Result<S::Item>> {
+        self.next_indexed()
+            .map(|next| next.map(|(_, _, item)| item))
+    }
+
+    /// At least the items held at the heads of the sources and in their reorder windows, which are
+    /// all emitted unless the merge is limited to a range, deduplicated or filtered, and at most
+    /// its limit, if it has one.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // The peeked item has been counted as emitted already.
+        let peeked = self.peeked.is_some() as usize;
+        let ready = peeked + self.deferred.is_some() as usize;
+        let held = self.held() - peeked;
+        if held == 0 {
+            return (ready, Some(ready));
+        }
+        let filtered = self.start.is_some() || self.end.is_some() || self.output.is_some();
+        let lower = if filtered {
+            0
+        } else if self.dedup || self.resolves_duplicates() {
+            1
+        } else {
+            held
+        };
+        match self.limit {
+            Some(limit) => {
+                let left = limit.saturating_sub(self.emitted) as usize;
+                (lower.min(left) + ready, Some(left + ready))
+            }
+            None => (lower + ready, None),
+        }
+    }
+}
+
+/// The heads of a merge, held in the data structure chosen by its `Strategy`.
+enum Queue<T>
+where
+    T: Ord,
+{
+    Heap(collections::BinaryHeap<T>),
+    LoserTree(LoserTree<T>),
+}
+
+impl<T> Queue<T>
+where
+    T: Ord,
+{
+    fn new(strategy: Strategy) -> Queue<T> {
+        match strategy {
+            Strategy::Heap | Strategy::Parallel { .. } => {
+                Queue::Heap(collections::BinaryHeap::new())
+            }
+            Strategy::LoserTree => Queue::LoserTree(LoserTree::new()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Queue::Heap(heap) => heap.len(),
+            Queue::LoserTree(tree) => tree.len(),
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        match self {
+            Queue::Heap(heap) => heap.push(item),
+            Queue::LoserTree(tree) => tree.push(item),
+        }
+    }
+
+    fn peek(&mut self) -> Option<&T> {
+        match self {
+            Queue::Heap(heap) => heap.peek(),
+            Queue::LoserTree(tree) => tree.peek(),
+        }
+    }
+
+    /// Every item, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        match self {
+            Queue::Heap(heap) => Box::new(heap.iter()),
+            Queue::LoserTree(tree) => Box::new(tree.iter()),
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        match self {
+            Queue::Heap(heap) => heap.pop(),
+            Queue::LoserTree(tree) => tree.pop(),
+        }
+    }
+
+    /// Call `f` with the greatest item, which it may change, and then restore the order.
+    fn update_top<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Option<R> {
+        match self {
+            Queue::Heap(heap) => heap.peek_mut().map(|mut top| f(&mut top)),
+            Queue::LoserTree(tree) => tree.update_top(f),
+        }
+    }
+
+    /// Remove the first item, in no particular order, that `f` returns true for.
+    fn remove<F: FnMut(&T) -> bool>(&mut self, f: F) -> Option<T> {
+        match self {
+            Queue::Heap(heap) => {
+                let mut items = mem::take(heap).into_vec();
+                let removed = items.iter().position(f).map(|i| items.swap_remove(i));
+                *heap = items.into();
+                removed
+            }
+            Queue::LoserTree(tree) => tree.remove(f),
+        }
+    }
+
+    /// Remove and return every item, in no particular order.
+    fn drain(&mut self) -> Vec<T> {
+        match self {
+            Queue::Heap(heap) => heap.drain().collect(),
+            Queue::LoserTree(tree) => {
+                let tree = mem::replace(tree, LoserTree::new());
+                tree.into_vec()
+            }
+        }
+    }
+}
+
+/// The last remaining source of a merge, along with the settings needed to keep merging it.
+pub(crate) struct Last<S>
+where
+    S: SortedSource,
+{
+    pub(crate) name: sync::Arc<str>,
+    pub(crate) source: S,
+    pub(crate) item: S::Item,
+    pub(crate) cmp: ItemComparator<S::Item>,
+    pub(crate) policy: OutOfOrderPolicy,
+    pub(crate) dedup: bool,
+}
+
+/// A source along with the items read ahead from it to fill its `ReorderWindow`, if any, and its
+/// position in the order sources were added.
+struct Buffered<S>
+where
+    S: SortedSource,
+{
+    index: usize,
+    source: S,
+    /// The items read ahead, with the lines they started on if the source counts them.
+    pending: collections::VecDeque<(Option<u64>, S::Item)>,
+    exhausted: bool,
+    /// How many items have been released to the merge.
+    released: u64,
+    /// The line the item released last started on, if the source counts lines, and otherwise its
+    /// position, `released`.
+    line_no: u64,
+}
+
+impl<S> Buffered<S>
+where
+    S: SortedSource,
+{
+    /// Like `next`, but read into the item in `spare`, if there is one, rather than allocating a
+    /// new one. The spare is left in place if the source is exhausted.
+    fn next_reusing(
+        &mut self,
+        window: Option<&ReorderWindow<S::Item>>,
+        cmp: &ItemComparator<S::Item>,
+        spare: &mut Option<S::Item>,
+    ) -> io::Result<Option<S::Item>> {
+        match (window, spare.take()) {
+            (None, Some(mut item)) => {
+                if self.source.next_into(&mut item)? {
+                    self.released += 1;
+                    self.line_no = self.source.line_no().unwrap_or(self.released);
+                    Ok(Some(item))
+                } else {
+                    *spare = Some(item);
+                    Ok(None)
+                }
+            }
+            _ => self.next(window, cmp),
+        }
+    }
+
+    fn next(
+        &mut self,
+        window: Option<&ReorderWindow<S::Item>>,
+        cmp: &ItemComparator<S::Item>,
+    ) -> io::Result<Option<S::Item>> {
+        let next = self.read(window, cmp)?;
+        Ok(next.map(|(line_no, item)| {
+            self.released += 1;
+            self.line_no = line_no.unwrap_or(self.released);
+            item
+        }))
+    }
+
+    /// The error for `next`, the item just released, sorting before `prev`.
+    fn out_of_order(&self, name: &str, prev: &S::Item, next: &S::Item) -> io::Error {
+        out_of_order_error(
+            name,
+            self.line_no,
+            self.source.describe(prev),
+            self.source.describe(next),
+        )
+    }
+
+    fn read(
+        &mut self,
+        window: Option<&ReorderWindow<S::Item>>,
+        cmp: &ItemComparator<S::Item>,
+    ) -> io::Result<Option<(Option<u64>, S::Item)>> {
+        let window = match window {
+            Some(window) => window,
+            None => {
+                let next = self.source.next()?;
+                return Ok(next.map(|item| (self.source.line_no(), item)));
+            }
+        };
+        while !self.exhausted {
+            if let ReorderWindow::Items(n) = window {
+                if self.pending.len() > *n {
+                    break;
+                }
+            }
+            let item = match self.source.next()? {
+                Some(item) => item,
+                None => {
+                    self.exhausted = true;
+                    break;
+                }
+            };
+            let settled = match (window, self.pending.front()) {
+                (ReorderWindow::Settled(settled), Some((_, front))) => settled(front, &item),
+                _ => false,
+            };
+            let i = self
+                .pending
+                .partition_point(|(_, pending)| cmp(pending, &item) != cmp::Ordering::Greater);
+            self.pending.insert(i, (self.source.line_no(), item));
+            if settled {
+                break;
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+/// A source along with the item at its head, ordered by that item unless the merge interleaves
+/// its sources otherwise.
+struct Head<S>
+where
+    S: SortedSource,
+{
+    source: Buffered<S>,
+    item: S::Item,
+    cmp: ItemComparator<S::Item>,
+    interleave: Interleave,
+}
+
+/// What reading the item after the one at a head came to.
+enum Advanced<I> {
+    /// It took the place of the item at the head, which is this.
+    Replaced(I),
+    Exhausted,
+    Failed(io::Error),
+    /// It is out of order under `OutOfOrderPolicy::Error`, and took the place of the item at the
+    /// head regardless.
+    OutOfOrder(io::Error),
+}
+
+impl<S> Head<S>
+where
+    S: SortedSource,
+{
+    /// Read the item after the one at the head from the source `name`, reusing the item in
+    /// `spare` if there is one, and check that it is in order.
+    fn advance(
+        &mut self,
+        name: &str,
+        window: Option<&ReorderWindow<S::Item>>,
+        cmp: &ItemComparator<S::Item>,
+        policy: OutOfOrderPolicy,
+        spare: &mut Option<S::Item>,
+    ) -> Advanced<S::Item> {
+        loop {
+            let next_item = match self.source.next_reusing(window, cmp, spare) {
+                Ok(Some(next_item)) => next_item,
+                Ok(None) => return Advanced::Exhausted,
+                Err(err) => return Advanced::Failed(err),
+            };
+            if cmp(&next_item, &self.item) == cmp::Ordering::Less {
+                #[cfg(feature = "tracing")]
+                crate::trace::out_of_order(name, self.source.line_no, policy);
+                match policy {
+                    OutOfOrderPolicy::Error => {
+                        let err = self.source.out_of_order(name, &self.item, &next_item);
+                        self.item = next_item;
+                        return Advanced::OutOfOrder(err);
+                    }
+                    OutOfOrderPolicy::Skip => {
+                        *spare = Some(next_item);
+                        continue;
+                    }
+                    OutOfOrderPolicy::EmitAnyway => {}
+                    OutOfOrderPolicy::WarnAndContinue => {
+                        let err = self.source.out_of_order(name, &self.item, &next_item);
+                        eprintln!("warning: {}", err)
+                    }
+                }
+            }
+            return Advanced::Replaced(mem::replace(&mut self.item, next_item));
+        }
+    }
+}
+
+impl<S> PartialEq for Head<S>
+where
+    S: SortedSource,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.source.index == other.source.index
+    }
+}
+
+impl<S> Eq for Head<S> where S: SortedSource {}
+
+impl<S> Ord for Head<S>
+where
+    S: SortedSource,
+{
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        let order = match self.interleave {
+            Interleave::Sorted => (self.cmp)(&self.item, &other.item),
+            // The source that has released the fewest items has had the fewest turns.
+            Interleave::RoundRobin => self.source.released.cmp(&other.source.released),
+            Interleave::Concat => cmp::Ordering::Equal,
+        };
+        order
+            .then_with(|| self.source.index.cmp(&other.source.index))
+            .reverse()
+    }
+}
+
+impl<S> PartialOrd for Head<S>
+where
+    S: SortedSource,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A multi-pass merge of files that opens at most `max_fan_in` of them at once. Each intermediate
+/// pass merges groups of inputs into temporary spill files, which are removed as soon as the next
+/// pass has consumed them.
+pub struct Plan {
+    inputs: Vec<path::PathBuf>,
+    max_fan_in: usize,
+    temp_dir: path::PathBuf,
+}
+
+/// Plan a merge of `inputs` that never opens more than `max_fan_in` files at once.
+///
+/// # Panics
+///
+/// Panics if `max_fan_in` is less than 2.
+pub fn plan(inputs: Vec<path::PathBuf>, max_fan_in: usize) -> Plan {
+    assert!(max_fan_in >= 2, "max_fan_in must be at least 2");
+    Plan {
+        inputs,
+        max_fan_in,
+        temp_dir: env::temp_dir(),
+    }
+}
+
+impl Plan {
+    /// Set the directory spill files are written to. Defaults to `std::env::temp_dir()`.
+    pub fn with_temp_dir<P: Into<path::PathBuf>>(mut self, temp_dir: P) -> Plan {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// The number of passes over the data the merge will take, including the final one.
+    pub fn passes(&self) -> usize {
+        let mut runs = self.inputs.len();
+        let mut passes = 1;
+        while runs > self.max_fan_in {
+            runs = runs.div_ceil(self.max_fan_in);
+            passes += 1;
+        }
+        passes
+    }
+
+    /// Run the merge, writing the final output to `w`. `new_heap` is called once per group to
+    /// create the heap for it, so every pass merges with the same ordering. Returns the number of
+    /// lines written to `w`.
+    pub fn run<K, F, W>(&self, mut new_heap: F, w: W) -> io::Result<u64>
+    where
+        F: FnMut() -> Heap<fs::File, K>,
+        W: io::Write,
+    {
+        let mut runs: Vec<Run> = self.inputs.iter().cloned().map(Run::input).collect();
+        while runs.len() > self.max_fan_in {
+            let mut next_runs = Vec::new();
+            let mut remaining = runs.into_iter();
+            loop {
+                let group: Vec<Run> = remaining.by_ref().take(self.max_fan_in).collect();
+                match group.len() {
+                    0 => break,
+                    1 => next_runs.extend(group),
+                    _ => {
+                        let spill = Run::spill(&self.temp_dir)?;
+                        let f = fs::File::create(&spill.path)?;
+                        merge_runs(new_heap(), &group)?.write_sorted_lines(f)?;
+                        next_runs.push(spill);
+                    }
+                }
+            }
+            runs = next_runs;
+        }
+        merge_runs(new_heap(), &runs)?.write_sorted_lines(w)
+    }
+}
+
+fn merge_runs<K>(mut heap: Heap<fs::File, K>, runs: &[Run]) -> io::Result<Heap<fs::File, K>> {
+    for run in runs {
+        heap.add_file(&run.path)?;
+    }
+    Ok(heap)
+}
+
+/// One input to a pass of a `Plan`: either an original input file or a spill file, which is
+/// deleted when dropped.
+pub(crate) struct Run {
+    pub(crate) path: path::PathBuf,
+    temporary: bool,
+}
+
+impl Run {
+    fn input(path: path::PathBuf) -> Run {
+        Run {
+            path,
+            temporary: false,
+        }
+    }
+
+    pub(crate) fn spill(temp_dir: &path::Path) -> io::Result<Run> {
+        static SPILLS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        let n = SPILLS.fetch_add(1, atomic::Ordering::SeqCst);
+        let path = temp_dir.join(format!("merge-sorted-files-rs-{}-{}.tmp", process::id(), n));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Run {
+            path,
+            temporary: true,
+        })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VecSource(std::vec::IntoIter<u32>);
+
+    impl SortedSource for VecSource {
+        type Item = u32;
+
+        fn next(&mut self) -> io::Result<Option<u32>> {
+            Ok(self.0.next())
+        }
+    }
+
+    fn source(items: Vec<u32>) -> VecSource {
+        VecSource(items.into_iter())
+    }
+
+    struct PairSource(std::vec::IntoIter<(u32, &'static str)>);
+
+    impl SortedSource for PairSource {
+        type Item = (u32, &'static str);
+
+        fn next(&mut self) -> io::Result<Option<(u32, &'static str)>> {
+            Ok(self.0.next())
+        }
+    }
+
+    #[test]
+    fn test_merge() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![1, 4, 9]))?;
+        merge.add_source("b".to_string(), source(vec![2, 3, 10]))?;
+        merge.add_source("c".to_string(), source(vec![]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![1, 2, 3, 4, 9, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_descending() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_order(Order::Desc);
+        merge.add_source("a".to_string(), source(vec![9, 4, 1]))?;
+        merge.add_source("b".to_string(), source(vec![10, 3, 2]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![10, 9, 4, 3, 2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_hint() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_limit(4);
+        merge.add_source("a".to_string(), source(vec![1, 4, 9]))?;
+        merge.add_source("b".to_string(), source(vec![2, 3]))?;
+        merge.add_source("c".to_string(), source(vec![]))?;
+        assert_eq!((merge.len_sources(), merge.size_hint()), (2, (2, Some(4))));
+        merge.by_ref().take(3).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!((merge.len_sources(), merge.size_hint()), (1, (1, Some(1))));
+        merge.next().transpose()?;
+        assert_eq!(merge.size_hint(), (0, Some(0)));
+        assert!(merge.next().is_none());
+
+        let mut merge = KWayMerge::new().dedup(true);
+        merge.add_source("a".to_string(), source(vec![1, 2]))?;
+        merge.add_source("b".to_string(), source(vec![1]))?;
+        assert_eq!(merge.size_hint(), (1, None));
+        merge.by_ref().collect::<io::Result<Vec<_>>>()?;
+        assert!(merge.is_empty());
+        assert_eq!(merge.size_hint(), (0, Some(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().dedup(true);
+        merge.add_source("a".to_string(), source(vec![1, 3, 3]))?;
+        merge.add_source("b".to_string(), source(vec![2, 1]))?;
+        assert_eq!(merge.peek().transpose()?, Some(&1));
+        assert_eq!(merge.next().transpose()?, Some(1));
+        // The error is returned once, by the first call to come across it.
+        assert!(merge.peek().expect("An error").is_err());
+        assert_eq!(merge.peek().transpose()?, Some(&1));
+        assert_eq!(merge.peek().transpose()?, Some(&1));
+        let mut items = Vec::new();
+        merge.for_each_item(|item| {
+            items.push(*item);
+            Ok(())
+        })?;
+        assert_eq!(items, vec![1, 3]);
+        assert!(merge.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_source() -> Result<(), io::Error> {
+        for strategy in &[Strategy::Heap, Strategy::LoserTree] {
+            let mut merge = KWayMerge::new()
+                .with_strategy(*strategy)
+                .with_reorder_window(ReorderWindow::Items(1));
+            merge.add_source("a".to_string(), source(vec![1, 4, 9]))?;
+            merge.add_source("b".to_string(), source(vec![2, 6, 3]))?;
+            merge.add_source("b".to_string(), source(vec![5]))?;
+            assert_eq!(merge.next().transpose()?, Some(1));
+            let (items, mut rest) = merge.remove_source("b").expect("A source named b");
+            assert_eq!((items, rest.next()?), (vec![2, 6], Some(3)));
+            merge.add_source("c".to_string(), source(vec![0, 7]))?;
+            let items = merge.by_ref().collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(items, vec![0, 4, 5, 7, 9]);
+            assert!(merge.remove_source("a").is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_iters() -> Result<(), io::Error> {
+        let words = |words: &[&str]| -> Vec<io::Result<String>> {
+            words.iter().map(|word| Ok(word.to_string())).collect()
+        };
+        let merge = merge_iters(vec![words(&["a", "d"]), words(&["b", "c"]), words(&[])])?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec!["a", "b", "c", "d"]);
+
+        let failing = vec![Ok(1), Err(io::Error::other("Read failed"))];
+        let merge = merge_iters(vec![failing, vec![Ok(3), Ok(2)]])?;
+        let items: Vec<Result<u32, String>> = merge
+            .map(|item| item.map_err(|err| err.to_string()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                Err("Error reading file [iterator 1]: Read failed".to_string()),
+                Err("Input lines in file [iterator 2] out of order at line 2!".to_string()),
+                Ok(2),
+            ]
+        );
+        Ok(())
+    }
+
+    /// A source that fetches its items a page at a time, as from a paginated API.
+    struct Pages(Vec<Vec<&'static str>>, std::vec::IntoIter<&'static str>);
+
+    impl SortedSource for Pages {
+        type Item = String;
+
+        fn next(&mut self) -> io::Result<Option<String>> {
+            loop {
+                if let Some(item) = self.1.next() {
+                    return Ok(Some(item.to_string()));
+                }
+                if self.0.is_empty() {
+                    return Ok(None);
+                }
+                self.1 = self.0.remove(0).into_iter();
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_boxed_sources() -> Result<(), io::Error> {
+        let mut merge: KWayMerge<Box<dyn SortedSource<Item = String>>> = KWayMerge::new();
+        merge.add_source(
+            "file".to_string(),
+            Box::new(crate::RecordReader::new("b\ne\n".as_bytes())?),
+        )?;
+        let pages = Pages(
+            vec![vec!["a", "c"], vec![], vec!["f"]],
+            Vec::new().into_iter(),
+        );
+        merge.add_source("api".to_string(), Box::new(pages))?;
+        let rows = vec![Ok("d".to_string())].into_iter();
+        merge.add_source("cursor".to_string(), Box::new(IterSource(rows)))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec!["a", "b", "c", "d", "e", "f"]);
+
+        let format = crate::RecordFormat {
+            delimiter: b',',
+            ..crate::RecordFormat::default()
+        };
+        let mut merge = KWayMerge::new();
+        let reader = crate::RecordReader::with_format("b,a,".as_bytes(), format)?;
+        merge.add_source("file".to_string(), reader)?;
+        let err = merge
+            .next()
+            .expect("An error")
+            .expect_err("Expected an error");
+        match MergeError::from_io(&err) {
+            Some(MergeError::OutOfOrder { prev, next, .. }) => {
+                assert_eq!((prev.as_deref(), next.as_deref()), (Some("b"), Some("a")))
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_ooo() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![2, 1]))?;
+        let err = merge.next().unwrap().expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [a] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_passes() {
+        let inputs = |n: usize| (0..n).map(|i| path::PathBuf::from(i.to_string())).collect();
+        assert_eq!(plan(inputs(0), 2).passes(), 1);
+        assert_eq!(plan(inputs(2), 2).passes(), 1);
+        assert_eq!(plan(inputs(3), 2).passes(), 2);
+        assert_eq!(plan(inputs(5), 2).passes(), 3);
+        assert_eq!(plan(inputs(100), 10).passes(), 2);
+    }
+
+    #[test]
+    fn test_plan_run() -> Result<(), io::Error> {
+        let dir =
+            env::temp_dir().join(format!("merge-sorted-files-rs-test-plan-{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let mut inputs = Vec::new();
+        for (i, contents) in ["a\nf\n", "b\ng\n", "c\n", "d\nh\n", "e\n"]
+            .iter()
+            .enumerate()
+        {
+            let input = dir.join(format!("input{}", i));
+            fs::write(&input, contents)?;
+            inputs.push(input);
+        }
+        let spill_dir = dir.join("spill");
+        fs::create_dir_all(&spill_dir)?;
+        let mut out = Vec::new();
+        let lines = plan(inputs, 2)
+            .with_temp_dir(&spill_dir)
+            .run(Heap::new, &mut out)?;
+        assert_eq!(lines, 8);
+        assert_eq!(out, b"a\nb\nc\nd\ne\nf\ng\nh\n");
+        assert_eq!(fs::read_dir(&spill_dir)?.count(), 0);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_ooo_skip() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_out_of_order_policy(OutOfOrderPolicy::Skip);
+        merge.add_source("a".to_string(), source(vec![2, 1, 0, 5, 3, 6]))?;
+        merge.add_source("b".to_string(), source(vec![4]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![2, 4, 5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_ooo_emit_anyway() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_out_of_order_policy(OutOfOrderPolicy::EmitAnyway);
+        merge.add_source("a".to_string(), source(vec![2, 1, 5]))?;
+        merge.add_source("b".to_string(), source(vec![4]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![2, 1, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_reorder_window_items() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_reorder_window(ReorderWindow::Items(2));
+        merge.add_source("a".to_string(), source(vec![3, 1, 2, 6, 4, 5]))?;
+        merge.add_source("b".to_string(), source(vec![0, 7]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_reorder_window_items_exceeded() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_reorder_window(ReorderWindow::Items(1));
+        merge.add_source("a".to_string(), source(vec![3, 2, 1]))?;
+        let err = merge.next().unwrap().expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [a] out of order at line 2!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_reorder_window_settled() -> Result<(), io::Error> {
+        let settled = |a: &u32, b: &u32| *b > a + 10;
+        let mut merge =
+            KWayMerge::new().with_reorder_window(ReorderWindow::Settled(sync::Arc::new(settled)));
+        merge.add_source("a".to_string(), source(vec![10, 5, 12, 30, 25, 50]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![5, 10, 12, 25, 30, 50]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dedup() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().dedup(true);
+        merge.add_source("a".to_string(), source(vec![1, 1, 2, 4]))?;
+        merge.add_source("b".to_string(), source(vec![1, 2, 3, 4, 4]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_counted() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![1, 1, 2, 4]))?;
+        merge.add_source("b".to_string(), source(vec![1, 3, 4]))?;
+        let items = merge.counted().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![(3, 1), (1, 2), (1, 3), (2, 4)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_next_with_source() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![1, 3]))?;
+        merge.add_source("b".to_string(), source(vec![2]))?;
+        let (name, item) = merge.next_with_source().unwrap()?;
+        assert_eq!((&*name, item), ("a", 1));
+        let (name, item) = merge.next_with_source().unwrap()?;
+        assert_eq!((&*name, item), ("b", 2));
+        let (name, item) = merge.next_with_source().unwrap()?;
+        assert_eq!((&*name, item), ("a", 3));
+        assert!(merge.next_with_source().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_stable() -> Result<(), io::Error> {
+        let mut merge =
+            KWayMerge::with_comparator(|a: &(u32, &str), b: &(u32, &str)| a.0.cmp(&b.0));
+        let sources = vec![
+            ("z", vec![(1, "z1"), (2, "z2")]),
+            ("a", vec![(1, "a1"), (2, "a2")]),
+        ];
+        for (name, items) in sources {
+            merge.add_source(name.to_string(), PairSource(items.into_iter()))?;
+        }
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        let names: Vec<_> = items.iter().map(|(_, name)| *name).collect();
+        assert_eq!(names, vec!["z1", "a1", "z2", "a2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_duplicate_policy() -> Result<(), io::Error> {
+        let merge = |policy: DuplicatePolicy| -> io::Result<Vec<&str>> {
+            let mut merge =
+                KWayMerge::with_comparator(|a: &(u32, &str), b: &(u32, &str)| a.0.cmp(&b.0))
+                    .with_duplicate_policy(policy);
+            let sources = vec![
+                ("a", vec![(1, "a1"), (2, "a2")], 2),
+                ("b", vec![(1, "b1"), (3, "b3")], 1),
+                ("c", vec![(1, "c1"), (2, "c2")], 0),
+            ];
+            for (name, items, priority) in sources {
+                merge.add_source_with_priority(
+                    name.to_string(),
+                    PairSource(items.into_iter()),
+                    priority,
+                )?;
+            }
+            let items = merge.collect::<io::Result<Vec<_>>>()?;
+            Ok(items.into_iter().map(|(_, name)| name).collect())
+        };
+        assert_eq!(
+            merge(DuplicatePolicy::KeepAll)?,
+            vec!["a1", "b1", "c1", "a2", "c2", "b3"]
+        );
+        assert_eq!(merge(DuplicatePolicy::FirstSource)?, vec!["a1", "a2", "b3"]);
+        assert_eq!(merge(DuplicatePolicy::LastSource)?, vec!["c1", "c2", "b3"]);
+        assert_eq!(
+            merge(DuplicatePolicy::HighestPriority)?,
+            vec!["a1", "a2", "b3"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_duplicate_merger() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::with_comparator(|a: &u32, b: &u32| (a / 10).cmp(&(b / 10)))
+            .with_duplicate_merger(|items: Vec<u32>| items.into_iter().max().unwrap_or(0))
+            .with_limit(2);
+        merge.add_source("a".to_string(), source(vec![11, 22, 33]))?;
+        merge.add_source("b".to_string(), source(vec![15, 37]))?;
+        let mut items = Vec::new();
+        while let Some(next) = merge.next_with_position() {
+            let (name, line_no, item) = next?;
+            items.push((name.to_string(), line_no, item));
+        }
+        assert_eq!(
+            items,
+            vec![("a".to_string(), 1, 15), ("a".to_string(), 2, 22)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_loser_tree() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().with_strategy(Strategy::LoserTree);
+        merge.add_source("a".to_string(), source(vec![1, 4, 9]))?;
+        merge.add_source("b".to_string(), source(vec![2, 3, 10]))?;
+        merge.add_source("c".to_string(), source(vec![]))?;
+        merge.add_source("d".to_string(), source(vec![2, 5]))?;
+        let items = merge.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(items, vec![1, 2, 2, 3, 4, 5, 9, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_long_runs() -> Result<(), io::Error> {
+        for strategy in [Strategy::Heap, Strategy::LoserTree] {
+            let mut merge = KWayMerge::new().with_strategy(strategy).count_comparisons();
+            for i in 0..8 {
+                merge.add_source(i.to_string(), source((i * 1000..(i + 1) * 1000).collect()))?;
+            }
+            let items = merge.by_ref().collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(items, (0..8000).collect::<Vec<_>>());
+            if strategy == Strategy::Heap {
+                // Checking each item is in order, and that it still comes first.
+                let comparisons = merge.progress().comparisons.unwrap_or_default();
+                assert!(comparisons < 3 * 8000 + 100, "{} comparisons", comparisons);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_next_with_position() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![1, 3]))?;
+        merge.add_source("b".to_string(), source(vec![2]))?;
+        let mut items = Vec::new();
+        while let Some(next) = merge.next_with_position() {
+            let (name, line_no, item) = next?;
+            items.push((name.to_string(), line_no, item));
+        }
+        assert_eq!(
+            items,
+            vec![
+                ("a".to_string(), 1, 1),
+                ("b".to_string(), 1, 2),
+                ("a".to_string(), 2, 3)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_for_each() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new().dedup(true);
+        merge.add_source("a".to_string(), source(vec![1, 1, 4, 9]))?;
+        merge.add_source("b".to_string(), source(vec![1, 3, 9, 10]))?;
+        let mut items = Vec::new();
+        merge.for_each_item(|item| {
+            items.push(*item);
+            Ok(())
+        })?;
+        assert_eq!(items, vec![1, 3, 4, 9, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_for_each_ooo() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        merge.add_source("a".to_string(), source(vec![1, 3, 2]))?;
+        let mut items = Vec::new();
+        let err = merge
+            .for_each_item(|item| {
+                items.push(*item);
+                Ok(())
+            })
+            .expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [a] out of order at line 3!"
+        );
+        assert_eq!(items, vec![1, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_range() -> Result<(), io::Error> {
+        let merge = || -> io::Result<KWayMerge<VecSource>> {
+            let mut merge = KWayMerge::new().with_start(3).with_end(9).dedup(true);
+            merge.add_source("a".to_string(), source(vec![1, 4, 4, 9, 10]))?;
+            merge.add_source("b".to_string(), source(vec![2, 3, 4, 7, 9, 11, 5]))?;
+            Ok(merge)
+        };
+        assert_eq!(merge()?.collect::<io::Result<Vec<_>>>()?, vec![3, 4, 7, 9]);
+        let mut items = Vec::new();
+        merge()?.with_limit(2).for_each_item(|item| {
+            items.push(*item);
+            Ok(())
+        })?;
+        assert_eq!(items, vec![3, 4]);
+        let counted = merge()?.with_limit(3).counted();
+        assert_eq!(
+            counted.collect::<io::Result<Vec<_>>>()?,
+            vec![(1, 3), (3, 4), (1, 7)]
+        );
+        let merge = merge()?.with_end_before(9);
+        assert_eq!(merge.collect::<io::Result<Vec<_>>>()?, vec![3, 4, 7]);
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! A tournament tree of losers, used as an alternative to `BinaryHeap` for wide merges.
+
+/// A priority queue that, like `BinaryHeap`, yields its greatest element first. Internal nodes
+/// record the loser of the match played there, so replacing the winner only replays the matches
+/// along one leaf-to-root path. Popping the winner defers that replay until the next operation,
+/// which makes the pop-then-push of a merge step cost a single pass up the tree.
+pub(crate) struct LoserTree<T>
+where
+    T: Ord,
+{
+    leaves: Vec<Option<T>>,
+    /// `tree[0]` is the leaf that won overall; `tree[i]` for `i >= 1` is the leaf that lost at
+    /// internal node `i`. Leaf `j` sits at node `leaves.len() + j`.
+    tree: Vec<usize>,
+    /// A leaf emptied by `pop` whose path has not been replayed yet.
+    vacated: Option<usize>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl<T> LoserTree<T>
+where
+    T: Ord,
+{
+    pub(crate) fn new() -> LoserTree<T> {
+        LoserTree {
+            leaves: Vec::new(),
+            tree: Vec::new(),
+            vacated: None,
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, item: T) {
+        self.len += 1;
+        if let Some(leaf) = self.vacated.take() {
+            self.leaves[leaf] = Some(item);
+            self.replay(leaf);
+        } else if let Some(leaf) = self.free.pop() {
+            // Only the path of the previous winner can be replayed incrementally, so filling any
+            // other empty leaf means replaying every match.
+            self.leaves[leaf] = Some(item);
+            self.rebuild();
+        } else {
+            self.leaves.push(Some(item));
+            self.rebuild();
+        }
+    }
+
+    pub(crate) fn peek(&mut self) -> Option<&T> {
+        self.settle();
+        let winner = *self.tree.first()?;
+        self.leaves[winner].as_ref()
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        self.settle();
+        let winner = *self.tree.first()?;
+        let item = self.leaves[winner].take()?;
+        self.len -= 1;
+        self.vacated = Some(winner);
+        Some(item)
+    }
+
+    /// Call `f` with the winner, which it may change, and replay its path.
+    pub(crate) fn update_top<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Option<R> {
+        self.settle();
+        let winner = *self.tree.first()?;
+        let updated = f(self.leaves[winner].as_mut()?);
+        self.replay(winner);
+        Some(updated)
+    }
+
+    /// Remove the first item, in no particular order, that `f` returns true for, and replay every
+    /// match.
+    pub(crate) fn remove<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Option<T> {
+        self.settle();
+        let leaf = self
+            .leaves
+            .iter()
+            .position(|item| item.as_ref().is_some_and(&mut f))?;
+        let item = self.leaves[leaf].take();
+        self.len -= 1;
+        self.free.push(leaf);
+        self.rebuild();
+        item
+    }
+
+    /// Every item, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.leaves.iter().flatten()
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<T> {
+        self.leaves.into_iter().flatten().collect()
+    }
+
+    /// Replay the path of a leaf vacated by `pop` that nothing was pushed into.
+    fn settle(&mut self) {
+        if let Some(leaf) = self.vacated.take() {
+            self.replay(leaf);
+            self.free.push(leaf);
+        }
+    }
+
+    /// Whether leaf `a` beats leaf `b`. Empty leaves lose to everything.
+    fn beats(&self, a: usize, b: usize) -> bool {
+        match (&self.leaves[a], &self.leaves[b]) {
+            (Some(a), Some(b)) => a > b,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    fn replay(&mut self, leaf: usize) {
+        let mut winner = leaf;
+        let mut node = (self.leaves.len() + leaf) / 2;
+        while node > 0 {
+            let loser = self.tree[node];
+            if self.beats(loser, winner) {
+                self.tree[node] = winner;
+                winner = loser;
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+    }
+
+    fn rebuild(&mut self) {
+        self.tree = vec![0; self.leaves.len()];
+        self.tree[0] = self.build(1);
+    }
+
+    /// Play every match in the subtree rooted at `node`, returning the subtree's winner.
+    fn build(&mut self, node: usize) -> usize {
+        let n = self.leaves.len();
+        if node >= n {
+            return node - n;
+        }
+        let left = self.build(2 * node);
+        let right = self.build(2 * node + 1);
+        if self.beats(left, right) {
+            self.tree[node] = right;
+            left
+        } else {
+            self.tree[node] = left;
+            right
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pops_greatest_first() {
+        let mut tree = LoserTree::new();
+        for item in &[3, 1, 4, 1, 5, 9, 2, 6] {
+            tree.push(*item);
+        }
+        let mut items = Vec::new();
+        while let Some(item) = tree.pop() {
+            items.push(item);
+        }
+        assert_eq!(items, vec![9, 6, 5, 4, 3, 2, 1, 1]);
+        assert_eq!(tree.len(), 0);
+    }
+
+    #[test]
+    fn test_interleaved_push_pop() {
+        let mut tree = LoserTree::new();
+        tree.push(5);
+        tree.push(7);
+        tree.push(3);
+        assert_eq!(tree.pop(), Some(7));
+        tree.push(6);
+        assert_eq!(tree.peek(), Some(&6));
+        assert_eq!(tree.pop(), Some(6));
+        assert_eq!(tree.pop(), Some(5));
+        tree.push(1);
+        tree.push(4);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.pop(), Some(4));
+        assert_eq!(tree.pop(), Some(3));
+        assert_eq!(tree.pop(), Some(1));
+        assert_eq!(tree.pop(), None);
+        assert_eq!(tree.peek(), None);
+    }
+
+    #[test]
+    fn test_update_top() {
+        let mut tree = LoserTree::new();
+        for item in &[5, 7, 3] {
+            tree.push(*item);
+        }
+        assert_eq!(tree.update_top(|top| *top = 6), Some(()));
+        assert_eq!(tree.peek(), Some(&6));
+        tree.update_top(|top| *top = 1);
+        assert_eq!(tree.pop(), Some(5));
+        assert_eq!(tree.pop(), Some(3));
+        assert_eq!(tree.pop(), Some(1));
+        assert_eq!(tree.update_top(|top| *top = 2), None);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = LoserTree::new();
+        for item in &[3, 9, 1, 7] {
+            tree.push(*item);
+        }
+        assert_eq!(tree.pop(), Some(9));
+        assert_eq!(tree.remove(|item| *item == 7), Some(7));
+        assert_eq!(tree.remove(|item| *item == 7), None);
+        tree.push(5);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.pop(), Some(5));
+        assert_eq!(tree.pop(), Some(3));
+        assert_eq!(tree.pop(), Some(1));
+        assert_eq!(tree.pop(), None);
+    }
+
+    #[test]
+    fn test_matches_binary_heap() {
+        let mut tree = LoserTree::new();
+        let mut heap = std::collections::BinaryHeap::new();
+        let mut state: u64 = 42;
+        for _ in 0..10_000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let value = (state >> 33) % 100;
+            if value < 55 {
+                tree.push(value);
+                heap.push(value);
+            } else {
+                assert_eq!(tree.pop(), heap.pop());
+            }
+            assert_eq!(tree.len(), heap.len());
+            assert_eq!(tree.peek(), heap.peek());
+        }
+    }
+}
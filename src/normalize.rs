@@ -0,0 +1,103 @@
+//! Unicode normalization of lines before they are compared.
+
+use std::borrow;
+use std::error;
+use std::fmt;
+use std::str;
+use std::sync;
+
+use unicode_normalization::{is_nfc_quick, is_nfkc_quick, IsNormalized, UnicodeNormalization};
+
+use crate::Comparator;
+
+/// A Unicode normalization form. Text that is equal in a form compares equal, e.g. the
+/// decomposed `e\u{301}` of macOS file names and the precomposed `é` under either form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition.
+    Nfc,
+    /// Compatibility composition, which also folds e.g. ligatures and full-width forms.
+    Nfkc,
+}
+
+/// The error returned when a normalization form can't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseNormalizationError(String);
+
+impl fmt::Display for ParseNormalizationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid normalization form [{}]", self.0)
+    }
+}
+
+impl error::Error for ParseNormalizationError {}
+
+impl str::FromStr for Normalization {
+    type Err = ParseNormalizationError;
+
+    /// Parse `nfc` or `nfkc`.
+    fn from_str(form: &str) -> Result<Normalization, ParseNormalizationError> {
+        match form {
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            _ => Err(ParseNormalizationError(form.to_string())),
+        }
+    }
+}
+
+impl Normalization {
+    /// `text` in this form, borrowed if it is already normalized.
+    pub fn normalize<'a>(&self, text: &'a str) -> borrow::Cow<'a, str> {
+        let quick = match self {
+            Normalization::Nfc => is_nfc_quick(text.chars()),
+            Normalization::Nfkc => is_nfkc_quick(text.chars()),
+        };
+        if quick == IsNormalized::Yes {
+            return borrow::Cow::Borrowed(text);
+        }
+        borrow::Cow::Owned(match self {
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfkc => text.nfkc().collect(),
+        })
+    }
+
+    /// A comparator that normalizes both lines before comparing them with `cmp`.
+    pub fn comparator(self, cmp: Comparator) -> Comparator {
+        sync::Arc::new(move |a: &str, b: &str| cmp(&self.normalize(a), &self.normalize(b)))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::string_lit_as_bytes)]
+mod tests {
+    use super::*;
+    use std::cmp;
+    use std::io;
+
+    use crate::Heap;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(Normalization::Nfc.normalize("e\u{301}"), "\u{e9}");
+        assert!(matches!(
+            Normalization::Nfc.normalize("plain"),
+            borrow::Cow::Borrowed(_)
+        ));
+        assert_eq!(Normalization::Nfc.normalize("\u{fb01}"), "\u{fb01}");
+        assert_eq!(Normalization::Nfkc.normalize("\u{fb01}"), "fi");
+        assert_eq!("nfkc".parse(), Ok(Normalization::Nfkc));
+        assert!("nfd".parse::<Normalization>().is_err());
+    }
+
+    #[test]
+    fn test_comparator() -> Result<(), io::Error> {
+        let cmp = Normalization::Nfc.comparator(sync::Arc::new(|a: &str, b: &str| a.cmp(b)));
+        assert_eq!(cmp("e\u{301}", "\u{e9}"), cmp::Ordering::Equal);
+        let mut heap = Heap::with_comparator(move |a, b| cmp(a, b));
+        heap.add_reader("nfd".to_string(), "cafe\u{301}\nz\n".as_bytes())?;
+        heap.add_reader("nfc".to_string(), "caf\u{e9}s\n".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["cafe\u{301}", "caf\u{e9}s", "z"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! Merging objects streamed from object stores, e.g. the sorted shards of a job in a bucket, and
+//! uploading the merged output to them. No cloud provider's client is built in, and nothing signs
+//! requests: `UnsignedS3Store` speaks unsigned S3 requests over plain HTTP to an S3-compatible
+//! server, and other stores implement `ObjectStore`.
+
+use std::fs;
+use std::io;
+use std::path;
+use std::process;
+use std::{collections, sync};
+
+use crate::{Heap, MultipartUpload};
+
+/// Where objects are read from, such as a bucket of a cloud object store, by the paths of the
+/// objects within it. Implement it over a client of the store, e.g. that of the `object_store`
+/// crate, to merge objects with `Heap::add_object_store`.
+pub trait ObjectStore: Send + Sync {
+    /// Stream the object at `path`.
+    fn get(&self, path: &str) -> io::Result<Box<dyn io::Read + Send>>;
+
+    /// How the object at `path` is named in error messages and statistics.
+    fn describe(&self, path: &str) -> String {
+        path.to_string()
+    }
+
+    /// Start uploading the object at `path` in parts, e.g. with a `MultipartWriter`. Stores only
+    /// read from fail with `io::ErrorKind::Unsupported`.
+    fn put_multipart(&self, path: &str) -> io::Result<Box<dyn MultipartUpload>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot upload [{}] to this store", self.describe(path)),
+        ))
+    }
+}
+
+/// The objects in a directory, by their paths beneath it.
+#[derive(Clone, Debug)]
+pub struct LocalStore {
+    root: path::PathBuf,
+}
+
+impl LocalStore {
+    pub fn new<P: Into<path::PathBuf>>(root: P) -> LocalStore {
+        LocalStore { root: root.into() }
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn get(&self, path: &str) -> io::Result<Box<dyn io::Read + Send>> {
+        Ok(Box::new(fs::File::open(self.root.join(path))?))
+    }
+
+    fn describe(&self, path: &str) -> String {
+        self.root.join(path).display().to_string()
+    }
+
+    /// Upload the parts to files in a directory beside the object, joined into it once complete.
+    fn put_multipart(&self, path: &str) -> io::Result<Box<dyn MultipartUpload>> {
+        let object = self.root.join(path);
+        let mut parts = object.clone().into_os_string();
+        parts.push(format!(".{}.parts", process::id()));
+        let parts = path::PathBuf::from(parts);
+        fs::create_dir_all(&parts)?;
+        Ok(Box::new(LocalUpload { object, parts }))
+    }
+}
+
+/// A multipart upload to a `LocalStore`.
+struct LocalUpload {
+    object: path::PathBuf,
+    /// The directory the parts are uploaded to, as files named by their numbers.
+    parts: path::PathBuf,
+}
+
+impl MultipartUpload for LocalUpload {
+    fn put_part(&self, part: u32, data: &[u8]) -> io::Result<()> {
+        fs::write(self.parts.join(part.to_string()), data)
+    }
+
+    fn complete(&self, parts: u32) -> io::Result<()> {
+        let joined = self.parts.join("joined");
+        let mut w = io::BufWriter::new(fs::File::create(&joined)?);
+        for part in 1..=parts {
+            io::copy(
+                &mut fs::File::open(self.parts.join(part.to_string()))?,
+                &mut w,
+            )?;
+        }
+        w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&joined, &self.object)?;
+        fs::remove_dir_all(&self.parts)
+    }
+
+    fn abort(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.parts)
+    }
+}
+
+/// The objects of a bucket of an S3-compatible server, beneath a plain HTTP base URL such as
+/// `http://localhost:9000/bucket` for a local MinIO, requested without credentials. Only buckets
+/// that anyone may read, or write to upload, work; AWS S3 itself needs signed requests.
+#[derive(Clone, Debug)]
+pub struct UnsignedS3Store {
+    base: String,
+}
+
+impl UnsignedS3Store {
+    pub fn new(base: &str) -> UnsignedS3Store {
+        UnsignedS3Store {
+            base: base.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl ObjectStore for UnsignedS3Store {
+    fn get(&self, path: &str) -> io::Result<Box<dyn io::Read + Send>> {
+        Ok(Box::new(crate::http::Body::get(&self.describe(path), 0)?))
+    }
+
+    fn describe(&self, path: &str) -> String {
+        format!("{}/{}", self.base, path.trim_start_matches('/'))
+    }
+
+    /// Upload the object with the multipart upload requests of S3, unsigned, as a server that
+    /// lets anyone write to the bucket accepts.
+    fn put_multipart(&self, path: &str) -> io::Result<Box<dyn MultipartUpload>> {
+        let url = self.describe(path);
+        let created =
+            crate::http::send(&format!("{}?uploads", url), "POST", "", &[])?.text(&[200])?;
+        let id = xml_element(&created, "UploadId")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No UploadId in response"))?
+            .to_string();
+        Ok(Box::new(UnsignedS3Upload {
+            url,
+            id,
+            etags: sync::Mutex::new(collections::BTreeMap::new()),
+        }))
+    }
+}
+
+/// The text of the first element `name` of `xml`, which is assumed to have no attributes.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..start + len])
+}
+
+/// A multipart upload to an `UnsignedS3Store`.
+struct UnsignedS3Upload {
+    url: String,
+    id: String,
+    /// The entity tags of the parts uploaded, which completing the upload lists.
+    etags: sync::Mutex<collections::BTreeMap<u32, String>>,
+}
+
+impl MultipartUpload for UnsignedS3Upload {
+    fn put_part(&self, part: u32, data: &[u8]) -> io::Result<()> {
+        let url = format!("{}?partNumber={}&uploadId={}", self.url, part, self.id);
+        let response = crate::http::send(&url, "PUT", "", data)?;
+        let etag = response.header("etag").unwrap_or("").to_string();
+        response.text(&[200])?;
+        self.etags
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(part, etag);
+        Ok(())
+    }
+
+    fn complete(&self, parts: u32) -> io::Result<()> {
+        let etags = self.etags.lock().unwrap_or_else(|err| err.into_inner());
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for part in 1..=parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part,
+                etags.get(&part).map_or("", |etag| etag.as_str())
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let url = format!("{}?uploadId={}", self.url, self.id);
+        let completed = crate::http::send(&url, "POST", "", body.as_bytes())?.text(&[200])?;
+        // S3 can report failing to complete an upload after the status of success.
+        if completed.contains("<Error>") {
+            return Err(io::Error::other(format!(
+                "Completing the upload of [{}] failed: {}",
+                self.url, completed
+            )));
+        }
+        Ok(())
+    }
+
+    fn abort(&self) -> io::Result<()> {
+        let url = format!("{}?uploadId={}", self.url, self.id);
+        crate::http::send(&url, "DELETE", "", &[])?.text(&[200, 204])?;
+        Ok(())
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Add the object at `path` in `store`, streamed as it is read and decompressed if
+    /// compression support is enabled. An error getting it is handled as for `add_path`.
+    pub fn add_object_store(&mut self, store: &dyn ObjectStore, path: &str) -> io::Result<()> {
+        let name = store.describe(path);
+        let added = match store.get(path) {
+            Ok(reader) => self.add_detected(name.clone(), reader, 0),
+            Err(err) => Err(err),
+        };
+        match added {
+            Ok(()) => Ok(()),
+            Err(err) => self.merge.failed_to_add(&name, err),
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_object_store() -> Result<(), io::Error> {
+        let root = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-object-store-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("part"))?;
+        fs::write(root.join("part/0"), "a\nc\n")?;
+        fs::write(root.join("part/1"), "b\n")?;
+        let store = LocalStore::new(&root);
+        let mut heap = Heap::new();
+        heap.add_object_store(&store, "part/0")?;
+        heap.add_object_store(&store, "part/1")?;
+        let err = Heap::new().add_object_store(&store, "part/2").unwrap_err();
+        assert!(err.to_string().contains("part/2]"), "{}", err);
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c"]);
+
+        let mut w = crate::MultipartWriter::new(store.put_multipart("merged")?).with_part_size(3);
+        io::Write::write_all(&mut w, b"a\nb\nc\n")?;
+        assert_eq!(w.finish()?, 2);
+        assert_eq!(fs::read_to_string(root.join("merged"))?, "a\nb\nc\n");
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! Merging on several threads at once: groups of the inputs of a heap for `Strategy::Parallel`,
+//! or ranges of the lines of all of them for `partitioned_merge`.
+
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::path;
+use std::sync;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::input;
+use crate::input::Input;
+use crate::merge::{KWayMerge, Run, Strategy};
+use crate::read_ahead::{ReadAhead, BLOCK_SIZE};
+#[cfg(feature = "encoding")]
+use crate::InputEncoding;
+use crate::{write_record, BlankLinePolicy, Counters, Heap, Line, LineEnding, LineSource};
+
+/// How many blocks each group merges ahead of the merge of the groups.
+const GROUP_BLOCKS: usize = 4;
+
+/// Writes blocks of merged lines to the channel a `ReadAhead` reads them from.
+struct Blocks(mpsc::SyncSender<io::Result<Vec<u8>>>);
+
+impl io::Write for Blocks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read + Send + 'static,
+    K: Send + 'static,
+{
+    /// Use `Strategy::Parallel`, merging up to `threads` groups of the inputs on threads of their
+    /// own. Lines merged in parallel are read back from their group, so `iter_with_source` and
+    /// `iter_with_position` name the group rather than the input, and `failed_sources` only
+    /// lists the inputs dropped before the merge started.
+    pub fn with_parallel_strategy(mut self, threads: usize) -> Heap<T, K> {
+        self.merge = self
+            .merge
+            .with_strategy(Strategy::Parallel { threads })
+            .with_parallelize(sync::Arc::new(merge_group));
+        self
+    }
+}
+
+/// Merge `group` on a thread of its own, returning a source named `name` of the lines it merges,
+/// which are read back the way the lines of its inputs were. The thread stops at the first error,
+/// which the source returns after the lines merged before it, or once the source is dropped.
+fn merge_group<T, K>(name: &str, mut group: KWayMerge<LineSource<T, K>>) -> LineSource<T, K>
+where
+    T: io::Read + Send + 'static,
+    K: Send + 'static,
+{
+    let first = group.first_source().expect("Groups aren't empty");
+    let name: sync::Arc<str> = sync::Arc::from(name);
+    // The lines were filtered as they were read from the inputs, so they aren't again.
+    let merged = |reader| LineSource {
+        reader: Input::ReadAhead(reader),
+        key: first.key.clone(),
+        filter: None,
+        comment_prefix: None,
+        blank_lines: BlankLinePolicy::Merge,
+        format: first.format,
+        invalid_utf8: first.invalid_utf8,
+        #[cfg(feature = "tracing")]
+        trace: crate::trace::SourceTrace::new(&name),
+        name: name.clone(),
+        counters: sync::Arc::new(Counters::default()),
+    };
+    let delimiter = first.format.delimiter;
+    let (tx, blocks) = mpsc::sync_channel(GROUP_BLOCKS);
+    let source = merged(ReadAhead::new(blocks));
+    thread::spawn(move || {
+        let mut w = io::BufWriter::with_capacity(BLOCK_SIZE, Blocks(tx.clone()));
+        // Each line is written as it was read, `\r` and all, so it reads back the same.
+        let merged =
+            group.for_each_item(|line| write_record(&mut w, line, delimiter, LineEnding::Preserve));
+        if let Err(err) = merged.and_then(|()| w.flush()) {
+            let _ = w.flush();
+            let _ = tx.send(Err(err));
+        }
+    });
+    source
+}
+
+/// How many lines are sampled from each input per partition to pick where partitions split.
+const SAMPLES_PER_PARTITION: u64 = 16;
+
+/// A merge of files split into ranges of lines which are merged on threads of their own, created
+/// by `partitioned_merge`.
+pub struct PartitionedMerge {
+    inputs: Vec<path::PathBuf>,
+    partitions: usize,
+    temp_dir: path::PathBuf,
+}
+
+/// Plan a merge of `inputs` split into up to `partitions` ranges of lines, each merged on a
+/// thread of its own from every input, with the files seeked to the start of the range where
+/// they can be. The ranges are split at lines sampled from the inputs, so they are roughly the
+/// same size if the inputs are regular, uncompressed files.
+pub fn partitioned_merge(inputs: Vec<path::PathBuf>, partitions: usize) -> PartitionedMerge {
+    PartitionedMerge {
+        inputs,
+        partitions: partitions.max(1),
+        temp_dir: env::temp_dir(),
+    }
+}
+
+impl PartitionedMerge {
+    /// Set the directory the partitions after the first are written to before being copied to
+    /// the output. Defaults to `std::env::temp_dir()`.
+    pub fn with_temp_dir<P: Into<path::PathBuf>>(mut self, temp_dir: P) -> PartitionedMerge {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Run the merge, writing the partitions to `w` one after the other. `new_heap` is called
+    /// once to sample the inputs and once per partition to create the heap merging it, which is
+    /// given the range of the partition, so it shouldn't have a range of its own, and which only
+    /// emits the header for the first partition. `with_limit` applies to each partition. Returns
+    /// the number of lines written to `w`.
+    pub fn run<K, F, W>(&self, new_heap: F, mut w: W) -> io::Result<u64>
+    where
+        F: Fn() -> Heap<fs::File, K> + Sync,
+        K: 'static,
+        W: io::Write,
+    {
+        let splits = self.splits(&new_heap)?;
+        let merge_partition = |i: usize, w: &mut dyn io::Write| -> io::Result<u64> {
+            let mut heap = new_heap();
+            if i > 0 {
+                heap = heap.with_start_line(&splits[i - 1]).with_emit_header(false);
+            }
+            if let Some(split) = splits.get(i) {
+                let end = heap.bound(split);
+                heap.merge = heap.merge.with_end_before(end);
+            }
+            for input in &self.inputs {
+                heap.add_file(input)?;
+            }
+            heap.write_sorted_lines(w)
+        };
+        thread::scope(|scope| {
+            let spilled: Vec<_> = (1..=splits.len())
+                .map(|i| {
+                    let temp_dir = &self.temp_dir;
+                    scope.spawn(move || -> io::Result<(u64, Run)> {
+                        let spill = Run::spill(temp_dir)?;
+                        let lines = merge_partition(i, &mut fs::File::create(&spill.path)?)?;
+                        Ok((lines, spill))
+                    })
+                })
+                .collect();
+            let mut lines = merge_partition(0, &mut w)?;
+            for partition in spilled {
+                let (partition_lines, spill) = partition
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))?;
+                io::copy(&mut fs::File::open(&spill.path)?, &mut w)?;
+                lines += partition_lines;
+            }
+            w.flush()?;
+            Ok(lines)
+        })
+    }
+
+    /// The lines the partitions are split at, in order, each being the first of the partition
+    /// after it: those at evenly spaced positions among the sampled lines, without repeats.
+    fn splits<K, F>(&self, new_heap: &F) -> io::Result<Vec<String>>
+    where
+        F: Fn() -> Heap<fs::File, K>,
+        K: 'static,
+    {
+        if self.partitions == 1 {
+            return Ok(Vec::new());
+        }
+        let heap = new_heap();
+        let mut samples = Vec::new();
+        for input in &self.inputs {
+            let per_input = SAMPLES_PER_PARTITION * self.partitions as u64;
+            samples.extend(heap.sample(input, per_input)?);
+        }
+        samples.sort_by(|a, b| heap.merge.compare(a, b));
+        let mut splits: Vec<&Line<K>> = Vec::new();
+        for i in 1..self.partitions {
+            let Some(sample) = samples.get(i * samples.len() / self.partitions) else {
+                break;
+            };
+            let repeated = splits
+                .last()
+                .is_some_and(|last| heap.merge.compare(last, sample) == cmp::Ordering::Equal);
+            if !repeated {
+                splits.push(sample);
+            }
+        }
+        Ok(splits.iter().map(|split| split.text.clone()).collect())
+    }
+}
+
+impl<K> Heap<fs::File, K>
+where
+    K: 'static,
+{
+    /// Up to `count` lines at evenly spaced offsets in the file at `path`, or none if it isn't a
+    /// regular, uncompressed UTF-8 file, or if reading them fails.
+    fn sample(&self, path: &path::Path, count: u64) -> io::Result<Vec<Line<K>>> {
+        #[cfg(feature = "encoding")]
+        if self.encoding != InputEncoding::Utf8 {
+            return Ok(Vec::new());
+        }
+        let mut f = fs::File::open(path)?;
+        let metadata = f.metadata()?;
+        if !metadata.is_file() || input::is_compressed(&mut f)? {
+            return Ok(Vec::new());
+        }
+        let size = metadata.len();
+        let mut samples = Vec::new();
+        for i in 1..=count {
+            match self.line_after(&mut f, size * i / (count + 1), 0) {
+                Ok((_, Some(line))) => samples.push(line),
+                Ok((_, None)) => break,
+                Err(_) => return Ok(samples),
+            }
+        }
+        Ok(samples)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use crate::{Heap, InvalidUtf8Policy, MergeError, OutOfOrderPolicy};
+    use std::fs;
+    use std::io;
+
+    fn parallel(threads: usize) -> Heap<&'static [u8]> {
+        Heap::new().with_parallel_strategy(threads)
+    }
+
+    #[test]
+    fn test_parallel() -> Result<(), io::Error> {
+        let inputs = ["a\nd\ng\n", "b\ne\n", "c\nf\n", "a\nh\n", "b\n"];
+        let mut heap = parallel(2).with_crlf(true).dedup(true);
+        for (i, input) in inputs.iter().enumerate() {
+            heap.add_reader(format!("file{}", i + 1), input.as_bytes())?;
+        }
+        heap.add_reader("file6".to_string(), "i\r\n".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 9);
+        assert_eq!(out, b"a\nb\nc\nd\ne\nf\ng\nh\ni\r\n");
+        let lines: Vec<u64> = heap.stats().iter().map(|source| source.lines).collect();
+        assert_eq!(lines, vec![3, 2, 2, 2, 1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_passthrough() -> Result<(), io::Error> {
+        let mut heap = parallel(2).with_invalid_utf8_policy(InvalidUtf8Policy::Passthrough);
+        heap.add_reader("file1".to_string(), b"a\n\xff\n".as_ref())?;
+        heap.add_reader("file2".to_string(), "b\n".as_bytes())?;
+        let mut out = Vec::new();
+        heap.write_sorted_lines(&mut out)?;
+        assert_eq!(out, b"a\nb\n\xff\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_ooo() -> Result<(), io::Error> {
+        let heap = || -> io::Result<Heap<&'static [u8]>> {
+            let mut heap = parallel(3);
+            heap.add_reader("file1".to_string(), "a\nc\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "d\nb\ne\n".as_bytes())?;
+            heap.add_reader("file3".to_string(), "b\n".as_bytes())?;
+            Ok(heap)
+        };
+        let err = heap()?.write_sorted_lines(io::sink()).unwrap_err();
+        match MergeError::from_io(&err) {
+            Some(MergeError::OutOfOrder { file, line_no, .. }) => {
+                assert_eq!((file.as_str(), *line_no), ("file2", 2))
+            }
+            _ => panic!("Unexpected error {:?}", err),
+        }
+        let heap = heap()?.with_out_of_order_policy(OutOfOrderPolicy::Skip);
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partitioned_merge() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir();
+        let paths: Vec<_> = (0..3)
+            .map(|i| {
+                dir.join(format!(
+                    "merge-sorted-files-rs-test-partitioned-{}-{}",
+                    std::process::id(),
+                    i
+                ))
+            })
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            let lines: String = (0..1000)
+                .filter(|n| n % 3 == i || n % 10 == 0)
+                .map(|n| format!("{:04}\n", n))
+                .collect();
+            fs::write(path, format!("n\n{}", lines))?;
+        }
+        let new_heap = || Heap::new().with_headers(true).with_emit_header(true);
+        let mut expected = Vec::new();
+        super::partitioned_merge(paths.clone(), 1).run(new_heap, &mut expected)?;
+        let merge = super::partitioned_merge(paths.clone(), 4);
+        assert_eq!(merge.splits(&new_heap)?.len(), 3);
+        let mut out = Vec::new();
+        assert_eq!(merge.run(new_heap, &mut out)?, 1200);
+        assert!(out.starts_with(b"n\n0000\n0000\n0000\n0001\n"));
+        assert_eq!(out, expected);
+        for path in &paths {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Reporting how far a merge has got while it runs.
+
+use std::io;
+use std::sync;
+use std::sync::atomic;
+use std::time;
+
+use crate::merge::MergeProgress;
+use crate::{registered, source_stats, Heap, Registry, SourceStats};
+
+/// How often `Heap::on_progress` reports progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressInterval {
+    /// Every this many merged lines.
+    Lines(u64),
+    /// Every time this many more bytes have been read from the inputs, which is checked every
+    /// `ProgressInterval::BYTES_CHECKED_EVERY` merged lines.
+    Bytes(u64),
+}
+
+impl ProgressInterval {
+    /// How many merged lines apart `ProgressInterval::Bytes` checks how much has been read.
+    pub const BYTES_CHECKED_EVERY: u64 = 64;
+}
+
+/// How far a merge has got, as reported by `Heap::on_progress` and `Heap::merge_stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeStats {
+    /// How much of each input has been read, in the order they were added.
+    pub sources: Vec<SourceStats>,
+    /// The number of merged lines emitted.
+    pub lines: u64,
+    /// The number of lines dropped as duplicates of the line before them, by `dedup` or the
+    /// duplicate policy, or collapsed into it by `counted`.
+    pub duplicates: u64,
+    /// The number of comparisons between lines, if `Heap::count_comparisons` was called.
+    pub comparisons: Option<u64>,
+    /// The time from when the heap was created until the merge ended, or until now if it hasn't.
+    pub elapsed: time::Duration,
+    /// Whether the merge has ended, either at the end of its inputs or of its range.
+    pub finished: bool,
+}
+
+impl MergeStats {
+    fn of(registry: &Registry, started: time::Instant, progress: &MergeProgress) -> MergeStats {
+        let ended = progress.ended.unwrap_or_else(time::Instant::now);
+        MergeStats {
+            sources: source_stats(registry),
+            lines: progress.emitted,
+            duplicates: progress.duplicates,
+            comparisons: progress.comparisons,
+            elapsed: ended.duration_since(started),
+            finished: progress.ended.is_some(),
+        }
+    }
+
+    /// The number of bytes read from all the inputs.
+    pub fn bytes(&self) -> u64 {
+        self.sources.iter().map(|source| source.bytes).sum()
+    }
+
+    /// The number of bytes there are to read from all the inputs, if the size of each is known.
+    pub fn size(&self) -> Option<u64> {
+        self.sources.iter().map(|source| source.size).sum()
+    }
+
+    /// Write the statistics as a JSON object, with the sources as an array of objects under
+    /// `sources` and the time elapsed in seconds under `elapsed`. Unknown sizes and comparison
+    /// counts are null.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: io::Write>(&self, w: W) -> io::Result<()> {
+        let sources: Vec<serde_json::Value> = self
+            .sources
+            .iter()
+            .map(|source| {
+                serde_json::json!({
+                    "name": source.name,
+                    "lines": source.lines,
+                    "bytes": source.bytes,
+                    "size": source.size,
+                })
+            })
+            .collect();
+        let stats = serde_json::json!({
+            "sources": sources,
+            "lines": self.lines,
+            "duplicates": self.duplicates,
+            "comparisons": self.comparisons,
+            "elapsed": self.elapsed.as_secs_f64(),
+            "finished": self.finished,
+        });
+        serde_json::to_writer(w, &stats)?;
+        Ok(())
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// How far the merge has got, or once it has ended, how it went.
+    pub fn merge_stats(&self) -> MergeStats {
+        MergeStats::of(&self.stats, self.started, &self.merge.progress())
+    }
+
+    /// Count the comparisons between lines from now on, for `MergeStats::comparisons`. This slows
+    /// down each comparison slightly.
+    pub fn count_comparisons(mut self) -> Heap<T, K> {
+        self.merge = self.merge.count_comparisons();
+        self
+    }
+
+    /// Call `report` with the progress of the merge at `interval`, including inputs added after
+    /// this, and once more when it ends at the end of its inputs or range. Merging the last input
+    /// left by copying it straight through is disabled, so progress is reported while it is read.
+    pub fn on_progress<F>(mut self, interval: ProgressInterval, report: F) -> Heap<T, K>
+    where
+        F: Fn(&MergeStats) + Send + Sync + 'static,
+    {
+        let (registry, started) = (self.stats.clone(), self.started);
+        let every = match interval {
+            ProgressInterval::Lines(lines) => lines,
+            ProgressInterval::Bytes(_) => ProgressInterval::BYTES_CHECKED_EVERY,
+        };
+        let reported = atomic::AtomicU64::new(0);
+        let hook = move |progress: &MergeProgress| {
+            let finished = progress.ended.is_some();
+            if let ProgressInterval::Bytes(bytes) = interval {
+                let read: u64 = registered(&registry)
+                    .iter()
+                    .map(|(_, counters)| counters.bytes.load(atomic::Ordering::Relaxed))
+                    .sum();
+                if !finished && read < reported.load(atomic::Ordering::Relaxed) + bytes {
+                    return;
+                }
+                reported.store(read, atomic::Ordering::Relaxed);
+            }
+            report(&MergeStats::of(&registry, started, progress));
+        };
+        self.merge = self.merge.with_progress(every, sync::Arc::new(hook));
+        self
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// The lines and whether the merge had finished at each report of merging `heap`.
+    fn reports(heap: Heap<&[u8]>, interval: ProgressInterval) -> io::Result<Vec<(u64, bool)>> {
+        let reports = sync::Arc::new(sync::Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        let mut heap = heap.on_progress(interval, move |stats| {
+            reported.lock().unwrap().push((stats.lines, stats.finished))
+        });
+        heap.write_sorted_lines(io::sink())?;
+        let reports = reports.lock().unwrap().clone();
+        Ok(reports)
+    }
+
+    fn heap() -> io::Result<Heap<&'static [u8]>> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\ne\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())?;
+        Ok(heap)
+    }
+
+    #[test]
+    fn test_progress_by_lines() -> Result<(), io::Error> {
+        assert_eq!(
+            reports(heap()?, ProgressInterval::Lines(2))?,
+            vec![(2, false), (4, false), (5, true)]
+        );
+        let heap = heap()?.with_limit(3);
+        assert_eq!(
+            reports(heap, ProgressInterval::Lines(2))?,
+            vec![(2, false), (3, true)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_by_bytes() -> Result<(), io::Error> {
+        let contents = format!("{}\n", "x".repeat(99)).repeat(200);
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), contents.as_bytes())?;
+        let reports = reports(heap, ProgressInterval::Bytes(8000))?;
+        let lines: Vec<u64> = reports.iter().map(|(lines, _)| *lines).collect();
+        assert_eq!(lines, vec![128, 200]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_stats() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-progress-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "a\nc\n")?;
+        let last = sync::Arc::new(sync::Mutex::new(None));
+        let reported = last.clone();
+        let mut heap: Heap<Box<dyn io::Read + Send>> = Heap::new()
+            .on_progress(ProgressInterval::Lines(10), move |stats| {
+                *reported.lock().unwrap() = Some(stats.clone())
+            });
+        heap.add_file(&path)?;
+        heap.add_reader("stdin".to_string(), Box::new("b\n".as_bytes()))?;
+        heap.write_sorted_lines(io::sink())?;
+        let stats = last.lock().unwrap().take().expect("Progress was reported");
+        assert_eq!((stats.lines, stats.finished), (3, true));
+        assert_eq!((stats.bytes(), stats.size()), (6, None));
+        assert_eq!(stats.sources[0].size, Some(4));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_stats() -> Result<(), io::Error> {
+        let mut heap = heap()?.dedup(true).count_comparisons();
+        heap.add_reader("file3".to_string(), "a\nd\nd\nf\n".as_bytes())?;
+        assert!(!heap.merge_stats().finished);
+        heap.write_sorted_lines(io::sink())?;
+        let stats = heap.merge_stats();
+        assert_eq!(
+            (stats.lines, stats.duplicates, stats.finished),
+            (6, 3, true)
+        );
+        assert!(stats.comparisons.is_some_and(|comparisons| comparisons > 0));
+        assert_eq!(heap.merge_stats().elapsed, stats.elapsed);
+        let lines: Vec<u64> = stats.sources.iter().map(|source| source.lines).collect();
+        assert_eq!(lines, vec![3, 2, 4]);
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_merge_stats_json() -> Result<(), io::Error> {
+        let mut heap = heap()?;
+        heap.write_sorted_lines(io::sink())?;
+        let mut json = Vec::new();
+        heap.merge_stats().write_json(&mut json)?;
+        let value: serde_json::Value = serde_json::from_slice(&json)?;
+        assert_eq!(value["lines"], 5);
+        assert_eq!(value["comparisons"], serde_json::Value::Null);
+        assert_eq!(value["sources"][1]["bytes"], 4);
+        Ok(())
+    }
+}
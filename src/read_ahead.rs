@@ -0,0 +1,157 @@
+//! Reading inputs ahead of the merge on threads of their own, so that a slow input holds up the
+//! merge only once the lines read ahead of it have been merged.
+
+use std::io;
+use std::sync;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::input::Input;
+use crate::Heap;
+
+/// The most bytes each block read ahead holds.
+pub(crate) const BLOCK_SIZE: usize = 64 * 1024;
+
+/// The contents of an input, read and decompressed in blocks by a thread of its own.
+pub(crate) struct ReadAhead {
+    blocks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    /// How much of `block` has been consumed.
+    pos: usize,
+}
+
+impl ReadAhead {
+    /// Read `reader` on a thread named after the input `name`, keeping up to `depth` blocks read
+    /// ahead of what has been consumed. The thread stops at the end of `reader`, at the first
+    /// error reading it, which is returned once the blocks before it are consumed, or once this
+    /// is dropped.
+    pub(crate) fn spawn<R>(name: &str, mut reader: R, depth: usize) -> io::Result<ReadAhead>
+    where
+        R: io::Read + Send + 'static,
+    {
+        let (tx, blocks) = mpsc::sync_channel(depth.max(1));
+        thread::Builder::new()
+            .name(format!("read-ahead {}", name))
+            .spawn(move || loop {
+                let mut block = vec![0; BLOCK_SIZE];
+                let read = match reader.read(&mut block) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        block.truncate(n);
+                        Ok(block)
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = read.is_err();
+                if tx.send(read).is_err() || failed {
+                    break;
+                }
+            })?;
+        Ok(ReadAhead::new(blocks))
+    }
+
+    /// Read the blocks sent to `blocks` until the channel is closed, or until the first error
+    /// sent to it.
+    pub(crate) fn new(blocks: mpsc::Receiver<io::Result<Vec<u8>>>) -> ReadAhead {
+        ReadAhead {
+            blocks,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl io::Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = io::Read::read(&mut io::BufRead::fill_buf(self)?, buf)?;
+        io::BufRead::consume(self, n);
+        Ok(n)
+    }
+}
+
+impl io::BufRead for ReadAhead {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.block.len() {
+            // The channel is closed once the thread has sent everything there is to read.
+            if let Ok(block) = self.blocks.recv() {
+                self.block = block?;
+                self.pos = 0;
+            }
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read + Send + 'static,
+    K: 'static,
+{
+    /// Read each input added from now on on a thread of its own, which keeps up to `depth` blocks
+    /// of 64 KiB of it read, and decompressed or transcoded, ahead of the merge, so the merge
+    /// doesn't wait on one input at a time when reading them is slow, e.g. over a network. The
+    /// merge itself, and splitting the blocks into lines, stay on the thread merging.
+    pub fn with_read_ahead(mut self, depth: usize) -> Heap<T, K> {
+        self.read_ahead = Some(sync::Arc::new(move |name: &str, input: Input<T>| {
+            Ok(Input::ReadAhead(ReadAhead::spawn(name, input, depth)?))
+        }));
+        self
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Read};
+
+    /// A reader that returns `contents` a few bytes at a time, then fails.
+    struct Failing(&'static [u8]);
+
+    impl io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("Read failed"));
+            }
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_ahead() -> Result<(), io::Error> {
+        let contents = "x".repeat(BLOCK_SIZE * 3 + 10);
+        let mut reader = ReadAhead::spawn("file1", io::Cursor::new(contents.clone()), 1)?;
+        let mut read = String::new();
+        reader.read_to_string(&mut read)?;
+        assert_eq!(read, contents);
+        assert!(reader.fill_buf()?.is_empty());
+
+        let mut reader = io::BufReader::new(ReadAhead::spawn("file2", Failing(b"a\nb\n"), 2)?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert_eq!(line, "a\n");
+        assert!(reader.read_to_string(&mut line).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_read_ahead() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_read_ahead(2).with_headers(true);
+        heap.add_reader("file1".to_string(), "h\na\nc\ne\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "h\nb\nd\n".as_bytes())?;
+        let lines = heap.by_ref().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(heap.header(), Some("h"));
+        let bytes: Vec<u64> = heap.stats().iter().map(|source| source.bytes).collect();
+        assert_eq!(bytes, vec![8, 6]);
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! The merged lines of a heap as a reader, so they can be handed to anything that reads, e.g. a
+//! compressor or an upload, without being written to a file first.
+
+use std::io;
+
+use crate::{write_record, Heap};
+
+/// How many bytes of merged lines a `MergedReader` buffers at a time, give or take a line.
+const CAPACITY: usize = 64 * 1024;
+
+/// The merged lines of a heap, as `Heap::write_sorted_lines` would write them, returned by
+/// `Heap::into_reader`.
+pub struct MergedReader<T, K = ()>
+where
+    T: io::Read,
+{
+    heap: Heap<T, K>,
+    buf: Vec<u8>,
+    /// How much of `buf` has been consumed.
+    pos: usize,
+    /// Whether the header, if it is emitted, has been buffered.
+    started: bool,
+    /// An error hit after buffering the lines before it, to be returned once they are consumed.
+    deferred: Option<io::Error>,
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Read the merged lines, each terminated by the delimiter, after the header if it is emitted,
+    /// as `write_sorted_lines` writes them. Lines are merged as they are read, 64 KiB at a time.
+    pub fn into_reader(self) -> MergedReader<T, K> {
+        MergedReader {
+            heap: self,
+            buf: Vec::new(),
+            pos: 0,
+            started: false,
+            deferred: None,
+        }
+    }
+}
+
+impl<T, K> MergedReader<T, K>
+where
+    T: io::Read,
+{
+    /// The heap the lines are merged by, e.g. for its `stats`.
+    pub fn heap(&self) -> &Heap<T, K> {
+        &self.heap
+    }
+
+    /// Buffer the next lines, up to about `CAPACITY` bytes of them.
+    fn refill(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+        let (delimiter, eol) = (self.heap.format.delimiter, self.heap.eol);
+        if !self.started {
+            self.started = true;
+            if self.heap.emit_header {
+                for header in &self.heap.header {
+                    write_record(&mut self.buf, header, delimiter, eol)?;
+                }
+            }
+        }
+        while self.buf.len() < CAPACITY {
+            match self.heap.merge.next() {
+                Some(Ok(line)) => write_record(&mut self.buf, &line, delimiter, eol)?,
+                Some(Err(err)) if self.buf.is_empty() => return Err(err),
+                Some(Err(err)) => {
+                    self.deferred = Some(err);
+                    break;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, K> io::Read for MergedReader<T, K>
+where
+    T: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = io::Read::read(&mut io::BufRead::fill_buf(self)?, buf)?;
+        io::BufRead::consume(self, n);
+        Ok(n)
+    }
+}
+
+impl<T, K> io::BufRead for MergedReader<T, K>
+where
+    T: io::Read,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            if let Some(err) = self.deferred.take() {
+                return Err(err);
+            }
+            self.refill()?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Read};
+
+    #[test]
+    fn test_into_reader() -> Result<(), io::Error> {
+        let mut heap = Heap::new().with_headers(true).with_emit_header(true);
+        heap.add_reader("file1".to_string(), "h\na\nc\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "h\nb\n".as_bytes())?;
+        let mut merged = String::new();
+        heap.into_reader().read_to_string(&mut merged)?;
+        assert_eq!(merged, "h\na\nb\nc\n");
+
+        let contents: String = (0..20_000).map(|i| format!("{:06}\n", i * 2)).collect();
+        let mut heap = Heap::new();
+        heap.add_reader("evens".to_string(), contents.as_bytes())?;
+        heap.add_reader("odd".to_string(), "000001\n".as_bytes())?;
+        let mut reader = heap.into_reader();
+        let lines = reader.by_ref().lines().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines.len(), 20_001);
+        assert_eq!(lines[..3], ["000000", "000001", "000002"]);
+        assert_eq!(reader.heap().stats()[0].lines, 20_000);
+
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\nb\n".as_bytes())?;
+        let mut reader = io::BufReader::new(heap.into_reader());
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert_eq!(line, "a\n");
+        assert!(reader.read_line(&mut line).is_err());
+        Ok(())
+    }
+}
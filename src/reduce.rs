@@ -0,0 +1,170 @@
+//! Rolling up runs of merged lines that share a key into one record each.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::str;
+use std::vec;
+
+use crate::Heap;
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+{
+    /// Group adjacent merged lines for which `key` returns equal keys, and yield what `reduce`
+    /// returns for each group, given its key and its lines in merged order. Only one group is
+    /// buffered at a time, so equal keys are expected to be adjacent in the merged order, as they
+    /// are when `key` extracts part of what the heap sorts by.
+    pub fn reduce_by_key<G, F, R>(
+        self,
+        key: F,
+        mut reduce: R,
+    ) -> impl Iterator<Item = io::Result<String>>
+    where
+        G: PartialEq,
+        F: Fn(&str) -> G,
+        R: FnMut(&G, vec::IntoIter<String>) -> String,
+    {
+        let mut lines = self.peekable();
+        iter::from_fn(move || {
+            let first = match lines.next()? {
+                Ok(first) => first,
+                Err(err) => return Some(Err(err)),
+            };
+            let group_key = key(&first);
+            let mut group = vec![first];
+            while let Some(Ok(line)) = lines.peek() {
+                if key(line) != group_key {
+                    break;
+                }
+                group.extend(lines.next().and_then(Result::ok));
+            }
+            // An error ends the group; it is yielded after the group's record.
+            Some(Ok(reduce(&group_key, group.into_iter())))
+        })
+    }
+}
+
+/// Common reductions for `Heap::reduce_by_key`, whose records are made of the key and the result
+/// separated by a delimiter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The number of lines.
+    Count,
+    /// The sum of the numbers in the 1-based field `n` of each line, with fields split as for a
+    /// `KeySpec`. Lines whose field isn't a number count as 0.
+    Sum(usize),
+    /// The first line, as it is.
+    First,
+    /// The last line, as it is.
+    Last,
+    /// All the lines, joined by the delimiter.
+    Concat,
+}
+
+/// The error returned when an aggregate isn't recognized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseAggregateError(String);
+
+impl fmt::Display for ParseAggregateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid aggregate [{}]", self.0)
+    }
+}
+
+impl error::Error for ParseAggregateError {}
+
+impl str::FromStr for Aggregate {
+    type Err = ParseAggregateError;
+
+    /// Parse `count`, `sum:N`, `first`, `last` or `concat`.
+    fn from_str(s: &str) -> Result<Aggregate, ParseAggregateError> {
+        let err = || ParseAggregateError(s.to_string());
+        match s {
+            "count" => Ok(Aggregate::Count),
+            "first" => Ok(Aggregate::First),
+            "last" => Ok(Aggregate::Last),
+            "concat" => Ok(Aggregate::Concat),
+            _ => match s.strip_prefix("sum:").map(str::parse) {
+                Some(Ok(n)) if n > 0 => Ok(Aggregate::Sum(n)),
+                _ => Err(err()),
+            },
+        }
+    }
+}
+
+impl Aggregate {
+    /// The record for a group of `lines` with `key`, with fields split on `separator`, or on
+    /// runs of blanks if it is `None`, and joined with `delimiter`.
+    pub fn reduce<I>(
+        &self,
+        key: &str,
+        mut lines: I,
+        separator: Option<char>,
+        delimiter: &str,
+    ) -> String
+    where
+        I: Iterator<Item = String>,
+    {
+        match self {
+            Aggregate::Count => format!("{}{}{}", key, delimiter, lines.count()),
+            Aggregate::Sum(n) => {
+                let sum: f64 = lines
+                    .map(|line| field(&line, separator, *n).trim().parse().unwrap_or(0.0))
+                    .sum();
+                format!("{}{}{}", key, delimiter, sum)
+            }
+            Aggregate::First => lines.next().unwrap_or_default(),
+            Aggregate::Last => lines.last().unwrap_or_default(),
+            Aggregate::Concat => lines.collect::<Vec<_>>().join(delimiter),
+        }
+    }
+}
+
+/// The 1-based field `n` of `line`, or an empty string if it has fewer fields.
+fn field(line: &str, separator: Option<char>, n: usize) -> &str {
+    match separator {
+        Some(separator) => line.split(separator).nth(n - 1),
+        None => line.split_whitespace().nth(n - 1),
+    }
+    .unwrap_or("")
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reduce_by_key() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("shard1".to_string(), "a 1\na 2\nc 5\n".as_bytes())?;
+        heap.add_reader("shard2".to_string(), "a 3\nb 4\n".as_bytes())?;
+        let key = |line: &str| line.split(' ').next().unwrap_or("").to_string();
+        let records = heap
+            .reduce_by_key(key, |key, lines| {
+                Aggregate::Sum(2).reduce(key, lines, Some(' '), "\t")
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(records, vec!["a\t6", "b\t4", "c\t5"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let lines = || vec!["k,1,x".to_string(), "k,2.5,y".to_string()].into_iter();
+        let reduce = |aggregate: &str| {
+            let aggregate: Aggregate = aggregate.parse().unwrap();
+            aggregate.reduce("k", lines(), Some(','), ",")
+        };
+        assert_eq!(reduce("count"), "k,2");
+        assert_eq!(reduce("sum:2"), "k,3.5");
+        assert_eq!(reduce("first"), "k,1,x");
+        assert_eq!(reduce("last"), "k,2.5,y");
+        assert_eq!(reduce("concat"), "k,1,x,k,2.5,y");
+        assert!("sum:0".parse::<Aggregate>().is_err());
+        assert!("median".parse::<Aggregate>().is_err());
+    }
+}
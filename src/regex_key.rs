@@ -0,0 +1,106 @@
+//! Sort keys captured from each line by a regular expression.
+
+use std::io;
+
+/// What to do with a line that the key's regex doesn't match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnmatchedPolicy {
+    /// Fail the merge.
+    #[default]
+    Error,
+    /// Merge the line with an empty key, so it sorts first.
+    Empty,
+    /// Drop the line from the output.
+    Skip,
+}
+
+/// Extracts the key of each line from the match of a regex, to use with `Heap::with_key`, and
+/// decides what becomes of lines it doesn't match, to use with `Heap::with_try_filter`.
+#[derive(Clone, Debug)]
+pub struct RegexKey {
+    regex: regex::Regex,
+    unmatched: UnmatchedPolicy,
+}
+
+impl RegexKey {
+    pub fn new(pattern: &str) -> io::Result<RegexKey> {
+        let regex = regex::Regex::new(pattern)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(RegexKey {
+            regex,
+            unmatched: UnmatchedPolicy::default(),
+        })
+    }
+
+    pub fn with_unmatched(mut self, unmatched: UnmatchedPolicy) -> RegexKey {
+        self.unmatched = unmatched;
+        self
+    }
+
+    /// The key of `line`: the regex's first capture group, else its whole match, else empty.
+    pub fn extract(&self, line: &str) -> String {
+        self.regex
+            .captures(line)
+            .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+            .map_or_else(String::new, |key| key.as_str().to_string())
+    }
+
+    /// Whether `line` should be merged, according to the policy for unmatched lines.
+    pub fn filter(&self, line: &str) -> io::Result<bool> {
+        if self.unmatched == UnmatchedPolicy::Empty || self.regex.is_match(line) {
+            return Ok(true);
+        }
+        match self.unmatched {
+            UnmatchedPolicy::Skip => Ok(false),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line doesn't match the key regex: {}", line),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::string_lit_as_bytes)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+
+    fn merge(key: RegexKey, inputs: &[&'static str]) -> io::Result<Vec<String>> {
+        let filter = key.clone();
+        let mut heap = Heap::with_key(move |line| key.extract(line))
+            .with_try_filter(move |line| filter.filter(line));
+        for (i, input) in inputs.iter().enumerate() {
+            heap.add_reader(format!("input{}", i), input.as_bytes())?;
+        }
+        heap.collect()
+    }
+
+    #[test]
+    fn test_extract() -> Result<(), io::Error> {
+        let key = RegexKey::new(r"id=(\d+)")?;
+        assert_eq!(key.extract("x id=42 y"), "42");
+        assert_eq!(key.extract("none"), "");
+        assert_eq!(RegexKey::new(r"\d+")?.extract("ab12cd"), "12");
+        assert!(RegexKey::new("(").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmatched() -> Result<(), io::Error> {
+        let inputs = ["b=2\nnone\nb=4\n", "b=1\nb=3\n"];
+        let key = RegexKey::new("b=(.*)")?;
+        let err = merge(key.clone(), &inputs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            merge(key.clone().with_unmatched(UnmatchedPolicy::Skip), &inputs)?,
+            vec!["b=1", "b=2", "b=3", "b=4"]
+        );
+        let inputs = ["none\nb=2\n", "b=1\n"];
+        assert_eq!(
+            merge(key.with_unmatched(UnmatchedPolicy::Empty), &inputs)?,
+            vec!["none", "b=1", "b=2"]
+        );
+        Ok(())
+    }
+}
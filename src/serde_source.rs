@@ -0,0 +1,871 @@
+//! Merging streams of typed values encoded with serde, e.g. the run files a Rust program wrote
+//! with bincode, and writing the merged values back out the same way.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Read};
+use std::marker;
+
+use serde_core::{de, ser};
+
+use crate::input::Input;
+use crate::merge::{KWayMerge, SortedSource};
+
+/// How a stream of values is encoded, one value after another with nothing between them.
+pub trait SerdeFormat {
+    /// Decode the next value of `r`, which has at least one byte left to read.
+    fn read<T, R>(&self, r: &mut R) -> io::Result<T>
+    where
+        T: de::DeserializeOwned,
+        R: io::BufRead;
+
+    /// Encode `value` onto `w`.
+    fn write<T, W>(&self, w: &mut W, value: &T) -> io::Result<()>
+    where
+        T: ser::Serialize + ?Sized,
+        W: io::Write;
+}
+
+/// The encoding of bincode 1's `serialize_into` and `deserialize_from`: integers are fixed-width
+/// and little-endian, and lengths are `u64`s. It isn't self-describing, so values must be read
+/// as the type they were written as.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl SerdeFormat for Bincode {
+    fn read<T, R>(&self, r: &mut R) -> io::Result<T>
+    where
+        T: de::DeserializeOwned,
+        R: io::BufRead,
+    {
+        T::deserialize(&mut Decoder { r }).map_err(|err| err.0)
+    }
+
+    fn write<T, W>(&self, w: &mut W, value: &T) -> io::Result<()>
+    where
+        T: ser::Serialize + ?Sized,
+        W: io::Write,
+    {
+        value.serialize(&mut Encoder { w }).map_err(|err| err.0)
+    }
+}
+
+/// Values as JSON, one per line.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonLines;
+
+#[cfg(feature = "json")]
+impl SerdeFormat for JsonLines {
+    fn read<T, R>(&self, r: &mut R) -> io::Result<T>
+    where
+        T: de::DeserializeOwned,
+        R: io::BufRead,
+    {
+        let mut line = Vec::new();
+        r.read_until(b'\n', &mut line)?;
+        Ok(serde_json::from_slice(&line)?)
+    }
+
+    fn write<T, W>(&self, w: &mut W, value: &T) -> io::Result<()>
+    where
+        T: ser::Serialize + ?Sized,
+        W: io::Write,
+    {
+        serde_json::to_writer(&mut *w, value)?;
+        w.write_all(b"\n")
+    }
+}
+
+/// The values of a reader encoded in `F`, by default bincode, as a `SortedSource`, so they can be
+/// merged as the values themselves, e.g. by `KWayMerge::new` if they are `Ord`. Compressed
+/// readers are decompressed if compression support is enabled.
+pub struct SerdeSource<T, R, F = Bincode>
+where
+    R: io::Read,
+{
+    reader: Input<R>,
+    format: F,
+    values: marker::PhantomData<fn() -> T>,
+}
+
+impl<T, R> SerdeSource<T, R>
+where
+    R: io::Read,
+{
+    /// Read the bincode-encoded values of `reader`.
+    pub fn new(reader: R) -> io::Result<SerdeSource<T, R>> {
+        SerdeSource::with_format(reader, Bincode)
+    }
+}
+
+impl<T, R, F> SerdeSource<T, R, F>
+where
+    R: io::Read,
+{
+    /// Read the values of `reader`, encoded in `format`.
+    pub fn with_format(reader: R, format: F) -> io::Result<SerdeSource<T, R, F>> {
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let reader = Input::detect(reader)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let reader = Input::plain(reader);
+        Ok(SerdeSource {
+            reader,
+            format,
+            values: marker::PhantomData,
+        })
+    }
+}
+
+impl<T, R, F> SortedSource for SerdeSource<T, R, F>
+where
+    T: de::DeserializeOwned + fmt::Debug,
+    R: io::Read,
+    F: SerdeFormat,
+{
+    type Item = T;
+
+    fn next(&mut self) -> io::Result<Option<T>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        self.format.read(&mut self.reader).map(Some)
+    }
+
+    fn describe(&self, value: &T) -> Option<String> {
+        Some(format!("{:?}", value))
+    }
+}
+
+/// Writes values encoded in `F`, by default bincode, as `SerdeSource` reads them.
+pub struct SerdeWriter<W, F = Bincode>
+where
+    W: io::Write,
+{
+    w: io::BufWriter<W>,
+    format: F,
+}
+
+impl<W> SerdeWriter<W>
+where
+    W: io::Write,
+{
+    /// Write bincode-encoded values to `w`.
+    pub fn new(w: W) -> SerdeWriter<W> {
+        SerdeWriter::with_format(w, Bincode)
+    }
+}
+
+impl<W, F> SerdeWriter<W, F>
+where
+    W: io::Write,
+    F: SerdeFormat,
+{
+    /// Write values encoded in `format` to `w`.
+    pub fn with_format(w: W, format: F) -> SerdeWriter<W, F> {
+        SerdeWriter {
+            w: io::BufWriter::new(w),
+            format,
+        }
+    }
+
+    pub fn write<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        self.format.write(&mut self.w, value)
+    }
+
+    /// Write the values `merge` yields, returning how many there were.
+    pub fn write_merged<S>(&mut self, merge: &mut KWayMerge<S>) -> io::Result<u64>
+    where
+        S: SortedSource,
+        S::Item: ser::Serialize,
+    {
+        let mut count = 0;
+        merge.for_each_item(|value| {
+            count += 1;
+            self.format.write(&mut self.w, value)
+        })?;
+        Ok(count)
+    }
+
+    /// Flush what is written and return the writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.w.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/// An error encoding or decoding bincode, for serde.
+#[derive(Debug)]
+struct Error(io::Error);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error(err)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<M: fmt::Display>(message: M) -> Error {
+        Error(io::Error::new(
+            io::ErrorKind::InvalidData,
+            message.to_string(),
+        ))
+    }
+}
+
+impl de::Error for Error {
+    fn custom<M: fmt::Display>(message: M) -> Error {
+        Error(io::Error::new(
+            io::ErrorKind::InvalidData,
+            message.to_string(),
+        ))
+    }
+}
+
+struct Encoder<'a, W> {
+    w: &'a mut W,
+}
+
+impl<W: io::Write> Encoder<'_, W> {
+    fn len(&mut self, len: usize) -> Result<(), Error> {
+        Ok(self.w.write_all(&(len as u64).to_le_bytes())?)
+    }
+
+    fn variant(&mut self, index: u32) -> Result<(), Error> {
+        Ok(self.w.write_all(&index.to_le_bytes())?)
+    }
+}
+
+macro_rules! serialize_number {
+    ($($method:ident: $ty:ty),*) => {
+        $(fn $method(self, v: $ty) -> Result<(), Error> {
+            Ok(self.w.write_all(&v.to_le_bytes())?)
+        })*
+    };
+}
+
+impl<W: io::Write> ser::Serializer for &mut Encoder<'_, W> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    serialize_number!(
+        serialize_i8: i8, serialize_i16: i16, serialize_i32: i32, serialize_i64: i64,
+        serialize_i128: i128, serialize_u8: u8, serialize_u16: u16, serialize_u32: u32,
+        serialize_u64: u64, serialize_u128: u128, serialize_f32: f32, serialize_f64: f64
+    );
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.serialize_u8(u8::from(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        Ok(self.w.write_all(v.encode_utf8(&mut [0; 4]).as_bytes())?)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.len(v.len())?;
+        Ok(self.w.write_all(v)?)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_u8(0)
+    }
+
+    fn serialize_some<T: ser::Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.serialize_u8(1)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+    ) -> Result<(), Error> {
+        self.variant(index)
+    }
+
+    fn serialize_newtype_struct<T: ser::Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ser::Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant(index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        let len = len.ok_or_else(|| {
+            <Error as ser::Error>::custom("Sequences must have a known length for bincode")
+        })?;
+        self.len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self, Error> {
+        self.variant(index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        let len = len.ok_or_else(|| {
+            <Error as ser::Error>::custom("Maps must have a known length for bincode")
+        })?;
+        self.len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self, Error> {
+        self.variant(index)?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Implements the serializers of compound values, whose parts are simply written in turn.
+macro_rules! serialize_parts {
+    ($($trait:ident::$method:ident),*) => {
+        $(impl<W: io::Write> ser::$trait for &mut Encoder<'_, W> {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), Error> {
+                Ok(())
+            }
+        })*
+    };
+}
+
+serialize_parts!(
+    SerializeSeq::serialize_element,
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field,
+    SerializeTupleVariant::serialize_field
+);
+
+impl<W: io::Write> ser::SerializeMap for &mut Encoder<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ser::Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ser::SerializeStruct for &mut Encoder<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ser::SerializeStructVariant for &mut Encoder<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Decoder<'a, R> {
+    r: &'a mut R,
+}
+
+impl<R: io::Read> Decoder<'_, R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        self.r.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        let len = u64::from_le_bytes(self.bytes()?);
+        usize::try_from(len)
+            .map_err(|_| <Error as de::Error>::custom(format!("Length {} is too long", len)))
+    }
+
+    fn byte_buf(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.len()?;
+        // The length isn't trusted to allocate up front, in case the input is corrupt.
+        let mut buf = Vec::new();
+        (&mut *self.r).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buf)
+    }
+}
+
+macro_rules! deserialize_number {
+    ($($method:ident: $ty:ident => $visit:ident),*) => {
+        $(fn $method<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            visitor.$visit($ty::from_le_bytes(self.bytes()?))
+        })*
+    };
+}
+
+impl<'de, R: io::Read> de::Deserializer<'de> for &mut Decoder<'_, R> {
+    type Error = Error;
+
+    deserialize_number!(
+        deserialize_i8: i8 => visit_i8, deserialize_i16: i16 => visit_i16,
+        deserialize_i32: i32 => visit_i32, deserialize_i64: i64 => visit_i64,
+        deserialize_i128: i128 => visit_i128, deserialize_u8: u8 => visit_u8,
+        deserialize_u16: u16 => visit_u16, deserialize_u32: u32 => visit_u32,
+        deserialize_u64: u64 => visit_u64, deserialize_u128: u128 => visit_u128,
+        deserialize_f32: f32 => visit_f32, deserialize_f64: f64 => visit_f64
+    );
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+        Err(de::Error::custom(
+            "bincode isn't self-describing, so values must be read as a known type",
+        ))
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.bytes::<1>()? {
+            [0] => visitor.visit_bool(false),
+            [1] => visitor.visit_bool(true),
+            [byte] => Err(de::Error::custom(format!("Invalid bool {}", byte))),
+        }
+    }
+
+    fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut bytes = [0; 4];
+        self.r.read_exact(&mut bytes[..1])?;
+        let len = match bytes[0] {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        self.r.read_exact(&mut bytes[1..len])?;
+        match std::str::from_utf8(&bytes[..len])
+            .ok()
+            .and_then(|c| c.chars().next())
+        {
+            Some(c) => visitor.visit_char(c),
+            None => Err(de::Error::custom("Invalid char")),
+        }
+    }
+
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match String::from_utf8(self.byte_buf()?) {
+            Ok(s) => visitor.visit_string(s),
+            Err(err) => Err(de::Error::custom(err)),
+        }
+    }
+
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_byte_buf(self.byte_buf()?)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.bytes::<1>()? {
+            [0] => visitor.visit_none(),
+            [1] => visitor.visit_some(self),
+            [byte] => Err(de::Error::custom(format!("Invalid option tag {}", byte))),
+        }
+    }
+
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let left = self.len()?;
+        visitor.visit_seq(Parts {
+            decoder: self,
+            left,
+        })
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_seq(Parts {
+            decoder: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let left = self.len()?;
+        visitor.visit_map(Parts {
+            decoder: self,
+            left,
+        })
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The elements of a sequence, tuple or map, or the fields of a struct.
+struct Parts<'b, 'a, R> {
+    decoder: &'b mut Decoder<'a, R>,
+    left: usize,
+}
+
+impl<'de, R: io::Read> de::SeqAccess<'de> for Parts<'_, '_, R> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de, R: io::Read> de::MapAccess<'de> for Parts<'_, '_, R> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de, R: io::Read> de::EnumAccess<'de> for &mut Decoder<'_, R> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index = u32::from_le_bytes(self.bytes()?);
+        let variant = seed.deserialize(de::value::U32Deserializer::<Error>::new(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, R: io::Read> de::VariantAccess<'de> for &mut Decoder<'_, R> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections;
+    use std::ops;
+    use std::time;
+
+    type Record = (
+        u64,
+        String,
+        Option<Vec<i32>>,
+        collections::BTreeMap<char, bool>,
+    );
+
+    fn record(key: u64) -> Record {
+        let flags = [('é', true), ('x', false)].iter().copied().collect();
+        (
+            key,
+            format!("value {}", key),
+            Some(vec![-1, key as i32]),
+            flags,
+        )
+    }
+
+    #[test]
+    fn test_serde_source() -> Result<(), io::Error> {
+        let mut runs = Vec::new();
+        for keys in [[1, 4, 5], [2, 3, 6]] {
+            let mut w = SerdeWriter::new(Vec::new());
+            for key in keys {
+                w.write(&record(key))?;
+            }
+            runs.push(w.finish()?);
+        }
+        // The encoding is bincode's: a u64, then the string's u64 length and bytes, and so on.
+        assert_eq!(&runs[0][..12], b"\x01\0\0\0\0\0\0\0\x07\0\0\0");
+
+        let mut merge = KWayMerge::new();
+        for (i, run) in runs.iter().enumerate() {
+            merge.add_source(i.to_string(), SerdeSource::<Record, _>::new(&run[..])?)?;
+        }
+        let mut w = SerdeWriter::new(Vec::new());
+        assert_eq!(w.write_merged(&mut merge)?, 6);
+        let merged = w.finish()?;
+        let mut source = SerdeSource::<Record, _>::new(&merged[..])?;
+        let mut records = Vec::new();
+        while let Some(record) = source.next()? {
+            records.push(record);
+        }
+        assert_eq!(records, (1..=6).map(record).collect::<Vec<_>>());
+
+        let mut truncated = SerdeSource::<Record, _>::new(&runs[0][..20])?;
+        assert_eq!(
+            truncated.next().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        Ok(())
+    }
+
+    /// Check that `value` encodes to `bytes` and decodes from them, consuming them all.
+    fn assert_bincode<T>(value: T, bytes: &[u8]) -> io::Result<()>
+    where
+        T: ser::Serialize + de::DeserializeOwned + PartialEq + fmt::Debug,
+    {
+        let mut encoded = Vec::new();
+        Bincode.write(&mut encoded, &value)?;
+        assert_eq!(encoded, bytes, "{:?}", value);
+        let mut r = bytes;
+        assert_eq!(Bincode.read::<T, _>(&mut r)?, value);
+        assert!(r.is_empty(), "{:?} leaves {:?}", value, r);
+        Ok(())
+    }
+
+    /// The bytes here are those bincode 1.3's `serialize` writes for each value.
+    #[test]
+    fn test_bincode_fixtures() -> Result<(), io::Error> {
+        // Enum variants are a u32 index, then their fields: `Result` and `Bound` are enums.
+        assert_bincode::<Result<i128, char>>(
+            Ok(-2),
+            b"\0\0\0\0\xfe\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff",
+        )?;
+        assert_bincode::<Result<i128, char>>(Err('é'), b"\x01\0\0\0\xc3\xa9")?;
+        assert_bincode(
+            i128::MAX,
+            b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f",
+        )?;
+        assert_bincode(ops::Bound::<u8>::Unbounded, b"\0\0\0\0")?;
+        // Options are a u8 tag, and sequences and maps a u64 length.
+        assert_bincode(
+            Some(vec![1u16, 300]),
+            b"\x01\x02\0\0\0\0\0\0\0\x01\0\x2c\x01",
+        )?;
+        assert_bincode(None::<u8>, b"\0")?;
+        let map: collections::BTreeMap<u8, f64> = [(1, 0.5), (2, -1.0)].iter().copied().collect();
+        assert_bincode(
+            map,
+            b"\x02\0\0\0\0\0\0\0\x01\0\0\0\0\0\0\xe0\x3f\x02\0\0\0\0\0\0\xf0\xbf",
+        )?;
+        // Tuples and structs are their fields in turn, with nothing around them.
+        assert_bincode(
+            (7u8, "hi".to_string(), true),
+            b"\x07\x02\0\0\0\0\0\0\0hi\x01",
+        )?;
+        let range = time::Duration::new(1, 5)..time::Duration::from_secs(2);
+        assert_bincode(
+            ops::Bound::Excluded(range),
+            b"\x02\0\0\0\x01\0\0\0\0\0\0\0\x05\0\0\0\x02\0\0\0\0\0\0\0\0\0\0\0",
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_lines() -> Result<(), io::Error> {
+        let mut merge = KWayMerge::new();
+        for input in ["[1,\"a\"]\n[3,\"c\"]\n", "[2,\"b\"]\n"] {
+            let source =
+                SerdeSource::<(u32, String), _, _>::with_format(input.as_bytes(), JsonLines)?;
+            merge.add_source(input.to_string(), source)?;
+        }
+        let mut w = SerdeWriter::with_format(Vec::new(), JsonLines);
+        w.write_merged(&mut merge)?;
+        assert_eq!(w.finish()?, b"[1,\"a\"]\n[2,\"b\"]\n[3,\"c\"]\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Set operations on sorted inputs, treating each input as a set of lines.
+
+use std::io;
+use std::iter;
+
+use crate::merge::{KWayMerge, SortedSource};
+
+/// Which items a set operation keeps. Items that compare equal are the same element, which is
+/// kept once however many times it occurs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetOperation {
+    /// Items in any source.
+    Union,
+    /// Items in every source.
+    Intersection,
+    /// Items in the first source and in no other.
+    Difference,
+}
+
+impl SetOperation {
+    /// Whether to keep an element found in the sources with `indices`, in ascending order, out
+    /// of `sources` sources.
+    fn keeps(self, indices: &[usize], sources: usize) -> bool {
+        match self {
+            SetOperation::Union => true,
+            SetOperation::Intersection => {
+                let distinct = 1 + indices.windows(2).filter(|w| w[0] != w[1]).count();
+                distinct == sources
+            }
+            SetOperation::Difference => indices.iter().all(|&index| index == 0),
+        }
+    }
+}
+
+impl<S> KWayMerge<S>
+where
+    S: SortedSource,
+{
+    /// Iterate over the elements `op` keeps, in sorted order, without buffering more than one
+    /// run of equal items. Each is yielded as the first of its equal items, from the earliest
+    /// source it is in.
+    pub fn set_operation(mut self, op: SetOperation) -> impl Iterator<Item = io::Result<S::Item>> {
+        iter::from_fn(move || loop {
+            let group = match self.next_group()? {
+                Ok(group) => group,
+                Err(err) => return Some(Err(err)),
+            };
+            let indices: Vec<usize> = group.iter().map(|(index, _, _)| *index).collect();
+            if !op.keeps(&indices, self.sources()) {
+                continue;
+            }
+            let (_, _, mut item) = group.into_iter().next().expect("Groups are never empty");
+            if self.emit(&mut item) {
+                return Some(Ok(item));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VecSource(std::vec::IntoIter<u32>);
+
+    impl SortedSource for VecSource {
+        type Item = u32;
+
+        fn next(&mut self) -> io::Result<Option<u32>> {
+            Ok(self.0.next())
+        }
+    }
+
+    fn set_operation(op: SetOperation, sources: Vec<Vec<u32>>) -> io::Result<Vec<u32>> {
+        let mut merge = KWayMerge::new();
+        for (i, items) in sources.into_iter().enumerate() {
+            merge.add_source(format!("source{}", i), VecSource(items.into_iter()))?;
+        }
+        merge.set_operation(op).collect()
+    }
+
+    #[test]
+    fn test_set_operations() -> Result<(), io::Error> {
+        let sources = || vec![vec![1, 2, 2, 4, 6], vec![2, 3, 4, 4], vec![0, 2, 4, 5, 6]];
+        assert_eq!(
+            set_operation(SetOperation::Union, sources())?,
+            vec![0, 1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(
+            set_operation(SetOperation::Intersection, sources())?,
+            vec![2, 4]
+        );
+        assert_eq!(set_operation(SetOperation::Difference, sources())?, vec![1]);
+        let with_empty = vec![vec![1, 2], vec![]];
+        assert_eq!(
+            set_operation(SetOperation::Intersection, with_empty)?,
+            Vec::<u32>::new()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Splitting the merged output across several files.
+
+use std::cmp;
+use std::io;
+use std::io::Write;
+
+use crate::{write_record, Heap, Line};
+
+/// Where `Heap::write_shards` ends one shard and starts the next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShardSplit {
+    /// Before a line that would take the shard past this many bytes of merged lines. A line
+    /// longer than that on its own gets a shard to itself.
+    Bytes(u64),
+    /// After this many lines.
+    Lines(u64),
+    /// At the first line at or after each of these lines, compared the same way merged lines
+    /// are. They are expected to be sorted the same way too.
+    Boundaries(Vec<String>),
+}
+
+/// A shard written by `Heap::write_shards`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    /// The 0-based index the shard was created with.
+    pub index: usize,
+    /// The number of merged lines in the shard.
+    pub lines: u64,
+    /// The number of bytes those lines take with their delimiters, not counting any header.
+    pub bytes: u64,
+    /// The first merged line of the shard, which sorts first in it.
+    pub first: String,
+    /// The last merged line of the shard, which sorts last in it.
+    pub last: String,
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Write the merged lines across shards split as `split` says, each of which is written to
+    /// what `create` returns for its index and starts with the header if it is emitted. Shards
+    /// are only created for lines to write, so there are none for an empty merge and, with
+    /// `ShardSplit::Boundaries`, none between boundaries without lines between them. Returns the
+    /// shards, in order.
+    pub fn write_shards<W, F>(
+        &mut self,
+        split: &ShardSplit,
+        mut create: F,
+    ) -> io::Result<Vec<Shard>>
+    where
+        W: io::Write,
+        F: FnMut(usize) -> io::Result<W>,
+    {
+        let boundaries: Vec<Line<K>> = match split {
+            ShardSplit::Boundaries(boundaries) => {
+                boundaries.iter().map(|line| self.bound(line)).collect()
+            }
+            _ => Vec::new(),
+        };
+        let mut boundaries = boundaries.into_iter().peekable();
+        let delimiter = self.format.delimiter;
+        let mut shards = Vec::new();
+        let mut current: Option<(io::BufWriter<W>, Shard)> = None;
+        let mut record = Vec::new();
+        while let Some(line) = self.merge.next() {
+            let line = line?;
+            record.clear();
+            write_record(&mut record, &line, delimiter, self.eol)?;
+            let mut crossed = false;
+            while let Some(boundary) = boundaries.peek() {
+                if self.merge.compare(&line, boundary) == cmp::Ordering::Less {
+                    break;
+                }
+                boundaries.next();
+                crossed = true;
+            }
+            let starts = match (&current, split) {
+                (None, _) => true,
+                (Some((_, shard)), ShardSplit::Bytes(max)) => {
+                    shard.bytes + record.len() as u64 > *max
+                }
+                (Some((_, shard)), ShardSplit::Lines(max)) => shard.lines >= *max,
+                (Some(_), ShardSplit::Boundaries(_)) => crossed,
+            };
+            if starts {
+                if let Some((mut w, shard)) = current.take() {
+                    w.flush()?;
+                    shards.push(shard);
+                }
+                let mut w = io::BufWriter::new(create(shards.len())?);
+                if self.emit_header {
+                    for header in &self.header {
+                        write_record(&mut w, header, delimiter, self.eol)?;
+                    }
+                }
+                let shard = Shard {
+                    index: shards.len(),
+                    lines: 0,
+                    bytes: 0,
+                    first: line.text.clone(),
+                    last: String::new(),
+                };
+                current = Some((w, shard));
+            }
+            let (w, shard) = current.as_mut().expect("A shard was just started");
+            w.write_all(&record)?;
+            shard.lines += 1;
+            shard.bytes += record.len() as u64;
+            shard.last = line.text;
+        }
+        if let Some((mut w, shard)) = current {
+            w.flush()?;
+            shards.push(shard);
+        }
+        Ok(shards)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shards(heap: &mut Heap<&[u8]>, split: ShardSplit) -> io::Result<Vec<(String, Shard)>> {
+        let mut outputs = vec![Vec::new(); 4];
+        let mut unused = outputs.iter_mut();
+        let shards = heap.write_shards(&split, |_| Ok(unused.next().unwrap()))?;
+        let outputs = outputs
+            .into_iter()
+            .map(|output| String::from_utf8(output).unwrap());
+        Ok(outputs.zip(shards).collect())
+    }
+
+    fn heap() -> io::Result<Heap<&'static [u8]>> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\ne\n".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd\n".as_bytes())?;
+        Ok(heap)
+    }
+
+    #[test]
+    fn test_shards_by_lines() -> Result<(), io::Error> {
+        let shards = shards(&mut heap()?, ShardSplit::Lines(2))?;
+        let contents: Vec<&str> = shards.iter().map(|(output, _)| output.as_str()).collect();
+        assert_eq!(contents, vec!["a\nb\n", "c\nd\n", "e\n"]);
+        let (_, shard) = &shards[1];
+        assert_eq!(
+            shard,
+            &Shard {
+                index: 1,
+                lines: 2,
+                bytes: 4,
+                first: "c".to_string(),
+                last: "d".to_string(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shards_by_bytes() -> Result<(), io::Error> {
+        let shards = shards(&mut heap()?, ShardSplit::Bytes(5))?;
+        let contents: Vec<&str> = shards.iter().map(|(output, _)| output.as_str()).collect();
+        assert_eq!(contents, vec!["a\nb\n", "c\nd\n", "e\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shards_by_boundaries() -> Result<(), io::Error> {
+        let boundaries = vec!["b".to_string(), "cc".to_string(), "d".to_string()];
+        let mut heap = heap()?.with_skip_header(1).with_emit_header(true);
+        heap.add_reader("file3".to_string(), "header\nf\n".as_bytes())?;
+        let shards = shards(&mut heap, ShardSplit::Boundaries(boundaries))?;
+        let contents: Vec<&str> = shards.iter().map(|(output, _)| output.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["header\na\n", "header\nb\nc\n", "header\nd\ne\nf\n"]
+        );
+        Ok(())
+    }
+}
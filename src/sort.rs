@@ -0,0 +1,220 @@
+//! Sorting inputs that turn out not to be sorted, so that an occasional unsorted one doesn't fail
+//! a merge: its lines are sorted in runs that fit in memory, each spilled to a temporary file, and
+//! the runs are merged in its place, as `sort` does for inputs larger than its buffer.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use crate::input::Input;
+use crate::merge::Run;
+use crate::{error, Heap, Line, LineSource, Original, SortedSource};
+
+/// How `Heap::add_path_sorted` sorts an input that isn't sorted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExternalSort {
+    /// About how many bytes of lines are held in memory and sorted at a time.
+    buffer_size: usize,
+    temp_dir: path::PathBuf,
+}
+
+impl Default for ExternalSort {
+    fn default() -> ExternalSort {
+        ExternalSort::new()
+    }
+}
+
+impl ExternalSort {
+    /// Sort in runs of 64 MiB of lines, spilled to `std::env::temp_dir()`.
+    pub fn new() -> ExternalSort {
+        ExternalSort {
+            buffer_size: 64 << 20,
+            temp_dir: env::temp_dir(),
+        }
+    }
+
+    /// Hold about `bytes` bytes of lines in memory at a time, and so in each run.
+    pub fn with_buffer_size(mut self, bytes: usize) -> ExternalSort {
+        self.buffer_size = bytes;
+        self
+    }
+
+    /// Set the directory runs are spilled to.
+    pub fn with_temp_dir<P: Into<path::PathBuf>>(mut self, temp_dir: P) -> ExternalSort {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+}
+
+/// A run spilled to a temporary file, which is removed once the run has been merged.
+struct SpilledRun {
+    file: fs::File,
+    _run: Run,
+}
+
+impl io::Read for SpilledRun {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl<K> Heap<Box<dyn io::Read + Send>, K> {
+    /// Add the file at `path` like `add_file`, first reading it through to check that it is
+    /// sorted. If it isn't, its lines are sorted with `sort` and merged in its place, under its
+    /// name. Returns whether it needed sorting. Errors are handled as for `add_path`.
+    ///
+    /// Every file is read twice, so one that is sorted costs a whole extra pass of reading and
+    /// decompressing it, and one that isn't costs that pass, the sort and its spilled runs. The
+    /// order isn't checked as the file is merged instead because by the time a line turns out of
+    /// place, those before it may have been written.
+    pub fn add_path_sorted(&mut self, path: &path::Path, sort: &ExternalSort) -> io::Result<bool> {
+        match self.add_sorted(path, sort) {
+            Ok(sorted) => Ok(sorted),
+            Err(err) => {
+                let name = path.display().to_string();
+                self.merge.failed_to_add(&name, err).map(|()| false)
+            }
+        }
+    }
+
+    fn add_sorted(&mut self, path: &path::Path, sort: &ExternalSort) -> io::Result<bool> {
+        let name = path.display().to_string();
+        let mut source = self.untracked_source(name.as_str().into(), self.open_detected(path)?);
+        self.read_header(&mut source)?;
+        if self.reads_sorted(&mut source)? {
+            self.add_file(path)?;
+            return Ok(false);
+        }
+        let reader = self.open_detected(path)?;
+        let mut source = self.source(&name, reader);
+        self.read_header(&mut source)?;
+        for run in self.sort_runs(&mut source, sort)? {
+            let file = fs::File::open(&run.path)?;
+            let reader: Box<dyn io::Read + Send> = Box::new(SpilledRun { file, _run: run });
+            let reader = Input::buffered(self.buffer_capacity, reader);
+            let source = self.untracked_source(name.as_str().into(), reader);
+            self.merge.add_source(name.clone(), source)?;
+        }
+        Ok(true)
+    }
+
+    /// Open the file at `path`, decompressing and decoding it as the heap does its inputs.
+    fn open_detected(&self, path: &path::Path) -> io::Result<Input<Box<dyn io::Read + Send>>> {
+        let file: Box<dyn io::Read + Send> = Box::new(fs::File::open(path)?);
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let reader = Input::detect_buffered(self.buffer_capacity, file)?;
+        #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+        let reader = Input::buffered(self.buffer_capacity, file);
+        #[cfg(feature = "encoding")]
+        let reader = reader.decode(self.encoding);
+        Ok(reader)
+    }
+
+    /// Whether the rest of the lines of `source` are sorted, reading up to the first that isn't.
+    fn reads_sorted<R: io::Read>(&self, source: &mut LineSource<R, K>) -> io::Result<bool> {
+        let mut previous = match next_line(source)? {
+            Some(line) => line,
+            None => return Ok(true),
+        };
+        while let Some(line) = next_line(source)? {
+            if self.merge.compare(&line, &previous) == std::cmp::Ordering::Less {
+                return Ok(false);
+            }
+            previous = line;
+        }
+        Ok(true)
+    }
+
+    /// Sort the rest of the lines of `source` into runs spilled to temporary files, each written
+    /// as its lines were read, so that reading it back yields the same lines.
+    fn sort_runs<R: io::Read>(
+        &self,
+        source: &mut LineSource<R, K>,
+        sort: &ExternalSort,
+    ) -> io::Result<Vec<Run>> {
+        let mut runs = Vec::new();
+        let mut lines = Vec::new();
+        let mut held = 0;
+        loop {
+            let line = next_line(source)?;
+            let full = match &line {
+                Some(line) => held + line.text.len() > sort.buffer_size,
+                None => true,
+            };
+            if full && !lines.is_empty() {
+                lines.sort_by(|a, b| self.merge.compare(a, b));
+                runs.push(self.spill(&lines, &sort.temp_dir)?);
+                lines.clear();
+                held = 0;
+            }
+            match line {
+                Some(line) => {
+                    held += line.text.len();
+                    lines.push(line);
+                }
+                None => return Ok(runs),
+            }
+        }
+    }
+
+    /// Write `lines` to a new spill file in `temp_dir`.
+    fn spill(&self, lines: &[Line<K>], temp_dir: &path::Path) -> io::Result<Run> {
+        let run = Run::spill(temp_dir)?;
+        let mut w = io::BufWriter::new(fs::File::create(&run.path)?);
+        for line in lines {
+            let Original { cr, bytes } = &line.original;
+            match bytes {
+                Some(bytes) => w.write_all(bytes)?,
+                None => w.write_all(line.text.as_bytes())?,
+            }
+            if *cr {
+                w.write_all(b"\r")?;
+            }
+            w.write_all(&[self.format.delimiter])?;
+        }
+        w.flush()?;
+        Ok(run)
+    }
+}
+
+/// The next line of `source`, with any error naming it.
+fn next_line<R: io::Read, K>(source: &mut LineSource<R, K>) -> io::Result<Option<Line<K>>> {
+    source
+        .next()
+        .map_err(|err| error::in_file(err, &source.name))
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_path_sorted() -> Result<(), io::Error> {
+        let dir =
+            env::temp_dir().join(format!("merge-sorted-files-rs-sort-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let (sorted, unsorted) = (dir.join("sorted"), dir.join("unsorted"));
+        fs::write(&sorted, "b\nd\nf\n")?;
+        fs::write(&unsorted, "e\ng\na\r\nc\n")?;
+        let spill_dir = dir.join("spill");
+        fs::create_dir_all(&spill_dir)?;
+        // A buffer this small spills a run for every two lines.
+        let sort = ExternalSort::new()
+            .with_buffer_size(2)
+            .with_temp_dir(&spill_dir);
+        let mut heap = Heap::new()
+            .with_crlf(true)
+            .with_line_ending(crate::LineEnding::Preserve);
+        assert!(!heap.add_path_sorted(&sorted, &sort)?);
+        assert!(heap.add_path_sorted(&unsorted, &sort)?);
+        assert_eq!(fs::read_dir(&spill_dir)?.count(), 2);
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sorted_lines(&mut out)?, 7);
+        assert_eq!(out, b"a\r\nb\nc\nd\ne\nf\ng\n");
+        assert_eq!(fs::read_dir(&spill_dir)?.count(), 0);
+        fs::remove_dir_all(&dir)
+    }
+}
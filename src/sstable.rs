@@ -0,0 +1,487 @@
+//! Writing merged lines as a minimal SSTable, for lookup files: blocks of lines, each compressed
+//! on its own, an index of the first line of each block and where it is, and a footer saying
+//! where the index is, so that a range of lines can be scanned without reading the whole file.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+use std::vec;
+
+use crate::Heap;
+
+const MAGIC: &[u8; 8] = b"MSFSST01";
+
+/// The index offset and length, as `u64`s, and the magic number.
+const FOOTER_LEN: usize = 24;
+
+/// How each block of an SSTable is compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockCompression {
+    #[default]
+    None,
+    #[cfg(feature = "gzip")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl BlockCompression {
+    /// How the compression is recorded in the index.
+    fn id(self) -> u8 {
+        match self {
+            BlockCompression::None => 0,
+            #[cfg(feature = "gzip")]
+            BlockCompression::Deflate => 1,
+            #[cfg(feature = "zstd")]
+            BlockCompression::Zstd => 2,
+        }
+    }
+
+    fn compress(self, block: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            BlockCompression::None => Ok(block.to_vec()),
+            #[cfg(feature = "gzip")]
+            BlockCompression::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                io::Write::write_all(&mut encoder, block)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            BlockCompression::Zstd => zstd::encode_all(block, 0),
+        }
+    }
+}
+
+/// Decompress a block compressed as `id` records.
+fn decompress(id: u8, block: Vec<u8>) -> io::Result<Vec<u8>> {
+    match id {
+        0 => Ok(block),
+        #[cfg(feature = "gzip")]
+        1 => {
+            let mut data = Vec::new();
+            flate2::read::DeflateDecoder::new(&block[..]).read_to_end(&mut data)?;
+            Ok(data)
+        }
+        #[cfg(feature = "zstd")]
+        2 => zstd::decode_all(&block[..]),
+        id => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported block compression {}", id),
+        )),
+    }
+}
+
+fn invalid_table() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid SSTable")
+}
+
+/// The index entry of a block.
+#[derive(Clone, Debug)]
+struct Block {
+    first: String,
+    offset: u64,
+    len: u64,
+    compression: u8,
+}
+
+/// Writes lines, added in byte order, as an SSTable that `SsTableReader` can scan.
+pub struct SsTableWriter<W: io::Write> {
+    w: W,
+    block_size: usize,
+    compression: BlockCompression,
+    /// The lines of the block being written, each prefixed with its length as a little-endian
+    /// `u32`.
+    block: Vec<u8>,
+    first: String,
+    last: String,
+    offset: u64,
+    index: Vec<Block>,
+    lines: u64,
+}
+
+impl<W: io::Write> SsTableWriter<W> {
+    /// How many bytes of lines go in a block by default, before compression.
+    pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+    pub fn new(w: W) -> SsTableWriter<W> {
+        SsTableWriter {
+            w,
+            block_size: Self::DEFAULT_BLOCK_SIZE,
+            compression: BlockCompression::None,
+            block: Vec::new(),
+            first: String::new(),
+            last: String::new(),
+            offset: 0,
+            index: Vec::new(),
+            lines: 0,
+        }
+    }
+
+    /// End each block once it holds at least `bytes` bytes of lines. Smaller blocks make scans
+    /// read less, and the index bigger.
+    pub fn with_block_size(mut self, bytes: usize) -> SsTableWriter<W> {
+        self.block_size = bytes.max(1);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: BlockCompression) -> SsTableWriter<W> {
+        self.compression = compression;
+        self
+    }
+
+    /// Add `line`, which must not sort before the line added last.
+    pub fn add(&mut self, line: &str) -> io::Result<()> {
+        if self.lines > 0 && line < self.last.as_str() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Lines of an SSTable must be in byte order, but [{}] follows [{}]",
+                    line, self.last
+                ),
+            ));
+        }
+        let len = u32::try_from(line.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Line is too long for an SSTable",
+            )
+        })?;
+        if self.block.is_empty() {
+            self.first.clear();
+            self.first.push_str(line);
+        }
+        self.block.extend_from_slice(&len.to_le_bytes());
+        self.block.extend_from_slice(line.as_bytes());
+        self.last.clear();
+        self.last.push_str(line);
+        self.lines += 1;
+        if self.block.len() >= self.block_size {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let compressed = self.compression.compress(&self.block)?;
+        self.w.write_all(&compressed)?;
+        self.index.push(Block {
+            first: self.first.clone(),
+            offset: self.offset,
+            len: compressed.len() as u64,
+            compression: self.compression.id(),
+        });
+        self.offset += compressed.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write what is left of the lines, the index and the footer, returning the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        let mut index = Vec::new();
+        index.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        for block in &self.index {
+            index.extend_from_slice(&(block.first.len() as u32).to_le_bytes());
+            index.extend_from_slice(block.first.as_bytes());
+            index.extend_from_slice(&block.offset.to_le_bytes());
+            index.extend_from_slice(&block.len.to_le_bytes());
+            index.push(block.compression);
+        }
+        self.w.write_all(&index)?;
+        self.w.write_all(&self.offset.to_le_bytes())?;
+        self.w.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.w.write_all(MAGIC)?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Reads parts of an SSTable written by `SsTableWriter`, by its index.
+pub struct SsTableReader<R> {
+    r: R,
+    index: Vec<Block>,
+}
+
+impl SsTableReader<fs::File> {
+    pub fn open<P: AsRef<path::Path>>(path: P) -> io::Result<SsTableReader<fs::File>> {
+        SsTableReader::new(fs::File::open(path)?)
+    }
+}
+
+impl<R: io::Read + io::Seek> SsTableReader<R> {
+    /// Read the index of the SSTable `r`.
+    pub fn new(mut r: R) -> io::Result<SsTableReader<R>> {
+        let len = r.seek(io::SeekFrom::End(0))?;
+        if len < FOOTER_LEN as u64 {
+            return Err(invalid_table());
+        }
+        r.seek(io::SeekFrom::Start(len - FOOTER_LEN as u64))?;
+        let mut footer = [0; FOOTER_LEN];
+        r.read_exact(&mut footer)?;
+        if &footer[16..] != MAGIC {
+            return Err(invalid_table());
+        }
+        let u64_at = |bytes: &[u8], at: usize| {
+            let mut n = [0; 8];
+            n.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(n)
+        };
+        let (index_offset, index_len) = (u64_at(&footer, 0), u64_at(&footer, 8));
+        if index_offset.checked_add(index_len) != Some(len - FOOTER_LEN as u64) {
+            return Err(invalid_table());
+        }
+        r.seek(io::SeekFrom::Start(index_offset))?;
+        let mut encoded = Vec::new();
+        (&mut r).take(index_len).read_to_end(&mut encoded)?;
+        let mut index = Vec::new();
+        let mut at = 8;
+        for _ in 0..u64_at(encoded.get(..8).ok_or_else(invalid_table)?, 0) {
+            let first_len: [u8; 4] = encoded
+                .get(at..at + 4)
+                .and_then(|len| <[u8; 4]>::try_from(len).ok())
+                .ok_or_else(invalid_table)?;
+            let first_len = u32::from_le_bytes(first_len) as usize;
+            let end = at + 4 + first_len;
+            let entry = encoded.get(at + 4..end + 17).ok_or_else(invalid_table)?;
+            let first =
+                String::from_utf8(entry[..first_len].to_vec()).map_err(|_| invalid_table())?;
+            index.push(Block {
+                first,
+                offset: u64_at(entry, first_len),
+                len: u64_at(entry, first_len + 8),
+                compression: entry[first_len + 16],
+            });
+            at = end + 17;
+        }
+        Ok(SsTableReader { r, index })
+    }
+
+    /// The number of blocks in the table.
+    pub fn len_blocks(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Iterate over the lines from `start` on, or from the first line, up to but not including
+    /// `end`, or to the last line, reading only the blocks they are in.
+    pub fn scan<'a>(&'a mut self, start: Option<&str>, end: Option<&'a str>) -> Scan<'a, R> {
+        let block = match start {
+            // The block before the first that starts at or after `start` may hold lines equal to
+            // it.
+            Some(start) => self
+                .index
+                .partition_point(|block| block.first.as_str() < start)
+                .saturating_sub(1),
+            None => 0,
+        };
+        Scan {
+            table: self,
+            start: start.map(str::to_string),
+            end,
+            block,
+            lines: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    fn read_block(&mut self, block: usize) -> io::Result<Vec<String>> {
+        let Block {
+            offset,
+            len,
+            compression,
+            ..
+        } = self.index[block];
+        self.r.seek(io::SeekFrom::Start(offset))?;
+        let mut compressed = Vec::new();
+        (&mut self.r).take(len).read_to_end(&mut compressed)?;
+        let data = decompress(compression, compressed)?;
+        let mut lines = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let len: [u8; 4] = data
+                .get(at..at + 4)
+                .and_then(|len| <[u8; 4]>::try_from(len).ok())
+                .ok_or_else(invalid_table)?;
+            let end = at + 4 + u32::from_le_bytes(len) as usize;
+            let line = data.get(at + 4..end).ok_or_else(invalid_table)?;
+            lines.push(String::from_utf8(line.to_vec()).map_err(|_| invalid_table())?);
+            at = end;
+        }
+        Ok(lines)
+    }
+}
+
+/// The lines of a range of an SSTable, from `SsTableReader::scan`.
+pub struct Scan<'a, R> {
+    table: &'a mut SsTableReader<R>,
+    start: Option<String>,
+    end: Option<&'a str>,
+    block: usize,
+    lines: vec::IntoIter<String>,
+    done: bool,
+}
+
+impl<R: io::Read + io::Seek> Iterator for Scan<'_, R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        while !self.done {
+            let line = match self.lines.next() {
+                Some(line) => line,
+                None if self.block < self.table.index.len() => {
+                    match self.table.read_block(self.block) {
+                        Ok(lines) => self.lines = lines.into_iter(),
+                        Err(err) => {
+                            self.done = true;
+                            return Some(Err(err));
+                        }
+                    }
+                    self.block += 1;
+                    continue;
+                }
+                None => break,
+            };
+            if self.start.as_ref().is_some_and(|start| line < *start) {
+                continue;
+            }
+            if self.end.is_some_and(|end| line.as_str() >= end) {
+                break;
+            }
+            return Some(Ok(line));
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read,
+    K: 'static,
+{
+    /// Write the merged lines to `table` and finish it, returning the number of lines. The lines
+    /// must be in byte order, as `Heap::new` merges them, for the table to be scanned, so lines
+    /// merged some other way, or emitted out of order, fail as invalid data; headers aren't
+    /// written.
+    pub fn write_sstable<W: io::Write>(&mut self, mut table: SsTableWriter<W>) -> io::Result<u64> {
+        for line in self.merge.by_ref() {
+            table.add(&line?.text)?;
+        }
+        let lines = table.lines;
+        table.finish()?;
+        Ok(lines)
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(compression: BlockCompression) -> io::Result<SsTableReader<io::Cursor<Vec<u8>>>> {
+        let odd: String = (1..100).step_by(2).map(|i| format!("{:03}\n", i)).collect();
+        let even: String = (0..100).step_by(2).map(|i| format!("{:03}\n", i)).collect();
+        let mut heap = Heap::new();
+        heap.add_reader("odd".to_string(), odd.as_bytes())?;
+        heap.add_reader("even".to_string(), even.as_bytes())?;
+        let mut table = SsTableWriter::new(Vec::new())
+            .with_block_size(40)
+            .with_compression(compression);
+        for line in heap.by_ref() {
+            table.add(&line?)?;
+        }
+        let out = table.finish()?;
+        SsTableReader::new(io::Cursor::new(out))
+    }
+
+    #[test]
+    fn test_sstable() -> Result<(), io::Error> {
+        #[allow(unused_mut)]
+        let mut compressions = vec![BlockCompression::None];
+        #[cfg(feature = "gzip")]
+        compressions.push(BlockCompression::Deflate);
+        #[cfg(feature = "zstd")]
+        compressions.push(BlockCompression::Zstd);
+        for compression in compressions {
+            let mut table = table(compression)?;
+            assert_eq!(table.len_blocks(), 17);
+            let lines = table.scan(None, None).collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(lines.len(), 100);
+            let lines = table
+                .scan(Some("041"), Some("045"))
+                .collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(lines, ["041", "042", "043", "044"]);
+            let lines = table
+                .scan(Some("0975"), None)
+                .collect::<io::Result<Vec<_>>>()?;
+            assert_eq!(lines, ["098", "099"]);
+            assert_eq!(table.scan(Some("1"), None).count(), 0);
+        }
+
+        let mut heap = Heap::new().with_order(crate::Order::Desc);
+        heap.add_reader("desc".to_string(), "b\na\n".as_bytes())?;
+        let err = heap
+            .write_sstable(SsTableWriter::new(Vec::new()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut heap = Heap::new();
+        heap.add_reader("file".to_string(), "a\nb\n".as_bytes())?;
+        let mut out = Vec::new();
+        assert_eq!(heap.write_sstable(SsTableWriter::new(&mut out))?, 2);
+        let lines = SsTableReader::new(io::Cursor::new(out))?
+            .scan(None, None)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, ["a", "b"]);
+        assert!(SsTableReader::new(io::Cursor::new(b"not a table".to_vec())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sstable_edges() -> Result<(), io::Error> {
+        let empty = SsTableWriter::new(Vec::new()).finish()?;
+        let mut table = SsTableReader::new(io::Cursor::new(empty))?;
+        assert_eq!(table.len_blocks(), 0);
+        assert_eq!(table.scan(None, None).count(), 0);
+        assert_eq!(table.scan(Some("a"), Some("b")).count(), 0);
+
+        // Every line is a block of its own, however long it is, so equal lines span blocks.
+        let mut w = SsTableWriter::new(Vec::new()).with_block_size(1);
+        for line in [
+            "",
+            "a",
+            "b",
+            "b",
+            "b",
+            "bb, a line much longer than a block",
+            "c",
+        ] {
+            w.add(line)?;
+        }
+        let err = w.add("b").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let out = w.finish()?;
+        let mut table = SsTableReader::new(io::Cursor::new(out.clone()))?;
+        assert_eq!(table.len_blocks(), 7);
+        let scan = |table: &mut SsTableReader<_>, start, end| {
+            table.scan(start, end).collect::<io::Result<Vec<_>>>()
+        };
+        assert_eq!(scan(&mut table, Some("b"), Some("b "))?, ["b", "b", "b"]);
+        assert_eq!(scan(&mut table, None, Some("a"))?, [""]);
+        assert_eq!(scan(&mut table, Some("c"), None)?, ["c"]);
+        assert!(scan(&mut table, Some("c"), Some("b"))?.is_empty());
+        assert!(scan(&mut table, Some("d"), None)?.is_empty());
+        assert_eq!(scan(&mut table, None, None)?.len(), 7);
+
+        // A table cut short, or whose footer points outside it, isn't read.
+        assert!(SsTableReader::new(io::Cursor::new(out[..out.len() - 1].to_vec())).is_err());
+        let mut moved = out.clone();
+        let footer = moved.len() - FOOTER_LEN;
+        moved[footer] ^= 1;
+        assert!(SsTableReader::new(io::Cursor::new(moved)).is_err());
+        Ok(())
+    }
+}
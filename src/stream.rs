@@ -0,0 +1,69 @@
+//! Consuming a `Heap` from async code as a `futures::Stream`.
+
+use std::io;
+use std::thread;
+
+use futures::SinkExt;
+use futures::Stream;
+
+use crate::Heap;
+
+impl<T, K> Heap<T, K>
+where
+    T: io::Read + Send + 'static,
+    K: Send + 'static,
+{
+    /// Merge on a background thread, handing the lines to the returned stream through a channel
+    /// of `capacity` lines. The thread blocks whenever the channel is full, so a slow consumer
+    /// holds back the merge rather than letting lines pile up; dropping the stream stops it.
+    pub fn into_stream(self, capacity: usize) -> impl Stream<Item = io::Result<String>> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(capacity);
+        thread::spawn(move || {
+            futures::executor::block_on(async move {
+                for line in self {
+                    let failed = line.is_err();
+                    if tx.send(line).await.is_err() || failed {
+                        break;
+                    }
+                }
+            })
+        });
+        rx
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_into_stream() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\ne".as_bytes())?;
+        heap.add_reader("file2".to_string(), "b\nd".as_bytes())?;
+        let lines = futures::executor::block_on(heap.into_stream(1).collect::<Vec<_>>());
+        let lines = lines.into_iter().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["a", "b", "c", "d", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_into_stream_ooo() -> Result<(), io::Error> {
+        let mut heap = Heap::new();
+        heap.add_reader("file1".to_string(), "a\nc\nb".as_bytes())?;
+        let lines = futures::executor::block_on(heap.into_stream(1).collect::<Vec<_>>());
+        assert_eq!(lines.len(), 2);
+        let err = lines
+            .into_iter()
+            .last()
+            .unwrap()
+            .expect_err("Expected an error");
+        assert_eq!(
+            format!("{}", err),
+            "Input lines in file [file1] out of order at line 3!"
+        );
+        Ok(())
+    }
+}
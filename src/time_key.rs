@@ -0,0 +1,267 @@
+//! Sort keys parsed from timestamps, for interleaving logs by time.
+
+use std::convert;
+use std::io;
+use std::str;
+
+/// How the timestamp of a line is written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// RFC 3339, e.g. `2024-05-01T12:00:00.5+02:00`.
+    Rfc3339,
+    /// Seconds since the Unix epoch, possibly with a fraction.
+    EpochSeconds,
+    /// Milliseconds since the Unix epoch.
+    EpochMillis,
+    /// Syslog, either the BSD format of RFC 3164, e.g. `May  1 12:00:00`, or RFC 5424, e.g.
+    /// `1 2024-05-01T12:00:00Z`, in either case after any `<PRI>` priority. Since BSD syslog has
+    /// no year, its timestamps are assumed to fall within a single year, so inputs in it can't be
+    /// merged with inputs in RFC 5424.
+    Syslog,
+    /// The bracketed timestamp of the Common Log Format of Apache and Nginx access logs, e.g.
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.0" 200 2326`.
+    CommonLog,
+    /// A `strftime` pattern such as `%Y-%m-%d %H:%M:%S`. Times without an offset are taken as UTC.
+    Strftime(String),
+}
+
+impl str::FromStr for TimeFormat {
+    type Err = convert::Infallible;
+
+    /// Parse `rfc3339`, `epoch`, `epoch-ms`, `syslog` or `clf`, or else a `strftime` pattern.
+    fn from_str(format: &str) -> Result<TimeFormat, Self::Err> {
+        Ok(match format {
+            "rfc3339" => TimeFormat::Rfc3339,
+            "epoch" => TimeFormat::EpochSeconds,
+            "epoch-ms" => TimeFormat::EpochMillis,
+            "syslog" => TimeFormat::Syslog,
+            "clf" => TimeFormat::CommonLog,
+            pattern => TimeFormat::Strftime(pattern.to_string()),
+        })
+    }
+}
+
+/// Parses the timestamp of each line into nanoseconds since the Unix epoch, to use as its key
+/// with `Heap::with_key`. The timestamp is read from the start of the line, or from the match of
+/// a capture regex. Lines without a parseable timestamp have a key of `None`, which sorts first.
+#[derive(Clone, Debug)]
+pub struct TimeKey {
+    format: TimeFormat,
+    capture: Option<regex::Regex>,
+}
+
+impl TimeKey {
+    pub fn new(format: TimeFormat) -> TimeKey {
+        TimeKey {
+            format,
+            capture: None,
+        }
+    }
+
+    /// Read the timestamp from where `capture` matches each line: its group named `time`, else its
+    /// first group, else the whole match.
+    pub fn with_capture(mut self, capture: &str) -> io::Result<TimeKey> {
+        let capture = regex::Regex::new(capture)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.capture = Some(capture);
+        Ok(self)
+    }
+
+    /// The timestamp of `line`, in nanoseconds since the Unix epoch.
+    pub fn extract(&self, line: &str) -> Option<i64> {
+        let text = match &self.capture {
+            Some(capture) => {
+                let captures = capture.captures(line)?;
+                captures
+                    .name("time")
+                    .or_else(|| captures.get(1))
+                    .or_else(|| captures.get(0))?
+                    .as_str()
+            }
+            None => line,
+        };
+        self.parse(text)
+    }
+
+    fn parse(&self, text: &str) -> Option<i64> {
+        match &self.format {
+            TimeFormat::Rfc3339 => {
+                let token = text.split_whitespace().next()?;
+                chrono::DateTime::parse_from_rfc3339(token)
+                    .ok()?
+                    .timestamp_nanos_opt()
+            }
+            TimeFormat::EpochSeconds => parse_epoch(text, 1_000_000_000),
+            TimeFormat::EpochMillis => parse_epoch(text, 1_000_000),
+            TimeFormat::Syslog => {
+                let text = match text.strip_prefix('<') {
+                    Some(rest) => rest.split_once('>')?.1,
+                    None => text,
+                };
+                if let Some((version, rest)) = text.split_once(' ') {
+                    if !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit()) {
+                        return TimeKey::new(TimeFormat::Rfc3339).parse(rest);
+                    }
+                }
+                // The year doesn't matter as long as it's the same for every line; a leap year
+                // lets February 29th through.
+                let stamped = format!("2000 {}", text.get(..15)?);
+                parse_strftime(&stamped, "%Y %b %e %H:%M:%S")
+            }
+            TimeFormat::CommonLog => {
+                let (_, stamp) = text.split_once('[')?;
+                let (stamp, _) = stamp.split_once(']')?;
+                parse_strftime(stamp, "%d/%b/%Y:%H:%M:%S %z")
+            }
+            TimeFormat::Strftime(pattern) => parse_strftime(text, pattern),
+        }
+    }
+}
+
+/// Parse a leading decimal number of units of `nanos` nanoseconds each.
+fn parse_epoch(text: &str, nanos: i64) -> Option<i64> {
+    let text = text.trim_start();
+    let int_len = text.bytes().take_while(u8::is_ascii_digit).count();
+    let int = text[..int_len].parse::<i64>().ok()?;
+    let mut value = int.checked_mul(nanos)?;
+    if let Some(frac) = text[int_len..].strip_prefix('.') {
+        let mut scale = nanos;
+        for digit in frac.bytes().take_while(u8::is_ascii_digit) {
+            scale /= 10;
+            value += i64::from(digit - b'0') * scale;
+        }
+    }
+    Some(value)
+}
+
+fn parse_strftime(text: &str, pattern: &str) -> Option<i64> {
+    if let Ok((time, _)) = chrono::DateTime::parse_and_remainder(text, pattern) {
+        return time.timestamp_nanos_opt();
+    }
+    let time = match chrono::NaiveDateTime::parse_and_remainder(text, pattern) {
+        Ok((time, _)) => time,
+        Err(_) => chrono::NaiveDate::parse_and_remainder(text, pattern)
+            .ok()?
+            .0
+            .and_hms_opt(0, 0, 0)?,
+    };
+    time.and_utc().timestamp_nanos_opt()
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn test_formats() -> Result<(), io::Error> {
+        let rfc3339 = TimeKey::new(TimeFormat::Rfc3339);
+        assert_eq!(rfc3339.extract("1970-01-01T00:00:01Z GET /"), Some(SECOND));
+        assert_eq!(rfc3339.extract("1970-01-01T01:00:01+01:00"), Some(SECOND));
+        assert_eq!(rfc3339.extract("GET /"), None);
+        let epoch = TimeKey::new(TimeFormat::EpochSeconds);
+        assert_eq!(epoch.extract("12.5 x"), Some(12 * SECOND + SECOND / 2));
+        let epoch_ms = TimeKey::new(TimeFormat::EpochMillis);
+        assert_eq!(epoch_ms.extract("1500"), Some(SECOND + SECOND / 2));
+        let strftime = TimeKey::new("%d/%m/%Y %H:%M:%S".parse().unwrap());
+        assert_eq!(strftime.extract("01/01/1970 00:00:02 x"), Some(2 * SECOND));
+        let date = TimeKey::new("%Y-%m-%d".parse().unwrap());
+        assert_eq!(date.extract("1970-01-02"), Some(86_400 * SECOND));
+        Ok(())
+    }
+
+    #[test]
+    fn test_syslog() -> Result<(), io::Error> {
+        let syslog = TimeKey::new("syslog".parse().unwrap());
+        let may = syslog.extract("May  1 12:00:00 host sshd[1]: x").unwrap();
+        let june = syslog
+            .extract("<13>Jun 10 01:00:00 host cron[2]: y")
+            .unwrap();
+        assert!(may < june);
+        assert_eq!(
+            syslog.extract("<13>May  1 12:00:01 host sshd[1]: x"),
+            Some(may + SECOND)
+        );
+        assert!(syslog.extract("Feb 29 00:00:00 host x").is_some());
+        assert_eq!(
+            syslog.extract("<34>1 1970-01-01T00:00:01.5Z host su - ID47 - x"),
+            Some(SECOND + SECOND / 2)
+        );
+        // Malformed lines have no timestamp.
+        for line in [
+            "",
+            "<13>",
+            "<13 May  1 12:00:00 host x",
+            "May  1",
+            "Foo  1 12:00:00 host x",
+            "May 32 12:00:00 host x",
+            "May  1 25:00:00 host x",
+            "<34>1 - host su - ID47 - x",
+            "<34>1 1970-13-01T00:00:00Z host x",
+        ] {
+            assert_eq!(syslog.extract(line), None, "{:?}", line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_common_log() -> Result<(), io::Error> {
+        let clf = TimeKey::new("clf".parse().unwrap());
+        assert_eq!(
+            clf.extract(r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.0" 200 2326"#),
+            Some(971_211_336 * SECOND)
+        );
+        assert_eq!(
+            clf.extract(r#"::1 - frank [01/Jan/1970:01:00:03 +0100] "GET / HTTP/1.0" 200 2"#),
+            Some(3 * SECOND)
+        );
+        // Malformed lines have no timestamp.
+        for line in [
+            "",
+            "::1 - - [-] x",
+            "::1 - - 10/Oct/2000:13:55:36 -0700 x",
+            "::1 - - [10/Oct/2000:13:55:36 -0700 x",
+            "::1 - - [10/Oct/2000:13:55:36] x",
+            "::1 - - [32/Oct/2000:13:55:36 -0700] x",
+            "::1 - - [10/Foo/2000:13:55:36 -0700] x",
+        ] {
+            assert_eq!(clf.extract(line), None, "{:?}", line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture() -> Result<(), io::Error> {
+        let key = TimeKey::new(TimeFormat::EpochSeconds).with_capture(r"ts=(\d+)")?;
+        assert_eq!(key.extract("level=info ts=3 msg=hi"), Some(3 * SECOND));
+        assert_eq!(key.extract("level=info"), None);
+        let key = TimeKey::new(TimeFormat::Rfc3339).with_capture(r"\[(?P<time>[^\]]+)\]")?;
+        assert_eq!(key.extract("x [1970-01-01T00:00:01Z] y"), Some(SECOND));
+        assert!(TimeKey::new(TimeFormat::Rfc3339).with_capture("(").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_merge() -> Result<(), io::Error> {
+        let key = TimeKey::new(TimeFormat::Rfc3339);
+        let mut heap = Heap::with_key(move |line| key.extract(line));
+        heap.add_reader(
+            "file1".to_string(),
+            "2024-01-01T10:00:00Z a\n2024-01-01T12:00:00+01:00 c".as_bytes(),
+        )?;
+        heap.add_reader("file2".to_string(), "2024-01-01T10:30:00Z b".as_bytes())?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            vec![
+                "2024-01-01T10:00:00Z a",
+                "2024-01-01T10:30:00Z b",
+                "2024-01-01T12:00:00+01:00 c"
+            ]
+        );
+        Ok(())
+    }
+}
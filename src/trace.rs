@@ -0,0 +1,191 @@
+//! Instrumentation of merges with `tracing`: a span for each input, events when one is found out
+//! of order or is exhausted, and periodic throughput events for each input and for the merge.
+
+use std::io;
+use std::sync::atomic;
+use std::time;
+
+use crate::{Counters, OutOfOrderPolicy};
+
+/// How many lines apart inputs and merges report their throughput.
+pub(crate) const THROUGHPUT_EVERY: u64 = 1 << 20;
+
+/// The span of an input, and how long the merge has spent reading it.
+pub(crate) struct SourceTrace {
+    span: tracing::Span,
+    busy: time::Duration,
+    /// The number of lines read at which the input next reports its throughput.
+    next: u64,
+    exhausted: bool,
+}
+
+impl SourceTrace {
+    pub(crate) fn new(name: &str) -> SourceTrace {
+        SourceTrace {
+            span: tracing::info_span!("source", name),
+            busy: time::Duration::ZERO,
+            next: THROUGHPUT_EVERY,
+            exhausted: false,
+        }
+    }
+
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Account for a read of the input that started at `started` and returned `read`, which is
+    /// false at its end.
+    pub(crate) fn read(
+        &mut self,
+        started: time::Instant,
+        counters: &Counters,
+        read: &io::Result<bool>,
+    ) {
+        self.busy += started.elapsed();
+        let lines = counters.lines.load(atomic::Ordering::Relaxed);
+        let bytes = counters.bytes.load(atomic::Ordering::Relaxed);
+        let busy_ms = self.busy.as_millis() as u64;
+        match read {
+            Ok(true) if lines >= self.next => {
+                self.next = lines + THROUGHPUT_EVERY;
+                let bytes_per_sec = (bytes as f64 / self.busy.as_secs_f64()) as u64;
+                tracing::info!(lines, bytes, busy_ms, bytes_per_sec, "source throughput");
+            }
+            Ok(false) if !self.exhausted => {
+                self.exhausted = true;
+                tracing::info!(lines, bytes, busy_ms, "source exhausted");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// When a merge started, for its throughput events.
+pub(crate) struct MergeTrace {
+    started: Option<time::Instant>,
+    /// The number of emitted items at which the merge next reports its throughput.
+    next: u64,
+}
+
+impl MergeTrace {
+    pub(crate) fn new() -> MergeTrace {
+        MergeTrace {
+            started: None,
+            next: THROUGHPUT_EVERY,
+        }
+    }
+
+    /// Account for the merge having emitted `emitted` items, as it is about to emit another.
+    pub(crate) fn emitting(&mut self, emitted: u64) {
+        let started = *self.started.get_or_insert_with(time::Instant::now);
+        if emitted >= self.next {
+            self.next = emitted + THROUGHPUT_EVERY;
+            let elapsed = started.elapsed();
+            let lines_per_sec = (emitted as f64 / elapsed.as_secs_f64()) as u64;
+            tracing::info!(
+                emitted,
+                elapsed_ms = elapsed.as_millis() as u64,
+                lines_per_sec,
+                "merge throughput"
+            );
+        }
+    }
+}
+
+/// Report the `line_no`th line of `file` sorting before the line before it, which `policy` deals
+/// with.
+pub(crate) fn out_of_order(file: &str, line_no: u64, policy: OutOfOrderPolicy) {
+    tracing::warn!(file, line_no, ?policy, "input out of order");
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+    use std::fmt;
+    use std::sync;
+
+    /// The message of each event, along with the name of the source whose span it happened in.
+    type Events = sync::Arc<sync::Mutex<Vec<(Option<String>, String)>>>;
+
+    /// A subscriber recording the message of each event, along with the name of the source whose
+    /// span it happened in, if any.
+    #[derive(Default)]
+    struct Recorder {
+        spans: sync::Mutex<Vec<String>>,
+        current: sync::Mutex<Vec<u64>>,
+        events: Events,
+    }
+
+    struct Field<'a>(&'a str, &'a mut Option<String>);
+
+    impl tracing::field::Visit for Field<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            if field.name() == self.0 {
+                *self.1 = Some(format!("{:?}", value).trim_matches('"').to_string());
+            }
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut name = None;
+            span.record(&mut Field("name", &mut name));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(name.unwrap_or_default());
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = None;
+            event.record(&mut Field("message", &mut message));
+            let source = self.current.lock().unwrap().last().map(|id| {
+                let spans = self.spans.lock().unwrap();
+                spans[*id as usize - 1].clone()
+            });
+            let message = message.unwrap_or_default();
+            self.events.lock().unwrap().push((source, message));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.current.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.current.lock().unwrap().pop();
+        }
+    }
+
+    #[test]
+    fn test_trace_events() -> Result<(), io::Error> {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        tracing::subscriber::with_default(recorder, || -> io::Result<()> {
+            let mut heap = Heap::new().with_out_of_order_policy(OutOfOrderPolicy::WarnAndContinue);
+            heap.add_reader("file1".to_string(), "a\nc\n".as_bytes())?;
+            heap.add_reader("file2".to_string(), "d\nb\n".as_bytes())?;
+            heap.write_sorted_lines(io::sink())?;
+            Ok(())
+        })?;
+        let events = events.lock().unwrap();
+        let exhausted: Vec<Option<&str>> = events
+            .iter()
+            .filter(|(_, message)| message == "source exhausted")
+            .map(|(source, _)| source.as_deref())
+            .collect();
+        assert_eq!(exhausted, vec![Some("file1"), Some("file2")]);
+        assert!(events
+            .iter()
+            .any(|(_, message)| message == "input out of order"));
+        Ok(())
+    }
+}
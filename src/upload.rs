@@ -0,0 +1,329 @@
+//! Writing the merged output straight to an object store, as a multipart upload, so it never has
+//! to exist on local disk.
+
+use std::io;
+use std::sync;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+/// An upload of an object in parts, started with `ObjectStore::put_multipart`.
+pub trait MultipartUpload: Send + Sync {
+    /// Upload `data` as the part numbered `part`, counting from 1. Parts may be uploaded
+    /// concurrently and out of order, and a part that failed may be uploaded again.
+    fn put_part(&self, part: u32, data: &[u8]) -> io::Result<()>;
+
+    /// Create the object from parts 1 to `parts`, in order.
+    fn complete(&self, parts: u32) -> io::Result<()>;
+
+    /// Give up on the upload, discarding the parts uploaded.
+    fn abort(&self) -> io::Result<()>;
+}
+
+/// Writes an object as a multipart upload: what is written is cut into parts of `part_size`
+/// bytes, bar the last, which are uploaded on `concurrency` threads while writing carries on,
+/// each retried a few times if it fails. `finish` completes the upload; dropping the writer
+/// without finishing it aborts the upload.
+pub struct MultipartWriter {
+    upload: sync::Arc<dyn MultipartUpload>,
+    part_size: usize,
+    concurrency: usize,
+    retries: u32,
+    buf: Vec<u8>,
+    /// The number of parts sent to be uploaded.
+    parts: u32,
+    /// Where parts are sent to be uploaded, once the first is.
+    parts_to_upload: Option<mpsc::SyncSender<(u32, Vec<u8>)>>,
+    uploaders: Vec<thread::JoinHandle<()>>,
+    /// The first error uploading a part, after which no more are uploaded.
+    failed: sync::Arc<sync::Mutex<Option<io::Error>>>,
+    finished: bool,
+}
+
+impl MultipartWriter {
+    /// The part size of S3, whose parts but the last must be at least 5 MiB.
+    pub const DEFAULT_PART_SIZE: usize = 8 << 20;
+
+    pub fn new(upload: Box<dyn MultipartUpload>) -> MultipartWriter {
+        MultipartWriter {
+            upload: sync::Arc::from(upload),
+            part_size: MultipartWriter::DEFAULT_PART_SIZE,
+            concurrency: 4,
+            retries: 3,
+            buf: Vec::new(),
+            parts: 0,
+            parts_to_upload: None,
+            uploaders: Vec::new(),
+            failed: sync::Arc::new(sync::Mutex::new(None)),
+            finished: false,
+        }
+    }
+
+    /// Cut the output into parts of `bytes` each, other than the last.
+    pub fn with_part_size(mut self, bytes: usize) -> MultipartWriter {
+        self.part_size = bytes.max(1);
+        self
+    }
+
+    /// Upload up to `uploads` parts at once. Since a part is held in memory until it has been
+    /// uploaded, writing waits once that many, and as many again, are.
+    pub fn with_concurrency(mut self, uploads: usize) -> MultipartWriter {
+        self.concurrency = uploads.max(1);
+        self
+    }
+
+    /// Try uploading each part up to `retries` more times if it fails, waiting longer each time.
+    pub fn with_retries(mut self, retries: u32) -> MultipartWriter {
+        self.retries = retries;
+        self
+    }
+
+    fn failure(&self) -> Option<io::Error> {
+        let failed = self.failed.lock().unwrap_or_else(|err| err.into_inner());
+        failed
+            .as_ref()
+            .map(|err| io::Error::new(err.kind(), err.to_string()))
+    }
+
+    /// Send `data` to be uploaded as the next part.
+    fn send_part(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if let Some(err) = self.failure() {
+            return Err(err);
+        }
+        if self.parts_to_upload.is_none() {
+            let (sender, receiver) = mpsc::sync_channel::<(u32, Vec<u8>)>(self.concurrency);
+            let receiver = sync::Arc::new(sync::Mutex::new(receiver));
+            for _ in 0..self.concurrency {
+                let (upload, failed, receiver) =
+                    (self.upload.clone(), self.failed.clone(), receiver.clone());
+                let retries = self.retries;
+                self.uploaders.push(thread::spawn(move || loop {
+                    let next = receiver
+                        .lock()
+                        .unwrap_or_else(|err| err.into_inner())
+                        .recv();
+                    let (part, data) = match next {
+                        Ok(next) => next,
+                        Err(_) => return,
+                    };
+                    if let Err(err) = put_part(&*upload, part, &data, retries) {
+                        failed
+                            .lock()
+                            .unwrap_or_else(|err| err.into_inner())
+                            .get_or_insert(err);
+                        return;
+                    }
+                }));
+            }
+            self.parts_to_upload = Some(sender);
+        }
+        self.parts += 1;
+        let sent = match &self.parts_to_upload {
+            Some(sender) => sender.send((self.parts, data)).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // Every uploader has given up after an error.
+            return Err(self
+                .failure()
+                .unwrap_or_else(|| io::Error::other("Uploading failed")));
+        }
+        Ok(())
+    }
+
+    /// Wait for the parts sent to be uploaded, returning the first error uploading one.
+    fn wait(&mut self) -> io::Result<()> {
+        self.parts_to_upload = None;
+        for uploader in self.uploaders.drain(..) {
+            let _ = uploader.join();
+        }
+        match self.failure() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Upload what is left and complete the upload, returning the number of parts it has. An
+    /// object with nothing written to it is uploaded as a single empty part.
+    pub fn finish(mut self) -> io::Result<u32> {
+        self.finished = true;
+        let result = (|| {
+            if !self.buf.is_empty() || self.parts == 0 {
+                let data = std::mem::take(&mut self.buf);
+                self.send_part(data)?;
+            }
+            self.wait()?;
+            self.upload.complete(self.parts)
+        })();
+        match result {
+            Ok(()) => Ok(self.parts),
+            Err(err) => {
+                let _ = self.wait();
+                let _ = self.upload.abort();
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Upload part `part`, trying again up to `retries` times.
+fn put_part(upload: &dyn MultipartUpload, part: u32, data: &[u8], retries: u32) -> io::Result<()> {
+    let mut backoff = time::Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match upload.put_part(part, data) {
+            Ok(()) => return Ok(()),
+            Err(_) if attempt < retries => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+impl io::Write for MultipartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= self.part_size {
+            let rest = self.buf.split_off(self.part_size);
+            let part = std::mem::replace(&mut self.buf, rest);
+            self.send_part(part)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Parts are only uploaded once they are full, so flushing does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MultipartWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.wait();
+            let _ = self.upload.abort();
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections;
+    use std::io::Write;
+
+    /// An upload to memory, which fails the first attempt at each part in `flaky`.
+    #[derive(Clone, Default)]
+    struct Memory {
+        parts: sync::Arc<sync::Mutex<collections::BTreeMap<u32, Vec<u8>>>>,
+        flaky: sync::Arc<sync::Mutex<Vec<u32>>>,
+        object: sync::Arc<sync::Mutex<Option<Vec<u8>>>>,
+        aborted: sync::Arc<sync::atomic::AtomicBool>,
+    }
+
+    impl MultipartUpload for Memory {
+        fn put_part(&self, part: u32, data: &[u8]) -> io::Result<()> {
+            let mut flaky = self.flaky.lock().unwrap();
+            if let Some(i) = flaky.iter().position(|flaky| *flaky == part) {
+                flaky.remove(i);
+                return Err(io::Error::other("Flaky"));
+            }
+            self.parts.lock().unwrap().insert(part, data.to_vec());
+            Ok(())
+        }
+
+        fn complete(&self, parts: u32) -> io::Result<()> {
+            let stored = self.parts.lock().unwrap();
+            let object = (1..=parts).flat_map(|part| stored[&part].clone()).collect();
+            *self.object.lock().unwrap() = Some(object);
+            Ok(())
+        }
+
+        fn abort(&self) -> io::Result<()> {
+            self.aborted.store(true, sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_multipart_writer() -> Result<(), io::Error> {
+        let upload = Memory::default();
+        upload.flaky.lock().unwrap().extend([2, 3]);
+        let mut w = MultipartWriter::new(Box::new(upload.clone()))
+            .with_part_size(4)
+            .with_concurrency(2);
+        let contents: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        for line in contents.lines() {
+            writeln!(w, "{}", line)?;
+        }
+        let parts = w.finish()?;
+        assert_eq!(parts as usize, contents.len().div_ceil(4));
+        let object = upload.object.lock().unwrap().take().unwrap();
+        assert_eq!(object, contents.as_bytes());
+        assert!(!upload.aborted.load(sync::atomic::Ordering::Relaxed));
+
+        // Nothing written is one empty part.
+        let upload = Memory::default();
+        assert_eq!(MultipartWriter::new(Box::new(upload.clone())).finish()?, 1);
+        assert_eq!(upload.object.lock().unwrap().take(), Some(Vec::new()));
+
+        // A part failing more often than it is retried fails the upload, which is aborted.
+        let upload = Memory::default();
+        upload.flaky.lock().unwrap().extend([1, 1]);
+        let mut w = MultipartWriter::new(Box::new(upload.clone())).with_retries(1);
+        w.write_all(b"a\n")?;
+        assert!(w.finish().is_err());
+        assert!(upload.aborted.load(sync::atomic::Ordering::Relaxed));
+        assert_eq!(upload.object.lock().unwrap().take(), None);
+
+        // So is an upload that is dropped before it is finished.
+        let upload = Memory::default();
+        let mut w = MultipartWriter::new(Box::new(upload.clone()));
+        w.write_all(b"a\n")?;
+        drop(w);
+        assert!(upload.aborted.load(sync::atomic::Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multipart_writer_edges() -> Result<(), io::Error> {
+        // Exactly one part's worth is one part, not a full part and an empty one.
+        let upload = Memory::default();
+        let mut w = MultipartWriter::new(Box::new(upload.clone())).with_part_size(4);
+        w.write_all(b"abcd")?;
+        assert_eq!(w.finish()?, 1);
+        assert_eq!(upload.object.lock().unwrap().take(), Some(b"abcd".to_vec()));
+
+        // A write of several parts is cut into all of them, and a part size of 0 is taken as 1.
+        let upload = Memory::default();
+        let mut w = MultipartWriter::new(Box::new(upload.clone()))
+            .with_part_size(0)
+            .with_concurrency(0);
+        w.write_all(b"abcdefg")?;
+        assert_eq!(w.finish()?, 7);
+        assert_eq!(upload.parts.lock().unwrap()[&7], b"g");
+        assert_eq!(
+            upload.object.lock().unwrap().take(),
+            Some(b"abcdefg".to_vec())
+        );
+
+        // A part failing on its only attempt fails the upload, once it has all been written.
+        let upload = Memory::default();
+        upload.flaky.lock().unwrap().push(2);
+        let mut w = MultipartWriter::new(Box::new(upload.clone()))
+            .with_part_size(2)
+            .with_concurrency(1)
+            .with_retries(0);
+        let _ = w.write_all(b"abcdef");
+        let err = w.finish().unwrap_err();
+        assert_eq!(err.to_string(), "Flaky");
+        assert!(upload.aborted.load(sync::atomic::Ordering::Relaxed));
+        assert_eq!(upload.object.lock().unwrap().take(), None);
+        assert!(!upload.parts.lock().unwrap().contains_key(&2));
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+//! Reading files through io_uring on Linux, keeping several reads of each file in flight at once
+//! rather than reading it one block at a time.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Seek;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::read_ahead::BLOCK_SIZE;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+/// `struct io_sqring_offsets`.
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_cqring_offsets`.
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_uring_params`.
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// `struct io_uring_sqe`, with only the fields a read uses.
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// `struct io_uring_cqe`.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// Memory shared with the kernel, unmapped when dropped.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is only ever accessed through the ring owning it.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    /// A pointer to what is `offset` bytes into the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// An io_uring instance, submitting reads and reaping their completions.
+pub(crate) struct Ring {
+    // The mappings are dropped before the ring they map is closed.
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    params: Params,
+    /// The number of reads queued and not yet submitted.
+    queued: u32,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    /// Set up a ring with room for `entries` reads in flight, failing e.g. on kernels without
+    /// io_uring, or where it is disabled.
+    pub(crate) fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries.max(1),
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        Ok(Ring {
+            sq: Mapping::new(&fd, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Mapping::new(&fd, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mapping::new(&fd, sqes_len, IORING_OFF_SQES)?,
+            params,
+            queued: 0,
+            fd,
+        })
+    }
+
+    /// The number of reads the ring has room for in flight.
+    fn entries(&self) -> u32 {
+        self.params.sq_entries
+    }
+
+    /// Queue a read of `file` at `offset` into `buf`, to be submitted by the next `wait`. The
+    /// caller keeps no more reads in flight than the ring has room for, and doesn't touch `buf`
+    /// until the read completes.
+    fn queue_read(&mut self, file: &fs::File, buf: &mut [u8], offset: u64, user_data: u64) {
+        let off = &self.params.sq_off;
+        unsafe {
+            let tail = &*self.sq.at::<AtomicU32>(off.tail);
+            let mask = *self.sq.at::<u32>(off.ring_mask);
+            let queued = tail.load(Ordering::Relaxed);
+            let index = queued & mask;
+            let sqe = Sqe {
+                opcode: IORING_OP_READ,
+                flags: 0,
+                ioprio: 0,
+                fd: file.as_raw_fd(),
+                off: offset,
+                addr: buf.as_mut_ptr() as u64,
+                len: buf.len() as u32,
+                rw_flags: 0,
+                user_data,
+                buf_index: 0,
+                personality: 0,
+                splice_fd_in: 0,
+                addr3: 0,
+                pad: 0,
+            };
+            ptr::write(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+            *self.sq.at::<u32>(off.array).add(index as usize) = index;
+            tail.store(queued.wrapping_add(1), Ordering::Release);
+        }
+        self.queued += 1;
+    }
+
+    /// Submit the queued reads, and wait for at least one read to complete.
+    fn wait(&mut self) -> io::Result<()> {
+        loop {
+            let entered = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.queued,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if entered >= 0 {
+                self.queued -= (entered as u32).min(self.queued);
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// The user data and result of the next completed read, if any.
+    fn reap(&mut self) -> Option<(u64, i32)> {
+        let off = &self.params.cq_off;
+        unsafe {
+            let head = &*self.cq.at::<AtomicU32>(off.head);
+            let tail = &*self.cq.at::<AtomicU32>(off.tail);
+            let mask = *self.cq.at::<u32>(off.ring_mask);
+            let reaped = head.load(Ordering::Relaxed);
+            if reaped == tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = ptr::read(self.cq.at::<Cqe>(off.cqes).add((reaped & mask) as usize));
+            head.store(reaped.wrapping_add(1), Ordering::Release);
+            Some((cqe.user_data, cqe.res))
+        }
+    }
+}
+
+/// A block of the file being read into.
+struct Block {
+    buf: Vec<u8>,
+    offset: u64,
+    /// The result of reading the block, once it has been read.
+    read: Option<i32>,
+}
+
+/// A file read from its current position on through a ring of its own, which keeps a read of each
+/// of its blocks in flight at successive offsets ahead of what has been consumed.
+pub(crate) struct UringFile {
+    ring: Ring,
+    file: fs::File,
+    blocks: Vec<Block>,
+    /// The blocks in flight or read and not yet consumed, in the order of their offsets.
+    order: VecDeque<usize>,
+    /// How much of the first block in `order` has been consumed.
+    pos: usize,
+    /// The offset of the next block to read, once reading has started.
+    next: Option<u64>,
+}
+
+impl UringFile {
+    /// Read `file` through `ring`, keeping as many blocks in flight as the ring has room for.
+    pub(crate) fn new(ring: Ring, file: fs::File) -> UringFile {
+        let blocks = (0..ring.entries())
+            .map(|_| Block {
+                buf: vec![0; BLOCK_SIZE],
+                offset: 0,
+                read: None,
+            })
+            .collect();
+        UringFile {
+            ring,
+            file,
+            blocks,
+            order: VecDeque::new(),
+            pos: 0,
+            next: None,
+        }
+    }
+
+    /// Queue a read of the next block of the file into block `i`.
+    fn queue(&mut self, i: usize, offset: u64) {
+        let block = &mut self.blocks[i];
+        block.offset = offset;
+        block.read = None;
+        self.ring
+            .queue_read(&self.file, &mut block.buf, offset, i as u64);
+        self.order.push_back(i);
+    }
+
+    /// Queue reads of the blocks from `offset` on into every block.
+    fn queue_all(&mut self, offset: u64) {
+        for i in 0..self.blocks.len() {
+            self.queue(i, offset + (i * BLOCK_SIZE) as u64);
+        }
+        self.next = Some(offset + (self.blocks.len() * BLOCK_SIZE) as u64);
+    }
+
+    /// Wait for at least one read to complete, and record the result of each that has.
+    fn wait(&mut self) -> io::Result<()> {
+        self.ring.wait()?;
+        while let Some((i, read)) = self.ring.reap() {
+            self.blocks[i as usize].read = Some(read);
+        }
+        Ok(())
+    }
+
+    /// Wait for every read in flight to complete, and discard them.
+    fn discard(&mut self) -> io::Result<()> {
+        while self.order.iter().any(|&i| self.blocks[i].read.is_none()) {
+            self.wait()?;
+        }
+        self.order.clear();
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl io::Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.next.is_none() {
+            let offset = self.file.stream_position()?;
+            self.queue_all(offset);
+        }
+        loop {
+            let i = match self.order.front() {
+                Some(&i) => i,
+                None => return Ok(0),
+            };
+            let (offset, read) = match self.blocks[i].read {
+                Some(read) => (self.blocks[i].offset, read),
+                None => {
+                    self.wait()?;
+                    continue;
+                }
+            };
+            if read < 0 {
+                let err = io::Error::from_raw_os_error(-read);
+                if err.kind() == io::ErrorKind::Interrupted || read == -libc::EAGAIN {
+                    let block = &mut self.blocks[i];
+                    block.read = None;
+                    self.ring
+                        .queue_read(&self.file, &mut block.buf, offset, i as u64);
+                    continue;
+                }
+                self.discard()?;
+                return Err(err);
+            }
+            let n = read as usize;
+            if self.pos < n {
+                let copied = buf.len().min(n - self.pos);
+                buf[..copied].copy_from_slice(&self.blocks[i].buf[self.pos..self.pos + copied]);
+                self.pos += copied;
+                return Ok(copied);
+            }
+            self.order.pop_front();
+            self.pos = 0;
+            if n == 0 {
+                self.discard()?;
+            } else if n < BLOCK_SIZE {
+                // The reads after a short one started past what it read, so read on from where
+                // it ended instead.
+                self.discard()?;
+                self.queue_all(offset + n as u64);
+            } else {
+                let next = self.next.unwrap_or_default();
+                self.queue(i, next);
+                self.next = Some(next + BLOCK_SIZE as u64);
+            }
+        }
+    }
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        // The kernel may still be reading into the blocks in flight, so they are leaked if the
+        // reads can't be waited for.
+        if self.discard().is_err() {
+            mem::forget(mem::take(&mut self.blocks));
+        }
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Heap;
+    use std::io::Read;
+
+    #[test]
+    fn test_uring_file() -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "merge-sorted-files-rs-test-uring-{}",
+            std::process::id()
+        ));
+        let contents: String = (0..50000).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&path, &contents)?;
+        let ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            // io_uring isn't available here, so there is nothing to test.
+            Err(_) => return fs::remove_file(&path),
+        };
+        let mut file = fs::File::open(&path)?;
+        file.seek(io::SeekFrom::Start(5))?;
+        let mut read = String::new();
+        UringFile::new(ring, file).read_to_string(&mut read)?;
+        assert_eq!(read, contents[5..]);
+
+        // Dropping a file with reads in flight waits for them.
+        let mut file = UringFile::new(Ring::new(4)?, fs::File::open(&path)?);
+        let mut start = [0; 4];
+        file.read_exact(&mut start)?;
+        assert_eq!(&start, b"line");
+        drop(file);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_io_uring() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir();
+        let path1 = dir.join(format!(
+            "merge-sorted-files-rs-test-io-uring1-{}",
+            std::process::id()
+        ));
+        let path2 = dir.join(format!(
+            "merge-sorted-files-rs-test-io-uring2-{}",
+            std::process::id()
+        ));
+        fs::write(&path1, "a\nc\ne\n")?;
+        fs::write(&path2, "b\nd\n")?;
+        let mut heap: Heap<Box<dyn io::Read + Send>> =
+            Heap::new().with_io_uring(2).with_start_line("b");
+        heap.add_file(&path1)?;
+        heap.add_file(&path2)?;
+        let lines = heap.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, vec!["b", "c", "d", "e"]);
+        fs::remove_file(&path1)?;
+        fs::remove_file(&path2)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! Merging live inputs that may never end, by timestamp, as far as a watermark allows rather than
+//! waiting on the slowest of them.
+
+use std::cmp;
+use std::collections;
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use crate::error;
+use crate::{write_record, Heap, Line, LineEnding};
+
+/// What the thread reading an input sends the merge.
+enum Event {
+    Line(usize, Line<Option<i64>>),
+    Ended(usize),
+    Failed(usize, io::Error),
+}
+
+/// The latest timestamp read from an input.
+#[derive(Clone, Copy)]
+enum Latest {
+    /// Nothing with a timestamp has been read from it yet.
+    Nothing,
+    At(i64),
+    Ended,
+}
+
+/// A line waiting for the watermark to pass it. Lines are ordered by timestamp, and then in the
+/// order they were read.
+struct Waiting {
+    line: Line<Option<i64>>,
+    read: u64,
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Waiting) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Waiting {}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Waiting) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Waiting) -> cmp::Ordering {
+        (self.line.key, self.read).cmp(&(other.line.key, other.read))
+    }
+}
+
+/// The lines of a heap keyed by timestamps in nanoseconds, e.g. by `TimeKey`, merged by
+/// `Heap::into_watermarked` as they arrive.
+pub struct WatermarkMerge {
+    events: mpsc::Receiver<Event>,
+    names: Vec<String>,
+    latest: Vec<Latest>,
+    /// How far behind the latest timestamp of the slowest input the watermark is, in nanoseconds.
+    lateness: i64,
+    waiting: collections::BinaryHeap<cmp::Reverse<Waiting>>,
+    read: u64,
+    header: Vec<Line<()>>,
+    delimiter: u8,
+    eol: LineEnding,
+}
+
+impl<T> Heap<T, Option<i64>>
+where
+    T: io::Read + Send + 'static,
+{
+    /// Merge the lines of inputs that may never end, e.g. followed files or commands, by their
+    /// timestamps, without waiting on each input in turn as the merge otherwise does. Each input
+    /// is read on a thread of its own, and lines are held until the watermark, the latest
+    /// timestamp read from the slowest input less `lateness`, passes them. So inputs may be out of
+    /// order by up to `lateness`; a line later than that is merged as soon as it is read, out of
+    /// order. Lines without a timestamp are merged as soon as they are read, and the rest of the
+    /// lines once every input has ended. The heap's settings for reading lines, e.g. its filter,
+    /// apply, while those for merging them, e.g. `dedup`, don't.
+    pub fn into_watermarked(self, lateness: time::Duration) -> io::Result<WatermarkMerge> {
+        let Heap {
+            merge,
+            header,
+            emit_header,
+            format,
+            eol,
+            ..
+        } = self;
+        let (peeked, sources) = merge.into_sources()?;
+        let (sender, events) = mpsc::channel();
+        let mut names = Vec::with_capacity(sources.len());
+        for (index, (name, lines, mut source)) in sources.into_iter().enumerate() {
+            names.push(name.to_string());
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for line in lines {
+                    if sender.send(Event::Line(index, line)).is_err() {
+                        return;
+                    }
+                }
+                loop {
+                    let event = match crate::SortedSource::next(&mut source) {
+                        Ok(Some(line)) => Event::Line(index, line),
+                        Ok(None) => Event::Ended(index),
+                        Err(err) => Event::Failed(index, err),
+                    };
+                    let last = !matches!(event, Event::Line(..));
+                    if sender.send(event).is_err() || last {
+                        return;
+                    }
+                }
+            });
+        }
+        let mut merge = WatermarkMerge {
+            events,
+            latest: vec![Latest::Nothing; names.len()],
+            names,
+            lateness: i64::try_from(lateness.as_nanos()).unwrap_or(i64::MAX),
+            waiting: collections::BinaryHeap::new(),
+            read: 0,
+            header: if emit_header { header } else { Vec::new() },
+            delimiter: format.delimiter,
+            eol,
+        };
+        if let Some(line) = peeked {
+            merge.wait(line);
+        }
+        Ok(merge)
+    }
+}
+
+impl WatermarkMerge {
+    /// The timestamp up to which lines are merged, if every input has read one or ended. Once
+    /// every input has ended, it is past every line.
+    fn watermark(&self) -> Option<i64> {
+        let mut slowest = i64::MAX;
+        for latest in &self.latest {
+            match latest {
+                Latest::Nothing => return None,
+                Latest::At(time) => slowest = slowest.min(*time),
+                Latest::Ended => {}
+            }
+        }
+        if self
+            .latest
+            .iter()
+            .all(|latest| matches!(latest, Latest::Ended))
+        {
+            return Some(i64::MAX);
+        }
+        Some(slowest.saturating_sub(self.lateness))
+    }
+
+    fn wait(&mut self, line: Line<Option<i64>>) {
+        self.read += 1;
+        self.waiting.push(cmp::Reverse(Waiting {
+            line,
+            read: self.read,
+        }));
+    }
+
+    /// The next line the watermark has passed, calling `idle` before waiting for one to be read.
+    fn next_line<F>(&mut self, mut idle: F) -> Option<io::Result<Line<Option<i64>>>>
+    where
+        F: FnMut() -> io::Result<()>,
+    {
+        loop {
+            if let Some(cmp::Reverse(first)) = self.waiting.peek() {
+                let passed = match (first.line.key, self.watermark()) {
+                    (None, _) => true,
+                    (Some(time), Some(watermark)) => time <= watermark,
+                    (Some(_), None) => false,
+                };
+                if passed {
+                    let cmp::Reverse(Waiting { line, .. }) = self.waiting.pop()?;
+                    return Some(Ok(line));
+                }
+            }
+            if self
+                .latest
+                .iter()
+                .all(|latest| matches!(latest, Latest::Ended))
+            {
+                return None;
+            }
+            let event = match self.events.try_recv() {
+                Ok(event) => event,
+                Err(_) => {
+                    if let Err(err) = idle() {
+                        return Some(Err(err));
+                    }
+                    match self.events.recv() {
+                        Ok(event) => event,
+                        // Every input has ended with a panic.
+                        Err(_) => {
+                            self.latest.fill(Latest::Ended);
+                            continue;
+                        }
+                    }
+                }
+            };
+            match event {
+                Event::Line(index, line) => {
+                    if let Some(time) = line.key {
+                        self.latest[index] = match self.latest[index] {
+                            Latest::At(latest) => Latest::At(latest.max(time)),
+                            _ => Latest::At(time),
+                        };
+                    }
+                    self.wait(line);
+                }
+                Event::Ended(index) => self.latest[index] = Latest::Ended,
+                Event::Failed(index, err) => {
+                    self.latest[index] = Latest::Ended;
+                    return Some(Err(error::in_file(err, &self.names[index])));
+                }
+            }
+        }
+    }
+
+    /// Write the merged lines as `Heap::write_sorted_lines` does, as they are merged: the output
+    /// is flushed whenever the merge waits for lines to be read.
+    pub fn write_lines<W: io::Write>(&mut self, w: W) -> io::Result<u64> {
+        let mut w = io::BufWriter::new(w);
+        for header in &self.header {
+            write_record(&mut w, header, self.delimiter, self.eol)?;
+        }
+        let mut count = 0;
+        while let Some(line) = self.next_line(|| w.flush()) {
+            write_record(&mut w, &line?, self.delimiter, self.eol)?;
+            count += 1;
+        }
+        w.flush()?;
+        Ok(count)
+    }
+}
+
+impl Iterator for WatermarkMerge {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        self.next_line(|| Ok(()))
+            .map(|line| line.map(|Line { text, .. }| text))
+    }
+}
+
+#[allow(clippy::string_lit_as_bytes)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    type Reader = Box<dyn io::Read + Send>;
+
+    fn timestamp(line: &str) -> Option<i64> {
+        line.split(' ').next()?.parse().ok()
+    }
+
+    #[test]
+    fn test_into_watermarked() -> Result<(), io::Error> {
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        heap.add_reader("file1".to_string(), Box::new("1 a\n4 d\n3 c\n".as_bytes()))?;
+        heap.add_reader("file2".to_string(), Box::new("2 b\nnone\n5 e\n".as_bytes()))?;
+        let merged = heap.into_watermarked(time::Duration::from_nanos(1))?;
+        let lines = merged.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines.len(), 6);
+        let timestamped: Vec<_> = lines.iter().filter(|line| *line != "none").collect();
+        assert_eq!(timestamped, ["1 a", "2 b", "3 c", "4 d", "5 e"]);
+
+        // Lines the watermark has passed are merged while an input has yet to end.
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "echo 1 a; echo 3 c; sleep 2"]);
+        heap.add_command(command)?;
+        heap.add_reader("file2".to_string(), Box::new("2 b\n4 d\n".as_bytes()))?;
+        let started = time::Instant::now();
+        let mut merged = heap.into_watermarked(time::Duration::ZERO)?;
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            out.push(merged.next().unwrap()?);
+        }
+        assert_eq!(out, ["1 a", "2 b", "3 c"]);
+        assert!(started.elapsed() < time::Duration::from_millis(1500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_watermarked_edges() -> Result<(), io::Error> {
+        // Empty inputs end the merge, rather than holding the watermark back.
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        heap.add_reader("file1".to_string(), Box::new("".as_bytes()))?;
+        heap.add_reader("file2".to_string(), Box::new("".as_bytes()))?;
+        let mut merged = heap.into_watermarked(time::Duration::ZERO)?;
+        assert!(merged.next().is_none());
+
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        heap.add_reader("file1".to_string(), Box::new("".as_bytes()))?;
+        heap.add_reader("file2".to_string(), Box::new("1 a\n2 b\n".as_bytes()))?;
+        let lines = heap
+            .into_watermarked(time::Duration::ZERO)?
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, ["1 a", "2 b"]);
+
+        // Lines with equal timestamps are merged in the order they were read.
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        heap.add_reader("file1".to_string(), Box::new("1 b\n1 a\n1 c\n".as_bytes()))?;
+        let lines = heap
+            .into_watermarked(time::Duration::from_secs(1))?
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, ["1 b", "1 a", "1 c"]);
+
+        // A line later than the lateness is merged as soon as it is read, out of order.
+        let mut heap = Heap::<Reader, _>::with_key(timestamp);
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "echo 5 a; echo 9 c; sleep 1; echo 1 late; sleep 1"]);
+        heap.add_command(command)?;
+        heap.add_reader("file2".to_string(), Box::new("".as_bytes()))?;
+        let mut merged = heap.into_watermarked(time::Duration::from_nanos(2))?;
+        let mut out = Vec::new();
+        for _ in 0..2 {
+            out.push(merged.next().unwrap()?);
+        }
+        assert_eq!(out, ["5 a", "1 late"]);
+        assert_eq!(merged.collect::<io::Result<Vec<_>>>()?, ["9 c"]);
+
+        // A header is written once, and lines are counted without it.
+        let mut heap = Heap::<Reader, _>::with_key(timestamp).with_headers(true);
+        heap.add_reader("file1".to_string(), Box::new("time\n2 b\n".as_bytes()))?;
+        heap.add_reader("file2".to_string(), Box::new("time\n1 a\n".as_bytes()))?;
+        let mut out = Vec::new();
+        let count = heap
+            .into_watermarked(time::Duration::ZERO)?
+            .write_lines(&mut out)?;
+        assert_eq!(count, 2);
+        assert_eq!(out, b"time\n1 a\n2 b\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,760 @@
+//! Tests of the command-line tool, run as a separate process on files in a temporary directory.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+/// A directory of its own for the test `name`, emptied first.
+fn temp_dir(name: &str) -> io::Result<path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "merge-sorted-files-rs-cli-{}-{}",
+        name,
+        process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn command(dir: &path::Path) -> process::Command {
+    let mut command = process::Command::new(env!("CARGO_BIN_EXE_merge-sorted-files-rs"));
+    command.current_dir(dir);
+    command
+}
+
+/// Run the tool in `dir` with `args`, and `stdin` as its standard input, which it may exit
+/// without reading.
+fn run(dir: &path::Path, args: &[&str], stdin: &str) -> io::Result<process::Output> {
+    let mut child = command(dir)
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(stdin.as_bytes());
+    match written {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+        _ => {}
+    }
+    child.wait_with_output()
+}
+
+fn stdout(output: &process::Output) -> &str {
+    std::str::from_utf8(&output.stdout).expect("the output is UTF-8")
+}
+
+#[test]
+fn test_color() -> Result<(), io::Error> {
+    let dir = temp_dir("color")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("b"), "2\n")?;
+    let plain = "1\n2\n3\n";
+    let colored = "\x1b[31m1\x1b[0m\n\x1b[32m2\x1b[0m\n\x1b[31m3\x1b[0m\n";
+    // The output here is a pipe rather than a terminal, so `auto` doesn't color it.
+    for (args, expected) in [
+        (&["--color", "always", "a", "b"][..], colored),
+        (&["--color", "never", "a", "b"][..], plain),
+        (&["--color", "auto", "a", "b"][..], plain),
+        (&["--color", "a", "b"][..], plain),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+    }
+    let output = run(&dir, &["--color", "always", "-H", "a", "b"], "")?;
+    assert_eq!(
+        stdout(&output),
+        "\x1b[31ma\x1b[0m:1\n\x1b[32mb\x1b[0m:2\n\x1b[31ma\x1b[0m:3\n"
+    );
+    // Counting lines writes no source to color them by.
+    let output = run(&dir, &["--color", "always", "--count", "a", "b"], "")?;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stdout(&run(&dir, &["--color", "auto", "--count", "a", "b"], "")?),
+        "      1 1\n      1 2\n      1 3\n"
+    );
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_follow_with_color_and_tag_source() -> Result<(), io::Error> {
+    let dir = temp_dir("follow-color")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("b"), "2\n")?;
+    for args in [
+        &["--follow", "--color", "always", "a", "b"][..],
+        &["--follow", "-H", "a", "b"][..],
+    ] {
+        let mut child = command(&dir)
+            .args(args)
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        // The lines are written as they are merged, although the inputs never end.
+        let out = child.stdout.take().expect("stdout is piped");
+        let (send, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::BufReader::new(out).lines() {
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let timeout = time::Duration::from_secs(10);
+        let (first, second) = (lines.recv_timeout(timeout), lines.recv_timeout(timeout));
+        child.kill()?;
+        child.wait()?;
+        let (first, second) = (
+            first.expect("a line is written")?,
+            second.expect("a line is written")?,
+        );
+        assert!(first.contains('1') && second.contains('2'), "{:?}", args);
+    }
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_exit_codes() -> Result<(), io::Error> {
+    let dir = temp_dir("exit-codes")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("b"), "2\n")?;
+    fs::write(dir.join("unsorted"), "2\n1\n")?;
+    fs::write(dir.join("-u"), "0\n")?;
+    for (args, code) in [
+        (&["a", "b"][..], 0),
+        (&["a", "unsorted"][..], 1),
+        (&["--uniqe", "a", "b"][..], 2),
+        (&["-x", "a"][..], 2),
+        (&["--head", "ten", "a"][..], 2),
+        (&["a", "missing"][..], 3),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert_eq!(output.status.code(), Some(code), "{:?}", args);
+    }
+    // A file `--check` can't open is named, as when merging.
+    let output = run(&dir, &["--check", "a", "missing"], "")?;
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[missing]"), "{}", stderr);
+    // After `--`, arguments are inputs even if they look like options.
+    let output = run(&dir, &["a", "--", "-u"], "")?;
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "0\n1\n3\n");
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_binary_errors() -> Result<(), io::Error> {
+    let dir = temp_dir("binary-errors")?;
+    fs::write(dir.join("whole"), b"\x01\0\0\0a")?;
+    fs::write(dir.join("truncated"), b"\x05\0\0\0abc")?;
+    // A record cut short is bad data, not a failure to read, and the file is named once.
+    let output = run(&dir, &["--binary", "whole", "truncated"], "")?;
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("[truncated]").count(), 1, "{}", stderr);
+    let output = run(&dir, &["--binary", "whole", "missing"], "")?;
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("[missing]").count(), 1, "{}", stderr);
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_sstable_order() -> Result<(), io::Error> {
+    let dir = temp_dir("sstable-order")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("unsorted"), "2\n1\n")?;
+    // Tables are scanned in byte order, so no other order can be merged into one.
+    for (args, flag) in [
+        (&["-n", "a"][..], "-n"),
+        (&["-k", "1", "a"][..], "-k"),
+        (&["-r", "a"][..], "--reverse"),
+    ] {
+        let output = run(&dir, &[&["--sstable", "-o", "table"], args].concat(), "")?;
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = format!("--sstable cannot be combined with {}", flag);
+        assert!(stderr.contains(&message), "{}", stderr);
+    }
+    // Lines emitted out of order are bad data.
+    let args = [
+        "--sstable",
+        "--out-of-order",
+        "emit",
+        "-o",
+        "table",
+        "a",
+        "unsorted",
+    ];
+    let output = run(&dir, &args, "")?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!dir.join("table").exists());
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_checksum() -> Result<(), io::Error> {
+    let dir = temp_dir("checksum")?;
+    fs::write(dir.join("a"), "a\nc\n")?;
+    fs::write(dir.join("b"), "b\n")?;
+    fs::write(dir.join("empty"), "")?;
+    // The digest is of the merged output, named as `sha256sum` names it.
+    let args = [
+        "--checksum",
+        "sha256",
+        "--checksum-file",
+        "sums",
+        "-o",
+        "out",
+    ];
+    let output = run(&dir, &[&args[..], &["a", "b"]].concat(), "")?;
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(dir.join("sums"))?,
+        "880553fca8fcea94e325ee2cfb48e5a985cc797f39a14cc6d3cedecfeb2ae4d2  out\n"
+    );
+    // Including when there is nothing to merge, with the digest going to standard error.
+    let output = run(&dir, &["--checksum", "sha256", "empty"], "")?;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  -\n"
+    );
+    let output = run(&dir, &["--checksum-file", "sums", "a"], "")?;
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(&dir, &["--checksum", "md5", "a"], "")?;
+    assert_eq!(output.status.code(), Some(2));
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn test_watermark() -> Result<(), io::Error> {
+    let dir = temp_dir("watermark")?;
+    fs::write(dir.join("a"), "2024-01-01T00:00:02Z a\n")?;
+    for (args, message) in [
+        (
+            &["--watermark", "1", "a"][..],
+            "--watermark requires --time-key",
+        ),
+        (
+            &["--watermark", "1", "--time-key", "rfc3339", "-u", "a"][..],
+            "--watermark cannot be combined with --unique",
+        ),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{}", stderr);
+    }
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_genomic() -> Result<(), io::Error> {
+    let dir = temp_dir("genomic")?;
+    fs::write(
+        dir.join("plain.vcf"),
+        "##fileformat=VCFv4.2\n#CHROM\tPOS\nchr10\t1\nchr2\t1\n",
+    )?;
+    fs::write(
+        dir.join("contigs.vcf"),
+        "##fileformat=VCFv4.2\n##contig=<ID=chr2>\n##contig=<ID=chr10>\n#CHROM\tPOS\nchr10\t5\n",
+    )?;
+    // Without `##contig` lines in the first input, contigs are ordered by name, whatever the
+    // headers of the other inputs say, and the headers are merged.
+    let output = run(&dir, &["--genomic", "vcf", "plain.vcf", "contigs.vcf"], "")?;
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        stdout(&output),
+        "##fileformat=VCFv4.2\n##contig=<ID=chr2>\n##contig=<ID=chr10>\n#CHROM\tPOS\n\
+         chr10\t1\nchr10\t5\nchr2\t1\n"
+    );
+    // With them, an input sorted by name is out of order.
+    let output = run(&dir, &["--genomic", "vcf", "contigs.vcf", "plain.vcf"], "")?;
+    assert_eq!(output.status.code(), Some(1));
+    // The contigs can't be read from standard input before merging it.
+    let output = run(&dir, &["--genomic", "vcf", "-"], "chr1\t1\n")?;
+    assert_eq!(output.status.code(), Some(2));
+    // Inputs whose columns differ can't be merged.
+    fs::write(dir.join("id.vcf"), "#CHROM\tPOS\tID\nchr2\t2\t.\n")?;
+    let output = run(&dir, &["--genomic", "vcf", "plain.vcf", "id.vcf"], "")?;
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[id.vcf]"), "{}", stderr);
+
+    // BED intervals with the same start sort by their ends.
+    fs::write(dir.join("a.bed"), "chr1\t5\t6\nchr1\t5\t9\n")?;
+    fs::write(dir.join("b.bed"), "chr1\t5\t7\nchr1\t6\t7\n")?;
+    let output = run(&dir, &["--genomic", "bed", "a.bed", "b.bed"], "")?;
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        stdout(&output),
+        "chr1\t5\t6\nchr1\t5\t7\nchr1\t5\t9\nchr1\t6\t7\n"
+    );
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "object-store")]
+#[test]
+fn test_object_uris() -> Result<(), io::Error> {
+    let dir = temp_dir("object-uris")?;
+    let output = run(&dir, &["object://bucket/a"], "")?;
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("requires --object-store-endpoint"),
+        "{}",
+        stderr
+    );
+    // Nothing claims to be S3 itself, so an `s3://` URI is just a name.
+    let output = run(
+        &dir,
+        &["--object-store-endpoint", "http://localhost:1", "s3://a"],
+        "",
+    )?;
+    assert_eq!(output.status.code(), Some(3));
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_help() -> Result<(), io::Error> {
+    let dir = temp_dir("help")?;
+    // Nothing after `--help` is read, and no input is merged.
+    let output = run(&dir, &["a", "--help", "--uniqe"], "")?;
+    assert!(output.status.success());
+    assert!(
+        stdout(&output).starts_with("Usage: "),
+        "{}",
+        stdout(&output)
+    );
+    assert!(stdout(&output).contains("--max-fan-in=N"));
+    assert!(output.stderr.is_empty());
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_key_modes() -> Result<(), io::Error> {
+    let dir = temp_dir("key-modes")?;
+    fs::write(dir.join("a"), "1\n")?;
+    // The flags that choose how lines are compared are named as they were given.
+    for (args, message) in [
+        (
+            &["-n", "--key-regex", "(.)", "a"][..],
+            "-n, --key-regex cannot be combined",
+        ),
+        (
+            &["-f", "-k", "1", "--key-regex", "(.)", "a"][..],
+            "-k, --key-regex cannot be combined",
+        ),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{}", stderr);
+    }
+    assert!(run(&dir, &["--check", "-n", "-k", "1", "a"], "")?
+        .status
+        .success());
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_error_format_json() -> Result<(), io::Error> {
+    let dir = temp_dir("error-format-json")?;
+    fs::write(dir.join("unsorted"), "2\n1\n")?;
+    let error = |args: &[&str]| -> io::Result<serde_json::Value> {
+        let output = run(&dir, &[&["--error-format", "json"], args].concat(), "")?;
+        Ok(serde_json::from_slice(&output.stderr)?)
+    };
+    assert_eq!(
+        error(&["--uniqe"])?,
+        serde_json::json!({
+            "kind": "usage",
+            "message": "Unknown option [--uniqe]",
+            "exit_code": 2,
+        })
+    );
+    let missing = error(&["missing"])?;
+    assert_eq!(
+        (&missing["kind"], &missing["exit_code"], &missing["file"]),
+        (&"io".into(), &3.into(), &"missing".into())
+    );
+    let unsorted = error(&["unsorted"])?;
+    assert_eq!(
+        (&unsorted["kind"], &unsorted["exit_code"]),
+        (&"out_of_order".into(), &1.into())
+    );
+    assert_eq!(
+        (&unsorted["file"], &unsorted["line"]),
+        (&"unsorted".into(), &2.into())
+    );
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_gnu_option_spellings() -> Result<(), io::Error> {
+    let dir = temp_dir("gnu-spellings")?;
+    fs::write(dir.join("a"), "x,1\ny,10\n")?;
+    fs::write(dir.join("b"), "z,2\n")?;
+    // The spellings `sort` accepts for the same key and separator.
+    for args in [
+        &["-t", ",", "-k", "2,2n"][..],
+        &["-t,", "-k2,2n"][..],
+        &["--field-separator=,", "--key=2,2n"][..],
+        &["--field-separator", ",", "--key", "2,2n"][..],
+        &["-t,", "-nk2,2"][..],
+        &["-t,", "-nk", "2,2"][..],
+    ] {
+        let output = run(&dir, &[args, &["a", "b"]].concat(), "")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), "x,1\nz,2\ny,10\n", "{:?}", args);
+    }
+    fs::write(dir.join("c"), "10\n2\n")?;
+    fs::write(dir.join("d"), "3\n")?;
+    for args in [
+        &["-n", "-r", "-o", "out"][..],
+        &["-nr", "-oout"][..],
+        &["-rn", "--output=out"][..],
+        &["--numeric-sort", "--reverse", "--output", "out"][..],
+    ] {
+        let _ = fs::remove_file(dir.join("out"));
+        let output = run(&dir, &[args, &["c", "d"]].concat(), "")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(
+            fs::read_to_string(dir.join("out"))?,
+            "10\n3\n2\n",
+            "{:?}",
+            args
+        );
+    }
+    // Options that take no value don't take an attached one either.
+    for args in [&["--unique=yes", "c"][..], &["-nq", "c"][..], &["-k"][..]] {
+        assert_eq!(run(&dir, args, "")?.status.code(), Some(2), "{:?}", args);
+    }
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn test_log_formats() -> Result<(), io::Error> {
+    let dir = temp_dir("log-formats")?;
+    fs::write(
+        dir.join("a"),
+        "May  1 12:00:00 host a: 1\nMay  1 12:00:02 host a: 3\n",
+    )?;
+    fs::write(dir.join("b"), "bad line\n<13>May  1 12:00:01 host b: 2\n")?;
+    let output = run(&dir, &["--format", "syslog", "a", "b"], "")?;
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "bad line\nMay  1 12:00:00 host a: 1\n<13>May  1 12:00:01 host b: 2\n\
+         May  1 12:00:02 host a: 3\n"
+    );
+    fs::write(
+        dir.join("c"),
+        "::1 - - [10/Oct/2000:13:55:36 -0700] \"GET /1\" 200 1\n",
+    )?;
+    fs::write(
+        dir.join("d"),
+        "::1 - - [10/Oct/2000:22:55:35 +0200] \"GET /0\" 200 1\n",
+    )?;
+    let output = run(&dir, &["--format", "clf", "c", "d"], "")?;
+    assert_eq!(
+        stdout(&output),
+        "::1 - - [10/Oct/2000:22:55:35 +0200] \"GET /0\" 200 1\n\
+         ::1 - - [10/Oct/2000:13:55:36 -0700] \"GET /1\" 200 1\n"
+    );
+    // Each names how timestamps are written, so only one of them can be given.
+    for args in [
+        &["--format", "syslog", "--time-key", "rfc3339", "a"][..],
+        &["--time-key", "rfc3339", "--format", "clf", "a"][..],
+        &["--format", "json", "a"][..],
+    ] {
+        assert_eq!(run(&dir, args, "")?.status.code(), Some(2), "{:?}", args);
+    }
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_bgzf_index() -> Result<(), io::Error> {
+    use merge_sorted_files_rs::BgzfIndex;
+    use std::io::Read;
+
+    let dir = temp_dir("bgzf-index")?;
+    let text: String = (0..30000).map(|i| format!("{:08}\n", i)).collect();
+    fs::write(dir.join("a"), &text)?;
+    let args = [
+        "--compress-output",
+        "bgzf",
+        "--bgzf-index",
+        "out.gzi",
+        "-o",
+        "out.gz",
+        "a",
+    ];
+    assert!(run(&dir, &args, "")?.status.success());
+    let bgzf = fs::read(dir.join("out.gz"))?;
+    let index = BgzfIndex::read_gzi(fs::File::open(dir.join("out.gzi"))?)?;
+    assert!(!index.is_empty());
+    // Every byte a virtual offset points to decompresses from its block to the same line.
+    for line in [0, 12345, 29999] {
+        let line = format!("{:08}\n", line);
+        let virtual_offset = index.virtual_offset(text.find(&line).unwrap() as u64);
+        let (block, within) = (virtual_offset >> 16, (virtual_offset & 0xffff) as usize);
+        let mut data = String::new();
+        flate2::read::MultiGzDecoder::new(&bgzf[block as usize..]).read_to_string(&mut data)?;
+        assert!(data[within..].starts_with(&line), "{:?}", line);
+    }
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_top() -> Result<(), io::Error> {
+    let dir = temp_dir("top")?;
+    fs::write(dir.join("a"), "1\n4\n5\n")?;
+    fs::write(dir.join("b"), "2\n3\n6\n")?;
+    fs::write(dir.join("ra"), "5\n4\n1\n")?;
+    fs::write(dir.join("rb"), "6\n3\n2\n")?;
+    for (args, expected) in [
+        (&["--top", "2", "a", "b"][..], "1\n2\n"),
+        (&["--top", "2", "-r", "ra", "rb"][..], "6\n5\n"),
+        (&["--top=4", "-r", "ra", "rb"][..], "6\n5\n4\n3\n"),
+        (&["--top", "10", "a", "b"][..], "1\n2\n3\n4\n5\n6\n"),
+        (&["--top", "0", "a", "b"][..], ""),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+        // It is the same as --head.
+        let head: Vec<&str> = args
+            .iter()
+            .map(|arg| match *arg {
+                "--top" => "--head",
+                "--top=4" => "--head=4",
+                arg => arg,
+            })
+            .collect();
+        assert_eq!(run(&dir, &head, "")?.stdout, output.stdout, "{:?}", head);
+    }
+    assert_eq!(run(&dir, &["--top", "a", "b"], "")?.status.code(), Some(2));
+    fs::remove_dir_all(&dir)
+}
+
+/// The names of the files in `dir`, sorted.
+fn file_names(dir: &path::Path) -> io::Result<Vec<String>> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn test_atomic_output() -> Result<(), io::Error> {
+    let dir = temp_dir("atomic-output")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("b"), "2\n")?;
+    fs::write(dir.join("unsorted"), "2\n1\n")?;
+    fs::write(dir.join("out"), "old\n")?;
+    // The output replaces the file only once it is complete, leaving no temporary file behind.
+    let output = run(&dir, &["-o", "out", "a", "b"], "")?;
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.join("out"))?, "1\n2\n3\n");
+    assert_eq!(file_names(&dir)?, vec!["a", "b", "out", "unsorted"]);
+    // On an error, the file is kept as it was.
+    fs::write(dir.join("out"), "old\n")?;
+    let output = run(&dir, &["-o", "out", "a", "unsorted"], "")?;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(fs::read_to_string(dir.join("out"))?, "old\n");
+    assert_eq!(file_names(&dir)?, vec!["a", "b", "out", "unsorted"]);
+    let output = run(&dir, &["-o", "new", "a", "missing"], "")?;
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(file_names(&dir)?, vec!["a", "b", "out", "unsorted"]);
+    // An input can be the output, as it is only replaced once it has been read.
+    let output = run(&dir, &["-o", "a", "a", "b"], "")?;
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.join("a"))?, "1\n2\n3\n");
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_stdin() -> Result<(), io::Error> {
+    let dir = temp_dir("stdin")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    for (args, expected) in [
+        (&["-", "a"][..], "1\n2\n3\n4\n"),
+        (&["a", "-"][..], "1\n2\n3\n4\n"),
+        (&["-H", "a", "-"][..], "a:1\n-:2\na:3\n-:4\n"),
+    ] {
+        let output = run(&dir, args, "2\n4\n")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+    }
+    let output = run(&dir, &["-", "a"], "2\n1\n")?;
+    assert_eq!(output.status.code(), Some(1));
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_files_from() -> Result<(), io::Error> {
+    let dir = temp_dir("files-from")?;
+    fs::write(dir.join("a"), "1\n4\n")?;
+    fs::write(dir.join("b c"), "2\n")?;
+    fs::write(dir.join("d\ne"), "3\n")?;
+    fs::write(dir.join("list"), "a\n\nb c\n")?;
+    fs::write(dir.join("list0"), "a\0d\ne\0")?;
+    fs::write(dir.join("empty"), "")?;
+    for (args, stdin, expected) in [
+        (&["--files-from", "list"][..], "", "1\n2\n4\n"),
+        (&["--files-from=list", "d\ne"][..], "", "1\n2\n3\n4\n"),
+        (&["--files-from", "-"][..], "b c\na\n", "1\n2\n4\n"),
+        (&["-0", "--files-from", "list0"][..], "", "1\n3\n4\n"),
+        (&["--null", "--files-from", "-"][..], "b c\0a", "1\n2\n4\n"),
+        (&["--files-from", "empty"][..], "", ""),
+        (&["--files-from", "empty", "b c"][..], "", "2\n"),
+    ] {
+        let output = run(&dir, args, stdin)?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+    }
+    // Without -0, the NUL-separated names run together into names that can't be opened.
+    assert_eq!(
+        run(&dir, &["--files-from", "list0"], "")?.status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        run(&dir, &["--files-from", "missing"], "")?.status.code(),
+        Some(3)
+    );
+    assert_eq!(
+        run(&dir, &["--files-from", "-", "-"], "a\n")?.status.code(),
+        Some(2)
+    );
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_expansion() -> Result<(), io::Error> {
+    let dir = temp_dir("expansion")?;
+    fs::create_dir_all(dir.join("runs/nested"))?;
+    // Every file holds the same line, so the merge keeps the order they were expanded in.
+    for name in [
+        "runs/part-2.txt",
+        "runs/part-10.txt",
+        "runs/part-1.txt",
+        "runs/skip.log",
+        "runs/nested/part-0.txt",
+    ] {
+        fs::write(dir.join(name), "x\n")?;
+    }
+    for (args, expected) in [
+        (
+            &["-H", "runs/part-*.txt"][..],
+            "runs/part-1.txt:x\nruns/part-10.txt:x\nruns/part-2.txt:x\n",
+        ),
+        (
+            &["-H", "--recursive", "runs"][..],
+            "runs/nested/part-0.txt:x\nruns/part-1.txt:x\nruns/part-10.txt:x\nruns/part-2.txt:x\n\
+             runs/skip.log:x\n",
+        ),
+        (
+            &[
+                "-H",
+                "--recursive",
+                "--include",
+                "*.txt",
+                "--exclude",
+                "part-1*",
+                "runs",
+            ][..],
+            "runs/nested/part-0.txt:x\nruns/part-2.txt:x\n",
+        ),
+        (
+            &["-H", "runs/part-2.txt", "runs/part-?.txt"][..],
+            "runs/part-2.txt:x\nruns/part-1.txt:x\nruns/part-2.txt:x\n",
+        ),
+    ] {
+        let output = run(&dir, args, "")?;
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+    }
+    // A glob that matches nothing and a directory without --recursive are usage errors.
+    for args in [&["runs/none-*.txt"][..], &["runs"][..], &["runs/[x"][..]] {
+        let output = run(&dir, args, "")?;
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+    }
+    let output = run(&dir, &["runs/none-*.txt"], "")?;
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No files match [runs/none-*.txt]"));
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_closed_output() -> Result<(), io::Error> {
+    let dir = temp_dir("closed-output")?;
+    // More than a pipe holds, so writing it fails once the reader has gone.
+    let lines: String = (0..200_000).map(|i| format!("{:08}\n", i)).collect();
+    fs::write(dir.join("a"), &lines)?;
+    fs::write(dir.join("b"), "")?;
+    let mut child = command(&dir)
+        .args(["a", "b"])
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    // Like `| head -1`, read a line and close the pipe.
+    let mut first = String::new();
+    io::BufReader::new(child.stdout.take().expect("stdout is piped")).read_line(&mut first)?;
+    assert_eq!(first, "00000000\n");
+    let output = child.wait_with_output()?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    fs::remove_dir_all(&dir)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupted() -> Result<(), io::Error> {
+    let dir = temp_dir("interrupted")?;
+    fs::write(dir.join("a"), "1\n3\n")?;
+    fs::write(dir.join("b"), "2\n")?;
+    let mut child = command(&dir)
+        .args(["--follow", "a", "b"])
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let mut out = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+    // Once all it can merge has been written, it waits for b to grow.
+    let mut merged = String::new();
+    out.read_line(&mut merged)?;
+    out.read_line(&mut merged)?;
+    assert_eq!(merged, "1\n2\n");
+    let status = process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()?;
+    assert!(status.success());
+    // The exit code is that of SIGINT, and the 3 is never merged, as b might yet have lines
+    // before it.
+    let mut rest = String::new();
+    io::Read::read_to_string(&mut out, &mut rest)?;
+    let output = child.wait_with_output()?;
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(rest, "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Interrupted after writing 2 lines\n"
+    );
+    fs::remove_dir_all(&dir)
+}